@@ -6,6 +6,8 @@ using namespace std;
 
 const int header_size = 1024 * 1024;
 const int page_size = 1024 * 4;
+// size of a device node in header, whose offset holds major and minor number
+const ssize_t device_node = -1;
 
 inline unsigned align_val(unsigned val) {
     unsigned o = page_size - 1;
@@ -26,6 +28,17 @@ int main(int argc, char** argv) {
     ssize_t cum_sz = header_size;
     for (int i = 2; i < argc; i++) {
         string filename(argv[i]);
+        // device node is given as dev:<path>:<major>:<minor>
+        if (filename.compare(0, 4, "dev:") == 0) {
+            auto p1 = filename.find(':', 4);
+            auto p2 = filename.find(':', p1 + 1);
+            string fsname = filename.substr(4, p1 - 4);
+            ssize_t major = stol(filename.substr(p1 + 1, p2 - p1 - 1));
+            ssize_t minor = stol(filename.substr(p2 + 1));
+            cout << "Creating device node " << fsname << " (" << major << ", " << minor << ")" << endl;
+            files.push_back({ (major << 32) | minor, device_node, fsname });
+            continue;
+        }
         auto pos = filename.find_last_of('/');
         string fsname = filename.substr(pos, filename.size() - pos);
         cout << "Processing " << filename << " (" << fsname << " in fs)" << endl;
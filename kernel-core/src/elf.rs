@@ -0,0 +1,267 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! ELF parsing
+//!
+//! Headers are read with bounds checked, so that a malformed executable
+//! is rejected with an error instead of being read past its end.
+
+use crate::page::EntryAttributes;
+use crate::mem::is_page_aligned;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ELFHeader {
+    pub magic: u32,
+    pub elf: [u8; 12],
+    pub etype: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub ptype: u32,
+    pub flags: u32,
+    pub off: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+pub const ELF_PROG_LOAD: u32 = 1;
+pub const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub const ELF_PROG_FLAG_WRITE: u32 = 2;
+pub const ELF_PROG_FLAG_READ: u32 = 4;
+pub const ELF_MAGIC: u32 = 0x464C457F;
+
+/// Why an ELF file is rejected
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    /// a header is beyond end of file
+    Truncated,
+    /// wrong magic number
+    Magic,
+    /// segment is smaller in memory than in file
+    Memsz,
+    /// segment wraps around end of address space
+    Vaddr,
+    /// segment doesn't start at a page boundary
+    VaddrAlign,
+    /// segment content is beyond end of file
+    Offset,
+}
+
+/// A loadable segment
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Segment {
+    /// start address in memory, which is page-aligned
+    pub vaddr: usize,
+    /// size in memory
+    pub memsz: usize,
+    /// start of content in file
+    pub offset: usize,
+    /// size of content in file. The rest of segment is zeroed.
+    pub filesz: usize,
+    /// `ELF_PROG_FLAG_*` of segment
+    pub flags: u32,
+}
+
+impl Segment {
+    pub fn is_exec(&self) -> bool {
+        self.flags & ELF_PROG_FLAG_EXEC != 0
+    }
+
+    /// Content of segment in file `a`
+    pub fn content<'a>(&self, a: &'a [u8]) -> &'a [u8] {
+        &a[self.offset..self.offset + self.filesz]
+    }
+}
+
+/// A parsed ELF file
+pub struct Elf {
+    /// entry address
+    pub entry: u64,
+    /// loadable segments, in order of program headers
+    pub segments: Vec<Segment>,
+}
+
+/// Read a `T` at `offset` of `a`
+fn read<T: Copy>(a: &[u8], offset: usize) -> Result<T, ElfError> {
+    match offset.checked_add(size_of::<T>()) {
+        Some(end) if end <= a.len() => {
+            Ok(unsafe { core::ptr::read_unaligned(a.as_ptr().add(offset) as *const T) })
+        }
+        _ => Err(ElfError::Truncated)
+    }
+}
+
+/// Parse ELF file `a`, and check its loadable segments can be loaded
+pub fn parse_elf(a: &[u8]) -> Result<Elf, ElfError> {
+    let elfhdr: ELFHeader = read(a, 0)?;
+    if elfhdr.magic != ELF_MAGIC {
+        return Err(ElfError::Magic);
+    }
+    let mut segments = Vec::new();
+    for i in 0..elfhdr.phnum as usize {
+        let offset = (elfhdr.phoff as usize)
+            .checked_add(i * size_of::<ProgramHeader>())
+            .ok_or(ElfError::Truncated)?;
+        let hdr: ProgramHeader = read(a, offset)?;
+        if hdr.ptype != ELF_PROG_LOAD {
+            continue;
+        }
+        if hdr.memsz < hdr.filesz {
+            return Err(ElfError::Memsz);
+        }
+        if hdr.vaddr.checked_add(hdr.memsz).is_none() {
+            return Err(ElfError::Vaddr);
+        }
+        if !is_page_aligned(hdr.vaddr as usize) {
+            return Err(ElfError::VaddrAlign);
+        }
+        match hdr.off.checked_add(hdr.filesz) {
+            Some(end) if end as usize <= a.len() => {}
+            _ => return Err(ElfError::Offset)
+        }
+        segments.push(Segment {
+            vaddr: hdr.vaddr as usize,
+            memsz: hdr.memsz as usize,
+            offset: hdr.off as usize,
+            filesz: hdr.filesz as usize,
+            flags: hdr.flags,
+        });
+    }
+    Ok(Elf { entry: elfhdr.entry, segments })
+}
+
+/// Page table entry flags of a segment from ELF program header flags
+pub fn segment_flags(flags: u32) -> usize {
+    // a writable page must also be readable on RISC-V
+    let mut bits = EntryAttributes::U as usize | EntryAttributes::R as usize;
+    if flags & ELF_PROG_FLAG_WRITE != 0 {
+        bits |= EntryAttributes::W as usize;
+    }
+    if flags & ELF_PROG_FLAG_EXEC != 0 {
+        bits |= EntryAttributes::X as usize;
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PAGE_SIZE;
+
+    const EHDR_SIZE: usize = size_of::<ELFHeader>();
+    const PHDR_SIZE: usize = size_of::<ProgramHeader>();
+
+    /// Build an ELF file with program headers right after ELF header,
+    /// followed by `data`
+    fn build(entry: u64, phdrs: &[ProgramHeader], data: &[u8]) -> Vec<u8> {
+        let mut a = Vec::new();
+        a.extend_from_slice(&ELF_MAGIC.to_le_bytes());
+        a.resize(24, 0);
+        a.extend_from_slice(&entry.to_le_bytes());
+        a.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        a.resize(56, 0);
+        a.extend_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        a.resize(EHDR_SIZE, 0);
+        for hdr in phdrs {
+            a.extend_from_slice(&hdr.ptype.to_le_bytes());
+            a.extend_from_slice(&hdr.flags.to_le_bytes());
+            for v in &[hdr.off, hdr.vaddr, hdr.paddr, hdr.filesz, hdr.memsz, hdr.align] {
+                a.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        a.extend_from_slice(data);
+        a
+    }
+
+    fn load(off: u64, vaddr: u64, filesz: u64, memsz: u64, flags: u32) -> ProgramHeader {
+        ProgramHeader { ptype: ELF_PROG_LOAD, flags, off, vaddr, paddr: vaddr, filesz, memsz, align: PAGE_SIZE as u64 }
+    }
+
+    /// Test parsing loadable segments, skipping other program headers
+    #[test]
+    fn test_parse() {
+        let data_off = (EHDR_SIZE + PHDR_SIZE * 3) as u64;
+        let mut note = load(0, 0, 0, 0, 0);
+        note.ptype = 4;
+        let text = load(data_off, 0x1000, 8, 8, ELF_PROG_FLAG_READ | ELF_PROG_FLAG_EXEC);
+        let bss = load(data_off + 8, 0x2000, 0, 0x3000, ELF_PROG_FLAG_READ | ELF_PROG_FLAG_WRITE);
+        let a = build(0x1004, &[text, note, bss], b"textdata");
+        let elf = parse_elf(&a).unwrap();
+        assert_eq!(elf.entry, 0x1004);
+        assert_eq!(elf.segments.len(), 2);
+        let seg = elf.segments[0];
+        assert_eq!((seg.vaddr, seg.memsz, seg.filesz), (0x1000, 8, 8));
+        assert!(seg.is_exec());
+        assert_eq!(seg.content(&a), b"textdata");
+        let seg = elf.segments[1];
+        assert_eq!((seg.vaddr, seg.memsz, seg.filesz), (0x2000, 0x3000, 0));
+        assert!(!seg.is_exec());
+        assert_eq!(seg.content(&a), b"");
+    }
+
+    /// Test files cut short are rejected
+    #[test]
+    fn test_truncated() {
+        assert_eq!(parse_elf(&[]).err(), Some(ElfError::Truncated));
+        let a = build(0, &[load(0, 0, 0, 0, 0)], &[]);
+        assert_eq!(parse_elf(&a[..EHDR_SIZE - 1]).err(), Some(ElfError::Truncated));
+        assert_eq!(parse_elf(&a[..a.len() - 1]).err(), Some(ElfError::Truncated));
+        let mut a = a;
+        // program headers beyond end of address space
+        a[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(parse_elf(&a).err(), Some(ElfError::Truncated));
+    }
+
+    /// Test each malformed segment is rejected
+    #[test]
+    fn test_bad_segment() {
+        let check = |hdr: ProgramHeader, err: ElfError| {
+            let a = build(0, &[hdr], &[0; 16]);
+            assert_eq!(parse_elf(&a).err(), Some(err));
+        };
+        let mut a = build(0, &[], &[]);
+        a[0] = 0;
+        assert_eq!(parse_elf(&a).err(), Some(ElfError::Magic));
+        let off = EHDR_SIZE as u64 + PHDR_SIZE as u64;
+        check(load(off, 0x1000, 16, 8, 0), ElfError::Memsz);
+        check(load(off, 0xffff_ffff_ffff_f000, 0, 0x2000, 0), ElfError::Vaddr);
+        check(load(off, 0x1004, 0, 0, 0), ElfError::VaddrAlign);
+        check(load(off + 1, 0x1000, 16, 16, 0), ElfError::Offset);
+        check(load(u64::MAX, 0x1000, 16, 16, 0), ElfError::Offset);
+        // segment ending at end of file is fine
+        let a = build(0, &[load(off, 0x1000, 16, 16, 0)], &[0; 16]);
+        assert!(parse_elf(&a).is_ok());
+    }
+
+    /// Test page flags of segments
+    #[test]
+    fn test_segment_flags() {
+        let ur = EntryAttributes::UR as usize;
+        assert_eq!(segment_flags(ELF_PROG_FLAG_READ), ur);
+        assert_eq!(segment_flags(ELF_PROG_FLAG_WRITE), EntryAttributes::URW as usize);
+        assert_eq!(segment_flags(ELF_PROG_FLAG_READ | ELF_PROG_FLAG_EXEC), EntryAttributes::URX as usize);
+        assert_eq!(segment_flags(0), ur);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! File system format
+//!
+//! Disk image made by `mkfs` starts with a header of `FILE_MAX` entries,
+//! one in each block. An entry holds file size, offset of file content
+//! on disk and a NUL-terminated path. Header ends at first entry of size 0.
+
+use alloc::vec;
+
+/// Size of a block
+pub const BSIZE: usize = 1024;
+
+/// Number of entries in header
+pub const FILE_MAX: usize = 1024;
+
+/// Size field of a device node in file system header. Offset field of
+/// a device node holds its major (high 32 bits) and minor number.
+pub const DEVICE_NODE: usize = usize::MAX;
+
+/// Offset of path in an entry
+const NAME_OFFSET: usize = 16;
+
+/// Largest major or minor number of a device node, each of which has
+/// 32 bits in header
+pub const DEVICE_NUMBER_MAX: usize = 0xffff_ffff;
+
+/// An entry in file system
+#[derive(Debug, PartialEq)]
+pub enum DirEntry {
+    /// regular file of `sz` bytes at `offset` on disk
+    File { offset: usize, sz: usize },
+    /// device node
+    Device { major: usize, minor: usize },
+}
+
+/// A disk holding file system
+pub trait BlockDevice {
+    /// Read block `blockno` into `data`
+    fn read_block(&self, blockno: usize, data: &mut [u8; BSIZE]);
+
+    /// Write `data` to block `blockno`
+    fn write_block(&self, blockno: usize, data: &[u8; BSIZE]);
+
+    /// Read contiguous blocks from `blockno` into `data`
+    fn read_blocks(&self, blockno: usize, data: &mut [[u8; BSIZE]]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            self.read_block(blockno + i, b);
+        }
+    }
+}
+
+fn read_usize(b: &[u8; BSIZE], pos: usize) -> usize {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&b[pos..pos + 8]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+fn write_usize(b: &mut [u8; BSIZE], pos: usize, val: usize) {
+    b[pos..pos + 8].copy_from_slice(&(val as u64).to_le_bytes());
+}
+
+/// Find entry of `path` in file system header. Returns its index and
+/// content, or index of the first free entry if not found.
+pub fn find_entry(dev: &dyn BlockDevice, path: &str) -> Result<(usize, DirEntry), Option<usize>> {
+    let mut b = [0; BSIZE];
+    for id in 0..FILE_MAX {
+        dev.read_block(id, &mut b);
+        let sz = read_usize(&b, 0);
+        let offset = read_usize(&b, 8);
+        if sz == 0 {
+            return Err(Some(id));
+        }
+        let name = &b[NAME_OFFSET..];
+        let name_sz = name.iter().position(|&d| d == 0).unwrap_or(name.len());
+        if &name[..name_sz] == path.as_bytes() {
+            let entry = if sz == DEVICE_NODE {
+                DirEntry::Device { major: offset >> 32, minor: offset & 0xffff_ffff }
+            } else {
+                DirEntry::File { offset, sz }
+            };
+            return Ok((id, entry));
+        }
+    }
+    Err(None)
+}
+
+/// Why a device node can't be created
+#[derive(Debug, PartialEq)]
+pub enum MknodError {
+    /// path is too long, or device number is out of range
+    Invalid,
+    /// path exists
+    Exists,
+    /// there's no room in header
+    NoSpace,
+}
+
+/// Create a device node of `major` and `minor` at `path`.
+pub fn mknod(dev: &dyn BlockDevice, path: &str, major: usize, minor: usize) -> Result<(), MknodError> {
+    if path.len() + 1 > BSIZE - NAME_OFFSET || major > DEVICE_NUMBER_MAX || minor > DEVICE_NUMBER_MAX {
+        return Err(MknodError::Invalid);
+    }
+    let id = match find_entry(dev, path) {
+        Ok(_) => return Err(MknodError::Exists),
+        Err(None) => return Err(MknodError::NoSpace),
+        Err(Some(id)) => id,
+    };
+    let mut b = [0; BSIZE];
+    write_usize(&mut b, 0, DEVICE_NODE);
+    write_usize(&mut b, 8, (major << 32) | minor);
+    b[NAME_OFFSET..NAME_OFFSET + path.len()].copy_from_slice(path.as_bytes());
+    dev.write_block(id, &b);
+    Ok(())
+}
+
+/// Read content of file of `sz` bytes at `offset` on disk, starting from
+/// `off` in file. Returns number of bytes read.
+pub fn read_at(dev: &dyn BlockDevice, offset: usize, sz: usize, off: usize, content: &mut [u8]) -> usize {
+    let end = sz.min(off.saturating_add(content.len()));
+    if off >= end { return 0; }
+    let first = (offset + off) / BSIZE;
+    let last = (offset + end - 1) / BSIZE;
+    let mut bufs = vec![[0; BSIZE]; last - first + 1];
+    dev.read_blocks(first, &mut bufs);
+    let mut pos = off;
+    for b in bufs.iter() {
+        let addr = offset + pos;
+        let blk_off = addr % BSIZE;
+        let sz = (BSIZE - blk_off).min(end - pos);
+        content[pos - off..pos - off + sz].copy_from_slice(&b[blk_off..blk_off + sz]);
+        pos += sz;
+    }
+    pos - off
+}
+
+/// Write `content` to file of `sz` bytes at `offset` on disk, starting
+/// from `off` in file.
+///
+/// As files can't grow on this file system, content beyond file size
+/// will be discarded. Returns number of bytes written.
+pub fn write_at(dev: &dyn BlockDevice, offset: usize, sz: usize, off: usize, content: &[u8]) -> usize {
+    let end = sz.min(off.saturating_add(content.len()));
+    let mut pos = off;
+    let mut b = [0; BSIZE];
+    while pos < end {
+        let addr = offset + pos;
+        let blockno = addr / BSIZE;
+        let blk_off = addr % BSIZE;
+        let sz = (BSIZE - blk_off).min(end - pos);
+        // partially written block is read first
+        if sz != BSIZE {
+            dev.read_block(blockno, &mut b);
+        }
+        b[blk_off..blk_off + sz].copy_from_slice(&content[pos - off..pos - off + sz]);
+        dev.write_block(blockno, &b);
+        pos += sz;
+    }
+    pos - off
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{mkfs, RamDisk};
+
+    /// Test looking up files and device nodes, and free entry after them
+    #[test]
+    fn test_find_entry() {
+        let disk = mkfs(&[("/a", b"hello"), ("/b", &[1; BSIZE * 2])]);
+        let a = find_entry(&disk, "/a").unwrap();
+        assert_eq!(a, (0, DirEntry::File { offset: FILE_MAX * BSIZE, sz: 5 }));
+        let b = find_entry(&disk, "/b").unwrap();
+        assert_eq!(b, (1, DirEntry::File { offset: FILE_MAX * BSIZE + 4096, sz: BSIZE * 2 }));
+        assert_eq!(find_entry(&disk, "/c"), Err(Some(2)));
+        // only whole path matches
+        assert_eq!(find_entry(&disk, "/").err(), Some(Some(2)));
+        assert_eq!(find_entry(&disk, "/ab").err(), Some(Some(2)));
+        assert_eq!(find_entry(&disk, "").err(), Some(Some(2)));
+    }
+
+    /// Test entries with unusual paths
+    #[test]
+    fn test_find_entry_name() {
+        let disk = mkfs(&[]);
+        // path fills entry without terminating NUL
+        let mut b = [b'x'; BSIZE];
+        write_usize(&mut b, 0, 1);
+        write_usize(&mut b, 8, 0);
+        disk.write_block(0, &b);
+        // path which is not UTF-8 is compared as bytes
+        let mut b = [0; BSIZE];
+        write_usize(&mut b, 0, 1);
+        b[NAME_OFFSET..NAME_OFFSET + 3].copy_from_slice(&[b'/', 0xff, 0xfe]);
+        disk.write_block(1, &b);
+        let long = "x".repeat(BSIZE - NAME_OFFSET);
+        assert_eq!(find_entry(&disk, &long).map(|(id, _)| id), Ok(0));
+        assert_eq!(find_entry(&disk, &long[1..]), Err(Some(2)));
+        assert_eq!(find_entry(&disk, "/"), Err(Some(2)));
+    }
+
+    /// Test header without free entry
+    #[test]
+    fn test_full_header() {
+        let disk = RamDisk::new(FILE_MAX);
+        let mut b = [0; BSIZE];
+        write_usize(&mut b, 0, 1);
+        b[NAME_OFFSET] = b'/';
+        for id in 0..FILE_MAX {
+            disk.write_block(id, &b);
+        }
+        assert_eq!(find_entry(&disk, "/"), Ok((0, DirEntry::File { offset: 0, sz: 1 })));
+        assert_eq!(find_entry(&disk, "/a"), Err(None));
+        assert_eq!(mknod(&disk, "/dev/null", 1, 3), Err(MknodError::NoSpace));
+    }
+
+    /// Test creating device nodes
+    #[test]
+    fn test_mknod() {
+        let disk = mkfs(&[("/a", b"hello")]);
+        assert_eq!(mknod(&disk, "/dev/console", 1, 0xffff_ffff), Ok(()));
+        assert_eq!(find_entry(&disk, "/dev/console"), Ok((1, DirEntry::Device { major: 1, minor: 0xffff_ffff })));
+        assert_eq!(mknod(&disk, "/dev/console", 1, 0), Err(MknodError::Exists));
+        assert_eq!(mknod(&disk, "/a", 1, 0), Err(MknodError::Exists));
+        assert_eq!(mknod(&disk, "/dev/big", 1, 0x1_0000_0000), Err(MknodError::Invalid));
+        assert_eq!(mknod(&disk, "/dev/big", 0x1_0000_0000, 1), Err(MknodError::Invalid));
+        assert_eq!(mknod(&disk, "/dev/max", 0xffff_ffff, 0), Ok(()));
+        assert_eq!(find_entry(&disk, "/dev/max"), Ok((2, DirEntry::Device { major: 0xffff_ffff, minor: 0 })));
+        let longest = "x".repeat(BSIZE - NAME_OFFSET - 1);
+        assert_eq!(mknod(&disk, &(longest.clone() + "x"), 2, 0), Err(MknodError::Invalid));
+        assert_eq!(mknod(&disk, &longest, 2, 1), Ok(()));
+        assert_eq!(mknod(&disk, &longest[1..], 2, 2), Ok(()));
+        assert_eq!(find_entry(&disk, &longest), Ok((3, DirEntry::Device { major: 2, minor: 1 })));
+        assert_eq!(find_entry(&disk, &longest[1..]), Ok((4, DirEntry::Device { major: 2, minor: 2 })));
+    }
+
+    /// Test reading within and across blocks, and beyond end of file
+    #[test]
+    fn test_read_at() {
+        let data: alloc::vec::Vec<u8> = (0..BSIZE * 3).map(|i| (i % 251) as u8).collect();
+        let disk = mkfs(&[("/a", &data)]);
+        let (offset, sz) = match find_entry(&disk, "/a").unwrap().1 {
+            DirEntry::File { offset, sz } => (offset, sz),
+            _ => unreachable!(),
+        };
+        let mut content = vec![0; BSIZE * 4];
+        for &(off, len) in &[(0, 1), (3, 10), (BSIZE - 1, 2), (10, BSIZE * 2), (0, BSIZE * 3), (BSIZE * 3 - 1, 1)] {
+            assert_eq!(read_at(&disk, offset, sz, off, &mut content[..len]), len);
+            assert_eq!(&content[..len], &data[off..off + len]);
+        }
+        // reads stop at end of file
+        assert_eq!(read_at(&disk, offset, sz, BSIZE * 2, &mut content), BSIZE);
+        assert_eq!(&content[..BSIZE], &data[BSIZE * 2..]);
+        assert_eq!(read_at(&disk, offset, sz, sz, &mut content), 0);
+        assert_eq!(read_at(&disk, offset, sz, sz + BSIZE, &mut content), 0);
+        assert_eq!(read_at(&disk, offset, sz, usize::MAX, &mut content), 0);
+        assert_eq!(read_at(&disk, offset, sz, 0, &mut []), 0);
+        // only blocks holding content read are read
+        disk.clear_reads();
+        assert_eq!(read_at(&disk, offset, sz, BSIZE - 1, &mut content[..2]), 2);
+        assert_eq!(disk.reads(), [offset / BSIZE, offset / BSIZE + 1]);
+    }
+
+    /// Test writing keeps content around it, and doesn't grow file
+    #[test]
+    fn test_write_at() {
+        let disk = mkfs(&[("/a", &[0; BSIZE * 2 + 10]), ("/b", &[7; 10])]);
+        let (offset, sz) = match find_entry(&disk, "/a").unwrap().1 {
+            DirEntry::File { offset, sz } => (offset, sz),
+            _ => unreachable!(),
+        };
+        assert_eq!(write_at(&disk, offset, sz, BSIZE - 2, &[1; 4]), 4);
+        assert_eq!(write_at(&disk, offset, sz, BSIZE * 2, &[2; 20]), 10);
+        assert_eq!(write_at(&disk, offset, sz, sz, &[3; 1]), 0);
+        assert_eq!(write_at(&disk, offset, sz, sz + 1, &[3; 1]), 0);
+        let mut content = vec![0; sz];
+        assert_eq!(read_at(&disk, offset, sz, 0, &mut content), sz);
+        let mut expected = vec![0; sz];
+        expected[BSIZE - 2..BSIZE + 2].copy_from_slice(&[1; 4]);
+        expected[BSIZE * 2..].copy_from_slice(&[2; 10]);
+        assert_eq!(content, expected);
+        // next file is untouched
+        let b = find_entry(&disk, "/b").unwrap().1;
+        assert_eq!(b, DirEntry::File { offset: offset + 4096, sz: 10 });
+        let mut content = [0; 10];
+        assert_eq!(read_at(&disk, offset + 4096, 10, 0, &mut content), 10);
+        assert_eq!(content, [7; 10]);
+        // whole block is written without being read
+        disk.clear_reads();
+        assert_eq!(write_at(&disk, offset, sz, BSIZE, &[5; BSIZE]), BSIZE);
+        assert!(disk.reads().is_empty());
+    }
+}
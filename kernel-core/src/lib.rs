@@ -0,0 +1,28 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Architecture-independent kernel logic
+//!
+//! Code here doesn't touch hardware, so it is built into kernel and also
+//! tested on host with `make unittest`. Tests run with mock page memory
+//! and in-memory block devices.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod elf;
+pub mod fs;
+pub mod mem;
+pub mod page;
+
+#[cfg(test)]
+mod mock;
+
+/// Page order
+pub const PAGE_ORDER: usize = 12;
+
+/// Page size
+pub const PAGE_SIZE: usize = 1 << PAGE_ORDER;
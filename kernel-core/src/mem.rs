@@ -0,0 +1,340 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Frame allocator
+
+use core::ops::Range;
+use core::mem::size_of;
+use core::fmt;
+use crate::{PAGE_ORDER, PAGE_SIZE};
+
+/// Frame allocator gives out one or more pages.
+///
+/// Memory is found in device tree at boot, so bookkeeping arrays are
+/// placed at start of heap, one entry for each page.
+pub struct Allocator {
+    /// Number of pages of allocation starting at each page, 0 if free
+    pub page_allocated: *mut usize,
+    /// Number of extra references to a page. A page is freed only when
+    /// it is deallocated with no extra reference.
+    pub page_ref: *mut u16,
+    /// Pages are handed out from `base_addr`, which is in HEAP after
+    /// bookkeeping arrays.
+    pub base_addr: usize,
+    /// Number of pages handed out from `base_addr`
+    pub pages: usize,
+}
+
+unsafe impl Send for Allocator {}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Align an address to upper bound according to specified order.
+pub const fn align_val(val: usize, order: usize) -> usize {
+    let o = (1usize << order) - 1;
+    (val + o) & !o
+}
+
+/// Align an address to lower bound according to specified order.
+pub const fn align_val_down(val: usize, order: usize) -> usize {
+    val & !((1usize << order) - 1)
+}
+
+/// Align an address to the begin of a page.
+pub const fn page_down(val: usize) -> usize {
+    align_val_down(val, PAGE_ORDER)
+}
+
+/// Whether an address is at the begin of a page.
+pub const fn is_page_aligned(val: usize) -> bool {
+    val & (PAGE_SIZE - 1) == 0
+}
+
+impl Allocator {
+    /// Returns a new allocator instance
+    /// 
+    /// `base_addr` and bookkeeping arrays should be intialized later.
+    pub const fn new() -> Self {
+        Allocator {
+            base_addr: 0,
+            page_allocated: core::ptr::null_mut(),
+            page_ref: core::ptr::null_mut(),
+            pages: 0,
+        }
+    }
+
+    /// Hand out pages in `heap`, keeping bookkeeping arrays at its start
+    ///
+    /// # Safety
+    ///
+    /// `heap` should be page aligned, writable, and not used by anything
+    /// else for as long as this allocator is used.
+    pub unsafe fn init(&mut self, heap: Range<usize>) {
+        let heap_pages = (heap.end - heap.start) / PAGE_SIZE;
+        let meta_size = heap_pages * (size_of::<usize>() + size_of::<u16>());
+        core::ptr::write_bytes(heap.start as *mut u8, 0, meta_size);
+        self.page_allocated = heap.start as *mut usize;
+        self.page_ref = (heap.start + heap_pages * size_of::<usize>()) as *mut u16;
+        self.base_addr = align_val(heap.start + meta_size, PAGE_ORDER);
+        self.pages = (heap.end - self.base_addr) / PAGE_SIZE;
+    }
+
+    fn allocated(&self) -> &[usize] {
+        unsafe { core::slice::from_raw_parts(self.page_allocated, self.pages) }
+    }
+
+    fn allocated_mut(&mut self) -> &mut [usize] {
+        unsafe { core::slice::from_raw_parts_mut(self.page_allocated, self.pages) }
+    }
+
+    fn refs(&self) -> &[u16] {
+        unsafe { core::slice::from_raw_parts(self.page_ref, self.pages) }
+    }
+
+    fn refs_mut(&mut self) -> &mut [u16] {
+        unsafe { core::slice::from_raw_parts_mut(self.page_ref, self.pages) }
+    }
+
+    /// End address of pages handed out
+    pub fn end(&self) -> usize {
+        self.offset_addr_of(self.pages)
+    }
+
+    fn offset_addr_of(&self, id: usize) -> usize {
+        self.base_addr + id * PAGE_SIZE
+    }
+
+    unsafe fn offset_id_of(&self, id: usize) -> *mut u8 {
+        self.offset_addr_of(id) as *mut u8
+    }
+
+    fn offset_page_of(&self, page: *mut u8) -> usize {
+        (page as usize - self.base_addr) / PAGE_SIZE
+    }
+
+    /// Allocate contiguous pages holding `size` bytes, and at least one
+    /// page. Panics if there's no room.
+    pub fn allocate(&mut self, size: usize) -> *mut u8 {
+        // rounded up without overflow
+        let page_required = (size / PAGE_SIZE + !is_page_aligned(size) as usize).max(1);
+        let pages = self.pages;
+        let page_allocated = self.allocated_mut();
+        for i in 0..(pages + 1).saturating_sub(page_required) {
+            if page_allocated[i] == 0 {
+                let mut found = true;
+                for j in 0..page_required {
+                    if page_allocated[i + j] != 0 {
+                        found = false;
+                        break;
+                    }
+                }
+                if found {
+                    for j in 0..page_required {
+                        page_allocated[i + j] = page_required;
+                    }
+                    unsafe { return self.offset_id_of(i); }
+                }
+            }
+        }
+        panic!("no available page")
+    }
+
+    pub fn deallocate(&mut self, addr: *mut u8) {
+        let id = self.offset_page_of(addr);
+        let page_ref = self.refs_mut();
+        if page_ref[id] != 0 {
+            page_ref[id] -= 1;
+            return;
+        }
+        let page_allocated = self.allocated_mut();
+        let page_stride = page_allocated[id];
+        for j in 0..page_stride {
+            page_allocated[j + id] = 0;
+        }
+    }
+
+    /// Add a reference to allocation at `addr`, so that it will be freed
+    /// after being deallocated one more time.
+    pub fn share(&mut self, addr: *mut u8) {
+        let id = self.offset_page_of(addr);
+        if self.allocated()[id] == 0 {
+            panic!("sharing unallocated page {:?}", addr);
+        }
+        self.refs_mut()[id] += 1;
+    }
+
+    /// Number of references to allocation at `addr`
+    pub fn ref_count(&self, addr: *mut u8) -> usize {
+        let id = self.offset_page_of(addr);
+        if self.allocated()[id] == 0 {
+            0
+        } else {
+            self.refs()[id] as usize + 1
+        }
+    }
+
+}
+
+/// Print page allocation status, one line for each allocation
+impl fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut j = 0;
+        while j < self.pages {
+            let size = self.allocated()[j];
+            let addr = unsafe { self.page_allocated.add(j) };
+            if size != 0 {
+                let from = self.offset_addr_of(j);
+                let to = self.offset_addr_of(j + size);
+                writeln!(f, "{} {:X} {:X}-{:X} (pages: {:X})", j, addr as usize, from, to, size)?;
+                j += size;
+            } else {
+                j += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Allocator on `pages` pages of host memory, which is returned along
+    /// so that it outlives allocator
+    fn allocator(pages: usize) -> (Allocator, Vec<u8>) {
+        let meta_size = pages * (size_of::<usize>() + size_of::<u16>());
+        let mut memory = alloc::vec![0; align_val(meta_size, PAGE_ORDER) + (pages + 1) * PAGE_SIZE];
+        let start = memory.as_mut_ptr() as usize;
+        // heap starts at a page boundary, with bookkeeping arrays in
+        // first pages
+        let heap_start = align_val(start, PAGE_ORDER);
+        let heap_end = heap_start + align_val(meta_size, PAGE_ORDER) + pages * PAGE_SIZE;
+        let mut alloc = Allocator::new();
+        unsafe { alloc.init(heap_start..heap_end); }
+        (alloc, memory)
+    }
+
+    /// Test aligning addresses to pages
+    #[test]
+    fn test_align() {
+        assert_eq!(align_val(0, PAGE_ORDER), 0);
+        assert_eq!(align_val(1, PAGE_ORDER), PAGE_SIZE);
+        assert_eq!(align_val(PAGE_SIZE, PAGE_ORDER), PAGE_SIZE);
+        assert_eq!(align_val_down(PAGE_SIZE + 1, PAGE_ORDER), PAGE_SIZE);
+        assert_eq!(page_down(PAGE_SIZE * 2 - 1), PAGE_SIZE);
+        assert_eq!(align_val(5, 1), 6);
+    }
+
+    /// Test bookkeeping arrays are placed before pages handed out
+    #[test]
+    fn test_init() {
+        let (alloc, _memory) = allocator(100);
+        assert_eq!(alloc.base_addr % PAGE_SIZE, 0);
+        assert!(alloc.base_addr >= alloc.page_ref as usize + 100 * size_of::<u16>());
+        assert_eq!(alloc.pages, 100);
+        assert_eq!(alloc.end(), alloc.base_addr + 100 * PAGE_SIZE);
+    }
+
+    /// Test allocations are page-aligned, disjoint and reused after freed
+    #[test]
+    fn test_allocate() {
+        let (mut alloc, _memory) = allocator(8);
+        let a = alloc.allocate(1);
+        let b = alloc.allocate(PAGE_SIZE * 2 + 1);
+        let c = alloc.allocate(PAGE_SIZE);
+        assert_eq!(a as usize, alloc.base_addr);
+        assert_eq!(b as usize, alloc.base_addr + PAGE_SIZE);
+        assert_eq!(c as usize, alloc.base_addr + PAGE_SIZE * 4);
+        alloc.deallocate(b);
+        assert_eq!(alloc.ref_count(b), 0);
+        // first fit
+        assert_eq!(alloc.allocate(PAGE_SIZE), b);
+        assert_eq!(alloc.allocate(PAGE_SIZE * 3) as usize, alloc.base_addr + PAGE_SIZE * 5);
+        assert_eq!(alloc.allocate(PAGE_SIZE * 2) as usize, alloc.base_addr + PAGE_SIZE * 2);
+    }
+
+    /// Test allocating every page, and zero bytes
+    #[test]
+    fn test_allocate_edges() {
+        let (mut alloc, _memory) = allocator(4);
+        let all = alloc.allocate(PAGE_SIZE * 4);
+        assert_eq!(all as usize, alloc.base_addr);
+        alloc.deallocate(all);
+        let a = alloc.allocate(0);
+        let b = alloc.allocate(0);
+        assert_ne!(a, b);
+        assert_eq!(alloc.ref_count(a), 1);
+    }
+
+    /// Test allocating more than there is
+    #[test]
+    #[should_panic(expected = "no available page")]
+    fn test_allocate_too_large() {
+        let (mut alloc, _memory) = allocator(4);
+        alloc.allocate(PAGE_SIZE * 5);
+    }
+
+    /// Test allocating with all pages in use
+    #[test]
+    #[should_panic(expected = "no available page")]
+    fn test_allocate_full() {
+        let (mut alloc, _memory) = allocator(4);
+        alloc.allocate(PAGE_SIZE * 3);
+        alloc.allocate(PAGE_SIZE);
+        alloc.allocate(1);
+    }
+
+    /// Test allocating size which overflows when rounded up
+    #[test]
+    #[should_panic(expected = "no available page")]
+    fn test_allocate_huge() {
+        let (mut alloc, _memory) = allocator(4);
+        alloc.allocate(usize::MAX);
+    }
+
+    /// Test shared allocation is freed after its last reference is dropped
+    #[test]
+    fn test_share() {
+        let (mut alloc, _memory) = allocator(4);
+        let addr = alloc.allocate(PAGE_SIZE * 2);
+        assert_eq!(alloc.ref_count(addr), 1);
+        alloc.share(addr);
+        alloc.share(addr);
+        assert_eq!(alloc.ref_count(addr), 3);
+        alloc.deallocate(addr);
+        alloc.deallocate(addr);
+        assert_eq!(alloc.ref_count(addr), 1);
+        assert_eq!(alloc.allocate(PAGE_SIZE * 2) as usize, alloc.base_addr + PAGE_SIZE * 2);
+        alloc.deallocate(addr);
+        assert_eq!(alloc.ref_count(addr), 0);
+        assert_eq!(alloc.allocate(PAGE_SIZE * 2), addr);
+    }
+
+    /// Test sharing a free page
+    #[test]
+    #[should_panic(expected = "sharing unallocated page")]
+    fn test_share_free() {
+        let (mut alloc, _memory) = allocator(4);
+        let addr = alloc.allocate(PAGE_SIZE);
+        alloc.deallocate(addr);
+        alloc.share(addr);
+    }
+
+    /// Test printing allocations
+    #[test]
+    fn test_debug() {
+        let (mut alloc, _memory) = allocator(4);
+        alloc.allocate(PAGE_SIZE);
+        alloc.allocate(PAGE_SIZE * 2);
+        let s = alloc::format!("{:?}", alloc);
+        assert_eq!(s.lines().count(), 2);
+        assert!(s.lines().nth(1).unwrap().ends_with("(pages: 2)"));
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Mock page memory and block devices for tests on host
+//!
+//! Pages and page tables are allocated with page alignment, and those
+//! allocations are handed out by a frame allocator on a region of host
+//! memory, just like kernel heap. Everything else goes to system
+//! allocator.
+
+use crate::fs::{BlockDevice, BSIZE, FILE_MAX};
+use crate::mem::{align_val, Allocator};
+use crate::{PAGE_ORDER, PAGE_SIZE};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::RefCell;
+use std::sync::{Mutex, MutexGuard, Once};
+
+/// Size of mock page memory
+const MEMORY_SIZE: usize = 64 * 1024 * 1024;
+
+static PAGES: Mutex<Allocator> = Mutex::new(Allocator::new());
+static INIT: Once = Once::new();
+/// Held by tests counting pages in use
+static EXCLUSIVE: Mutex<()> = Mutex::new(());
+
+struct MockAllocator;
+
+fn pages() -> MutexGuard<'static, Allocator> {
+    INIT.call_once(|| unsafe {
+        let start = System.alloc(Layout::from_size_align(MEMORY_SIZE, PAGE_SIZE).unwrap()) as usize;
+        PAGES.lock().unwrap().init(start..start + MEMORY_SIZE);
+    });
+    PAGES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+unsafe impl GlobalAlloc for MockAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() >= PAGE_SIZE {
+            pages().allocate(layout.size())
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.align() >= PAGE_SIZE {
+            pages().deallocate(ptr)
+        } else {
+            System.dealloc(ptr, layout)
+        }
+    }
+}
+
+#[global_allocator]
+static GA: MockAllocator = MockAllocator;
+
+/// Number of pages allocated in mock page memory
+fn pages_in_use() -> usize {
+    let alloc = pages();
+    (0..alloc.pages).filter(|&id| {
+        alloc.ref_count((alloc.base_addr + id * PAGE_SIZE) as *mut u8) != 0
+    }).count()
+}
+
+/// Run `f` while no other test counts pages. Returns number of pages
+/// it leaves allocated.
+pub fn pages_leaked<F: FnOnce()>(f: F) -> isize {
+    let _guard = EXCLUSIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let before = pages_in_use() as isize;
+    f();
+    pages_in_use() as isize - before
+}
+
+/// Add a reference to page at `paddr`, as kernel does before mapping
+/// a shared page
+pub fn share(paddr: usize) {
+    pages().share(paddr as *mut u8);
+}
+
+/// Number of references to page at `paddr`
+pub fn ref_count(paddr: usize) -> usize {
+    pages().ref_count(paddr as *mut u8)
+}
+
+/// Block device on host memory, which records blocks read
+pub struct RamDisk {
+    blocks: RefCell<Vec<[u8; BSIZE]>>,
+    reads: RefCell<Vec<usize>>,
+}
+
+impl RamDisk {
+    pub fn new(nblocks: usize) -> Self {
+        Self {
+            blocks: RefCell::new(vec![[0; BSIZE]; nblocks]),
+            reads: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Blocks read so far, in order
+    pub fn reads(&self) -> Vec<usize> {
+        self.reads.borrow().clone()
+    }
+
+    pub fn clear_reads(&self) {
+        self.reads.borrow_mut().clear();
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn read_block(&self, blockno: usize, data: &mut [u8; BSIZE]) {
+        data.copy_from_slice(&self.blocks.borrow()[blockno]);
+        self.reads.borrow_mut().push(blockno);
+    }
+
+    fn write_block(&self, blockno: usize, data: &[u8; BSIZE]) {
+        self.blocks.borrow_mut()[blockno].copy_from_slice(data);
+    }
+}
+
+/// Make a file system of `files`, each of a path and content, in the
+/// same layout as `mkfs`
+pub fn mkfs(files: &[(&str, &[u8])]) -> RamDisk {
+    let header_size = FILE_MAX * BSIZE;
+    let size = files.iter().fold(header_size, |sz, (_, data)| sz + align_val(data.len(), PAGE_ORDER));
+    let mut image = vec![0u8; size];
+    let mut offset = header_size;
+    for (id, (path, data)) in files.iter().enumerate() {
+        let entry = &mut image[id * BSIZE..(id + 1) * BSIZE];
+        entry[0..8].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        entry[8..16].copy_from_slice(&(offset as u64).to_le_bytes());
+        entry[16..16 + path.len()].copy_from_slice(path.as_bytes());
+        image[offset..offset + data.len()].copy_from_slice(data);
+        offset += align_val(data.len(), PAGE_ORDER);
+    }
+    let disk = RamDisk::new(size / BSIZE);
+    let mut b = [0; BSIZE];
+    for (blockno, chunk) in image.chunks(BSIZE).enumerate() {
+        b.copy_from_slice(chunk);
+        disk.write_block(blockno, &b);
+    }
+    disk
+}
@@ -0,0 +1,617 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Paging implementaion and page table abstraction
+//!
+//! Page tables are Sv39 tables, with physical address of pages and tables
+//! being the address they are allocated at. Kernel identity-maps its heap,
+//! and tests on host allocate them from mock page memory.
+
+use crate::mem;
+use crate::{PAGE_ORDER, PAGE_SIZE};
+use alloc::boxed::Box;
+use core::fmt;
+
+const TABLE_ENTRY_CNT: usize = 512;
+
+#[repr(C)]
+#[repr(align(4096))]
+pub struct Table {
+    pub entries: [Entry; TABLE_ENTRY_CNT],
+}
+
+#[repr(C)]
+#[repr(align(4096))]
+pub struct Page {
+    pub data: [u8; PAGE_SIZE]
+}
+
+impl Page {
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            data: [0; PAGE_SIZE]
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct Entry(usize);
+
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct VPN(usize);
+
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct PPN(usize);
+
+pub enum EntryAttributes {
+    /// Reserved for software. Page is shared and reference-counted in allocator.
+    S = 1 << 8,
+    D = 1 << 7,
+    A = 1 << 6,
+    G = 1 << 5,
+    U = 1 << 4,
+    X = 1 << 3,
+    W = 1 << 2,
+    R = 1 << 1,
+    V = 1 << 0,
+    RW = 0b11 << 1,
+    RX = 0b101 << 1,
+    UR = 0b10010,
+    URW = 0b10110,
+    URX = 0b11010,
+}
+
+impl Entry {
+    pub fn is_d(&self) -> bool {
+        self.0 & EntryAttributes::D as usize != 0
+    }
+    pub fn is_a(&self) -> bool {
+        self.0 & EntryAttributes::A as usize != 0
+    }
+    pub fn is_g(&self) -> bool {
+        self.0 & EntryAttributes::G as usize != 0
+    }
+    pub fn is_u(&self) -> bool {
+        self.0 & EntryAttributes::U as usize != 0
+    }
+    pub fn is_x(&self) -> bool {
+        self.0 & EntryAttributes::X as usize != 0
+    }
+    pub fn is_w(&self) -> bool {
+        self.0 & EntryAttributes::W as usize != 0
+    }
+    pub fn is_r(&self) -> bool {
+        self.0 & EntryAttributes::R as usize != 0
+    }
+    pub fn is_v(&self) -> bool {
+        self.0 & EntryAttributes::V as usize != 0
+    }
+    pub fn is_s(&self) -> bool {
+        self.0 & EntryAttributes::S as usize != 0
+    }
+    pub fn is_leaf(&self) -> bool {
+        self.0 & 0xe != 0
+    }
+    pub fn paddr(&self) -> PPN {
+        PPN((self.0 & !0x3ff) << 2)
+    }
+    pub fn flags(&self) -> usize {
+        self.0 & 0x3ff
+    }
+    pub const fn new(ppn: usize, flags: usize) -> Self {
+        Self(((ppn & !0xfff) >> 2) | flags)
+    }
+}
+
+impl PPN {
+    pub fn ppn0(&self) -> usize {
+        (self.0 >> 12) & 0x1ff
+    }
+    pub fn ppn1(&self) -> usize {
+        (self.0 >> 21) & 0x1ff
+    }
+    pub fn ppn2(&self) -> usize {
+        (self.0 >> 30) & 0x3ff_ffff
+    }
+    pub fn idx(&self, id: usize) -> usize {
+        match id {
+            0 => self.ppn0(),
+            1 => self.ppn1(),
+            2 => self.ppn2(),
+            _ => unreachable!(),
+        }
+    }
+    pub fn clone_page(&self) -> Box<Page> {
+        let mut pg = Page::new();
+        unsafe { core::ptr::copy(self.0 as *const u8, pg.data.as_mut_ptr(), PAGE_SIZE); }
+        pg
+    }
+}
+
+impl VPN {
+    pub fn vpn0(&self) -> usize {
+        (self.0 >> 12) & 0x1ff
+    }
+    pub fn vpn1(&self) -> usize {
+        (self.0 >> 21) & 0x1ff
+    }
+    pub fn vpn2(&self) -> usize {
+        (self.0 >> 30) & 0x1ff
+    }
+    pub fn idx(&self, id: usize) -> usize {
+        match id {
+            0 => self.vpn0(),
+            1 => self.vpn1(),
+            2 => self.vpn2(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Table {
+    pub const fn new() -> Self {
+        Table {
+            entries: [Entry(0); TABLE_ENTRY_CNT],
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        TABLE_ENTRY_CNT
+    }
+
+    /// Tables always have `TABLE_ENTRY_CNT` entries
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn map(&mut self, vaddr: usize, pg: Box<Page>, flags: usize) {
+        if flags & EntryAttributes::U as usize == 0 {
+            panic!("you may only map user page");
+        }
+        self.map_addr(vaddr, Box::into_raw(pg) as usize, flags, 0);
+    }
+
+    /// Map shared user page at `paddr`. Caller should have added a
+    /// reference to the page in frame allocator, which will be dropped
+    /// when it is unmapped.
+    pub fn map_shared(&mut self, vaddr: usize, paddr: usize, flags: usize) {
+        if flags & EntryAttributes::U as usize == 0 {
+            panic!("you may only map user page");
+        }
+        self.map_addr(vaddr, paddr, flags | EntryAttributes::S as usize, 0);
+    }
+
+    pub fn kernel_map(&mut self, vaddr: usize, paddr: usize, flags: usize) {
+        if flags & EntryAttributes::U as usize != 0 {
+            panic!("you may only map kernel page");
+        }
+        self.map_addr(vaddr, paddr, flags, 0);
+    }
+
+    fn map_addr(&mut self, vaddr: usize, paddr: usize, flags: usize, level: usize) {
+        if !mem::is_page_aligned(paddr) {
+            panic!("paddr {:x} not aligned", paddr);
+        }
+        if !mem::is_page_aligned(vaddr) {
+            panic!("vaddr {:x} not aligned", vaddr);
+        }
+        let vpn = VPN(vaddr);
+        let mut v = &mut self.entries[vpn.vpn2()];
+        for lvl in (level..2).rev() {
+            if !v.is_v() {
+                let page = Box::new(Table::new());
+                *v = Entry::new(Box::into_raw(page) as usize, EntryAttributes::V as usize);
+            }
+            let entry = v.paddr().0 as *mut Entry;
+            v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
+        }
+        *v = Entry::new(paddr, flags | EntryAttributes::V as usize)
+    }
+
+    /// Get leaf entry mapping `vaddr`
+    pub fn entry_of(&self, vaddr: usize) -> Option<Entry> {
+        let vpn = VPN(vaddr);
+        let mut v = &self.entries[vpn.vpn2()];
+        if !mem::is_page_aligned(vaddr) {
+            panic!("vaddr {:x} not aligned", vaddr);
+        }
+        for lvl in (0..2).rev() {
+            if !v.is_v() {
+                return None;
+            }
+            let entry = v.paddr().0 as *mut Entry;
+            v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
+        }
+        if !v.is_v() {
+            return None;
+        }
+        Some(*v)
+    }
+
+    pub fn paddr_of(&self, vaddr: usize) -> Option<usize> {
+        self.entry_of(vaddr).map(|v| v.paddr().0)
+    }
+
+    /// Unmap user page at `vaddr` and return the page previously mapped
+    pub fn unmap(&mut self, vaddr: usize) -> Option<Box<Page>> {
+        if !mem::is_page_aligned(vaddr) {
+            panic!("vaddr {:x} not aligned", vaddr);
+        }
+        let vpn = VPN(vaddr);
+        let mut v = &mut self.entries[vpn.vpn2()];
+        for lvl in (0..2).rev() {
+            if !v.is_v() {
+                return None;
+            }
+            let entry = v.paddr().0 as *mut Entry;
+            v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
+        }
+        if !v.is_v() || !v.is_u() {
+            return None;
+        }
+        let pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+        *v = Entry(0);
+        Some(pg)
+    }
+
+    /// Unmap kernel page at `vaddr`. The page itself is not freed.
+    pub fn kernel_unmap(&mut self, vaddr: usize) {
+        if !mem::is_page_aligned(vaddr) {
+            panic!("vaddr {:x} not aligned", vaddr);
+        }
+        let vpn = VPN(vaddr);
+        let mut v = &mut self.entries[vpn.vpn2()];
+        for lvl in (0..2).rev() {
+            if !v.is_v() {
+                return;
+            }
+            let entry = v.paddr().0 as *mut Entry;
+            v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
+        }
+        if v.is_u() {
+            panic!("you may only unmap kernel page");
+        }
+        *v = Entry(0);
+    }
+
+    pub fn id_map_range(&mut self, start: usize, end: usize, bits: usize) {
+        let mut memaddr = mem::align_val_down(start, PAGE_ORDER);
+        let num_kb_pages = (mem::align_val(end, 12) - memaddr) / PAGE_SIZE;
+        for _ in 0..num_kb_pages {
+            self.map_addr(memaddr, memaddr, bits, 0);
+            memaddr += PAGE_SIZE;
+        }
+    }
+
+    pub fn map_range(&mut self, start: usize, end: usize, vaddr_start: usize, bits: usize) {
+        let mut memaddr = start & !(PAGE_SIZE - 1);
+        let mut vaddr_start = vaddr_start & !(PAGE_SIZE - 1);
+        let num_kb_pages = (mem::align_val(end, 12) - memaddr) / PAGE_SIZE;
+
+        for _ in 0..num_kb_pages {
+            self.map_addr(vaddr_start, memaddr, bits, 0);
+            memaddr += 1 << 12;
+            vaddr_start += 1 << 12;
+        }
+    }
+
+    /* TODO: use same function for drop_walk, unmap_user and walk */
+
+    fn drop_walk(&mut self) {
+        for i in 0..self.len() {
+            let v = &mut self.entries[i];
+            if v.is_v() {
+                if v.is_leaf() {
+                    if v.is_u() {
+                        // drop user page
+                        let _pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+                    }
+                } else {
+                    // drop page table, which drops pages mapped in it
+                    let _table = unsafe { Box::from_raw(v.paddr().0 as *mut Table) };
+                }
+            }
+        }
+    }
+
+    fn clone_walk(&self, share: &mut dyn FnMut(usize)) -> Box<Self> {
+        let mut pgtable = Table::new();
+        for i in 0..self.len() {
+            let v = &self.entries[i];
+            if v.is_v() {
+                if v.is_leaf() {
+                    if v.is_s() {
+                        // shared page is not copied
+                        share(v.paddr().0);
+                        pgtable.entries[i] = *v;
+                    } else if v.is_u() {
+                        let pg = v.paddr().clone_page();
+                        pgtable.entries[i] = Entry::new(Box::into_raw(pg) as usize, v.flags());
+                    }
+                } else {
+                    let table = unsafe { (v.paddr().0 as *mut Table).as_mut().unwrap() };
+                    let pg = table.clone_walk(share);
+                    pgtable.entries[i] = Entry::new(Box::into_raw(pg) as usize, v.flags());
+                }
+            }
+        }
+        Box::new(pgtable)
+    }
+
+    /// Copy table and user pages mapped, calling `share` with physical
+    /// address of each shared page, which is mapped instead of copied
+    pub fn clone_with(&self, share: &mut dyn FnMut(usize)) -> Box<Self> {
+        self.clone_walk(share)
+    }
+
+    /// Unmap and free all user pages
+    pub fn unmap_user(&mut self) {
+        for i in 0..self.len() {
+            let v = &mut self.entries[i];
+            if v.is_v() {
+                if v.is_leaf() {
+                    if v.is_u() {
+                        // drop user page
+                        let _pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+                        *v = Entry(0);
+                    }
+                } else {
+                    // drop page table
+                    let table = unsafe { (v.paddr().0 as *mut Table).as_mut().unwrap() };
+                    table.unmap_user();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        self.drop_walk();
+    }
+}
+
+impl Table {
+    fn fmt_walk(&self, f: &mut fmt::Formatter, level: usize, vpn: usize) -> fmt::Result {
+        for i in 0..self.len() {
+            let v = &self.entries[i];
+            if !v.is_v() {
+                continue;
+            }
+            for _ in 0..(2 - level) {
+                write!(f, ".")?;
+            }
+            let vaddr = (vpn << 9 | i) << (9 * level + 12);
+            if !v.is_leaf() {
+                writeln!(f, "{}: 0x{:X} -> 0x{:X}", i, vaddr, v.paddr().0)?;
+                let table = unsafe { &*(v.paddr().0 as *const Table) };
+                table.fmt_walk(f, level - 1, (vpn << 9) | i)?;
+            } else {
+                let u_flag = if v.is_u() { "U" } else { "" };
+                let r_flag = if v.is_r() { "R" } else { "" };
+                let w_flag = if v.is_w() { "W" } else { "" };
+                let x_flag = if v.is_x() { "X" } else { "" };
+                writeln!(f, "{}: 0x{:X} -> 0x{:X}  {}{}{}{}", i, vaddr, v.paddr().0, u_flag, r_flag, w_flag, x_flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print all mappings, one line for each entry
+impl fmt::Debug for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_walk(f, 2, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{pages_leaked, ref_count, share};
+    use alloc::format;
+
+    /// Highest virtual address on Sv39 with sign bit cleared
+    const MAXVA: usize = 1 << (9 + 9 + 9 + 12 - 1);
+
+    /// Test splitting virtual address into page numbers
+    #[test]
+    fn test_vpn() {
+        let vpn = VPN((3 << 30) | (2 << 21) | (1 << 12) | 0x123);
+        assert_eq!((vpn.vpn2(), vpn.vpn1(), vpn.vpn0()), (3, 2, 1));
+        assert_eq!((vpn.idx(2), vpn.idx(1), vpn.idx(0)), (3, 2, 1));
+        let ppn = Entry::new(0x8020_3000, EntryAttributes::RW as usize).paddr();
+        assert_eq!(ppn.0, 0x8020_3000);
+        assert_eq!((ppn.ppn2(), ppn.ppn1(), ppn.ppn0()), (2, 1, 3));
+    }
+
+    /// Test mapping, looking up and unmapping user page
+    #[test]
+    fn test_map() {
+        assert_eq!(pages_leaked(|| {
+            let mut pgtable = Box::new(Table::new());
+            let vaddr = 0x40_0000;
+            let pg = Page::new();
+            let paddr = &*pg as *const _ as usize;
+            assert!(pgtable.entry_of(vaddr).is_none());
+            pgtable.map(vaddr, pg, EntryAttributes::URW as usize);
+            let entry = pgtable.entry_of(vaddr).unwrap();
+            assert!(entry.is_v() && entry.is_u() && entry.is_w() && !entry.is_x());
+            assert_eq!(pgtable.paddr_of(vaddr), Some(paddr));
+            assert!(pgtable.entry_of(vaddr + PAGE_SIZE).is_none());
+            let pg = pgtable.unmap(vaddr).unwrap();
+            assert_eq!(&*pg as *const _ as usize, paddr);
+            assert!(pgtable.entry_of(vaddr).is_none());
+            assert!(pgtable.unmap(vaddr).is_none());
+        }), 0);
+    }
+
+    /// Test mapping at both ends of address space, and dropping table
+    /// frees user pages and intermediate tables
+    #[test]
+    fn test_map_edges() {
+        assert_eq!(pages_leaked(|| {
+            let mut pgtable = Box::new(Table::new());
+            let top = MAXVA - PAGE_SIZE;
+            pgtable.map(0, Page::new(), EntryAttributes::UR as usize);
+            pgtable.map(top, Page::new(), EntryAttributes::URX as usize);
+            assert!(pgtable.entry_of(0).unwrap().is_r());
+            assert!(pgtable.entry_of(top).unwrap().is_x());
+            assert!(pgtable.entries[0].is_v());
+            assert!(pgtable.entries[VPN(top).vpn2()].is_v());
+            assert!(pgtable.entry_of(top - PAGE_SIZE).is_none());
+            assert!(pgtable.entry_of(1 << 30).is_none());
+        }), 0);
+    }
+
+    /// Test dropping table frees each shared page mapped once
+    #[test]
+    fn test_drop_shared() {
+        assert_eq!(pages_leaked(|| {
+            let paddr = Box::into_raw(Page::new()) as usize;
+            share(paddr);
+            let mut pgtable = Box::new(Table::new());
+            pgtable.map_shared(0x1000, paddr, EntryAttributes::URW as usize);
+            drop(pgtable);
+            assert_eq!(ref_count(paddr), 1);
+            drop(unsafe { Box::from_raw(paddr as *mut Page) });
+        }), 0);
+    }
+
+    /// Test looking up unaligned address
+    #[test]
+    #[should_panic(expected = "not aligned")]
+    fn test_entry_of_unaligned() {
+        Table::new().entry_of(PAGE_SIZE + 1);
+    }
+
+    /// Test mapping a kernel page as user page
+    #[test]
+    #[should_panic(expected = "you may only map user page")]
+    fn test_map_kernel_flags() {
+        pages_leaked(|| Table::new().map(0, Page::new(), EntryAttributes::RW as usize));
+    }
+
+    /// Test kernel pages are neither freed nor unmapped as user pages
+    #[test]
+    fn test_kernel_map() {
+        assert_eq!(pages_leaked(|| {
+            let pg = Box::into_raw(Page::new());
+            {
+                let mut pgtable = Box::new(Table::new());
+                pgtable.kernel_map(0x1000, pg as usize, EntryAttributes::RW as usize);
+                pgtable.map(0x2000, Page::new(), EntryAttributes::URW as usize);
+                assert!(pgtable.unmap(0x1000).is_none());
+                pgtable.unmap_user();
+                assert!(pgtable.entry_of(0x2000).is_none());
+                assert_eq!(pgtable.paddr_of(0x1000), Some(pg as usize));
+                pgtable.kernel_unmap(0x1000);
+                assert!(pgtable.entry_of(0x1000).is_none());
+                // unmapping what's not mapped is fine
+                pgtable.kernel_unmap(0x1000);
+                pgtable.kernel_unmap(1 << 30);
+            }
+            drop(unsafe { Box::from_raw(pg) });
+        }), 0);
+    }
+
+    /// Test unmapping user page as kernel page
+    #[test]
+    #[should_panic(expected = "you may only unmap kernel page")]
+    fn test_kernel_unmap_user() {
+        pages_leaked(|| {
+            let mut pgtable = Box::new(Table::new());
+            pgtable.map(0, Page::new(), EntryAttributes::URW as usize);
+            pgtable.kernel_unmap(0);
+        });
+    }
+
+    /// Test mapping ranges, which are rounded to pages
+    #[test]
+    fn test_map_range() {
+        assert_eq!(pages_leaked(|| {
+            let mut pgtable = Box::new(Table::new());
+            pgtable.id_map_range(0x8000_0010, 0x8000_2001, EntryAttributes::RW as usize);
+            for vaddr in &[0x8000_0000, 0x8000_1000, 0x8000_2000] {
+                assert_eq!(pgtable.paddr_of(*vaddr), Some(*vaddr));
+            }
+            assert!(pgtable.entry_of(0x8000_3000).is_none());
+            assert!(pgtable.entry_of(0x7fff_f000).is_none());
+            // range crossing boundary of a level-1 table
+            pgtable.map_range(0x9000_0000, 0x9000_2000, 0x1ff000, EntryAttributes::RX as usize);
+            assert_eq!(pgtable.paddr_of(0x1ff000), Some(0x9000_0000));
+            assert_eq!(pgtable.paddr_of(0x200000), Some(0x9000_1000));
+            assert!(pgtable.entry_of(0x201000).is_none());
+        }), 0);
+    }
+
+    /// Test cloned table has its own copy of user pages
+    #[test]
+    fn test_clone() {
+        assert_eq!(pages_leaked(|| {
+            let mut pgtable = Box::new(Table::new());
+            let mut pg = Page::new();
+            pg.data[0] = 42;
+            pgtable.map(0, pg, EntryAttributes::URW as usize);
+            let cloned = pgtable.clone_with(&mut |_| panic!("no shared page"));
+            let (from, to) = (pgtable.paddr_of(0).unwrap(), cloned.paddr_of(0).unwrap());
+            assert_ne!(from, to);
+            assert_eq!(unsafe { *(to as *const u8) }, 42);
+            assert_eq!(cloned.entry_of(0).unwrap().flags(), pgtable.entry_of(0).unwrap().flags());
+        }), 0);
+    }
+
+    /// Test shared page is mapped in cloned table, and freed after
+    /// both tables are dropped
+    #[test]
+    fn test_clone_shared() {
+        assert_eq!(pages_leaked(|| {
+            let paddr = Box::into_raw(Page::new()) as usize;
+            let mut pgtable = Box::new(Table::new());
+            share(paddr);
+            pgtable.map_shared(0x1000, paddr, EntryAttributes::URW as usize);
+            // the reference of allocation itself is given up
+            drop(unsafe { Box::from_raw(paddr as *mut Page) });
+            assert_eq!(ref_count(paddr), 1);
+            let mut shared = 0;
+            let cloned = pgtable.clone_with(&mut |p| {
+                shared += 1;
+                share(p);
+            });
+            assert_eq!(shared, 1);
+            assert_eq!(cloned.paddr_of(0x1000), Some(paddr));
+            assert!(cloned.entry_of(0x1000).unwrap().is_s());
+            assert_eq!(ref_count(paddr), 2);
+            drop(pgtable);
+            assert_eq!(ref_count(paddr), 1);
+            drop(cloned);
+            assert_eq!(ref_count(paddr), 0);
+        }), 0);
+    }
+
+    /// Test printing mappings
+    #[test]
+    fn test_debug() {
+        pages_leaked(|| {
+            let mut pgtable = Box::new(Table::new());
+            pgtable.kernel_map((1 << 30) | (2 << 21) | (3 << 12), 0x8000_0000, EntryAttributes::RW as usize);
+            let s = format!("{:?}", pgtable);
+            let lines: alloc::vec::Vec<&str> = s.lines().collect();
+            assert_eq!(lines.len(), 3);
+            assert!(lines[0].starts_with("1: 0x40000000 -> "));
+            assert!(lines[1].starts_with(".2: 0x40400000 -> "));
+            assert_eq!(lines[2], "..3: 0x40403000 -> 0x80000000  RW");
+        });
+    }
+}
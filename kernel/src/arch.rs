@@ -12,11 +12,18 @@ use crate::symbols::*;
 use core::sync::atomic::Ordering;
 
 /// Get current time from MMIO
+#[cfg(not(feature = "sbi"))]
 pub fn time() -> Duration {
-    let mtime = crate::clint::CLINT_MTIME_BASE as *const u64;
+    let mtime = crate::clint::CLINT_MTIME() as *const u64;
     Duration::from_nanos(unsafe { mtime.read_volatile() } * 100)
 }
 
+/// Get current time from `time` CSR, as firmware may protect CLINT
+#[cfg(feature = "sbi")]
+pub fn time() -> Duration {
+    Duration::from_nanos(riscv::register::time::read() as u64 * 100)
+}
+
 /// Build satp value from mode, asid and page table base addr
 pub fn build_satp(mode: usize, asid: usize, addr: usize) -> usize {
     if addr % PAGE_SIZE != 0 {
@@ -120,6 +127,16 @@ pub fn sp() -> usize {
     sp
 }
 
+/// Frame pointer of current function, which is kept as kernel is built with
+/// `-C force-frame-pointers=yes`
+#[inline(always)]
+#[allow(unused_assignments)]
+pub fn fp() -> usize {
+    let mut fp: usize = 0;
+    unsafe { llvm_asm!("mv $0, s0" : "=r"(fp) ::: "volatile"); }
+    fp
+}
+
 pub fn wait_forever() -> ! {
     loop {
         unsafe {
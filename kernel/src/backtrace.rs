@@ -0,0 +1,102 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Symbolized backtraces
+//!
+//! Kernel is built with frame pointers, so each frame holds return address
+//! at `fp - 8` and frame pointer of caller at `fp - 16`. Return addresses
+//! are looked up in symbol table of section `.ksyms`, which is generated
+//! by `utils/ksyms.py` from kernel linked without it, and then embedded.
+//! Kernel built by `make test` has an empty table, and only addresses are
+//! printed. Either way, `addr2line -e kernel.elf <address>` or
+//! `info line *<address>` in GDB finds source lines.
+
+use crate::symbols::*;
+use crate::page::KERNEL_PGTABLE;
+use crate::mem::page_down;
+use crate::panic_println;
+
+/// Frames printed at most
+const MAX_DEPTH: usize = 32;
+
+/// An entry of symbol table
+#[repr(C)]
+struct Symbol {
+    addr: usize,
+    name: *const u8,
+    len: usize,
+}
+
+/// Symbol table, sorted by address
+fn symbols() -> &'static [Symbol] {
+    let len = (KSYMS_END() - KSYMS_START()) / core::mem::size_of::<Symbol>();
+    unsafe { core::slice::from_raw_parts(KSYMS_START() as *const Symbol, len) }
+}
+
+/// Find function containing `pc`. Returns its name and offset of `pc` in it.
+pub fn lookup(pc: usize) -> Option<(&'static str, usize)> {
+    if pc < TEXT_START() || pc >= TEXT_END() {
+        return None;
+    }
+    let symbols = symbols();
+    let id = match symbols.binary_search_by_key(&pc, |sym| sym.addr) {
+        Ok(id) => id,
+        Err(0) => return None,
+        Err(id) => id - 1,
+    };
+    let sym = &symbols[id];
+    let name = unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(sym.name, sym.len)) };
+    Some((name, pc - sym.addr))
+}
+
+/// Check if frame at `fp` can be read
+fn frame_valid(fp: usize) -> bool {
+    // stack is 16-byte aligned, so the two slots are in one page
+    if fp % 16 != 0 || fp < 16 || fp >= MAXVA {
+        return false;
+    }
+    if crate::arch::r_satp() == 0 {
+        // paging is not enabled yet, and only boot stacks are used
+        return fp > KERNEL_STACK_START() && fp <= KERNEL_STACK_END();
+    }
+    KERNEL_PGTABLE.paddr_of(page_down(fp - 16)).is_some()
+}
+
+/// Print one frame, in the same format as `bt` in GDB
+fn print_frame(depth: usize, pc: usize) {
+    // return address is after the call, which may be a compressed one
+    match lookup(pc - 2) {
+        Some((name, offset)) => panic_println!("#{:<2} 0x{:016x} in {}+0x{:x}", depth, pc, name, offset + 2),
+        None => panic_println!("#{:<2} 0x{:016x} in ??", depth, pc),
+    }
+}
+
+/// Walk frames from `fp` and print return address of each of them
+pub fn print_from(fp: usize) {
+    panic_println!("backtrace:");
+    let mut fp = fp;
+    for depth in 0..MAX_DEPTH {
+        if !frame_valid(fp) {
+            return;
+        }
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            return;
+        }
+        print_frame(depth, ra);
+        // caller's frame is above on stack
+        if prev <= fp {
+            return;
+        }
+        fp = prev;
+    }
+    panic_println!("...");
+}
+
+/// Print backtrace of caller
+#[inline(always)]
+pub fn print() {
+    print_from(crate::arch::fp());
+}
@@ -6,15 +6,19 @@
 //! RISC-V Core Local Interrupter
 
 #![allow(non_snake_case)]
+// under SBI, timer is programmed by firmware instead
+#![cfg_attr(feature = "sbi", allow(dead_code))]
 
 use crate::symbols::{NCPUS, SCHEDULER_INTERVAL};
 use crate::println;
 use crate::arch::{hart_id, sp};
+use crate::fdt::machine;
 
+/// CLINT base address on QEMU RISC-V, used without device tree
 pub const CLINT_BASE: usize = 0x200_0000;
-pub const CLINT_MTIMECMP_BASE: usize = CLINT_BASE + 0x4000;
-pub const fn CLINT_MTIMECMP(hart: usize) -> usize { CLINT_MTIMECMP_BASE + 8 * hart }
-pub const CLINT_MTIME_BASE: usize = CLINT_BASE + 0xBFF8;
+pub fn CLINT_MTIMECMP(hart: usize) -> usize { machine().clint.base + 0x4000 + 8 * hart }
+pub fn CLINT_MTIME() -> usize { machine().clint.base + 0xBFF8 }
+pub fn CLINT_MSIP(hart: usize) -> usize { machine().clint.base + 4 * hart }
 
 /// space for timer trap to save information.
 static mut MSCRATCH0: [[u64; 8]; NCPUS] = [[0; 8]; NCPUS];
@@ -25,7 +29,7 @@ pub unsafe fn timer_init() {
     let id = mhartid::read();
     let interval = SCHEDULER_INTERVAL as u64;
     let mtimecmp = CLINT_MTIMECMP(id) as *mut u64;
-    let mtime = CLINT_MTIME_BASE as *const u64;
+    let mtime = CLINT_MTIME() as *const u64;
     mtimecmp.write_volatile(mtime.read_volatile() + interval);
     let scratch = &mut MSCRATCH0[id];
 
@@ -34,6 +38,7 @@ pub unsafe fn timer_init() {
     scratch[3] = mtime as u64;
     scratch[4] = mtimecmp as u64;
     scratch[5] = interval;
+    scratch[6] = CLINT_MSIP(id) as u64;
 
     // set machine-mode trap handler as timervec in kernelvec.S
     mtvec::write(crate::symbols::timervec as usize, mtvec::TrapMode::Direct);
@@ -43,6 +48,19 @@ pub unsafe fn timer_init() {
 
     // enable machine-mode timer interrupt.
     mie::set_mtimer();
+
+    // enable machine-mode software interrupt, which is an IPI.
+    mie::set_msoft();
+}
+
+/// Send IPI to harts in `hart_mask`. It is raised as machine-mode
+/// software interrupt, which `timervec` passes on to supervisor mode.
+pub fn send_ipi(hart_mask: usize) {
+    for hart in 0..NCPUS {
+        if hart_mask & (1 << hart) != 0 {
+            unsafe { (CLINT_MSIP(hart) as *mut u32).write_volatile(1); }
+        }
+    }
 }
 
 pub fn debug() {
@@ -3,121 +3,45 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! ELF parsing
+//! ELF loading
+//!
+//! ELF files are parsed and checked by `kernel_core::elf`.
 
+use kernel_core::elf;
 use crate::panic;
 use crate::mem;
 use crate::arch;
 use crate::page;
-use crate::process;
+use crate::process::{self, VmaList, Vma, VmaKind};
+use crate::page::EntryAttributes;
 use crate::symbols::*;
 use crate::{info, println};
 
-#[repr(C)]
-pub struct ELFHeader {
-    pub magic: u32,
-    pub elf: [u8; 12],
-    pub etype: u16,
-    pub machine: u16,
-    pub version: u32,
-    pub entry: u64,
-    pub phoff: u64,
-    pub shoff: u64,
-    pub flags: u32,
-    pub ehsize: u16,
-    pub phentsize: u16,
-    pub phnum: u16,
-    pub shentsize: u16,
-    pub shnum: u16,
-    pub shstrndx: u16,
-}
-
-#[repr(C)]
-pub struct ProgramHeader {
-    pub ptype: u32,
-    pub flags: u32,
-    pub off: u64,
-    pub vaddr: u64,
-    pub paddr: u64,
-    pub filesz: u64,
-    pub memsz: u64,
-    pub align: u64,
-}
-
-const ELF_PROG_LOAD: u32 = 1;
-const ELF_PROG_FLAG_EXEC: u32 = 1;
-const ELF_PROG_FLAG_WRITE: u32 = 2;
-const ELF_PROG_FLAG_READ: u32 = 4;
-const ELF_MAGIC: u32 = 0x464C457F;
-
-pub fn parse_elf(a: &[u8], pgtable: &mut page::Table) -> u64 {
-    let a = a.as_ptr();
-    /* TODO: Use something safer */
-    // peek head of byte array to get ELF information
-    let elfhdr = unsafe { &*(a as *const ELFHeader) };
-    if elfhdr.magic != ELF_MAGIC {
-        panic!("wrong magic number");
-    }
-    let mut proghdr = unsafe {
-        let offset_u8 = a.offset(elfhdr.phoff as isize);
-        offset_u8 as *const ProgramHeader
+/// Load all segments of ELF file `a` into `pgtable`, record them in `vmas`
+/// and returns entry address.
+///
+/// Content in file is mapped on load, while the rest of each segment
+/// (e.g. `.bss`) is left to page fault handler.
+pub fn parse_elf(a: &[u8], pgtable: &mut page::Table, vmas: &mut VmaList) -> u64 {
+    let elf = match elf::parse_elf(a) {
+        Ok(elf) => elf,
+        Err(err) => panic!("bad elf: {:?}", err)
     };
-    for _i in 0..elfhdr.phnum {
-        let hdr: &ProgramHeader = unsafe {
-            let hdr = &*proghdr;
-            proghdr = proghdr.offset(1);
-            hdr
-        };
-        if hdr.ptype != ELF_PROG_LOAD {
-            continue;
-        }
-        if hdr.memsz < hdr.filesz {
-            panic!("bad elf: memsz");
-        }
-        if hdr.vaddr + hdr.memsz < hdr.vaddr {
-            panic!("bad elf: vaddr");
-        }
-        if hdr.vaddr as usize % PAGE_SIZE != 0 {
-            println!("{:X}", hdr.vaddr);
-            panic!("bad elf: vaddr align")
-        }
-        load_segment(
-            pgtable,
-            hdr.vaddr as usize,
-            a,
-            hdr.off as usize,
-            hdr.filesz as usize,
-        );
-        /* println!(
-            "map segment ELF 0x{:X}~0x{:X} -> MEM 0x{:X}",
-            hdr.off,
-            hdr.off + hdr.filesz,
-            hdr.vaddr
-        ); */
+    for seg in elf.segments.iter() {
+        let flags = elf::segment_flags(seg.flags);
+        load_segment(pgtable, seg.vaddr, seg.content(a), flags);
+        let kind = if seg.is_exec() { VmaKind::Text } else { VmaKind::Data };
+        vmas.push(Vma::new(seg.vaddr, seg.vaddr + seg.memsz, flags, kind));
     }
-    elfhdr.entry
+    elf.entry
 }
 
-fn load_segment(
-    pgtable: &mut page::Table,
-    vaddr: usize,
-    elf: *const u8,
-    offset: usize,
-    sz: usize,
-) {
-    let num_pages = mem::align_val(sz, PAGE_ORDER) / PAGE_SIZE;
-    for i in 0..num_pages {
+/// Map pages holding `content` at `vaddr`
+fn load_segment(pgtable: &mut page::Table, vaddr: usize, content: &[u8], flags: usize) {
+    // only copy file content, so that the rest of last page is zeroed
+    for (i, chunk) in content.chunks(PAGE_SIZE).enumerate() {
         let mut seg = page::Page::new();
-        let src = elf as *const u8;
-        unsafe {
-            let src = src.add(offset + i * PAGE_SIZE);
-            core::ptr::copy(src, seg.data.as_mut_ptr(), PAGE_SIZE);
-        }
-        use page::EntryAttributes;
-        pgtable.map(
-            vaddr + i * PAGE_SIZE,
-            seg,
-            EntryAttributes::URX as usize
-        );
+        seg.data[..chunk.len()].copy_from_slice(chunk);
+        pgtable.map(vaddr + i * PAGE_SIZE, seg, flags);
     }
 }
@@ -0,0 +1,483 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Flattened device tree
+//!
+//! QEMU passes address of device tree blob in a1. Boot hart parses it in
+//! `kinit`, before any driver is initialized, and keeps what kernel needs
+//! in `Machine`. Parsing doesn't allocate, as allocator itself is sized
+//! by memory found in device tree.
+//!
+//! Without a valid device tree, addresses of QEMU virt machine are used.
+
+use crate::symbols::NCPUS;
+use crate::virtio::{VIRTIO_SLOTS, VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE};
+use crate::uart::UART_BASE_ADDR;
+use crate::clint::CLINT_BASE;
+use crate::plic::{PLIC_BASE, UART0_IRQ, VIRTIO0_IRQ};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Maximum depth of nodes
+const MAX_DEPTH: usize = 16;
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let b = data.get(off..off + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Null-terminated string at `off`
+fn cstr(data: &[u8], off: usize) -> Option<&str> {
+    let rest = data.get(off..)?;
+    let len = rest.iter().position(|&c| c == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+const fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+/// A flattened device tree blob
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Check header of blob in `data`
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(data, 4)? as usize;
+        let off_struct = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let size_strings = be32(data, 32)? as usize;
+        let size_struct = be32(data, 36)? as usize;
+        let data = data.get(..total)?;
+        Some(Self {
+            structs: data.get(off_struct..off_struct + size_struct)?,
+            strings: data.get(off_strings..off_strings + size_strings)?,
+        })
+    }
+
+    /// Blob at physical address `addr`
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt<'static>> {
+        if addr == 0 || addr % 4 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, 8);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(header, 4)? as usize;
+        Fdt::new(core::slice::from_raw_parts(addr as *const u8, total))
+    }
+
+    /// All nodes in depth-first order
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            fdt: *self,
+            off: 0,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH + 1],
+        }
+    }
+}
+
+/// A node of device tree
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// name with unit address, such as `uart@10000000`
+    pub name: &'a str,
+    /// depth of node, 0 for root
+    pub depth: usize,
+    /// offset of properties in structure block
+    props: usize,
+    /// `#address-cells` and `#size-cells` of parent
+    cells: (u32, u32),
+}
+
+impl<'a> Node<'a> {
+    /// Iterate over properties as name and value
+    pub fn props(&self) -> Props<'a> {
+        Props { fdt: self.fdt, off: self.props }
+    }
+
+    /// Value of property `name`
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|&(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    /// Value of string property `name`
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        cstr(self.prop(name)?, 0)
+    }
+
+    /// Check if any string in `compatible` is `compat`
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        match self.prop("compatible") {
+            Some(value) => value.split(|&c| c == 0).any(|s| s == compat.as_bytes()),
+            None => false
+        }
+    }
+
+    /// Address and size of `n`th region in `reg`
+    pub fn reg(&self, n: usize) -> Option<(usize, usize)> {
+        let (addr_cells, size_cells) = (self.cells.0 as usize, self.cells.1 as usize);
+        let value = self.prop("reg")?;
+        let stride = (addr_cells + size_cells) * 4;
+        let entry = value.get(n * stride..(n + 1) * stride)?;
+        let read = |cells: &[u8]| cells.chunks(4).fold(0usize, |acc, c| {
+            (acc << 32) | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as usize
+        });
+        Some((read(&entry[..addr_cells * 4]), read(&entry[addr_cells * 4..])))
+    }
+
+    /// First interrupt in `interrupts`
+    pub fn interrupt(&self) -> Option<u32> {
+        be32(self.prop("interrupts")?, 0)
+    }
+}
+
+/// Iterator over nodes of device tree
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+    depth: usize,
+    /// `#address-cells` and `#size-cells` for children of each depth
+    cells: [(u32, u32); MAX_DEPTH + 1],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        let data = self.fdt.structs;
+        loop {
+            match be32(data, self.off)? {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(data, self.off + 4)?;
+                    self.off = align4(self.off + 4 + name.len() + 1);
+                    let depth = self.depth;
+                    if depth >= MAX_DEPTH {
+                        return None;
+                    }
+                    let props = self.off;
+                    let mut cells = (2, 1);
+                    loop {
+                        match be32(data, self.off)? {
+                            FDT_PROP => {
+                                let len = be32(data, self.off + 4)? as usize;
+                                let name = cstr(self.fdt.strings, be32(data, self.off + 8)? as usize)?;
+                                match name {
+                                    "#address-cells" => cells.0 = be32(data, self.off + 12)?,
+                                    "#size-cells" => cells.1 = be32(data, self.off + 12)?,
+                                    _ => {}
+                                }
+                                self.off = align4(self.off + 12 + len);
+                            }
+                            FDT_NOP => self.off += 4,
+                            _ => break
+                        }
+                    }
+                    self.cells[depth + 1] = cells;
+                    self.depth += 1;
+                    return Some(Node { fdt: self.fdt, name, depth, props, cells: self.cells[depth] });
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                    self.off += 4;
+                }
+                FDT_NOP => self.off += 4,
+                // properties are consumed with their node
+                _ => return None
+            }
+        }
+    }
+}
+
+/// Iterator over properties of a node
+pub struct Props<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+}
+
+impl<'a> Iterator for Props<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.fdt.structs;
+        loop {
+            match be32(data, self.off)? {
+                FDT_PROP => {
+                    let len = be32(data, self.off + 4)? as usize;
+                    let name = cstr(self.fdt.strings, be32(data, self.off + 8)? as usize)?;
+                    let value = data.get(self.off + 12..self.off + 12 + len)?;
+                    self.off = align4(self.off + 12 + len);
+                    return Some((name, value));
+                }
+                FDT_NOP => self.off += 4,
+                _ => return None
+            }
+        }
+    }
+}
+
+/// A memory-mapped device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    /// PLIC interrupt, 0 if none
+    pub irq: u32,
+}
+
+impl MmioDevice {
+    pub const fn new(base: usize, size: usize, irq: u32) -> Self {
+        Self { base, size, irq }
+    }
+}
+
+/// Hardware found in device tree
+pub struct Machine {
+    /// address of device tree blob, 0 if there is none
+    pub dtb: usize,
+    /// size of device tree blob
+    pub dtb_size: usize,
+    /// RAM holding kernel
+    pub memory: (usize, usize),
+    /// number of harts
+    pub harts: usize,
+    pub uart: MmioDevice,
+    pub clint: MmioDevice,
+    pub plic: MmioDevice,
+    /// sifive_test device, which powers off or resets machine, base 0 if none
+    pub test: MmioDevice,
+    /// virtio-mmio slots, in order of address
+    pub virtio: [MmioDevice; VIRTIO_SLOTS],
+    pub virtio_count: usize,
+}
+
+impl Machine {
+    /// QEMU virt machine with 128 MiB of RAM and 4 harts
+    pub const fn qemu_virt() -> Self {
+        const fn slot(i: usize) -> MmioDevice {
+            MmioDevice::new(VIRTIO_MMIO_BASE + i * VIRTIO_MMIO_SIZE, VIRTIO_MMIO_SIZE, VIRTIO0_IRQ + i as u32)
+        }
+        Self {
+            dtb: 0,
+            dtb_size: 0,
+            memory: (0x8000_0000, 0x8000_0000 + 128 * 1024 * 1024),
+            harts: 4,
+            uart: MmioDevice::new(UART_BASE_ADDR, 0x100, UART0_IRQ),
+            clint: MmioDevice::new(CLINT_BASE, 0x10000, 0),
+            plic: MmioDevice::new(PLIC_BASE, 0x400_0000, 0),
+            test: MmioDevice::new(0x10_0000, 0x1000, 0),
+            virtio: [slot(0), slot(1), slot(2), slot(3), slot(4), slot(5), slot(6), slot(7)],
+            virtio_count: VIRTIO_SLOTS,
+        }
+    }
+
+    /// Fill in hardware found in `fdt`. Memory region is the one
+    /// containing `kernel`.
+    fn parse(&mut self, fdt: &Fdt, kernel: usize) {
+        let mut harts = 0;
+        let mut virtio = 0;
+        for node in fdt.nodes() {
+            let mmio = || {
+                let (base, size) = node.reg(0)?;
+                Some(MmioDevice::new(base, size, node.interrupt().unwrap_or(0)))
+            };
+            if node.prop_str("device_type") == Some("memory") {
+                let mut i = 0;
+                while let Some((base, size)) = node.reg(i) {
+                    if base <= kernel && kernel < base + size {
+                        self.memory = (base, base + size);
+                    }
+                    i += 1;
+                }
+            } else if node.prop_str("device_type") == Some("cpu") {
+                if node.prop_str("status").map_or(true, |s| s == "okay") {
+                    harts += 1;
+                }
+            } else if node.is_compatible("ns16550a") {
+                if let Some(dev) = mmio() { self.uart = dev; }
+            } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+                if let Some(dev) = mmio() { self.clint = dev; }
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+                if let Some(dev) = mmio() { self.plic = dev; }
+            } else if node.is_compatible("sifive,test0") {
+                if let Some(dev) = mmio() { self.test = dev; }
+            } else if node.is_compatible("virtio,mmio") {
+                if let Some(dev) = mmio() {
+                    if virtio < VIRTIO_SLOTS {
+                        self.virtio[virtio] = dev;
+                        virtio += 1;
+                    }
+                }
+            }
+        }
+        if harts > 0 {
+            self.harts = harts;
+        }
+        // device tree lists slots from the highest address
+        let slots = &mut self.virtio[..virtio];
+        slots.sort_unstable_by_key(|dev| dev.base);
+        self.virtio_count = virtio;
+    }
+
+    /// Index of virtio slot raising `irq`
+    pub fn virtio_slot(&self, irq: u32) -> Option<usize> {
+        self.virtio[..self.virtio_count].iter().position(|dev| dev.irq == irq)
+    }
+}
+
+/// Hardware of this machine. It is in data section, as it's filled in
+/// before other harts may proceed to boot.
+static mut MACHINE: Machine = Machine::qemu_virt();
+
+/// Hardware of this machine
+pub fn machine() -> &'static Machine {
+    unsafe { &MACHINE }
+}
+
+/// Parse device tree at `dtb`, which may be 0 if there is none.
+/// Memory found is the region containing `kernel`.
+///
+/// Should be called in boot hart before others start.
+pub unsafe fn init(dtb: usize, kernel: usize) {
+    let fdt = match Fdt::from_addr(dtb) {
+        Some(fdt) => fdt,
+        None => return
+    };
+    // under SBI, firmware console is used if device tree has no UART
+    #[cfg(feature = "sbi")]
+    {
+        MACHINE.uart = MmioDevice::new(0, 0, 0);
+    }
+    // power off device is used only if found
+    MACHINE.test = MmioDevice::new(0, 0, 0);
+    MACHINE.dtb = dtb;
+    MACHINE.dtb_size = be32(core::slice::from_raw_parts(dtb as *const u8, 8), 4).unwrap() as usize;
+    MACHINE.parse(&fdt, kernel);
+    if MACHINE.harts > NCPUS {
+        MACHINE.harts = NCPUS;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Build a blob with root `#address-cells = 2`, `#size-cells = 2`
+    /// and the given children, each as name and properties
+    fn build(children: &[(&str, &[(&str, &[u8])])]) -> Vec<u8> {
+        let mut structs = Vec::new();
+        let mut strings: Vec<u8> = Vec::new();
+        let push = |v: &mut Vec<u8>, x: u32| v.extend_from_slice(&x.to_be_bytes());
+        let name = |v: &mut Vec<u8>, s: &str| {
+            v.extend_from_slice(s.as_bytes());
+            v.push(0);
+            while v.len() % 4 != 0 { v.push(0); }
+        };
+        let prop = |v: &mut Vec<u8>, strings: &mut Vec<u8>, n: &str, value: &[u8]| {
+            let off = strings.len() as u32;
+            strings.extend_from_slice(n.as_bytes());
+            strings.push(0);
+            v.extend_from_slice(&FDT_PROP.to_be_bytes());
+            v.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            v.extend_from_slice(&off.to_be_bytes());
+            v.extend_from_slice(value);
+            while v.len() % 4 != 0 { v.push(0); }
+        };
+        push(&mut structs, FDT_BEGIN_NODE);
+        name(&mut structs, "");
+        prop(&mut structs, &mut strings, "#address-cells", &2u32.to_be_bytes());
+        prop(&mut structs, &mut strings, "#size-cells", &2u32.to_be_bytes());
+        for (child, props) in children {
+            push(&mut structs, FDT_BEGIN_NODE);
+            name(&mut structs, child);
+            for (n, value) in props.iter() {
+                prop(&mut structs, &mut strings, n, value);
+            }
+            push(&mut structs, FDT_END_NODE);
+        }
+        push(&mut structs, FDT_END_NODE);
+        push(&mut structs, FDT_END);
+
+        let off_struct = 40;
+        let off_strings = off_struct + structs.len();
+        let total = off_strings + strings.len();
+        let mut blob = Vec::new();
+        for &x in [FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, 0, 17, 16, 0,
+                   strings.len() as u32, structs.len() as u32].iter() {
+            push(&mut blob, x);
+        }
+        blob.extend_from_slice(&structs);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    fn reg(base: u64, size: u64) -> [u8; 16] {
+        let mut r = [0; 16];
+        r[..8].copy_from_slice(&base.to_be_bytes());
+        r[8..].copy_from_slice(&size.to_be_bytes());
+        r
+    }
+
+    /// Test parsing a blob built in memory
+    #[test_case]
+    fn test_parse() {
+        let mem = reg(0x8000_0000, 0x2000_0000);
+        let uart = reg(0x1000_0000, 0x100);
+        let v0 = reg(0x1000_1000, 0x1000);
+        let v1 = reg(0x1000_2000, 0x1000);
+        let blob = build(&[
+            ("memory@80000000", &[("device_type", b"memory\0"), ("reg", &mem)]),
+            ("cpu@0", &[("device_type", b"cpu\0")]),
+            ("cpu@1", &[("device_type", b"cpu\0"), ("status", b"okay\0")]),
+            ("cpu@2", &[("device_type", b"cpu\0"), ("status", b"disabled\0")]),
+            ("uart@10000000", &[("compatible", b"ns16550a\0"), ("reg", &uart), ("interrupts", &[0, 0, 0, 10])]),
+            ("virtio_mmio@10002000", &[("compatible", b"virtio,mmio\0"), ("reg", &v1), ("interrupts", &[0, 0, 0, 2])]),
+            ("virtio_mmio@10001000", &[("compatible", b"virtio,mmio\0"), ("reg", &v0), ("interrupts", &[0, 0, 0, 1])]),
+        ]);
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.nodes().count(), 8);
+        let root = fdt.nodes().next().unwrap();
+        assert_eq!((root.name, root.depth), ("", 0));
+        assert!(fdt.nodes().skip(1).all(|n| n.depth == 1));
+        assert!(Fdt::new(&blob[4..]).is_none());
+
+        let mut m = Machine::qemu_virt();
+        m.parse(&fdt, 0x8020_0000);
+        assert_eq!(m.memory, (0x8000_0000, 0xa000_0000));
+        assert_eq!(m.harts, 2);
+        assert_eq!(m.uart, MmioDevice::new(0x1000_0000, 0x100, 10));
+        assert_eq!(m.virtio_count, 2);
+        assert_eq!(m.virtio[0].base, 0x1000_1000);
+        assert_eq!(m.virtio_slot(2), Some(1));
+        assert_eq!(m.virtio_slot(3), None);
+    }
+
+    /// Test hardware found on this machine
+    #[test_case]
+    fn test_machine() {
+        let m = machine();
+        assert!(m.dtb != 0);
+        assert!(m.harts >= 1 && m.harts <= NCPUS);
+        assert!(m.memory.0 <= crate::symbols::TEXT_START() && crate::symbols::TEXT_START() < m.memory.1);
+        assert!(m.virtio_count >= 1);
+        assert_ne!(m.uart.irq, 0);
+        assert_ne!(m.test.base, 0);
+    }
+}
@@ -3,19 +3,72 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! File in core-os including file in filesystem, device, pipe and symbol link
+//! File in core-os including file in filesystem, device, pipe, shared memory, channel, socket and symbol link
 
 pub mod device;
-pub use device::{Device, Console};
+pub use device::{Device, DeviceHandle, OpenDevice, SeekFrom, Console, open_device, register_driver};
+
+pub mod blkdev;
 
 pub mod fsfile;
-pub use fsfile::FsFile;
+pub use fsfile::{FsFile, DirEntry, MknodError};
+
+pub mod shm;
+pub use shm::SharedMemory;
+
+pub mod channel;
+pub use channel::{Channel, ChannelError};
+
+pub mod pipe;
+pub use pipe::{PipeEnd, PipeError};
+
+pub mod poll;
+pub use poll::{poll, PollEntry};
 
-use alloc::boxed::Box;
+pub mod socket;
+pub use socket::Socket;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::process::WaitQueue;
+
+/// File is readable
+pub const POLLIN: usize = 0x1;
+/// File is writable
+pub const POLLOUT: usize = 0x4;
+/// File descriptor is invalid
+pub const POLLNVAL: usize = 0x20;
 
 /// File in core-os
 pub enum File {
-    Device(Box<dyn Device>),
+    Device(OpenDevice),
     FsFile(FsFile),
-    Pipe
+    Shm(SharedMemory),
+    Channel(Channel),
+    Socket(Socket),
+    Pipe(PipeEnd),
+}
+
+impl File {
+    /// Returns ready events (`POLLIN`, `POLLOUT`) of file
+    pub fn poll(&self) -> usize {
+        match self {
+            File::Device(dev) => dev.poll(),
+            File::FsFile(_) | File::Shm(_) => POLLIN | POLLOUT,
+            File::Channel(chan) => chan.poll(),
+            File::Socket(sock) => sock.poll(),
+            File::Pipe(pipe) => pipe.poll(),
+        }
+    }
+
+    /// Wait queues and channels on which readiness changes are notified
+    pub fn wait_queues(&self) -> Vec<(&WaitQueue, usize)> {
+        match self {
+            File::Device(dev) => dev.wait_queue().into_iter().collect(),
+            File::Channel(chan) => chan.wait_queues().to_vec(),
+            File::Socket(sock) => sock.wait_queues(),
+            File::Pipe(pipe) => vec![pipe.wait_queue()],
+            _ => Vec::new()
+        }
+    }
 }
@@ -0,0 +1,153 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Raw block device on virtio disk
+//!
+//! Block device is read and written at byte offsets. Partial blocks are
+//! read from disk, modified and written back.
+
+use alloc::sync::Arc;
+use crate::virtio::{VirtIO, Buf, BSIZE};
+use crate::virtio::blk::disk;
+use super::device::{Device, DeviceHandle, SeekFrom, ENOTTY};
+
+/// Major number of virtio block devices
+pub const MAJOR_VIRTIO_BLK: usize = 254;
+/// Minor number of `/dev/vda`. Minor number `n` is the `n`th disk found.
+pub const MINOR_VDA: usize = 0;
+/// Minor number of `/dev/vdb`
+pub const MINOR_VDB: usize = 1;
+
+/// ioctl command to get number of 512-byte sectors, which is returned
+pub const BLKGETSIZE: usize = 0x1260;
+/// ioctl command to get block size, which is returned
+pub const BLKSSZGET: usize = 0x1268;
+
+/// Block device of virtio disk
+pub struct BlockDevice {
+    disk: &'static VirtIO,
+}
+
+impl BlockDevice {
+    fn size(&self) -> usize {
+        self.disk.capacity()
+    }
+}
+
+impl Device for BlockDevice {
+    /// read from current offset, no further than end of disk
+    fn read(&self, handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
+        let virtio = self.disk;
+        let off = handle.offset;
+        let end = self.size().min(off + content.len());
+        let mut pos = off;
+        while pos < end {
+            let b = virtio.read(1, (pos / BSIZE) as u32);
+            let blk_off = pos % BSIZE;
+            let sz = (BSIZE - blk_off).min(end - pos);
+            content[pos - off..pos - off + sz].copy_from_slice(&b.data[blk_off..blk_off + sz]);
+            pos += sz;
+        }
+        handle.offset = pos;
+        (pos - off) as i32
+    }
+
+    /// write at current offset, no further than end of disk
+    fn write(&self, handle: &mut DeviceHandle, content: &[u8]) -> i32 {
+        let virtio = self.disk;
+        let off = handle.offset;
+        let end = self.size().min(off + content.len());
+        let mut pos = off;
+        while pos < end {
+            let blockno = (pos / BSIZE) as u32;
+            let blk_off = pos % BSIZE;
+            let sz = (BSIZE - blk_off).min(end - pos);
+            let mut b = if sz == BSIZE {
+                let mut b = box Buf::new();
+                b.dev = 1;
+                b.blockno = blockno;
+                b
+            } else {
+                virtio.read(1, blockno)
+            };
+            b.data[blk_off..blk_off + sz].copy_from_slice(&content[pos - off..pos - off + sz]);
+            virtio.write(b);
+            pos += sz;
+        }
+        handle.offset = pos;
+        (pos - off) as i32
+    }
+
+    fn ioctl(&self, _handle: &mut DeviceHandle, cmd: usize, _arg: usize) -> i32 {
+        match cmd {
+            BLKGETSIZE => (self.size() / 512) as i32,
+            BLKSSZGET => BSIZE as i32,
+            _ => -ENOTTY
+        }
+    }
+
+    fn seek(&self, handle: &mut DeviceHandle, pos: SeekFrom) -> Option<usize> {
+        handle.seek_within(pos, self.size())
+    }
+}
+
+/// Create block device of `minor` number
+pub fn open_blk(minor: usize) -> Option<Arc<dyn Device>> {
+    Some(Arc::new(BlockDevice { disk: disk(minor)? }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::open_device;
+    use crate::virtio::VIRTIO;
+
+    /// Test capacity and seeking beyond end of disk
+    #[test_case]
+    fn test_size() {
+        let vda = open_device(MAJOR_VIRTIO_BLK, MINOR_VDA).unwrap();
+        let size = vda.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(size, VIRTIO().capacity());
+        assert_eq!(vda.ioctl(BLKGETSIZE, 0) as usize, size / 512);
+        assert!(vda.seek(SeekFrom::End(1)).is_none());
+        let mut buf = [0; 4];
+        assert_eq!(vda.read(&mut buf), 0);
+    }
+
+    /// Test reading and writing across block boundary
+    #[test_case]
+    fn test_rw() {
+        let vda = open_device(MAJOR_VIRTIO_BLK, MINOR_VDA).unwrap();
+        let mut header = [0; 24];
+        assert_eq!(vda.read(&mut header), 24);
+        assert_eq!(&header[..], &VIRTIO().read(1, 0).data[..24]);
+        let mut buf = [0; 16];
+        vda.seek(SeekFrom::Start(BSIZE - 8)).unwrap();
+        assert_eq!(vda.read(&mut buf), 16);
+        vda.seek(SeekFrom::Current(-16)).unwrap();
+        assert_eq!(vda.write(&buf), 16);
+        let mut buf2 = [1; 16];
+        vda.seek(SeekFrom::Start(BSIZE - 8)).unwrap();
+        assert_eq!(vda.read(&mut buf2), 16);
+        assert_eq!(buf, buf2);
+    }
+
+    /// Test second disk, if attached, is independent from the first one
+    #[test_case]
+    fn test_second_disk() {
+        assert!(open_device(MAJOR_VIRTIO_BLK, 7).is_none());
+        let vdb = match open_device(MAJOR_VIRTIO_BLK, MINOR_VDB) {
+            Some(vdb) => vdb,
+            None => return
+        };
+        let data = [0x5a; 16];
+        assert_eq!(vdb.write(&data), 16);
+        let mut buf = [0; 16];
+        vdb.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(vdb.read(&mut buf), 16);
+        assert_eq!(buf, data);
+        assert_ne!(&VIRTIO().read(1, 0).data[..16], &data[..]);
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Message-passing channel
+//!
+//! A channel is a bounded queue of messages. Unlike pipes, each message
+//! keeps its boundary, and may carry a file as a capability. Senders block
+//! when the queue is full, and receivers block when it is empty.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::process::WaitQueue;
+use crate::arch;
+use super::{File, POLLIN, POLLOUT};
+
+/// Maximum size of a message in bytes
+pub const MAX_MESSAGE: usize = 1024;
+/// Maximum number of messages in a channel
+pub const MAX_CAPACITY: usize = 64;
+
+/// Channel operation error
+#[derive(Debug, PartialEq)]
+pub enum ChannelError {
+    /// invalid capacity or message
+    Invalid,
+    /// message is larger than `MAX_MESSAGE`
+    TooLarge,
+    /// timeout before operation can be done
+    TimedOut,
+}
+
+/// A message and the file passed with it
+pub struct Message {
+    pub data: Vec<u8>,
+    pub file: Option<Arc<File>>,
+}
+
+pub struct Channel {
+    capacity: usize,
+    queue: Mutex<VecDeque<Message>>,
+    /// processes waiting for a message
+    readers: WaitQueue,
+    /// processes waiting for a free slot
+    writers: WaitQueue,
+}
+
+impl Channel {
+    /// Create a channel holding at most `capacity` messages
+    pub fn new(capacity: usize) -> Result<Self, ChannelError> {
+        if capacity == 0 || capacity > MAX_CAPACITY {
+            return Err(ChannelError::Invalid);
+        }
+        Ok(Self {
+            capacity,
+            queue: Mutex::new(VecDeque::new(), "channel"),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+        })
+    }
+
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Sleep on `queue` until woken up or `deadline` has passed
+    fn wait<'a>(&self, queue: &WaitQueue, lck: MutexGuard<'a, VecDeque<Message>>, deadline: Option<Duration>)
+                -> Result<MutexGuard<'a, VecDeque<Message>>, ChannelError> {
+        match deadline {
+            Some(deadline) => {
+                if arch::time() >= deadline {
+                    return Err(ChannelError::TimedOut);
+                }
+                Ok(queue.sleep_timeout(self.channel(), lck, deadline).0)
+            }
+            None => Ok(queue.sleep(self.channel(), lck))
+        }
+    }
+
+    /// Send `data` and `file` as one message, waiting at most `timeout`
+    /// for a free slot.
+    pub fn send(&self, data: &[u8], file: Option<Arc<File>>, timeout: Option<Duration>) -> Result<(), ChannelError> {
+        if data.len() > MAX_MESSAGE {
+            return Err(ChannelError::TooLarge);
+        }
+        let deadline = timeout.map(|t| arch::time() + t);
+        let mut queue = self.queue.lock();
+        while queue.len() >= self.capacity {
+            queue = self.wait(&self.writers, queue, deadline)?;
+        }
+        queue.push_back(Message { data: data.to_vec(), file });
+        // all readers are woken up, as some of them may be polling
+        self.readers.wakeup(self.channel());
+        Ok(())
+    }
+
+    /// Receive a message into `content`, waiting at most `timeout` for one.
+    ///
+    /// Message longer than `content` is truncated. Returns number of bytes
+    /// received and the file passed with message.
+    pub fn recv(&self, content: &mut [u8], timeout: Option<Duration>) -> Result<(usize, Option<Arc<File>>), ChannelError> {
+        let deadline = timeout.map(|t| arch::time() + t);
+        let mut queue = self.queue.lock();
+        let msg = loop {
+            match queue.pop_front() {
+                Some(msg) => break msg,
+                None => queue = self.wait(&self.readers, queue, deadline)?
+            }
+        };
+        self.writers.wakeup(self.channel());
+        drop(queue);
+        let sz = msg.data.len().min(content.len());
+        content[..sz].copy_from_slice(&msg.data[..sz]);
+        Ok((sz, msg.file))
+    }
+
+    /// Number of messages in queue
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    /// Returns ready events. Channel is readable if there are messages,
+    /// and writable if there are free slots.
+    pub fn poll(&self) -> usize {
+        let len = self.len();
+        let mut events = 0;
+        if len > 0 {
+            events |= POLLIN;
+        }
+        if len < self.capacity {
+            events |= POLLOUT;
+        }
+        events
+    }
+
+    /// Wait queues and channels on which readiness changes are notified
+    pub fn wait_queues(&self) -> [(&WaitQueue, usize); 2] {
+        [(&self.readers, self.channel()), (&self.writers, self.channel())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test messages keep their boundaries and order
+    #[test_case]
+    fn test_send_recv() {
+        let chan = Channel::new(4).unwrap();
+        chan.send(b"hello", None, None).unwrap();
+        chan.send(b"world!", None, None).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(chan.recv(&mut buf, None).unwrap().0, 5);
+        assert_eq!(&buf[..5], b"hello");
+        let mut small = [0; 3];
+        assert_eq!(chan.recv(&mut small, None).unwrap().0, 3);
+        assert_eq!(&small, b"wor");
+        assert_eq!(chan.len(), 0);
+    }
+
+    /// Test timeout on full and empty channel
+    #[test_case]
+    fn test_bounded() {
+        let chan = Channel::new(1).unwrap();
+        let timeout = Some(Duration::from_millis(0));
+        chan.send(b"1", None, timeout).unwrap();
+        assert_eq!(chan.send(b"2", None, timeout), Err(ChannelError::TimedOut));
+        let mut buf = [0; 1];
+        assert_eq!(chan.recv(&mut buf, timeout).unwrap().0, 1);
+        assert_eq!(chan.recv(&mut buf, timeout).err(), Some(ChannelError::TimedOut));
+        assert_eq!(chan.send(&[0; MAX_MESSAGE + 1], None, None), Err(ChannelError::TooLarge));
+        assert_eq!(Channel::new(0).err(), Some(ChannelError::Invalid));
+    }
+}
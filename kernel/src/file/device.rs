@@ -3,29 +3,168 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! Device trait for devices such as Console
+//! Device trait, device driver registry and built-in devices
+//!
+//! Device nodes in file system record major and minor number of devices.
+//! On open, driver registered with major number creates the device of
+//! minor number.
 
-use crate::uart::UART;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use crate::sleeplock::SleepLock;
+use crate::uart::{UART, UART_RX_QUEUE, uart_rx_channel};
+use crate::process::WaitQueue;
+use crate::spinlock::Mutex;
+use crate::{random, panic};
+use super::{POLLIN, POLLOUT};
+
+/// Per-open state of a device, passed to device operations
+pub struct DeviceHandle {
+    /// read and write offset of seekable devices
+    pub offset: usize,
+    /// driver-specific state, set in `Device::open`
+    pub private: Option<Box<dyn Any + Send + Sync>>,
+}
+
+/// Seek position, same as `whence` and `offset` of `lseek`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+impl DeviceHandle {
+    pub const fn new() -> Self {
+        Self { offset: 0, private: None }
+    }
+
+    /// Move offset to `pos` in a device of `size` bytes. Returns new offset.
+    /// Offset may not be moved beyond end of device.
+    pub fn seek_within(&mut self, pos: SeekFrom, size: usize) -> Option<usize> {
+        let offset = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => add_signed(self.offset, x),
+            SeekFrom::End(x) => add_signed(size, x),
+        }?;
+        if offset > size {
+            return None;
+        }
+        self.offset = offset;
+        Some(offset)
+    }
+}
+
+fn add_signed(base: usize, x: isize) -> Option<usize> {
+    if x < 0 {
+        base.checked_sub(x.wrapping_neg() as usize)
+    } else {
+        base.checked_add(x as usize)
+    }
+}
+
+/// Inappropriate ioctl for device
+pub const ENOTTY: i32 = 25;
 
 /// Device trait
 ///
-/// All device should implement their own synchronize mechanisms.
+/// A device is shared by all its opens, and all device should implement
+/// their own synchronize mechanisms. State of each open is kept in
+/// `DeviceHandle`.
 pub trait Device: Send + Sync {
+    /// Called when device is opened. Returns `None` if it can't be opened.
+    fn open(&self, _handle: &mut DeviceHandle) -> Option<()> {
+        Some(())
+    }
+    /// Called when the last file descriptor of an open is closed
+    fn close(&self, _handle: &mut DeviceHandle) {}
     /// Read from file to content and returns number of characters (<= `content.len()`) read.
-    fn read(&self, content: &mut [u8]) -> i32;
+    fn read(&self, handle: &mut DeviceHandle, content: &mut [u8]) -> i32;
     /// Write content to file and returns number of characters written.
-    fn write(&self, content: &[u8]) -> i32;
+    fn write(&self, handle: &mut DeviceHandle, content: &[u8]) -> i32;
+    /// Device-specific control operation `cmd`. Returns `-ENOTTY` by default.
+    fn ioctl(&self, _handle: &mut DeviceHandle, _cmd: usize, _arg: usize) -> i32 {
+        -ENOTTY
+    }
+    /// Move offset of a seekable device. Returns new offset, or `None`
+    /// if device is not seekable (default) or `pos` is invalid.
+    fn seek(&self, _handle: &mut DeviceHandle, _pos: SeekFrom) -> Option<usize> {
+        None
+    }
+    /// Returns ready events (`POLLIN`, `POLLOUT`). Device is always ready by default.
+    fn poll(&self) -> usize {
+        POLLIN | POLLOUT
+    }
+    /// Wait queue and channel on which device notifies readiness changes
+    fn wait_queue(&self) -> Option<(&WaitQueue, usize)> {
+        None
+    }
+}
+
+/// An open device
+///
+/// Operations may sleep, so per-open state is protected by a sleep lock.
+pub struct OpenDevice {
+    dev: Arc<dyn Device>,
+    handle: SleepLock<DeviceHandle>,
+}
+
+impl OpenDevice {
+    /// Open `dev`. Returns `None` if device refuses to be opened.
+    pub fn open(dev: Arc<dyn Device>) -> Option<Self> {
+        let mut handle = DeviceHandle::new();
+        dev.open(&mut handle)?;
+        Some(Self { dev, handle: SleepLock::new(handle, "device handle") })
+    }
+
+    pub fn read(&self, content: &mut [u8]) -> i32 {
+        self.dev.read(&mut self.handle.acquire(), content)
+    }
+
+    pub fn write(&self, content: &[u8]) -> i32 {
+        self.dev.write(&mut self.handle.acquire(), content)
+    }
+
+    pub fn ioctl(&self, cmd: usize, arg: usize) -> i32 {
+        self.dev.ioctl(&mut self.handle.acquire(), cmd, arg)
+    }
+
+    pub fn seek(&self, pos: SeekFrom) -> Option<usize> {
+        self.dev.seek(&mut self.handle.acquire(), pos)
+    }
+
+    pub fn poll(&self) -> usize {
+        self.dev.poll()
+    }
+
+    pub fn wait_queue(&self) -> Option<(&WaitQueue, usize)> {
+        self.dev.wait_queue()
+    }
+}
+
+impl Drop for OpenDevice {
+    fn drop(&mut self) {
+        // no one else can hold the handle, and file may be dropped
+        // with spinlocks held, so don't go to sleep here
+        self.dev.close(unsafe { self.handle.get() });
+    }
 }
 
+/// ioctl command to get number of bytes available for reading.
+/// Unlike Linux, the number is returned instead of written to `arg`.
+pub const FIONREAD: usize = 0x541b;
+
 /// Console device
 pub struct Console {}
 
 impl Device for Console {
-    /// read from console
-    fn read(&self, content: &mut [u8]) -> i32 {
+    /// read characters received from console
+    fn read(&self, _handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
         let mut uart = UART().lock();
         for i in 0..content.len() {
-            match uart.get() {
+            match uart.pop_rx() {
                 Some(ch) => { content[i] = ch; }
                 _ => { return i as i32; }
             }
@@ -34,11 +173,189 @@ impl Device for Console {
     }
 
     /// write to console
-    fn write(&self, content: &[u8]) -> i32 {
+    fn write(&self, _handle: &mut DeviceHandle, content: &[u8]) -> i32 {
         let mut uart = UART().lock();
         for i in 0..content.len() {
             uart.put(content[i]);
         }
         return content.len() as i32;
     }
+
+    /// `FIONREAD` returns number of characters received and not yet read
+    fn ioctl(&self, _handle: &mut DeviceHandle, cmd: usize, _arg: usize) -> i32 {
+        match cmd {
+            FIONREAD => UART().lock().rx_len() as i32,
+            _ => -ENOTTY
+        }
+    }
+
+    /// console is readable if there are characters received
+    fn poll(&self) -> usize {
+        if UART().lock().rx_len() > 0 {
+            POLLIN | POLLOUT
+        } else {
+            POLLOUT
+        }
+    }
+
+    fn wait_queue(&self) -> Option<(&WaitQueue, usize)> {
+        Some((&UART_RX_QUEUE, uart_rx_channel()))
+    }
+}
+
+/// Null device, discarding all writes and reading nothing
+pub struct Null {}
+
+impl Device for Null {
+    fn read(&self, _handle: &mut DeviceHandle, _content: &mut [u8]) -> i32 {
+        0
+    }
+
+    fn write(&self, _handle: &mut DeviceHandle, content: &[u8]) -> i32 {
+        content.len() as i32
+    }
+}
+
+/// Zero device, reading zeros and discarding all writes
+pub struct Zero {}
+
+impl Device for Zero {
+    fn read(&self, _handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
+        for x in content.iter_mut() {
+            *x = 0;
+        }
+        content.len() as i32
+    }
+
+    fn write(&self, _handle: &mut DeviceHandle, content: &[u8]) -> i32 {
+        content.len() as i32
+    }
+}
+
+/// Random device, reading from kernel entropy pool. Writes are mixed into
+/// pool without raising its entropy estimate.
+pub struct Random {}
+
+impl Device for Random {
+    fn read(&self, _handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
+        random::fill(content);
+        content.len() as i32
+    }
+
+    fn write(&self, _handle: &mut DeviceHandle, content: &[u8]) -> i32 {
+        random::add_entropy(content, 0);
+        content.len() as i32
+    }
+}
+
+/// Create device of minor number, or `None` if there's no such device
+pub type DeviceOpen = fn(minor: usize) -> Option<Arc<dyn Device>>;
+
+/// Major number of memory devices
+pub const MAJOR_MEM: usize = 1;
+/// Major number of terminals
+pub const MAJOR_TTY: usize = 5;
+/// Major number of misc devices
+pub const MAJOR_MISC: usize = 10;
+
+/// Minor number of `/dev/null`
+pub const MINOR_NULL: usize = 3;
+/// Minor number of `/dev/zero`
+pub const MINOR_ZERO: usize = 5;
+/// Minor number of `/dev/random`
+pub const MINOR_RANDOM: usize = 8;
+/// Minor number of `/dev/console`
+pub const MINOR_CONSOLE: usize = 1;
+/// Minor number of `/dev/hwrng`
+pub const MINOR_HWRNG: usize = 183;
+
+/// Major numbers and drivers of registered devices
+static DRIVERS: Mutex<Vec<(usize, DeviceOpen)>> = Mutex::new(Vec::new(), "drivers");
+
+/// Register driver of `major` number
+pub fn register_driver(major: usize, open: DeviceOpen) {
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().any(|&(x, _)| x == major) {
+        panic!("major {} already registered", major);
+    }
+    drivers.push((major, open));
+}
+
+/// Open device of `major` and `minor` number
+pub fn open_device(major: usize, minor: usize) -> Option<OpenDevice> {
+    let open = DRIVERS.lock().iter().find(|&&(x, _)| x == major)?.1;
+    OpenDevice::open(open(minor)?)
+}
+
+fn open_mem(minor: usize) -> Option<Arc<dyn Device>> {
+    match minor {
+        MINOR_NULL => Some(Arc::new(Null {})),
+        MINOR_ZERO => Some(Arc::new(Zero {})),
+        MINOR_RANDOM => Some(Arc::new(Random {})),
+        _ => None
+    }
+}
+
+fn open_tty(minor: usize) -> Option<Arc<dyn Device>> {
+    match minor {
+        MINOR_CONSOLE => Some(Arc::new(Console {})),
+        _ => None
+    }
+}
+
+fn open_misc(minor: usize) -> Option<Arc<dyn Device>> {
+    match minor {
+        MINOR_HWRNG => crate::virtio::rng::open_hwrng(),
+        _ => None
+    }
+}
+
+/// Register built-in device drivers
+pub fn init() {
+    register_driver(MAJOR_MEM, open_mem);
+    register_driver(MAJOR_TTY, open_tty);
+    register_driver(MAJOR_MISC, open_misc);
+    register_driver(super::blkdev::MAJOR_VIRTIO_BLK, super::blkdev::open_blk);
+    register_driver(crate::virtio::console::MAJOR_HVC, crate::virtio::console::open_hvc);
+    register_driver(crate::virtio::input::MAJOR_INPUT, crate::virtio::input::open_input);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test opening devices through registry
+    #[test_case]
+    fn test_registry() {
+        assert!(open_device(MAJOR_MEM, MINOR_NULL).is_some());
+        assert!(open_device(MAJOR_TTY, MINOR_CONSOLE).is_some());
+        assert!(open_device(MAJOR_MEM, 0).is_none());
+        assert!(open_device(0, 0).is_none());
+    }
+
+    /// Test null, zero and random devices
+    #[test_case]
+    fn test_mem() {
+        let mut buf = [1; 16];
+        assert_eq!(open_device(MAJOR_MEM, MINOR_NULL).unwrap().read(&mut buf), 0);
+        assert_eq!(open_device(MAJOR_MEM, MINOR_ZERO).unwrap().read(&mut buf), 16);
+        assert_eq!(buf, [0; 16]);
+        assert_eq!(open_device(MAJOR_MEM, MINOR_RANDOM).unwrap().read(&mut buf[..13]), 13);
+        assert_ne!(buf, [0; 16]);
+    }
+
+    /// Test seeking within bounds
+    #[test_case]
+    fn test_seek() {
+        let mut handle = DeviceHandle::new();
+        assert_eq!(handle.seek_within(SeekFrom::Start(10), 100), Some(10));
+        assert_eq!(handle.seek_within(SeekFrom::Current(-4), 100), Some(6));
+        assert_eq!(handle.seek_within(SeekFrom::Current(-7), 100), None);
+        assert_eq!(handle.seek_within(SeekFrom::End(-1), 100), Some(99));
+        assert_eq!(handle.seek_within(SeekFrom::End(1), 100), None);
+        assert_eq!(handle.offset, 99);
+        let console = open_device(MAJOR_TTY, MINOR_CONSOLE).unwrap();
+        assert_eq!(console.seek(SeekFrom::Start(0)), None);
+        assert_eq!(console.ioctl(0, 0), -ENOTTY);
+    }
 }
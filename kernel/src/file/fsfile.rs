@@ -5,9 +5,21 @@
 
 //! File on file system
 
-use crate::virtio::{VIRTIO, VirtIO, BSIZE};
+use crate::virtio::{VIRTIO, Buf};
+use kernel_core::fs::{self, BlockDevice, BSIZE};
+pub use kernel_core::fs::{DirEntry, MknodError};
 use crate::{print, println};
 use crate::spinlock::Mutex;
+use crate::sleeplock::SleepLock;
+
+/// Open for reading only
+pub const O_RDONLY: usize = 0;
+/// Open for writing only
+pub const O_WRONLY: usize = 1;
+/// Open for reading and writing
+pub const O_RDWR: usize = 2;
+/// Bits of access mode in `mode` of `open`
+const O_ACCMODE: usize = 3;
 
 pub struct FsFile {
     offset: usize,
@@ -17,42 +29,57 @@ pub struct FsFile {
     writable: bool,
 }
 
-const HEADER_SIZE: usize = 1024;
-const FILE_MAX: usize = 1024;
+/// Lock of file system header, held while a free entry is found and
+/// written, so that two device nodes won't take the same entry
+static HEADER_LOCK: SleepLock<()> = SleepLock::new((), "fs header");
+
+/// Disk holding file system, which is VirtIO block device 1
+struct Disk;
+
+impl BlockDevice for Disk {
+    fn read_block(&self, blockno: usize, data: &mut [u8; BSIZE]) {
+        data.copy_from_slice(&VIRTIO().read(1, blockno as u32).data);
+    }
+
+    fn write_block(&self, blockno: usize, data: &[u8; BSIZE]) {
+        let mut b = box Buf::new();
+        b.blockno = blockno as u32;
+        b.data.copy_from_slice(data);
+        VIRTIO().write(b);
+    }
+
+    /// Blocks are read with as few requests as possible
+    fn read_blocks(&self, blockno: usize, data: &mut [[u8; BSIZE]]) {
+        let bufs = VIRTIO().read_blocks(1, blockno as u32, data.len());
+        for (d, b) in data.iter_mut().zip(bufs) {
+            d.copy_from_slice(&b.data);
+        }
+    }
+}
 
 impl FsFile {
-    fn get_file_info(virtio: &mut VirtIO, path: &str) -> Option<(usize, usize)> {
-        for id in 0..FILE_MAX {
-            let b = virtio.read(1, id as u32);
-            let sz = unsafe { core::ptr::read(b.data.as_ptr() as *const usize) };
-            let offset = unsafe { core::ptr::read((b.data.as_ptr() as *const usize).add(1)) };
-            if sz == 0 {
-                break;
-            }
-            let name_sz = {
-                let mut i = 16;
-                loop {
-                    let d = b.data[i];
-                    if d == 0 {
-                        break;
-                    }
-                    i += 1;
-                    if i == b.data.len() { break; }
-                }
-                i - 16
-            };
-            let u8_slice = unsafe { core::slice::from_raw_parts(b.data.as_ptr().add(16), name_sz) };
-            let name = core::str::from_utf8(u8_slice).unwrap();
-            if name == path {
-                return Some((offset, sz));
-            }
+    fn get_file_info(path: &str) -> Option<(usize, usize)> {
+        match fs::find_entry(&Disk, path) {
+            Ok((_, DirEntry::File { offset, sz })) => Some((offset, sz)),
+            _ => None
         }
-        None
     }
 
+    /// Look up `path` in file system
+    pub fn stat(path: &str) -> Option<DirEntry> {
+        fs::find_entry(&Disk, path).ok().map(|(_, entry)| entry)
+    }
+
+    /// Create a device node of `major` and `minor` at `path`
+    pub fn mknod(path: &str, major: usize, minor: usize) -> Result<(), MknodError> {
+        let _header = HEADER_LOCK.acquire();
+        fs::mknod(&Disk, path, major, minor)
+    }
+
+    /// Open file at `path` with access `mode`, which is one of `O_RDONLY`,
+    /// `O_WRONLY` and `O_RDWR`
     pub fn open(path: &str, mode: usize) -> Self {
-        let virtio = VIRTIO();
-        let (offset, sz) = match Self::get_file_info(virtio, path) {
+        let (offset, sz) = match Self::get_file_info(path) {
             Some(x) => x,
             None => { panic!("{} not found", path); }
         };
@@ -60,64 +87,118 @@ impl FsFile {
             offset,
             sz,
             rw_offset: Mutex::new((0, 0), "file rw offset"),
-            readable: true,
-            writable: true,
+            readable: mode & O_ACCMODE != O_WRONLY,
+            writable: mode & O_ACCMODE != O_RDONLY,
         }
     }
 
+    /// Check if file is opened for writing
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Read from read offset, and move it forward.
+    /// Returns number of bytes read, which is 0 at end of file.
     pub fn read(&self, content: &mut [u8]) -> i32 {
-        use crate::info;
         if !self.readable { return -1; }
-        let virtio = VIRTIO();
         let read_offset = self.rw_offset.lock().0;
-        let read_sz = (self.sz - read_offset).min(content.len());
-        let result = virtio.read(1, ((self.offset + read_offset) / BSIZE) as u32);
-        // content.copy_from_slice(&result.data[0..content.len()]);
-        for i in 0..read_sz {
-            content[i] = result.data[i];
-        }
+        let read_sz = self.read_at(read_offset, content);
         self.rw_offset.lock().0 = read_offset + read_sz;
-        // println!("off: {:x}, rsz: {:x}, sz: {:x}", read_offset, read_sz, self.sz);
         return read_sz as i32;
     }
 
     pub fn write(&self, content: &[u8]) -> i32 {
         if !self.writable { return -1; }
-        unimplemented!()
+        let write_offset = self.rw_offset.lock().0;
+        let write_sz = self.write_at(write_offset, content);
+        self.rw_offset.lock().0 = write_offset + write_sz;
+        return write_sz as i32;
     }
-}
 
-pub mod tests {
-    use super::*;
+    /// Size of file
+    pub fn size(&self) -> usize {
+        self.sz
+    }
+
+    /// Read file content at `off` without moving read offset.
+    /// Returns number of bytes read.
+    pub fn read_at(&self, off: usize, content: &mut [u8]) -> usize {
+        fs::read_at(&Disk, self.offset, self.sz, off, content)
+    }
 
-    pub fn tests() -> &'static [(&'static str, fn())] {
-        &[
-            ("open", test_open),
-            ("read", test_read),
-            ("read_elf", test_read_elf),
-        ]
+    /// Write `content` to file at `off` without moving write offset.
+    ///
+    /// As files can't grow on this file system, content beyond file size
+    /// will be discarded. Returns number of bytes written.
+    pub fn write_at(&self, off: usize, content: &[u8]) -> usize {
+        if !self.writable { return 0; }
+        fs::write_at(&Disk, self.offset, self.sz, off, content)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     use crate::{print, println};
 
     /// Test open
-    pub fn test_open() {
+    #[test_case]
+    fn test_open() {
         let f = FsFile::open("/test.txt", 0);
     }
 
     /// Test read
-    pub fn test_read() {
+    #[test_case]
+    fn test_read() {
         let f = FsFile::open("/test.txt", 0);
         let mut content = [0; 10];
         assert_eq!(f.read(&mut content), 10);
         assert_eq!(content, [48, 49, 50, 51, 52, 53, 54, 55, 56, 57]);
     }
 
+    /// Test reads continue from read offset
+    #[test_case]
+    fn test_read_offset() {
+        let f = FsFile::open("/test.txt", 0);
+        let mut content = [0; 4];
+        assert_eq!(f.read(&mut content), 4);
+        assert_eq!(f.read(&mut content), 4);
+        assert_eq!(&content, b"4567");
+        let mut rest = [0; 64];
+        assert_eq!(f.read(&mut rest) as usize, f.size() - 8);
+        assert_eq!(f.read(&mut rest), 0);
+    }
+
+    /// Test writes continue from write offset, and need file opened for write
+    #[test_case]
+    fn test_write() {
+        // content is written back unchanged
+        let f = FsFile::open("/test.txt", O_RDWR);
+        assert_eq!(f.write(b"0123"), 4);
+        assert_eq!(f.write(b"4567"), 4);
+        let mut content = [0; 8];
+        assert_eq!(f.read_at(0, &mut content), 8);
+        assert_eq!(&content, b"01234567");
+        assert_eq!(FsFile::open("/test.txt", O_RDONLY).write(b"0"), -1);
+    }
+
     /// Test read
-    pub fn test_read_elf() {
+    #[test_case]
+    fn test_read_elf() {
         let f = FsFile::open("/test1", 0);
         let mut content = [0; 1024];
         while f.read(&mut content) == 1024 {}
     }
+
+    /// Test read at offset
+    #[test_case]
+    fn test_read_at() {
+        let f = FsFile::open("/test.txt", 0);
+        let mut content = [0; 4];
+        assert_eq!(f.read_at(3, &mut content), 4);
+        assert_eq!(content, [51, 52, 53, 54]);
+        assert_eq!(f.read_at(f.size(), &mut content), 0);
+    }
 }
 
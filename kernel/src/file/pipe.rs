@@ -0,0 +1,198 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Pipe
+//!
+//! A pipe is a bounded byte stream with a read end and a write end.
+//! Readers block when it is empty, and see end of file once the write end
+//! is closed. Writers block when it is full, and fail once the read end is
+//! closed.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::process::{WaitQueue, group_exited};
+use super::{POLLIN, POLLOUT};
+
+/// Maximum number of bytes buffered in a pipe
+pub const PIPE_SIZE: usize = 4096;
+
+/// Pipe operation error
+#[derive(Debug, PartialEq)]
+pub enum PipeError {
+    /// reading from write end, or writing to read end
+    WrongEnd,
+    /// read end is closed
+    Broken,
+    /// process is killed while waiting
+    Interrupted,
+}
+
+struct Buffer {
+    data: VecDeque<u8>,
+    read_open: bool,
+    write_open: bool,
+}
+
+struct Pipe {
+    buf: Mutex<Buffer>,
+    /// processes waiting for data
+    readers: WaitQueue,
+    /// processes waiting for free space
+    writers: WaitQueue,
+}
+
+impl Pipe {
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+}
+
+/// One end of a pipe. The end is closed when this is dropped.
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    write: bool,
+}
+
+impl PipeEnd {
+    /// Create a pipe, and returns its read end and write end
+    pub fn new() -> (PipeEnd, PipeEnd) {
+        let pipe = Arc::new(Pipe {
+            buf: Mutex::new(Buffer {
+                data: VecDeque::new(),
+                read_open: true,
+                write_open: true,
+            }, "pipe"),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+        });
+        (PipeEnd { pipe: pipe.clone(), write: false }, PipeEnd { pipe, write: true })
+    }
+
+    /// Sleep on `queue` until woken up, unless process has been killed
+    fn wait<'a>(&self, queue: &WaitQueue, lck: MutexGuard<'a, Buffer>) -> Result<MutexGuard<'a, Buffer>, PipeError> {
+        if group_exited() {
+            return Err(PipeError::Interrupted);
+        }
+        Ok(queue.sleep(self.pipe.channel(), lck))
+    }
+
+    /// Read at most `content.len()` bytes, waiting until there is any.
+    /// Returns 0 at end of file.
+    pub fn read(&self, content: &mut [u8]) -> Result<usize, PipeError> {
+        if self.write {
+            return Err(PipeError::WrongEnd);
+        }
+        let mut buf = self.pipe.buf.lock();
+        while buf.data.is_empty() && buf.write_open && !content.is_empty() {
+            buf = self.wait(&self.pipe.readers, buf)?;
+        }
+        let sz = buf.data.len().min(content.len());
+        for (dst, src) in content.iter_mut().zip(buf.data.drain(..sz)) {
+            *dst = src;
+        }
+        self.pipe.writers.wakeup(self.pipe.channel());
+        Ok(sz)
+    }
+
+    /// Write all of `content`, waiting for free space if pipe is full.
+    /// Returns number of bytes written, which is less than `content.len()`
+    /// only if read end is closed meanwhile.
+    pub fn write(&self, content: &[u8]) -> Result<usize, PipeError> {
+        if !self.write {
+            return Err(PipeError::WrongEnd);
+        }
+        let mut buf = self.pipe.buf.lock();
+        let mut written = 0;
+        while written < content.len() {
+            if !buf.read_open {
+                break;
+            }
+            if buf.data.len() >= PIPE_SIZE {
+                buf = self.wait(&self.pipe.writers, buf)?;
+                continue;
+            }
+            let sz = (PIPE_SIZE - buf.data.len()).min(content.len() - written);
+            buf.data.extend(&content[written..written + sz]);
+            written += sz;
+            // all readers are woken up, as some of them may be polling
+            self.pipe.readers.wakeup(self.pipe.channel());
+        }
+        match written {
+            0 if !content.is_empty() => Err(PipeError::Broken),
+            _ => Ok(written)
+        }
+    }
+
+    /// Returns ready events. Read end is readable if there is data or
+    /// write end is closed, and write end is writable if there is free
+    /// space or read end is closed.
+    pub fn poll(&self) -> usize {
+        let buf = self.pipe.buf.lock();
+        if self.write && (buf.data.len() < PIPE_SIZE || !buf.read_open) {
+            POLLOUT
+        } else if !self.write && (!buf.data.is_empty() || !buf.write_open) {
+            POLLIN
+        } else {
+            0
+        }
+    }
+
+    /// Wait queue and channel on which readiness changes are notified
+    pub fn wait_queue(&self) -> (&WaitQueue, usize) {
+        if self.write {
+            (&self.pipe.writers, self.pipe.channel())
+        } else {
+            (&self.pipe.readers, self.pipe.channel())
+        }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut buf = self.pipe.buf.lock();
+        if self.write {
+            buf.write_open = false;
+            self.pipe.readers.wakeup(self.pipe.channel());
+        } else {
+            buf.read_open = false;
+            self.pipe.writers.wakeup(self.pipe.channel());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test data is read in order, and end of file after write end is closed
+    #[test_case]
+    fn test_pipe() {
+        let (r, w) = PipeEnd::new();
+        assert_eq!(w.write(b"hello"), Ok(5));
+        assert_eq!(w.write(b"world"), Ok(5));
+        let mut buf = [0; 8];
+        assert_eq!(r.read(&mut buf), Ok(8));
+        assert_eq!(&buf, b"hellowor");
+        assert_eq!(r.poll(), POLLIN);
+        assert_eq!(r.read(&mut buf), Ok(2));
+        assert_eq!(r.poll(), 0);
+        assert_eq!(w.poll(), POLLOUT);
+        assert_eq!(r.write(b"x"), Err(PipeError::WrongEnd));
+        assert_eq!(w.read(&mut buf), Err(PipeError::WrongEnd));
+        drop(w);
+        assert_eq!(r.poll(), POLLIN);
+        assert_eq!(r.read(&mut buf), Ok(0));
+    }
+
+    /// Test writing to a pipe whose read end is closed
+    #[test_case]
+    fn test_broken() {
+        let (r, w) = PipeEnd::new();
+        drop(r);
+        assert_eq!(w.poll(), POLLOUT);
+        assert_eq!(w.write(b"hello"), Err(PipeError::Broken));
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Wait for readiness of several files
+//!
+//! Current process is added to wait queues of all files polled, and
+//! sleeps until any of them wakes it up, or timeout has passed.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::arch;
+use crate::process::{prepare_sleep, sleep_prepared, cancel_sleep, add_timeout, remove_timeout};
+use super::{File, POLLNVAL};
+
+/// A file to be polled
+pub struct PollEntry {
+    /// file polled, `None` for invalid file descriptor
+    pub file: Option<Arc<File>>,
+    /// events to wait for
+    pub events: usize,
+    /// events ready, set by `poll`
+    pub revents: usize,
+}
+
+/// Set `revents` of all entries. Returns number of entries ready.
+fn check(entries: &mut [PollEntry]) -> usize {
+    let mut ready = 0;
+    for entry in entries.iter_mut() {
+        entry.revents = match &entry.file {
+            Some(file) => file.poll() & entry.events,
+            None => POLLNVAL
+        };
+        if entry.revents != 0 {
+            ready += 1;
+        }
+    }
+    ready
+}
+
+/// poll syscall
+///
+/// Wait until any file in `entries` is ready for its events, or `timeout`
+/// has passed. Returns number of entries ready, which is 0 on timeout.
+pub fn poll(entries: &mut [PollEntry], timeout: Option<Duration>) -> usize {
+    let deadline = timeout.map(|t| arch::time() + t);
+    let files: Vec<Arc<File>> = entries.iter().filter_map(|x| x.file.clone()).collect();
+    let queues: Vec<_> = files.iter().flat_map(|f| f.wait_queues()).collect();
+    loop {
+        for (queue, channel) in &queues {
+            queue.add_waiter(*channel);
+        }
+        if let Some(deadline) = deadline {
+            add_timeout(deadline);
+        }
+        // readiness is checked after marked as sleeping, so that
+        // wakeups after checking won't be lost
+        prepare_sleep();
+        let ready = check(entries);
+        let timed_out = deadline.map_or(false, |d| arch::time() >= d);
+        if ready > 0 || timed_out {
+            cancel_sleep();
+        } else {
+            sleep_prepared();
+        }
+        if deadline.is_some() {
+            remove_timeout();
+        }
+        for (queue, channel) in &queues {
+            queue.remove_waiter(*channel);
+        }
+        if ready > 0 || timed_out {
+            return ready;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{Channel, POLLIN, POLLOUT};
+
+    fn entry(file: &Arc<File>, events: usize) -> PollEntry {
+        PollEntry { file: Some(file.clone()), events, revents: 0 }
+    }
+
+    /// Test readiness of channel and invalid file
+    #[test_case]
+    fn test_ready() {
+        let chan = Arc::new(File::Channel(Channel::new(1).unwrap()));
+        let mut entries = [entry(&chan, POLLIN | POLLOUT)];
+        assert_eq!(poll(&mut entries, None), 1);
+        assert_eq!(entries[0].revents, POLLOUT);
+        if let File::Channel(c) = &*chan {
+            c.send(b"1", None, None).unwrap();
+        }
+        assert_eq!(poll(&mut entries, None), 1);
+        assert_eq!(entries[0].revents, POLLIN);
+        let mut entries = [PollEntry { file: None, events: POLLIN, revents: 0 }];
+        assert_eq!(poll(&mut entries, None), 1);
+        assert_eq!(entries[0].revents, POLLNVAL);
+    }
+
+    /// Test polling with timeout when nothing is ready
+    #[test_case]
+    fn test_timeout() {
+        let chan = Arc::new(File::Channel(Channel::new(1).unwrap()));
+        let mut entries = [entry(&chan, POLLIN)];
+        assert_eq!(poll(&mut entries, Some(Duration::from_millis(10))), 0);
+        assert_eq!(entries[0].revents, 0);
+    }
+}
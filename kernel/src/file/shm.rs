@@ -0,0 +1,82 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Shared memory object
+//!
+//! A shared memory object is a fixed number of zeroed pages. Mapping it
+//! with `MAP_SHARED` maps these pages into user page table, and adds a
+//! reference to each of them in allocator. Pages are freed when the
+//! object and all its mappings are gone.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::page::Page;
+use crate::mem::align_val;
+use crate::symbols::*;
+
+pub struct SharedMemory {
+    pages: Vec<Box<Page>>,
+}
+
+impl SharedMemory {
+    /// Create a shared memory object of at least `size` bytes
+    pub fn new(size: usize) -> Self {
+        let npages = align_val(size, PAGE_ORDER) / PAGE_SIZE;
+        Self {
+            pages: (0..npages).map(|_| Page::new()).collect()
+        }
+    }
+
+    /// Size of object, which is always a multiple of page size
+    pub fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    /// Physical address of page at `offset`
+    pub fn paddr_of(&self, offset: usize) -> Option<usize> {
+        self.pages.get(offset / PAGE_SIZE).map(|pg| &**pg as *const Page as usize)
+    }
+
+    /// Page at `offset`
+    pub fn page_of(&self, offset: usize) -> Option<&Page> {
+        self.pages.get(offset / PAGE_SIZE).map(|pg| &**pg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{self, Table, EntryAttributes};
+    use crate::mem::ALLOC;
+
+    fn ref_count(paddr: usize) -> usize {
+        ALLOC().lock().ref_count(paddr as *mut u8)
+    }
+
+    /// Test size is rounded up to pages
+    #[test_case]
+    fn test_size() {
+        let shm = SharedMemory::new(PAGE_SIZE + 1);
+        assert_eq!(shm.size(), PAGE_SIZE * 2);
+        assert!(shm.paddr_of(PAGE_SIZE * 2).is_none());
+    }
+
+    /// Test pages are reference-counted across mappings and page table clones
+    #[test_case]
+    fn test_share() {
+        let shm = SharedMemory::new(PAGE_SIZE);
+        let paddr = shm.paddr_of(0).unwrap();
+        let mut pgtable = box Table::new();
+        page::share(paddr);
+        pgtable.map_shared(0x1000, paddr, EntryAttributes::URW as usize);
+        assert_eq!(ref_count(paddr), 2);
+        let cloned = pgtable.clone_with(&mut page::share);
+        assert_eq!(cloned.paddr_of(0x1000), Some(paddr));
+        assert_eq!(ref_count(paddr), 3);
+        drop(pgtable.unmap(0x1000));
+        drop(cloned);
+        assert_eq!(ref_count(paddr), 1);
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Socket of network stack
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::net::{Ipv4Addr, SocketAddr, NetError, LOCAL_ADDR};
+use crate::net::udp::UdpSocket;
+use crate::net::tcp::TcpSocket;
+use crate::process::WaitQueue;
+
+/// Internet address family
+pub const AF_INET: usize = 2;
+/// Reliable byte stream
+pub const SOCK_STREAM: usize = 1;
+/// Unreliable datagrams
+pub const SOCK_DGRAM: usize = 2;
+pub const IPPROTO_TCP: usize = 6;
+pub const IPPROTO_UDP: usize = 17;
+
+/// Return instead of waiting
+pub const MSG_DONTWAIT: usize = 0x40;
+
+pub enum Socket {
+    Udp(Arc<UdpSocket>),
+    Tcp(Arc<TcpSocket>),
+}
+
+impl Socket {
+    /// Create socket of `domain`, `ty` and `protocol`, where `protocol` 0
+    /// means default one of type. Returns `None` if not supported.
+    pub fn new(domain: usize, ty: usize, protocol: usize) -> Option<Self> {
+        match (domain, ty, protocol) {
+            (AF_INET, SOCK_DGRAM, 0) | (AF_INET, SOCK_DGRAM, IPPROTO_UDP) => Some(Socket::Udp(UdpSocket::new())),
+            (AF_INET, SOCK_STREAM, 0) | (AF_INET, SOCK_STREAM, IPPROTO_TCP) => Some(Socket::Tcp(TcpSocket::new())),
+            _ => None
+        }
+    }
+
+    /// Bind socket to local address, which must be unspecified or an
+    /// address of this host
+    pub fn bind(&self, addr: SocketAddr) -> Result<(), NetError> {
+        if addr.addr != Ipv4Addr::UNSPECIFIED && addr.addr != LOCAL_ADDR && !addr.addr.is_loopback() {
+            return Err(NetError::Invalid);
+        }
+        match self {
+            Socket::Udp(udp) => udp.bind(addr.port).map(|_| ()),
+            Socket::Tcp(tcp) => tcp.bind(addr.port).map(|_| ())
+        }
+    }
+
+    /// Set default destination of datagram socket, or connect stream
+    /// socket, waiting until connection is established
+    pub fn connect(&self, addr: SocketAddr) -> Result<(), NetError> {
+        match self {
+            Socket::Udp(udp) => {
+                udp.connect(addr);
+                Ok(())
+            }
+            Socket::Tcp(tcp) => tcp.connect(addr)
+        }
+    }
+
+    /// Send `data` to `dst`, or connected peer if `dst` is `None`.
+    /// Stream sockets always send to connected peer. Returns number of
+    /// bytes sent.
+    pub fn send_to(&self, data: &[u8], dst: Option<SocketAddr>) -> Result<usize, NetError> {
+        match self {
+            Socket::Udp(udp) => udp.send_to(data, dst),
+            Socket::Tcp(tcp) => tcp.send(data, false)
+        }
+    }
+
+    /// Receive data into `content`, waiting for it unless `nonblock`.
+    /// Returns number of bytes received and their source, where 0 bytes
+    /// from a stream socket means end of stream.
+    pub fn recv_from(&self, content: &mut [u8], nonblock: bool) -> Result<(usize, SocketAddr), NetError> {
+        match self {
+            Socket::Udp(udp) => udp.recv_from(content, nonblock),
+            Socket::Tcp(tcp) => tcp.recv(content, nonblock)
+        }
+    }
+
+    /// Returns ready events of socket
+    pub fn poll(&self) -> usize {
+        match self {
+            Socket::Udp(udp) => udp.poll(),
+            Socket::Tcp(tcp) => tcp.poll()
+        }
+    }
+
+    /// Wait queues and channels on which readiness changes are notified
+    pub fn wait_queues(&self) -> Vec<(&WaitQueue, usize)> {
+        match self {
+            Socket::Udp(udp) => [udp.wait_queue()].to_vec(),
+            Socket::Tcp(tcp) => [tcp.wait_queue()].to_vec()
+        }
+    }
+
+    /// Listen for connections on stream socket
+    pub fn listen(&self, backlog: usize) -> Result<(), NetError> {
+        match self {
+            Socket::Tcp(tcp) => tcp.listen(backlog),
+            _ => Err(NetError::Invalid)
+        }
+    }
+
+    /// Accept a connection on listening socket, waiting for one unless
+    /// `nonblock`. Returns connected socket and address of peer.
+    pub fn accept(&self, nonblock: bool) -> Result<(Socket, SocketAddr), NetError> {
+        match self {
+            Socket::Tcp(tcp) => tcp.accept(nonblock).map(|(conn, addr)| (Socket::Tcp(conn), addr)),
+            _ => Err(NetError::Invalid)
+        }
+    }
+}
+
+impl Drop for Socket {
+    /// Close connection when the last file descriptor is closed
+    fn drop(&mut self) {
+        if let Socket::Tcp(tcp) = self {
+            tcp.close();
+        }
+    }
+}
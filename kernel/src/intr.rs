@@ -6,7 +6,7 @@
 //! Handle interrupts
 
 use riscv::register::*;
-use crate::plic;
+use crate::fdt::machine;
 use crate::uart::uartintr;
 use crate::arch;
 use crate::virtio::virtiointr;
@@ -25,24 +25,52 @@ pub fn devintr() -> Option<Intr> {
     if cause.is_interrupt() && cause.code() == 9 {
         let plic = crate::plic::PLIC();
         if let Some(interrupt) = plic.next() {
-            match interrupt {
-                plic::UART0_IRQ => {
-                    uartintr();
-                },
-                plic::VIRTIO0_IRQ => {
-                    virtiointr();
-                },
-                _ => {
-                    println!("Unrecognized external interrupt: {}", interrupt);
-                }
+            let machine = machine();
+            if interrupt == machine.uart.irq {
+                uartintr();
+            } else if machine.virtio_slot(interrupt).is_some() {
+                virtiointr(interrupt);
+            } else {
+                println!("Unrecognized external interrupt: {}", interrupt);
             }
             plic.complete(interrupt);
         }
         Some(Intr::Device)
     } else if cause.is_interrupt() && cause.code() == 1 {
         arch::w_sip(arch::r_sip() & !2);
-        Some(Intr::Timer)
+        // another hart may have panicked
+        crate::power::check_stopped();
+        if cfg!(feature = "sbi") {
+            // IPI from another hart, which has queued a process here
+            Some(Intr::Timer)
+        } else {
+            // timer interrupt forwarded by timervec in machine mode
+            Some(timer_tick())
+        }
+    } else if cause.is_interrupt() && cause.code() == 5 {
+        // supervisor timer interrupt, programmed through SBI
+        #[cfg(feature = "sbi")]
+        crate::sbi::next_timer();
+        Some(timer_tick())
     } else {
         None
     }
 }
+
+/// Send software interrupt to harts in `hart_mask`
+pub fn send_ipi(hart_mask: usize) {
+    #[cfg(feature = "sbi")]
+    crate::sbi::send_ipi(hart_mask);
+    #[cfg(not(feature = "sbi"))]
+    crate::clint::send_ipi(hart_mask);
+}
+
+/// Process timer tick
+fn timer_tick() -> Intr {
+    crate::process::wakeup_timeouts();
+    // one hart is enough to drive TCP timers
+    if arch::hart_id() == 0 {
+        crate::net::tcp::tick();
+    }
+    Intr::Timer
+}
@@ -15,6 +15,10 @@
 #![feature(box_syntax)]
 #![feature(alloc_prelude)]
 #![feature(llvm_asm)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test::runner)]
+#![reexport_test_harness_main = "test_main"]
+#![cfg_attr(test, no_main)]
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
@@ -35,21 +39,55 @@ mod trap;
 mod uart;
 mod plic;
 mod clint;
+#[cfg(feature = "sbi")]
+mod sbi;
 mod syscall;
 mod start;
 mod jump;
 mod virtio;
 mod intr;
+#[cfg(test)]
 mod test;
 mod sleeplock;
 mod file;
+mod net;
+mod random;
+mod fdt;
+mod power;
+mod backtrace;
 
 #[no_mangle]
 extern "C" fn eh_personality() {}
 
 /// Panic handler
+///
+/// Other harts are stopped first, so that only panicking hart prints.
+/// A failed test in kernel built by `make test` goes on with next test
+/// instead, leaving other harts running.
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    #[cfg(test)]
+    {
+        if test::in_test() {
+            print_panic(info);
+            test::fail();
+        }
+    }
+    match power::stop_others() {
+        Ok(()) => print_panic(info),
+        Err(hart) if hart == arch::hart_id() => {
+            // panicked again while panicking, without unwinding. Nothing
+            // here may panic, or it would recurse.
+            panic_println!("hart {} panicked while panicking: {}", hart, info);
+        }
+        // another hart is panicking
+        Err(_) => power::halt()
+    }
+    abort();
+}
+
+/// Print panic message and backtrace
+fn print_panic(info: &core::panic::PanicInfo) {
     panic_println!("hart {} aborting: ", arch::hart_id());
     if let Some(p) = info.location() {
         panic_println!(
@@ -61,11 +99,11 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         panic_println!("no information available.");
     }
-    abort();
+    backtrace::print();
 }
 
-/// Abort function
+/// Abort function, which powers off with failure so that QEMU exits
 #[no_mangle]
 extern "C" fn abort() -> ! {
-    arch::wait_forever();
+    power::exit(power::EXIT_FAILURE);
 }
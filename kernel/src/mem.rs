@@ -4,6 +4,9 @@
 // https://opensource.org/licenses/MIT
 
 //! Allocator implementation
+//!
+//! Frame allocator itself lives in `kernel_core::mem`, so that it can be
+//! tested on host.
 
 use core::ops::Range;
 use crate::info;
@@ -12,118 +15,14 @@ use crate::symbols::*;
 use crate::spinlock::Mutex;
 use crate::page::EntryAttributes;
 use crate::page::{Table, KERNEL_PGTABLE};
-use crate::uart::UART_BASE_ADDR;
+use crate::fdt;
 use crate::process::*;
 use riscv::{register::*, asm};
 use crate::mem;
 use crate::arch;
 
 
-/// Maximum number of pages. As QEMU and linker script `kernel.ld`
-/// are set to have 128MB of RAM, maximum number of pages can be calculated.
-pub const MAX_PAGE: usize = 128 * 1024 * 1024 / (1 << 12);
-
-/// Frame allocator gives out one or more pages.
-pub struct Allocator {
-    /// A bool array records whether a page is handed out
-    pub page_allocated: [usize; MAX_PAGE],
-    /// Pages are handed out from `base_addr`, which is the start address
-    /// of HEAP.
-    pub base_addr: usize,
-}
-
-/// Align an address to upper bound according to specified order.
-pub const fn align_val(val: usize, order: usize) -> usize {
-    let o = (1usize << order) - 1;
-    (val + o) & !o
-}
-
-/// Align an address to lower bound according to specified order.
-pub const fn align_val_down(val: usize, order: usize) -> usize {
-    val & !((1usize << order) - 1)
-}
-
-/// Align an address to the begin of a page.
-pub const fn page_down(val: usize) -> usize {
-    align_val_down(val, PAGE_ORDER)
-}
-
-impl Allocator {
-    /// Returns a new allocator instance
-    /// 
-    /// `base_addr` should be intialized later.
-    pub const fn new() -> Self {
-        Allocator {
-            base_addr: 0,
-            page_allocated: [0; MAX_PAGE],
-        }
-    }
-
-    fn offset_addr_of(&self, id: usize) -> usize {
-        let addr = self.base_addr + id * PAGE_SIZE;
-        addr
-    }
-
-    unsafe fn offset_id_of(&self, id: usize) -> *mut u8 {
-        self.offset_addr_of(id) as *mut u8
-    }
-
-    fn offset_page_of(&self, page: *mut u8) -> usize {
-        let id = (page as usize - self.base_addr) / PAGE_SIZE;
-        id
-    }
-
-    pub fn allocate(&mut self, size: usize) -> *mut u8 {
-        let page_required = align_val(size, PAGE_ORDER) / PAGE_SIZE;
-        for i in 0..MAX_PAGE {
-            if self.page_allocated[i] == 0 {
-                let mut found = true;
-                for j in 0..page_required {
-                    if self.page_allocated[i + j] != 0 {
-                        found = false;
-                        break;
-                    }
-                }
-                if found {
-                    for j in 0..page_required {
-                        self.page_allocated[i + j] = page_required;
-                    }
-                    unsafe { return self.offset_id_of(i); }
-                }
-            }
-        }
-        panic!("no available page")
-    }
-
-    pub fn deallocate(&mut self, addr: *mut u8) {
-        let id = self.offset_page_of(addr);
-        let page_stride = self.page_allocated[id];
-        for j in 0..page_stride {
-            self.page_allocated[j + id] = 0;
-        }
-    }
-
-    /// Print page allocation status
-    pub fn debug(&self) {
-        let mut j = 0;
-        loop {
-            let size = self.page_allocated[j];
-            let addr = &self.page_allocated as *const usize;
-            let addr = unsafe { addr.add(j) };
-            if size != 0 {
-                let from = self.offset_addr_of(j);
-                let to = self.offset_addr_of(j + size);
-                println!("{} {:X} {:X}-{:X} (pages: {:X})", j, addr as usize, from, to, size);
-                j += size;
-            } else {
-                j += 1;
-            }
-            if j == MAX_PAGE {
-                break;
-            }
-        }
-    }
-}
+pub use kernel_core::mem::*;
 
 static __ALLOC: Mutex<Allocator> = Mutex::new(Allocator::new(), "global allocator");
 
@@ -131,14 +30,15 @@ static __ALLOC: Mutex<Allocator> = Mutex::new(Allocator::new(), "global allocato
 /// Initialize allocator and kernel page table
 /// This function should only be called in boot hart
 pub unsafe fn init() {
-    // Initialize allocator
-    ALLOC().get().base_addr = align_val(HEAP_START(), PAGE_ORDER);
-
-    // workaround for non-zero data region
-    let mut alloc = ALLOC().get();
-    for i in 0..MAX_PAGE {
-        alloc.page_allocated[i] = 0;
+    // Initialize allocator with memory after kernel found in device tree
+    let machine = fdt::machine();
+    let heap_start = align_val(HEAP_START(), PAGE_ORDER);
+    let mut heap_end = page_down(machine.memory.1);
+    // QEMU places device tree blob at end of RAM
+    if machine.dtb >= heap_start && machine.dtb < heap_end {
+        heap_end = page_down(machine.dtb);
     }
+    ALLOC().get().init(heap_start..heap_end);
 
     let pgtable: &mut Table = &mut *(&KERNEL_PGTABLE as *const _ as *mut _); // to bypass mut ref
     pgtable.id_map_range(
@@ -166,30 +66,36 @@ pub unsafe fn init() {
         KERNEL_STACK_END(),
         EntryAttributes::RW as usize,
     );
-    pgtable.kernel_map(
-        UART_BASE_ADDR,
-        UART_BASE_ADDR,
-        EntryAttributes::RW as usize,
-    );
-    pgtable.kernel_map(
-        VIRTIO_MMIO_BASE,
-        VIRTIO_MMIO_BASE,
-        EntryAttributes::RW as usize,
-    );
+    if machine.uart.base != 0 {
+        pgtable.kernel_map(
+            machine.uart.base,
+            machine.uart.base,
+            EntryAttributes::RW as usize,
+        );
+    }
+    for dev in machine.virtio[..machine.virtio_count].iter() {
+        pgtable.id_map_range(dev.base, dev.base + dev.size, EntryAttributes::RW as usize);
+    }
     pgtable.kernel_map(
         TRAMPOLINE_START,
         TRAMPOLINE_TEXT_START(),
         EntryAttributes::RX as usize,
     );
     pgtable.id_map_range(
-        HEAP_START(),
-        HEAP_START() + HEAP_SIZE(),
+        heap_start,
+        heap_end,
         EntryAttributes::RW as usize,
     );
+    if machine.test.base != 0 {
+        let test = machine.test;
+        pgtable.id_map_range(test.base, test.base + test.size, EntryAttributes::RW as usize);
+    }
     // CLINT
-    pgtable.id_map_range(CLINT_BASE, CLINT_BASE + 0x10000, EntryAttributes::RW as usize);
+    let clint = machine.clint;
+    pgtable.id_map_range(clint.base, clint.base + clint.size, EntryAttributes::RW as usize);
     // PLIC
-    pgtable.id_map_range(PLIC_BASE, PLIC_BASE + 0x400000, EntryAttributes::RW as usize);
+    let plic = machine.plic;
+    pgtable.id_map_range(plic.base, plic.base + plic.size, EntryAttributes::RW as usize);
 }
 
 pub fn hartinit() {
@@ -205,11 +111,8 @@ pub fn hartinit() {
 pub fn ALLOC() -> &'static Mutex<Allocator> { &__ALLOC }
 
 use core::alloc::{GlobalAlloc, Layout};
-use crate::plic::PLIC_BASE;
-use crate::clint::CLINT_BASE;
 use crate::arch::hart_id;
 use crate::process::my_cpu;
-use crate::virtio::VIRTIO_MMIO_BASE;
 
 struct OsAllocator {}
 
@@ -259,6 +162,23 @@ pub fn debug() {
     }
 }
 
-pub fn alloc_stack() -> *mut u8 {
-    ALLOC().lock().allocate(PAGE_SIZE * 1024)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test allocating pages and counting references to them
+    #[test_case]
+    fn test_allocate() {
+        let mut alloc = ALLOC().lock();
+        let addr = alloc.allocate(PAGE_SIZE * 2 + 1);
+        assert_eq!(addr as usize % PAGE_SIZE, 0);
+        assert!(addr as usize >= alloc.base_addr && (addr as usize) < alloc.end());
+        assert_eq!(alloc.ref_count(addr), 1);
+        alloc.share(addr);
+        assert_eq!(alloc.ref_count(addr), 2);
+        alloc.deallocate(addr);
+        assert_eq!(alloc.ref_count(addr), 1);
+        alloc.deallocate(addr);
+        assert_eq!(alloc.ref_count(addr), 0);
+    }
 }
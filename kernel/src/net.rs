@@ -0,0 +1,180 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Minimal network stack
+//!
+//! Frames received by network interface go up through Ethernet, ARP and
+//! IPv4 to ICMP, UDP and TCP. Address is statically configured to match QEMU
+//! user-mode networking. Packets sent to our own address are looped back
+//! without reaching the interface.
+
+pub mod ether;
+pub mod arp;
+pub mod ipv4;
+pub mod icmp;
+pub mod udp;
+pub mod tcp;
+
+use crate::spinlock::Mutex;
+
+/// Ethernet hardware address
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MacAddr(pub [u8; 6]);
+
+/// IPv4 address
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0, 0, 0, 0]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255, 255, 255, 255]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    /// Address in network byte order
+    pub fn to_be(&self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_be(addr: u32) -> Self {
+        Self(addr.to_be_bytes())
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.0[0] == 127
+    }
+
+    /// Check if address is on local network
+    pub fn is_local(&self) -> bool {
+        self.to_be() & NETMASK.to_be() == LOCAL_ADDR.to_be() & NETMASK.to_be()
+    }
+}
+
+/// Address and port of a TCP or UDP endpoint
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SocketAddr {
+    pub addr: Ipv4Addr,
+    pub port: u16,
+}
+
+impl SocketAddr {
+    pub const fn new(addr: Ipv4Addr, port: u16) -> Self {
+        Self { addr, port }
+    }
+}
+
+/// Address of this host, as assigned by QEMU user-mode networking
+pub const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+/// Address of gateway, which is also the host
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+/// Network operation error
+#[derive(Debug, PartialEq)]
+pub enum NetError {
+    /// invalid argument
+    Invalid,
+    /// address is already bound
+    AddrInUse,
+    /// no destination address is given
+    NotConnected,
+    /// packet can't be sent, e.g. there is no network interface
+    Unreachable,
+    /// data is larger than a packet
+    TooLarge,
+    /// operation would block
+    WouldBlock,
+    /// connection is refused by peer
+    Refused,
+    /// connection is reset by peer
+    Reset,
+    /// peer doesn't respond
+    TimedOut,
+}
+
+/// A network interface sending and receiving Ethernet frames
+pub trait NetInterface: Sync {
+    /// Hardware address of interface
+    fn mac(&self) -> MacAddr;
+    /// Send one frame, returns false if it is dropped
+    fn send(&self, frame: &[u8]) -> bool;
+}
+
+/// The only network interface
+static INTERFACE: Mutex<Option<&'static dyn NetInterface>> = Mutex::new(None, "netif");
+
+/// Attach network interface. Received frames should be passed to `receive`.
+pub fn attach(iface: &'static dyn NetInterface) {
+    let mut interface = INTERFACE.lock();
+    if interface.is_some() {
+        panic!("network interface already attached");
+    }
+    *interface = Some(iface);
+}
+
+/// Network interface attached
+pub fn interface() -> Option<&'static dyn NetInterface> {
+    *INTERFACE.lock()
+}
+
+/// Process a frame received by network interface
+pub fn receive(frame: &[u8]) {
+    ether::receive(frame);
+}
+
+/// Read big-endian u16 at `off`
+pub fn get_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([buf[off], buf[off + 1]])
+}
+
+/// Write big-endian u16 at `off`
+pub fn put_u16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+/// Internet checksum of `data`, starting from partial sum `sum`
+pub fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    let mut i = 0;
+    while i + 1 < data.len() {
+        sum += get_u16(data, i) as u32;
+        i += 2;
+    }
+    if i < data.len() {
+        sum += (data[i] as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test checksum of a known IPv4 header
+    #[test_case]
+    fn test_checksum() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7
+        ];
+        assert_eq!(checksum(&header, 0), 0xb861);
+        put_u16(&mut header, 10, 0xb861);
+        assert_eq!(checksum(&header, 0), 0);
+        assert_eq!(checksum(&[0xff], 0), 0x00ff);
+    }
+
+    /// Test classifying addresses
+    #[test_case]
+    fn test_address() {
+        assert!(GATEWAY.is_local());
+        assert!(!Ipv4Addr::new(8, 8, 8, 8).is_local());
+        assert!(Ipv4Addr::new(127, 0, 0, 1).is_loopback());
+        assert_eq!(Ipv4Addr::from_be(LOCAL_ADDR.to_be()), LOCAL_ADDR);
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Address Resolution Protocol
+//!
+//! IPv4 packets to a host whose hardware address is unknown are held
+//! until an ARP reply comes. Only one packet is held for each host.
+
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use super::{MacAddr, Ipv4Addr, NetError, LOCAL_ADDR, interface, get_u16, put_u16, ether};
+use super::ether::{BROADCAST, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+
+/// Size of ARP packet for IPv4 over Ethernet
+pub const ARP_LEN: usize = 28;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+/// Maximum number of entries in cache
+const CACHE_SIZE: usize = 16;
+
+/// Known hardware addresses, most recently learned last
+static CACHE: Mutex<Vec<(Ipv4Addr, MacAddr)>> = Mutex::new(Vec::new(), "arp cache");
+
+/// Packets waiting for address resolution
+static PENDING: Mutex<Vec<(Ipv4Addr, Vec<u8>)>> = Mutex::new(Vec::new(), "arp pending");
+
+/// Look up hardware address of `ip` in cache
+pub fn lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    CACHE.lock().iter().find(|&&(x, _)| x == ip).map(|&(_, mac)| mac)
+}
+
+/// Add or update cache entry
+fn learn(ip: Ipv4Addr, mac: MacAddr) {
+    let mut cache = CACHE.lock();
+    cache.retain(|&(x, _)| x != ip);
+    if cache.len() >= CACHE_SIZE {
+        cache.remove(0);
+    }
+    cache.push((ip, mac));
+}
+
+/// Build an ARP packet
+fn packet(oper: u16, sha: MacAddr, spa: Ipv4Addr, tha: MacAddr, tpa: Ipv4Addr) -> [u8; ARP_LEN] {
+    let mut pkt = [0; ARP_LEN];
+    put_u16(&mut pkt, 0, 1);
+    put_u16(&mut pkt, 2, ETHERTYPE_IPV4);
+    pkt[4] = 6;
+    pkt[5] = 4;
+    put_u16(&mut pkt, 6, oper);
+    pkt[8..14].copy_from_slice(&sha.0);
+    pkt[14..18].copy_from_slice(&spa.0);
+    pkt[18..24].copy_from_slice(&tha.0);
+    pkt[24..28].copy_from_slice(&tpa.0);
+    pkt
+}
+
+/// Process a received ARP packet. Requests for our address are answered,
+/// and sender address is learned in any case.
+pub fn receive(pkt: &[u8]) {
+    if pkt.len() < ARP_LEN || get_u16(pkt, 0) != 1 || get_u16(pkt, 2) != ETHERTYPE_IPV4
+        || pkt[4] != 6 || pkt[5] != 4 {
+        return;
+    }
+    let mut sha = [0; 6];
+    sha.copy_from_slice(&pkt[8..14]);
+    let sha = MacAddr(sha);
+    let mut spa = [0; 4];
+    spa.copy_from_slice(&pkt[14..18]);
+    let spa = Ipv4Addr(spa);
+    let mut tpa = [0; 4];
+    tpa.copy_from_slice(&pkt[24..28]);
+    let tpa = Ipv4Addr(tpa);
+
+    learn(spa, sha);
+
+    if get_u16(pkt, 6) == ARP_REQUEST && tpa == LOCAL_ADDR {
+        if let Some(iface) = interface() {
+            let reply = packet(ARP_REPLY, iface.mac(), LOCAL_ADDR, sha, spa);
+            ether::send(sha, ETHERTYPE_ARP, &reply).ok();
+        }
+    }
+
+    let waiting: Vec<Vec<u8>> = {
+        let mut pending = PENDING.lock();
+        let (waiting, rest) = pending.drain(..).partition(|&(ip, _)| ip == spa);
+        *pending = rest;
+        waiting.into_iter().map(|(_, pkt)| pkt).collect()
+    };
+    for pkt in waiting {
+        ether::send(sha, ETHERTYPE_IPV4, &pkt).ok();
+    }
+}
+
+/// Send IPv4 `pkt` to `next_hop` on local network, resolving its hardware
+/// address first if unknown.
+pub fn send_ipv4(next_hop: Ipv4Addr, pkt: Vec<u8>) -> Result<(), NetError> {
+    if let Some(mac) = lookup(next_hop) {
+        return ether::send(mac, ETHERTYPE_IPV4, &pkt);
+    }
+    if next_hop == Ipv4Addr::BROADCAST {
+        return ether::send(BROADCAST, ETHERTYPE_IPV4, &pkt);
+    }
+    let iface = interface().ok_or(NetError::Unreachable)?;
+    {
+        let mut pending = PENDING.lock();
+        // newer packet replaces older one
+        pending.retain(|&(ip, _)| ip != next_hop);
+        pending.push((next_hop, pkt));
+    }
+    let request = packet(ARP_REQUEST, iface.mac(), LOCAL_ADDR, MacAddr([0; 6]), next_hop);
+    ether::send(BROADCAST, ETHERTYPE_ARP, &request)
+}
@@ -0,0 +1,61 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Ethernet II framing
+
+use alloc::vec::Vec;
+use super::{MacAddr, NetError, interface, get_u16, put_u16, arp, ipv4};
+
+/// Size of Ethernet header
+pub const ETH_HLEN: usize = 14;
+/// Maximum size of payload
+pub const ETH_MTU: usize = 1500;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+
+/// Process a received frame addressed to us or broadcast
+pub fn receive(frame: &[u8]) {
+    let iface = match interface() {
+        Some(iface) => iface,
+        None => return
+    };
+    if frame.len() < ETH_HLEN {
+        return;
+    }
+    let mut dst = [0; 6];
+    dst.copy_from_slice(&frame[0..6]);
+    let dst = MacAddr(dst);
+    if dst != iface.mac() && dst != BROADCAST {
+        return;
+    }
+    let payload = &frame[ETH_HLEN..];
+    match get_u16(frame, 12) {
+        ETHERTYPE_ARP => arp::receive(payload),
+        ETHERTYPE_IPV4 => ipv4::receive(payload),
+        _ => {}
+    }
+}
+
+/// Send `payload` of `ethertype` to `dst`
+pub fn send(dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    let iface = interface().ok_or(NetError::Unreachable)?;
+    if payload.len() > ETH_MTU {
+        return Err(NetError::TooLarge);
+    }
+    let mut frame = Vec::with_capacity(ETH_HLEN + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&iface.mac().0);
+    frame.extend_from_slice(&[0, 0]);
+    put_u16(&mut frame, 12, ethertype);
+    frame.extend_from_slice(payload);
+    if iface.send(&frame) {
+        Ok(())
+    } else {
+        Err(NetError::Unreachable)
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Internet Control Message Protocol, only answering echo requests
+
+use super::{Ipv4Addr, checksum, put_u16, ipv4};
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Size of ICMP header
+pub const ICMP_HLEN: usize = 8;
+
+/// Process a received ICMP message from `src`
+pub fn receive(src: Ipv4Addr, msg: &[u8]) {
+    if msg.len() < ICMP_HLEN || checksum(msg, 0) != 0 {
+        return;
+    }
+    if msg[0] == ICMP_ECHO_REQUEST && msg[1] == 0 {
+        // reply carries same identifier, sequence number and data
+        let mut reply = msg.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        put_u16(&mut reply, 2, 0);
+        let sum = checksum(&reply, 0);
+        put_u16(&mut reply, 2, sum);
+        ipv4::send(src, ipv4::IPPROTO_ICMP, &reply).ok();
+    }
+}
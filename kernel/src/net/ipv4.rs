@@ -0,0 +1,99 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Internet Protocol version 4
+//!
+//! Options are ignored and fragmented packets are dropped.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use super::{Ipv4Addr, NetError, LOCAL_ADDR, GATEWAY, get_u16, put_u16, checksum, arp, icmp, udp, tcp};
+use super::ether::ETH_MTU;
+
+/// Size of header without options
+pub const IP_HLEN: usize = 20;
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+/// Time to live of packets sent
+const TTL: u8 = 64;
+
+/// Identification of next packet sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Process a received IPv4 packet
+pub fn receive(pkt: &[u8]) {
+    if pkt.len() < IP_HLEN || pkt[0] >> 4 != 4 {
+        return;
+    }
+    let hlen = (pkt[0] & 0xf) as usize * 4;
+    let total = get_u16(pkt, 2) as usize;
+    if hlen < IP_HLEN || total < hlen || total > pkt.len() || checksum(&pkt[..hlen], 0) != 0 {
+        return;
+    }
+    // more fragments flag or fragment offset
+    if get_u16(pkt, 6) & 0x3fff != 0 {
+        return;
+    }
+    let mut src = [0; 4];
+    src.copy_from_slice(&pkt[12..16]);
+    let src = Ipv4Addr(src);
+    let mut dst = [0; 4];
+    dst.copy_from_slice(&pkt[16..20]);
+    let dst = Ipv4Addr(dst);
+    if dst != LOCAL_ADDR && dst != Ipv4Addr::BROADCAST && !dst.is_loopback() {
+        return;
+    }
+    let payload = &pkt[hlen..total];
+    match pkt[9] {
+        IPPROTO_ICMP => icmp::receive(src, payload),
+        IPPROTO_TCP => tcp::receive(src, dst, payload),
+        IPPROTO_UDP => udp::receive(src, dst, payload),
+        _ => {}
+    }
+}
+
+/// Partial checksum of pseudo header used by TCP and UDP
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: usize) -> u32 {
+    (src.to_be() >> 16) + (src.to_be() & 0xffff)
+        + (dst.to_be() >> 16) + (dst.to_be() & 0xffff)
+        + proto as u32 + len as u32
+}
+
+/// Source address used for packets to `dst`
+pub fn source_of(dst: Ipv4Addr) -> Ipv4Addr {
+    if dst.is_loopback() { dst } else { LOCAL_ADDR }
+}
+
+/// Send `payload` of `proto` to `dst`. Packets to this host are looped back.
+pub fn send(dst: Ipv4Addr, proto: u8, payload: &[u8]) -> Result<(), NetError> {
+    if IP_HLEN + payload.len() > ETH_MTU {
+        return Err(NetError::TooLarge);
+    }
+    let src = source_of(dst);
+    let mut pkt = Vec::with_capacity(IP_HLEN + payload.len());
+    pkt.resize(IP_HLEN, 0);
+    pkt[0] = 0x45;
+    put_u16(&mut pkt, 2, (IP_HLEN + payload.len()) as u16);
+    put_u16(&mut pkt, 4, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    // don't fragment
+    put_u16(&mut pkt, 6, 0x4000);
+    pkt[8] = TTL;
+    pkt[9] = proto;
+    pkt[12..16].copy_from_slice(&src.0);
+    pkt[16..20].copy_from_slice(&dst.0);
+    let sum = checksum(&pkt[..IP_HLEN], 0);
+    put_u16(&mut pkt, 10, sum);
+    pkt.extend_from_slice(payload);
+
+    if dst == LOCAL_ADDR || dst.is_loopback() {
+        receive(&pkt);
+        return Ok(());
+    }
+    let next_hop = if dst.is_local() || dst == Ipv4Addr::BROADCAST { dst } else { GATEWAY };
+    arp::send_ipv4(next_hop, pkt)
+}
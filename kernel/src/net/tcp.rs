@@ -0,0 +1,884 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Transmission Control Protocol
+//!
+//! A compact TCP without out-of-order reassembly or congestion control.
+//! Segments arriving out of order are dropped, and lost segments are
+//! recovered by go-back-N retransmission driven by timer interrupts.
+//!
+//! Segments are built while holding lock of a connection, and sent after
+//! the lock is released, as segments to this host loop back into
+//! `receive` right away.
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::spinlock::Mutex;
+use crate::process::WaitQueue;
+use crate::file::{POLLIN, POLLOUT};
+use crate::arch;
+use super::{Ipv4Addr, SocketAddr, NetError, get_u16, put_u16, checksum, ipv4};
+use super::ipv4::{IP_HLEN, IPPROTO_TCP};
+use super::ether::ETH_MTU;
+
+/// Size of TCP header without options
+pub const TCP_HLEN: usize = 20;
+/// Maximum size of data in one segment
+pub const MSS: usize = ETH_MTU - IP_HLEN - TCP_HLEN;
+/// Size of send and receive buffer of each connection
+pub const TCP_BUF_SIZE: usize = 8192;
+/// Maximum number of connections waiting to be accepted
+pub const BACKLOG_MAX: usize = 16;
+
+/// Initial retransmission timeout
+const RTO_INITIAL: Duration = Duration::from_millis(500);
+/// Number of retransmissions before giving up
+const MAX_RETRIES: usize = 8;
+/// Time spent in TIME-WAIT state
+const TIME_WAIT: Duration = Duration::from_secs(1);
+
+/// First port assigned to sockets connecting without binding
+const EPHEMERAL_START: u16 = 49152;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Connection state, as in RFC 793
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// `a` is before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// A parsed segment
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Length in sequence space
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+/// A segment to be sent after lock is released
+struct Outgoing {
+    dst: Ipv4Addr,
+    data: Vec<u8>,
+}
+
+/// Build a segment
+fn build(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, flags: u8, window: u16, data: &[u8]) -> Outgoing {
+    let mut seg = Vec::with_capacity(TCP_HLEN + data.len());
+    seg.resize(TCP_HLEN, 0);
+    put_u16(&mut seg, 0, src.port);
+    put_u16(&mut seg, 2, dst.port);
+    seg[4..8].copy_from_slice(&seq.to_be_bytes());
+    seg[8..12].copy_from_slice(&ack.to_be_bytes());
+    seg[12] = (TCP_HLEN as u8 / 4) << 4;
+    seg[13] = flags;
+    put_u16(&mut seg, 14, window);
+    seg.extend_from_slice(data);
+    let sum = checksum(&seg, ipv4::pseudo_header_sum(src.addr, dst.addr, IPPROTO_TCP, seg.len()));
+    put_u16(&mut seg, 16, sum);
+    Outgoing { dst: dst.addr, data: seg }
+}
+
+/// Reset in reply to a segment which belongs to no connection
+fn reset_reply(seg: &Segment, out: &mut Vec<Outgoing>) {
+    if seg.flags & RST != 0 {
+        return;
+    }
+    if seg.flags & ACK != 0 {
+        out.push(build(seg.dst, seg.src, seg.ack, 0, RST, 0, &[]));
+    } else {
+        out.push(build(seg.dst, seg.src, 0, seg.seq.wrapping_add(seg.len()), RST | ACK, 0, &[]));
+    }
+}
+
+/// Send segments built, ignoring failures as they will be retransmitted
+fn transmit(out: Vec<Outgoing>) {
+    for seg in out {
+        ipv4::send(seg.dst, IPPROTO_TCP, &seg.data).ok();
+    }
+}
+
+/// Initial sequence number of a new connection
+fn initial_seq() -> u32 {
+    static COUNTER: Mutex<u32> = Mutex::new(0, "tcp iss");
+    let mut counter = COUNTER.lock();
+    *counter = counter.wrapping_add(64000);
+    (arch::time().as_micros() as u32 / 4).wrapping_add(*counter)
+}
+
+/// Transmission control block
+struct Tcb {
+    state: State,
+    /// local address, with port 0 if unbound
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// oldest unacknowledged sequence number
+    snd_una: u32,
+    /// next sequence number to be sent
+    snd_nxt: u32,
+    /// window advertised by peer
+    snd_wnd: u32,
+    /// next sequence number expected
+    rcv_nxt: u32,
+    /// data not yet acknowledged, starting from `snd_una`
+    send_buf: VecDeque<u8>,
+    /// data received and not yet read
+    recv_buf: VecDeque<u8>,
+    /// FIN is to be sent after all data
+    fin_queued: bool,
+    fin_acked: bool,
+    /// socket is held by a file
+    owned: bool,
+    /// time of retransmission, or end of TIME-WAIT
+    deadline: Option<Duration>,
+    rto: Duration,
+    retries: usize,
+    /// error to be reported to user
+    error: Option<NetError>,
+    /// established connections not yet accepted, for listening socket
+    backlog: VecDeque<Arc<TcpSocket>>,
+    backlog_max: usize,
+    /// listening socket which created this connection
+    parent: Weak<TcpSocket>,
+}
+
+impl Tcb {
+    /// Receive window advertised
+    fn window(&self) -> u16 {
+        (TCP_BUF_SIZE - self.recv_buf.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn segment(&self, seq: u32, flags: u8, data: &[u8]) -> Outgoing {
+        build(self.local, self.remote, seq, self.rcv_nxt, flags, self.window(), data)
+    }
+
+    fn ack(&self, out: &mut Vec<Outgoing>) {
+        out.push(self.segment(self.snd_nxt, ACK, &[]));
+    }
+
+    /// Peer has closed its side of connection
+    fn eof(&self) -> bool {
+        match self.state {
+            State::CloseWait | State::LastAck | State::Closing | State::TimeWait | State::Closed => true,
+            _ => false
+        }
+    }
+
+    /// Data may be sent in this state
+    fn can_send(&self) -> bool {
+        self.state == State::Established || self.state == State::CloseWait
+    }
+
+    fn set_closed(&mut self, error: Option<NetError>) {
+        self.state = State::Closed;
+        self.deadline = None;
+        if self.error.is_none() {
+            self.error = error;
+        }
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.deadline = Some(arch::time() + TIME_WAIT);
+    }
+
+    /// Send data and FIN within window of peer, and start retransmission timer
+    fn output(&mut self, out: &mut Vec<Outgoing>) {
+        match self.state {
+            State::Established | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck => {}
+            _ => return
+        }
+        // a closed window is probed with one byte on each retransmission
+        let wnd = self.snd_wnd.max(1) as usize;
+        loop {
+            let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if sent < self.send_buf.len() {
+                if sent >= wnd {
+                    break;
+                }
+                let n = MSS.min(self.send_buf.len() - sent).min(wnd - sent);
+                let data: Vec<u8> = self.send_buf.iter().skip(sent).take(n).copied().collect();
+                out.push(self.segment(self.snd_nxt, ACK | PSH, &data));
+                self.snd_nxt = self.snd_nxt.wrapping_add(n as u32);
+            } else if self.fin_queued && !self.fin_acked && sent == self.send_buf.len() {
+                out.push(self.segment(self.snd_nxt, FIN | ACK, &[]));
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                break;
+            } else {
+                break;
+            }
+        }
+        if self.snd_nxt != self.snd_una && self.deadline.is_none() {
+            self.deadline = Some(arch::time() + self.rto);
+        }
+    }
+
+    /// Process acknowledgement of `ack`
+    fn acknowledge(&mut self, ack: u32) {
+        if !seq_lt(self.snd_una, ack) || !seq_le(ack, self.snd_nxt) {
+            return;
+        }
+        let n = ack.wrapping_sub(self.snd_una) as usize;
+        let data = n.min(self.send_buf.len());
+        self.send_buf.drain(..data);
+        self.snd_una = self.snd_una.wrapping_add(data as u32);
+        if n > data && self.fin_queued {
+            self.snd_una = self.snd_una.wrapping_add(1);
+            self.fin_acked = true;
+        }
+        self.retries = 0;
+        self.rto = RTO_INITIAL;
+        self.deadline = if self.snd_una == self.snd_nxt { None } else { Some(arch::time() + self.rto) };
+    }
+
+    /// Retransmit unacknowledged segments from `snd_una`
+    fn retransmit(&mut self, out: &mut Vec<Outgoing>) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.set_closed(Some(NetError::TimedOut));
+            return;
+        }
+        self.rto *= 2;
+        match self.state {
+            State::SynSent => out.push(self.segment(self.snd_una, SYN, &[])),
+            State::SynReceived => out.push(self.segment(self.snd_una, SYN | ACK, &[])),
+            _ => {
+                self.snd_nxt = self.snd_una;
+                self.deadline = None;
+                self.output(out);
+            }
+        }
+        self.deadline = Some(arch::time() + self.rto);
+    }
+}
+
+/// What should be done after a segment is processed and lock is released
+enum Action {
+    None,
+    /// a new connection is created by listening socket
+    NewChild(Arc<TcpSocket>),
+    /// connection created by listening socket is established
+    Established,
+}
+
+pub struct TcpSocket {
+    tcb: Mutex<Tcb>,
+    /// processes waiting for any change of connection
+    events: WaitQueue,
+}
+
+/// Connections and listening sockets, which are kept until closed by both
+/// user and peer
+static SOCKETS: Mutex<Vec<Arc<TcpSocket>>> = Mutex::new(Vec::new(), "tcp sockets");
+
+impl TcpSocket {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::with_tcb(SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0), SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0), true, Weak::new()))
+    }
+
+    fn with_tcb(local: SocketAddr, remote: SocketAddr, owned: bool, parent: Weak<TcpSocket>) -> Self {
+        Self {
+            tcb: Mutex::new(Tcb {
+                state: State::Closed,
+                local,
+                remote,
+                snd_una: 0,
+                snd_nxt: 0,
+                snd_wnd: 0,
+                rcv_nxt: 0,
+                send_buf: VecDeque::new(),
+                recv_buf: VecDeque::new(),
+                fin_queued: false,
+                fin_acked: false,
+                owned,
+                deadline: None,
+                rto: RTO_INITIAL,
+                retries: 0,
+                error: None,
+                backlog: VecDeque::new(),
+                backlog_max: 0,
+                parent,
+            }, "tcp"),
+            events: WaitQueue::new(),
+        }
+    }
+
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    fn wake(&self) {
+        self.events.wakeup(self.channel());
+    }
+
+    /// Bind socket to local `port`, or an unused port if `port` is 0
+    pub fn bind(self: &Arc<Self>, port: u16) -> Result<u16, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let in_use = |p: u16| sockets.iter().any(|s| s.tcb.lock().local.port == p);
+        let port = if port == 0 {
+            (EPHEMERAL_START..=u16::MAX).find(|&p| !in_use(p)).ok_or(NetError::AddrInUse)?
+        } else if in_use(port) {
+            return Err(NetError::AddrInUse);
+        } else {
+            port
+        };
+        let mut tcb = self.tcb.lock();
+        if tcb.local.port != 0 {
+            return Err(NetError::Invalid);
+        }
+        tcb.local.port = port;
+        drop(tcb);
+        sockets.push(self.clone());
+        Ok(port)
+    }
+
+    fn ensure_bound(self: &Arc<Self>) -> Result<(), NetError> {
+        if self.tcb.lock().local.port == 0 {
+            self.bind(0)?;
+        }
+        Ok(())
+    }
+
+    /// Local address of socket
+    pub fn local(&self) -> SocketAddr {
+        self.tcb.lock().local
+    }
+
+    /// Listen for connections, keeping at most `backlog` of them until accepted
+    pub fn listen(self: &Arc<Self>, backlog: usize) -> Result<(), NetError> {
+        self.ensure_bound()?;
+        let mut tcb = self.tcb.lock();
+        match tcb.state {
+            State::Closed | State::Listen => {}
+            _ => return Err(NetError::Invalid)
+        }
+        tcb.state = State::Listen;
+        tcb.backlog_max = backlog.max(1).min(BACKLOG_MAX);
+        Ok(())
+    }
+
+    /// Connect to `remote`, waiting until connection is established
+    pub fn connect(self: &Arc<Self>, remote: SocketAddr) -> Result<(), NetError> {
+        self.ensure_bound()?;
+        let mut out = Vec::new();
+        {
+            let mut tcb = self.tcb.lock();
+            if tcb.state != State::Closed {
+                return Err(NetError::Invalid);
+            }
+            let iss = initial_seq();
+            tcb.remote = remote;
+            tcb.local.addr = ipv4::source_of(remote.addr);
+            tcb.state = State::SynSent;
+            tcb.error = None;
+            tcb.snd_una = iss;
+            tcb.snd_nxt = iss.wrapping_add(1);
+            tcb.deadline = Some(arch::time() + tcb.rto);
+            out.push(tcb.segment(iss, SYN, &[]));
+        }
+        transmit(out);
+        let mut tcb = self.tcb.lock();
+        loop {
+            match tcb.state {
+                State::SynSent => tcb = self.events.sleep(self.channel(), tcb),
+                State::Closed => return Err(tcb.error.take().unwrap_or(NetError::Refused)),
+                _ => return Ok(())
+            }
+        }
+    }
+
+    /// Accept a connection, waiting for one unless `nonblock`
+    pub fn accept(&self, nonblock: bool) -> Result<(Arc<TcpSocket>, SocketAddr), NetError> {
+        let mut tcb = self.tcb.lock();
+        loop {
+            if tcb.state != State::Listen {
+                return Err(NetError::Invalid);
+            }
+            if let Some(child) = tcb.backlog.pop_front() {
+                drop(tcb);
+                let mut child_tcb = child.tcb.lock();
+                child_tcb.owned = true;
+                let remote = child_tcb.remote;
+                drop(child_tcb);
+                return Ok((child, remote));
+            }
+            if nonblock {
+                return Err(NetError::WouldBlock);
+            }
+            tcb = self.events.sleep(self.channel(), tcb);
+        }
+    }
+
+    /// Queue `data` to be sent, waiting for room unless `nonblock`.
+    /// Returns number of bytes queued.
+    pub fn send(&self, data: &[u8], nonblock: bool) -> Result<usize, NetError> {
+        let mut out = Vec::new();
+        let n = {
+            let mut tcb = self.tcb.lock();
+            loop {
+                if let Some(err) = tcb.error.take() {
+                    return Err(err);
+                }
+                if !tcb.can_send() {
+                    return Err(NetError::NotConnected);
+                }
+                if tcb.send_buf.len() < TCP_BUF_SIZE || data.is_empty() {
+                    break;
+                }
+                if nonblock {
+                    return Err(NetError::WouldBlock);
+                }
+                tcb = self.events.sleep(self.channel(), tcb);
+            }
+            let n = data.len().min(TCP_BUF_SIZE - tcb.send_buf.len());
+            tcb.send_buf.extend(&data[..n]);
+            tcb.output(&mut out);
+            n
+        };
+        transmit(out);
+        Ok(n)
+    }
+
+    /// Read received data into `content`, waiting for it unless `nonblock`.
+    /// Returns 0 if peer has closed connection.
+    pub fn recv(&self, content: &mut [u8], nonblock: bool) -> Result<(usize, SocketAddr), NetError> {
+        let mut out = Vec::new();
+        let result = {
+            let mut tcb = self.tcb.lock();
+            loop {
+                if !tcb.recv_buf.is_empty() {
+                    break;
+                }
+                if let Some(err) = tcb.error.take() {
+                    return Err(err);
+                }
+                if tcb.eof() {
+                    return Ok((0, tcb.remote));
+                }
+                match tcb.state {
+                    State::Listen | State::Closed => return Err(NetError::NotConnected),
+                    _ => {}
+                }
+                if nonblock {
+                    return Err(NetError::WouldBlock);
+                }
+                tcb = self.events.sleep(self.channel(), tcb);
+            }
+            let was_full = tcb.recv_buf.len() > TCP_BUF_SIZE / 2;
+            let n = content.len().min(tcb.recv_buf.len());
+            for (dst, src) in content.iter_mut().zip(tcb.recv_buf.drain(..n)) {
+                *dst = src;
+            }
+            // tell peer that window opens again
+            if was_full && tcb.recv_buf.len() <= TCP_BUF_SIZE / 2 && !tcb.eof() {
+                tcb.ack(&mut out);
+            }
+            (n, tcb.remote)
+        };
+        transmit(out);
+        Ok(result)
+    }
+
+    /// Close socket held by user. Connection is closed gracefully after
+    /// all data sent are acknowledged.
+    pub fn close(&self) {
+        let mut out = Vec::new();
+        let backlog = {
+            let mut tcb = self.tcb.lock();
+            tcb.owned = false;
+            match tcb.state {
+                State::Listen | State::SynSent => tcb.set_closed(None),
+                State::SynReceived | State::Established => {
+                    tcb.state = State::FinWait1;
+                    tcb.fin_queued = true;
+                    tcb.output(&mut out);
+                }
+                State::CloseWait => {
+                    tcb.state = State::LastAck;
+                    tcb.fin_queued = true;
+                    tcb.output(&mut out);
+                }
+                _ => {}
+            }
+            core::mem::replace(&mut tcb.backlog, VecDeque::new())
+        };
+        // connections never accepted are reset
+        for child in backlog {
+            child.abort(&mut out);
+        }
+        transmit(out);
+        reap();
+    }
+
+    /// Reset connection
+    fn abort(&self, out: &mut Vec<Outgoing>) {
+        let mut tcb = self.tcb.lock();
+        match tcb.state {
+            State::Closed | State::Listen | State::SynSent => {}
+            _ => out.push(tcb.segment(tcb.snd_nxt, RST | ACK, &[]))
+        }
+        tcb.owned = false;
+        tcb.set_closed(None);
+        self.wake();
+    }
+
+    /// Process a segment of this connection or listening socket
+    fn handle(self: &Arc<Self>, seg: &Segment, out: &mut Vec<Outgoing>) -> Action {
+        let mut tcb = self.tcb.lock();
+        match tcb.state {
+            State::Closed => {
+                reset_reply(seg, out);
+                Action::None
+            }
+            State::Listen => {
+                if seg.flags & RST != 0 {
+                    return Action::None;
+                }
+                if seg.flags & ACK != 0 || seg.flags & SYN == 0 {
+                    reset_reply(seg, out);
+                    return Action::None;
+                }
+                if tcb.backlog.len() >= tcb.backlog_max {
+                    return Action::None;
+                }
+                let child = Arc::new(Self::with_tcb(seg.dst, seg.src, false, Arc::downgrade(self)));
+                {
+                    let mut c = child.tcb.lock();
+                    let iss = initial_seq();
+                    c.state = State::SynReceived;
+                    c.rcv_nxt = seg.seq.wrapping_add(1);
+                    c.snd_una = iss;
+                    c.snd_nxt = iss.wrapping_add(1);
+                    c.snd_wnd = seg.window as u32;
+                    c.deadline = Some(arch::time() + c.rto);
+                    out.push(c.segment(iss, SYN | ACK, &[]));
+                }
+                Action::NewChild(child)
+            }
+            State::SynSent => {
+                if seg.flags & ACK != 0 && seg.ack != tcb.snd_nxt {
+                    reset_reply(seg, out);
+                    return Action::None;
+                }
+                if seg.flags & RST != 0 {
+                    if seg.flags & ACK != 0 {
+                        tcb.set_closed(Some(NetError::Refused));
+                        self.wake();
+                    }
+                    return Action::None;
+                }
+                if seg.flags & SYN != 0 && seg.flags & ACK != 0 {
+                    tcb.rcv_nxt = seg.seq.wrapping_add(1);
+                    tcb.snd_una = seg.ack;
+                    tcb.snd_wnd = seg.window as u32;
+                    tcb.state = State::Established;
+                    tcb.deadline = None;
+                    tcb.retries = 0;
+                    tcb.rto = RTO_INITIAL;
+                    tcb.ack(out);
+                    tcb.output(out);
+                    self.wake();
+                }
+                Action::None
+            }
+            _ => {
+                let mut action = Action::None;
+                if seg.flags & RST != 0 {
+                    if seg.seq == tcb.rcv_nxt {
+                        let err = if tcb.state == State::SynReceived { None } else { Some(NetError::Reset) };
+                        tcb.set_closed(err);
+                        self.wake();
+                    }
+                    return Action::None;
+                }
+                if seg.flags & SYN != 0 {
+                    // SYN retransmitted by peer, or SYN in a synchronized connection
+                    if tcb.state == State::SynReceived {
+                        out.push(tcb.segment(tcb.snd_una, SYN | ACK, &[]));
+                    } else {
+                        tcb.ack(out);
+                    }
+                    return Action::None;
+                }
+                if seg.flags & ACK == 0 {
+                    return Action::None;
+                }
+                if tcb.state == State::SynReceived {
+                    if seg.ack != tcb.snd_nxt {
+                        reset_reply(seg, out);
+                        return Action::None;
+                    }
+                    // our SYN is acknowledged
+                    tcb.snd_una = seg.ack;
+                    tcb.state = State::Established;
+                    tcb.deadline = None;
+                    tcb.retries = 0;
+                    tcb.rto = RTO_INITIAL;
+                    action = Action::Established;
+                }
+                tcb.acknowledge(seg.ack);
+                tcb.snd_wnd = seg.window as u32;
+                if tcb.fin_acked {
+                    match tcb.state {
+                        State::FinWait1 => tcb.state = State::FinWait2,
+                        State::Closing => tcb.enter_time_wait(),
+                        State::LastAck => tcb.set_closed(None),
+                        _ => {}
+                    }
+                }
+
+                if !seg.data.is_empty() || seg.flags & FIN != 0 {
+                    if seg.seq == tcb.rcv_nxt {
+                        match tcb.state {
+                            State::Established | State::FinWait1 | State::FinWait2 => {
+                                let n = seg.data.len().min(TCP_BUF_SIZE - tcb.recv_buf.len());
+                                tcb.recv_buf.extend(&seg.data[..n]);
+                                tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(n as u32);
+                                if seg.flags & FIN != 0 && n == seg.data.len() {
+                                    tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+                                    match tcb.state {
+                                        State::Established => tcb.state = State::CloseWait,
+                                        State::FinWait1 => if tcb.fin_acked {
+                                            tcb.enter_time_wait()
+                                        } else {
+                                            tcb.state = State::Closing
+                                        },
+                                        _ => tcb.enter_time_wait()
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    // acknowledge anything received, including duplicates
+                    // and segments out of order
+                    if tcb.state != State::Closed {
+                        tcb.ack(out);
+                    }
+                }
+                tcb.output(out);
+                self.wake();
+                action
+            }
+        }
+    }
+
+    /// Returns ready events. Socket is readable if there is data, a
+    /// connection to accept, end of stream or error, and is writable if
+    /// there is room in send buffer.
+    pub fn poll(&self) -> usize {
+        let tcb = self.tcb.lock();
+        let mut events = 0;
+        if !tcb.recv_buf.is_empty() || !tcb.backlog.is_empty() || tcb.error.is_some()
+            || (tcb.eof() && tcb.state != State::Closed) {
+            events |= POLLIN;
+        }
+        if tcb.can_send() && tcb.send_buf.len() < TCP_BUF_SIZE {
+            events |= POLLOUT;
+        }
+        events
+    }
+
+    /// Wait queue and channel on which changes of connection are notified
+    pub fn wait_queue(&self) -> (&WaitQueue, usize) {
+        (&self.events, self.channel())
+    }
+}
+
+/// Find connection of a segment, or listening socket of its port
+fn find(seg: &Segment) -> Option<Arc<TcpSocket>> {
+    let sockets = SOCKETS.lock();
+    let mut listener = None;
+    for s in sockets.iter() {
+        let tcb = s.tcb.lock();
+        if tcb.local.port != seg.dst.port {
+            continue;
+        }
+        if tcb.remote == seg.src && tcb.state != State::Listen && tcb.state != State::Closed {
+            return Some(s.clone());
+        }
+        if tcb.state == State::Listen {
+            listener = Some(s.clone());
+        }
+    }
+    listener
+}
+
+/// Remove sockets closed by both user and peer
+fn reap() {
+    SOCKETS.lock().retain(|s| {
+        let tcb = s.tcb.lock();
+        tcb.owned || tcb.state != State::Closed
+    });
+}
+
+/// Process a received TCP segment from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, pkt: &[u8]) {
+    if pkt.len() < TCP_HLEN || checksum(pkt, ipv4::pseudo_header_sum(src, dst, IPPROTO_TCP, pkt.len())) != 0 {
+        return;
+    }
+    let hlen = (pkt[12] >> 4) as usize * 4;
+    if hlen < TCP_HLEN || hlen > pkt.len() {
+        return;
+    }
+    let seg = Segment {
+        src: SocketAddr::new(src, get_u16(pkt, 0)),
+        dst: SocketAddr::new(dst, get_u16(pkt, 2)),
+        seq: u32::from_be_bytes([pkt[4], pkt[5], pkt[6], pkt[7]]),
+        ack: u32::from_be_bytes([pkt[8], pkt[9], pkt[10], pkt[11]]),
+        flags: pkt[13],
+        window: get_u16(pkt, 14),
+        data: &pkt[hlen..],
+    };
+    let mut out = Vec::new();
+    match find(&seg) {
+        Some(sock) => match sock.handle(&seg, &mut out) {
+            Action::NewChild(child) => {
+                // child must be found by the reply to its SYN-ACK
+                SOCKETS.lock().push(child);
+            }
+            Action::Established => {
+                let parent = sock.tcb.lock().parent.upgrade();
+                match parent {
+                    Some(parent) => {
+                        parent.tcb.lock().backlog.push_back(sock.clone());
+                        parent.wake();
+                    }
+                    None => sock.abort(&mut out)
+                }
+            }
+            Action::None => {}
+        }
+        None => reset_reply(&seg, &mut out)
+    }
+    transmit(out);
+}
+
+/// Retransmit segments and finish TIME-WAIT on timeout.
+/// Called from timer interrupt.
+pub fn tick() {
+    let now = arch::time();
+    let sockets: Vec<Arc<TcpSocket>> = SOCKETS.lock().clone();
+    let mut out = Vec::new();
+    for s in sockets.iter() {
+        let mut tcb = s.tcb.lock();
+        match tcb.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => continue
+        }
+        if tcb.state == State::TimeWait {
+            tcb.set_closed(None);
+        } else {
+            tcb.retransmit(&mut out);
+        }
+        s.wake();
+    }
+    drop(sockets);
+    transmit(out);
+    reap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::LOCAL_ADDR;
+
+    /// Test comparing sequence numbers across wrap-around
+    #[test_case]
+    fn test_sequence() {
+        assert!(seq_lt(1, 2));
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(!seq_lt(0, u32::MAX));
+        assert!(seq_le(5, 5));
+    }
+
+    /// Test connection to this host, which is handled synchronously
+    #[test_case]
+    fn test_loopback() {
+        let server = TcpSocket::new();
+        let port = server.bind(0).unwrap();
+        server.listen(4).unwrap();
+        let client = TcpSocket::new();
+        client.connect(SocketAddr::new(LOCAL_ADDR, port)).unwrap();
+        let (conn, remote) = server.accept(true).unwrap();
+        assert_eq!(remote, client.local());
+        assert_eq!(server.accept(true).err(), Some(NetError::WouldBlock));
+
+        assert_eq!(client.send(b"hello", false), Ok(5));
+        assert_eq!(conn.poll(), POLLIN | POLLOUT);
+        let mut buf = [0; 16];
+        assert_eq!(conn.recv(&mut buf, true), Ok((5, remote)));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(conn.recv(&mut buf, true), Err(NetError::WouldBlock));
+
+        // data sent by accepted socket is acknowledged in whole
+        assert_eq!(conn.send(b"world", false), Ok(5));
+        assert_eq!(client.recv(&mut buf, true).map(|(n, _)| n), Ok(5));
+        assert_eq!(&buf[..5], b"world");
+        {
+            let tcb = conn.tcb.lock();
+            assert_eq!(tcb.snd_una, tcb.snd_nxt);
+            assert!(tcb.send_buf.is_empty());
+            assert!(tcb.deadline.is_none());
+        }
+
+        assert_eq!(conn.send(b"bye", false), Ok(3));
+        conn.close();
+        assert_eq!(client.recv(&mut buf, true).map(|(n, _)| n), Ok(3));
+        assert_eq!(&buf[..3], b"bye");
+        assert_eq!(client.recv(&mut buf, true).map(|(n, _)| n), Ok(0));
+        {
+            let tcb = conn.tcb.lock();
+            assert!(tcb.fin_acked);
+            assert_eq!(tcb.snd_una, tcb.snd_nxt);
+        }
+        client.close();
+        server.close();
+        assert!(SOCKETS.lock().iter().all(|s| !Arc::ptr_eq(s, &server) && !Arc::ptr_eq(s, &client)));
+    }
+
+    /// Test connecting to a port nobody listens on
+    #[test_case]
+    fn test_refused() {
+        let client = TcpSocket::new();
+        assert_eq!(client.connect(SocketAddr::new(LOCAL_ADDR, 9)), Err(NetError::Refused));
+        client.close();
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! User Datagram Protocol
+//!
+//! Each bound socket owns a local port. Datagrams to a port nobody has
+//! bound are dropped silently, as are datagrams arriving at a full queue.
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use crate::process::WaitQueue;
+use crate::file::{POLLIN, POLLOUT};
+use super::{Ipv4Addr, SocketAddr, NetError, get_u16, put_u16, checksum, ipv4};
+use super::ipv4::{IP_HLEN, IPPROTO_UDP};
+use super::ether::ETH_MTU;
+
+/// Size of UDP header
+pub const UDP_HLEN: usize = 8;
+/// Maximum size of data in one datagram
+pub const UDP_MAX_DATA: usize = ETH_MTU - IP_HLEN - UDP_HLEN;
+/// Maximum number of datagrams queued in a socket
+pub const UDP_QUEUE_LEN: usize = 64;
+
+/// First port assigned to sockets sending without binding
+const EPHEMERAL_START: u16 = 49152;
+
+struct UdpState {
+    /// local port, assigned on bind or first send
+    port: Option<u16>,
+    /// default destination, and the only source accepted if set
+    peer: Option<SocketAddr>,
+    /// received datagrams and their sources
+    queue: VecDeque<(SocketAddr, Vec<u8>)>,
+}
+
+pub struct UdpSocket {
+    state: Mutex<UdpState>,
+    /// processes waiting for a datagram
+    readers: WaitQueue,
+}
+
+/// Bound ports and their sockets
+static PORTS: Mutex<Vec<(u16, Weak<UdpSocket>)>> = Mutex::new(Vec::new(), "udp ports");
+
+/// Checksum of UDP datagram with IPv4 pseudo header
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) -> u16 {
+    checksum(datagram, ipv4::pseudo_header_sum(src, dst, IPPROTO_UDP, datagram.len()))
+}
+
+/// Process a received UDP datagram from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < UDP_HLEN {
+        return;
+    }
+    let len = get_u16(datagram, 4) as usize;
+    if len < UDP_HLEN || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if get_u16(datagram, 6) != 0 && udp_checksum(src, dst, datagram) != 0 {
+        return;
+    }
+    let port = get_u16(datagram, 2);
+    let socket = PORTS.lock().iter().find(|(p, _)| *p == port).and_then(|(_, s)| s.upgrade());
+    if let Some(socket) = socket {
+        socket.deliver(SocketAddr::new(src, get_u16(datagram, 0)), &datagram[UDP_HLEN..]);
+    }
+}
+
+/// Send `data` from `src_port` to `dst`
+pub fn send(src_port: u16, dst: SocketAddr, data: &[u8]) -> Result<(), NetError> {
+    if data.len() > UDP_MAX_DATA {
+        return Err(NetError::TooLarge);
+    }
+    let mut datagram = Vec::with_capacity(UDP_HLEN + data.len());
+    datagram.resize(UDP_HLEN, 0);
+    put_u16(&mut datagram, 0, src_port);
+    put_u16(&mut datagram, 2, dst.port);
+    put_u16(&mut datagram, 4, (UDP_HLEN + data.len()) as u16);
+    datagram.extend_from_slice(data);
+    let sum = match udp_checksum(ipv4::source_of(dst.addr), dst.addr, &datagram) {
+        // zero means no checksum
+        0 => 0xffff,
+        sum => sum
+    };
+    put_u16(&mut datagram, 6, sum);
+    ipv4::send(dst.addr, IPPROTO_UDP, &datagram)
+}
+
+impl UdpSocket {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(UdpState {
+                port: None,
+                peer: None,
+                queue: VecDeque::new(),
+            }, "udp"),
+            readers: WaitQueue::new(),
+        })
+    }
+
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Bind socket to local `port`, or an unused port if `port` is 0.
+    /// Returns port bound.
+    pub fn bind(self: &Arc<Self>, port: u16) -> Result<u16, NetError> {
+        let mut ports = PORTS.lock();
+        let mut state = self.state.lock();
+        if state.port.is_some() {
+            return Err(NetError::Invalid);
+        }
+        let in_use = |p: u16| ports.iter().any(|&(x, _)| x == p);
+        let port = if port == 0 {
+            (EPHEMERAL_START..=u16::MAX).find(|&p| !in_use(p)).ok_or(NetError::AddrInUse)?
+        } else if in_use(port) {
+            return Err(NetError::AddrInUse);
+        } else {
+            port
+        };
+        ports.push((port, Arc::downgrade(self)));
+        state.port = Some(port);
+        Ok(port)
+    }
+
+    /// Local port of socket
+    pub fn port(&self) -> Option<u16> {
+        self.state.lock().port
+    }
+
+    /// Set default destination, and only accept datagrams from it
+    pub fn connect(&self, peer: SocketAddr) {
+        let mut state = self.state.lock();
+        state.peer = Some(peer);
+        state.queue.retain(|(src, _)| *src == peer);
+    }
+
+    /// Queue datagram received from `src`
+    fn deliver(&self, src: SocketAddr, data: &[u8]) {
+        let mut state = self.state.lock();
+        if state.peer.map_or(false, |peer| peer != src) || state.queue.len() >= UDP_QUEUE_LEN {
+            return;
+        }
+        state.queue.push_back((src, data.to_vec()));
+        // all readers are woken up, as some of them may be polling
+        self.readers.wakeup(self.channel());
+    }
+
+    /// Send `data` to `dst`, or to connected peer if `dst` is `None`.
+    /// Socket is bound to an unused port if not yet bound.
+    pub fn send_to(self: &Arc<Self>, data: &[u8], dst: Option<SocketAddr>) -> Result<usize, NetError> {
+        let (port, peer) = {
+            let state = self.state.lock();
+            (state.port, state.peer)
+        };
+        let dst = dst.or(peer).ok_or(NetError::NotConnected)?;
+        let port = match port {
+            Some(port) => port,
+            None => self.bind(0)?
+        };
+        send(port, dst, data)?;
+        Ok(data.len())
+    }
+
+    /// Receive a datagram into `content`, waiting for one unless `nonblock`.
+    ///
+    /// Datagram longer than `content` is truncated. Returns number of bytes
+    /// received and source of datagram.
+    pub fn recv_from(&self, content: &mut [u8], nonblock: bool) -> Result<(usize, SocketAddr), NetError> {
+        let mut state = self.state.lock();
+        let (src, data) = loop {
+            match state.queue.pop_front() {
+                Some(datagram) => break datagram,
+                None if nonblock => return Err(NetError::WouldBlock),
+                None => state = self.readers.sleep(self.channel(), state)
+            }
+        };
+        drop(state);
+        let sz = data.len().min(content.len());
+        content[..sz].copy_from_slice(&data[..sz]);
+        Ok((sz, src))
+    }
+
+    /// Returns ready events. Socket is readable if there are datagrams,
+    /// and is always writable.
+    pub fn poll(&self) -> usize {
+        if self.state.lock().queue.is_empty() { POLLOUT } else { POLLIN | POLLOUT }
+    }
+
+    /// Wait queue and channel on which arrival of datagrams is notified
+    pub fn wait_queue(&self) -> (&WaitQueue, usize) {
+        (&self.readers, self.channel())
+    }
+}
+
+impl Drop for UdpSocket {
+    /// Release local port
+    fn drop(&mut self) {
+        if let Some(port) = unsafe { self.state.get() }.port {
+            PORTS.lock().retain(|&(x, _)| x != port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::LOCAL_ADDR;
+
+    /// Test binding ports and releasing them on drop
+    #[test_case]
+    fn test_bind() {
+        let a = UdpSocket::new();
+        assert_eq!(a.bind(7000), Ok(7000));
+        assert_eq!(a.bind(7001), Err(NetError::Invalid));
+        let b = UdpSocket::new();
+        assert_eq!(b.bind(7000), Err(NetError::AddrInUse));
+        let port = b.bind(0).unwrap();
+        assert!(port >= EPHEMERAL_START);
+        drop(a);
+        let c = UdpSocket::new();
+        assert_eq!(c.bind(7000), Ok(7000));
+    }
+
+    /// Test datagrams sent to this host, and filtering by connected peer
+    #[test_case]
+    fn test_loopback() {
+        let a = UdpSocket::new();
+        let b = UdpSocket::new();
+        let pa = a.bind(0).unwrap();
+        let pb = b.bind(0).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(b.recv_from(&mut buf, true), Err(NetError::WouldBlock));
+        assert_eq!(b.poll(), POLLOUT);
+        let lo = Ipv4Addr::new(127, 0, 0, 1);
+        assert_eq!(a.send_to(b"hello", Some(SocketAddr::new(lo, pb))), Ok(5));
+        assert_eq!(b.poll(), POLLIN | POLLOUT);
+        assert_eq!(b.recv_from(&mut buf, true), Ok((5, SocketAddr::new(lo, pa))));
+        assert_eq!(&buf[..5], b"hello");
+
+        let dst = SocketAddr::new(LOCAL_ADDR, pb);
+        b.connect(SocketAddr::new(LOCAL_ADDR, pa));
+        let c = UdpSocket::new();
+        c.send_to(b"dropped", Some(dst)).unwrap();
+        a.send_to(b"hi", Some(dst)).unwrap();
+        assert_eq!(b.recv_from(&mut buf, true), Ok((2, SocketAddr::new(LOCAL_ADDR, pa))));
+        assert_eq!(b.recv_from(&mut buf, true), Err(NetError::WouldBlock));
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Power management
+//!
+//! Machine is powered off or reset with SBI system reset extension under
+//! SBI, or with sifive_test device found in device tree. QEMU exits on
+//! the latter with a pass or fail code, which ends automated test runs.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::fdt::machine;
+use crate::arch;
+use crate::info;
+
+/// `reboot` commands, same as Linux
+pub const REBOOT_CMD_HALT: u32 = 0xCDEF0123;
+pub const REBOOT_CMD_POWER_OFF: u32 = 0x4321FEDC;
+pub const REBOOT_CMD_RESTART: u32 = 0x01234567;
+
+/// Values written to sifive_test device
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Exit code of QEMU when kernel fails, such as on panic
+pub const EXIT_FAILURE: u32 = 1;
+
+/// What to do on shutdown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// stop all harts
+    Halt,
+    /// power off, making QEMU exit with code
+    PowerOff(u32),
+    /// reset machine
+    Restart,
+}
+
+/// Set when system is shutting down. Harts stop scheduling on seeing it.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Check if system is shutting down
+pub fn halting() -> bool {
+    HALTING.load(Ordering::Relaxed)
+}
+
+/// No hart has stopped others
+const NO_HART: usize = usize::MAX;
+
+/// Hart which has stopped all other harts, such as on panic
+static STOPPED_BY: AtomicUsize = AtomicUsize::new(NO_HART);
+
+/// Bit mask of harts stopped by `stop_others`
+static STOPPED_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How long `stop_others` waits for other harts
+const STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Stop all other harts with an IPI, so that only current hart runs from
+/// now on. Returns the hart which has already done so, if any.
+///
+/// Harts with interrupt disabled stop only after enabling it, and are
+/// waited for no more than `STOP_TIMEOUT`.
+pub fn stop_others() -> Result<(), usize> {
+    let hart = arch::hart_id();
+    if let Err(by) = STOPPED_BY.compare_exchange(NO_HART, hart, Ordering::SeqCst, Ordering::SeqCst) {
+        return Err(by);
+    }
+    let others = crate::process::ONLINE_HARTS.load(Ordering::SeqCst) & !(1 << hart);
+    crate::intr::send_ipi(others);
+    let deadline = arch::time() + STOP_TIMEOUT;
+    while STOPPED_HARTS.load(Ordering::SeqCst) & others != others && arch::time() < deadline {}
+    Ok(())
+}
+
+/// Stop current hart if another hart has called `stop_others`. Called on
+/// software interrupt.
+pub fn check_stopped() {
+    let hart = arch::hart_id();
+    let by = STOPPED_BY.load(Ordering::SeqCst);
+    if by != NO_HART && by != hart {
+        STOPPED_HARTS.fetch_or(1 << hart, Ordering::SeqCst);
+        halt();
+    }
+}
+
+/// Write `value` to sifive_test device, if there is one
+fn finisher(value: u32) {
+    let base = machine().test.base;
+    if base != 0 {
+        unsafe { (base as *mut u32).write_volatile(value); }
+    }
+}
+
+/// Power off, making QEMU exit with `code`. Returns if there's no way
+/// to do so.
+fn power_off(code: u32) {
+    #[cfg(feature = "sbi")]
+    {
+        if code == 0 {
+            crate::sbi::system_reset(crate::sbi::RESET_TYPE_SHUTDOWN, crate::sbi::RESET_REASON_NONE);
+        }
+    }
+    if code == 0 {
+        finisher(FINISHER_PASS);
+    } else {
+        // exit code can only be passed with sifive_test device
+        finisher(FINISHER_FAIL | (code & 0xffff) << 16);
+        #[cfg(feature = "sbi")]
+        crate::sbi::system_reset(crate::sbi::RESET_TYPE_SHUTDOWN, crate::sbi::RESET_REASON_FAILURE);
+    }
+}
+
+/// Reset machine. Returns if there's no way to do so.
+fn restart() {
+    #[cfg(feature = "sbi")]
+    crate::sbi::system_reset(crate::sbi::RESET_TYPE_COLD_REBOOT, crate::sbi::RESET_REASON_NONE);
+    finisher(FINISHER_RESET);
+}
+
+/// Stop current hart
+pub fn halt() -> ! {
+    arch::intr_off();
+    arch::wait_forever()
+}
+
+/// Power off immediately with `code`, without writing back disks.
+/// Used when kernel can't proceed, such as on panic.
+pub fn exit(code: u32) -> ! {
+    HALTING.store(true, Ordering::Relaxed);
+    power_off(code);
+    halt()
+}
+
+/// Write back disks, stop scheduling on all harts and then halt, power
+/// off or restart by `action`. Should be called by a process.
+pub fn shutdown(action: Action) -> ! {
+    info!("syncing disks...");
+    // waiting for disk puts current process into sleep, so harts should
+    // keep scheduling until it's done
+    crate::virtio::blk::flush_all();
+    if HALTING.swap(true, Ordering::Relaxed) {
+        // another hart is shutting down
+        halt();
+    }
+    match action {
+        Action::Halt => info!("system halted"),
+        Action::PowerOff(code) => {
+            info!("power off");
+            power_off(code);
+            info!("power off not supported, system halted");
+        }
+        Action::Restart => {
+            info!("restarting");
+            restart();
+            info!("restart not supported, system halted");
+        }
+    }
+    halt()
+}
+
+/// Action of `reboot` command `cmd`
+pub fn action_of(cmd: u32) -> Option<Action> {
+    match cmd {
+        REBOOT_CMD_HALT => Some(Action::Halt),
+        REBOOT_CMD_POWER_OFF => Some(Action::PowerOff(0)),
+        REBOOT_CMD_RESTART => Some(Action::Restart),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test `reboot` commands, without shutting down
+    #[test_case]
+    fn test_command() {
+        assert_eq!(action_of(REBOOT_CMD_POWER_OFF), Some(Action::PowerOff(0)));
+        assert_eq!(action_of(REBOOT_CMD_HALT), Some(Action::Halt));
+        assert_eq!(action_of(REBOOT_CMD_RESTART), Some(Action::Restart));
+        assert_eq!(action_of(0), None);
+        assert!(!halting());
+    }
+}
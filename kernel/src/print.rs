@@ -10,10 +10,38 @@ use crate::spinlock::Mutex;
 
 pub static INFO_LOCK: Mutex<()> = Mutex::new((), "info");
 
+/// Firmware console, used under SBI if there's no UART in device tree
+#[cfg(feature = "sbi")]
+struct SbiConsole;
+
+#[cfg(feature = "sbi")]
+impl fmt::Write for SbiConsole {
+	fn write_str(&mut self, out: &str) -> fmt::Result {
+		for c in out.bytes() {
+			crate::sbi::console_putchar(c);
+		}
+		Ok(())
+	}
+}
+
+/// Check if output should go to firmware console
+#[cfg(feature = "sbi")]
+fn use_sbi_console() -> bool {
+	crate::fdt::machine().uart.base == 0
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
 	use core::fmt::Write;
+	// lock is also held for firmware console, so that lines are not mixed
 	let mut uart = crate::uart::UART().lock();
+	#[cfg(feature = "sbi")]
+	{
+		if use_sbi_console() {
+			SbiConsole.write_fmt(args).unwrap();
+			return;
+		}
+	}
 	uart.write_fmt(args).unwrap();
 }
 
@@ -21,8 +49,16 @@ pub fn _print(args: fmt::Arguments) {
 pub fn _panic_print(args: fmt::Arguments) {
     use core::fmt::Write;
     use crate::uart::*;
-	let mut uart = Uart::new(UART_BASE_ADDR);
-	uart.write_fmt(args).unwrap();
+	#[cfg(feature = "sbi")]
+	{
+		if use_sbi_console() {
+			SbiConsole.write_fmt(args).ok();
+			return;
+		}
+	}
+	// errors are ignored, as panicking here would recurse
+	let mut uart = Uart::new(crate::fdt::machine().uart.base);
+	uart.write_fmt(args).ok();
 }
 
 /// Print information
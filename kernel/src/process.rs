@@ -20,17 +20,48 @@ mod context;
 
 pub use context::*;
 
-mod schedule;
+pub mod schedule;
 
 pub use schedule::*;
 
+pub mod vma;
+
+pub use vma::*;
+
+mod mmap;
+
+pub use mmap::*;
+
+mod policy;
+
+pub use policy::*;
+
+mod waitqueue;
+
+pub use waitqueue::*;
+
+pub mod thread;
+
+pub use thread::*;
+
+pub mod wait;
+
+pub use wait::*;
+
+mod futex;
+
+pub use futex::*;
+
 use crate::symbols::*;
 use crate::spinlock::Mutex;
 use crate::arch;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicBool;
 use core::mem::MaybeUninit;
 use core::borrow::BorrowMut;
 use crate::arch::hart_id;
+use crate::page::{Page, Table, EntryAttributes, KERNEL_PGTABLE};
 
 /// An array holding all CPU information
 static mut CPUS: [CPU; NCPUS] = [CPU::zero(); NCPUS];
@@ -39,40 +70,57 @@ static mut CPUS: [CPU; NCPUS] = [CPU::zero(); NCPUS];
 ///
 /// `NoProc`: No process associated with this pid
 ///
-/// `Scheduled`: This process is scheduled on one CPU
+/// `Scheduled`: This process is scheduled on one CPU, or this pid is
+/// reserved for a process being created
 /// 
 /// `Pooling`: This process is not being scheduled
 ///
-/// `BeingSlept`: This process holds a sleep lock and is to be put back
+/// `BeingSlept`: This process is going to sleep and is to be put back.
+/// If it is woken up before put back, this slot is turned back into
+/// `Scheduled`, so that the process will be made runnable on put back.
+/// Exit flag of its thread group is kept, so that it is woken up when
+/// the group is killed.
 pub enum ProcInPool {
     NoProc,
     Scheduled,
     Pooling(Box<Process>),
-    BeingSlept,
+    BeingSlept(Arc<AtomicBool>),
 }
 
-/// An array holding all process information.
+const EMPTY_SLOT: Mutex<ProcInPool> = Mutex::new(ProcInPool::NoProc, "proc slot");
+
+/// An array holding all process information. Each slot is locked separately.
 /// 
 /// # Examples
 ///
 /// ```
-/// let pool = PROCS_POOL.lock();
-/// let p = &mut pool[0];
-/// if p.0 == false {
-///     println!("This pid is not occupied by any process.");
-/// }
-/// if p.0 == true {
-///     if p.1.is_none() {
-///         println!("The process is running on one CPU.");
-///     } else {
-///         println!("The process is to be scheduled.");
-///         assert_eq!(p.1.pid, 0);  // process of PID x is stored at PROCS_POOL[x]
+/// let slot = PROCS_POOL[0].lock();
+/// match &*slot {
+///     ProcInPool::NoProc => println!("This pid is not occupied by any process."),
+///     ProcInPool::Scheduled => println!("The process is running on one CPU."),
+///     ProcInPool::Pooling(p) => {
+///         println!("The process is not running.");
+///         assert_eq!(p.pid, 0);  // process of PID x is stored at PROCS_POOL[x]
 ///     }
+///     ProcInPool::BeingSlept(_) => println!("The process is going to sleep."),
 /// }
 /// ```
-pub static PROCS_POOL: Mutex<[ProcInPool; NMAXPROCS]> = Mutex::new([ProcInPool::NoProc; NMAXPROCS], "proc pool");
+pub static PROCS_POOL: [Mutex<ProcInPool>; NMAXPROCS] = [EMPTY_SLOT; NMAXPROCS];
 
-pub unsafe fn init() {}
+/// Map kernel stacks of all process slots into kernel page table.
+///
+/// This function should only be called in boot hart, after kernel page
+/// table is enabled and before other harts boot.
+pub unsafe fn init() {
+    let pgtable: &mut Table = &mut *(&KERNEL_PGTABLE as *const _ as *mut _); // to bypass mut ref
+    for id in 0..NMAXPROCS {
+        for i in 0..KSTACK_PAGE {
+            let page = Box::into_raw(Page::new()) as usize;
+            pgtable.kernel_map(KSTACK(id) + i * PAGE_SIZE, page, EntryAttributes::RW as usize);
+        }
+    }
+    riscv::asm::sfence_vma(0, 0);
+}
 
 /// Get CPU object of current hart.
 pub fn my_cpu() -> &'static mut CPU {
@@ -124,11 +172,10 @@ pub fn debug() {
             _ => {}
         }
     }
-    let pool = unsafe { PROCS_POOL.get() };
     for i in 0..NMAXPROCS {
-        match &pool[i] {
+        match unsafe { PROCS_POOL[i].get() } {
             ProcInPool::Pooling(x) => { println!("{} pooling with state {:?}", x.pid, x.state); }
-            ProcInPool::BeingSlept => { println!("{} being slept", i); }
+            ProcInPool::BeingSlept(_) => { println!("{} being slept", i); }
             _ => {}
         }
     }
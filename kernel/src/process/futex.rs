@@ -0,0 +1,93 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Fast user-space mutex
+//!
+//! A futex is a 32-bit word in user memory. Futexes are keyed on physical
+//! address, so that threads and processes sharing a page may wait on the
+//! same futex through different virtual addresses.
+
+use core::time::Duration;
+use crate::spinlock::Mutex;
+use crate::arch;
+use super::{my_proc, PageFault, WaitQueue};
+
+/// Futex operation error
+#[derive(Debug, PartialEq)]
+pub enum FutexError {
+    /// address is not aligned or not accessible
+    Invalid,
+    /// value at address is not the expected one
+    Again,
+    /// timeout before woken up
+    TimedOut,
+}
+
+/// Futexes hashed into the same bucket share a lock and a wait queue
+struct FutexBucket {
+    lock: Mutex<()>,
+    queue: WaitQueue,
+}
+
+/// Number of futex buckets
+pub const NFUTEX_BUCKET: usize = 64;
+
+const EMPTY_BUCKET: FutexBucket = FutexBucket {
+    lock: Mutex::new((), "futex"),
+    queue: WaitQueue::new(),
+};
+
+static FUTEX_BUCKETS: [FutexBucket; NFUTEX_BUCKET] = [EMPTY_BUCKET; NFUTEX_BUCKET];
+
+fn bucket_of(paddr: usize) -> &'static FutexBucket {
+    &FUTEX_BUCKETS[(paddr >> 2) % NFUTEX_BUCKET]
+}
+
+/// Physical address of futex at user address `addr`
+fn futex_paddr(addr: usize) -> Result<usize, FutexError> {
+    if addr % 4 != 0 {
+        return Err(FutexError::Invalid);
+    }
+    my_proc().mm.acquire().user_paddr(addr, PageFault::Load).ok_or(FutexError::Invalid)
+}
+
+/// futex_wait syscall
+///
+/// Sleep if futex at `addr` contains `val`, until woken up by `futex_wake`
+/// or `timeout` has passed.
+pub fn futex_wait(addr: usize, val: u32, timeout: Option<Duration>) -> Result<(), FutexError> {
+    let deadline = timeout.map(|t| arch::time() + t);
+    let paddr = futex_paddr(addr)?;
+    let bucket = bucket_of(paddr);
+    let lock = bucket.lock.lock();
+    // value is checked with bucket locked, so that a wakeup after
+    // value is changed won't be lost
+    if unsafe { (paddr as *const u32).read_volatile() } != val {
+        return Err(FutexError::Again);
+    }
+    match deadline {
+        Some(deadline) => {
+            let (_lock, timed_out) = bucket.queue.sleep_timeout(paddr, lock, deadline);
+            if timed_out {
+                return Err(FutexError::TimedOut);
+            }
+        }
+        None => {
+            bucket.queue.sleep(paddr, lock);
+        }
+    }
+    Ok(())
+}
+
+/// futex_wake syscall
+///
+/// Wake up at most `n` processes waiting on futex at `addr`.
+/// Returns number of processes woken up.
+pub fn futex_wake(addr: usize, n: usize) -> Result<usize, FutexError> {
+    let paddr = futex_paddr(addr)?;
+    let bucket = bucket_of(paddr);
+    let _lock = bucket.lock.lock();
+    Ok(bucket.queue.wakeup_n(paddr, n))
+}
@@ -0,0 +1,241 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Memory mapping of anonymous memory and files
+
+use alloc::sync::Arc;
+use super::{AddressSpace, Vma, VmaKind, VmaFile, USER_STACK_LIMIT, my_proc};
+use crate::symbols::*;
+use crate::mem::page_down;
+use crate::page::{Page, EntryAttributes};
+use crate::file::{File, SharedMemory};
+
+/// Pages may be read
+pub const PROT_READ: usize = 0x1;
+/// Pages may be written
+pub const PROT_WRITE: usize = 0x2;
+/// Pages may be executed
+pub const PROT_EXEC: usize = 0x4;
+
+/// Changes are written back to file
+pub const MAP_SHARED: usize = 0x01;
+/// Changes are private to this process
+pub const MAP_PRIVATE: usize = 0x02;
+/// Place mapping exactly at `addr`
+pub const MAP_FIXED: usize = 0x10;
+/// Mapping is not backed by any file
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// Why a mapping can't be created
+#[derive(Debug, PartialEq)]
+pub enum MmapError {
+    /// arguments are not aligned, or file can't be mapped
+    Invalid,
+    /// file is not writable, but a writable shared mapping is requested
+    Access,
+    /// no room in address space
+    NoMemory,
+}
+
+/// Page table entry flags from `prot`
+fn prot_flags(prot: usize) -> usize {
+    let mut flags = EntryAttributes::U as usize;
+    // a writable page must also be readable on RISC-V
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        flags |= EntryAttributes::R as usize;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= EntryAttributes::W as usize;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags |= EntryAttributes::X as usize;
+    }
+    flags
+}
+
+/// Fill a newly allocated page at `vaddr` from backing file of `vma`
+pub fn fill_page(vma: &Vma, vaddr: usize, page: &mut Page) {
+    if let (Some(f), Some(offset)) = (&vma.file, vma.file_offset(vaddr)) {
+        match &*f.file {
+            File::FsFile(file) => { file.read_at(offset, &mut page.data); }
+            File::Shm(shm) => {
+                if let Some(pg) = shm.page_of(offset) {
+                    page.data.copy_from_slice(&pg.data);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Check if changes to pages of `vma` are shared. Such pages are mapped
+/// as shared pages, so that forked processes map the same pages.
+pub fn is_shared(vma: &Vma) -> bool {
+    vma.file.as_ref().map_or(false, |f| f.shared)
+}
+
+/// Physical address of page at `vaddr` in a shared memory mapping, which
+/// should be mapped instead of a newly allocated page
+pub fn shared_paddr(vma: &Vma, vaddr: usize) -> Option<usize> {
+    let f = vma.file.as_ref()?;
+    match (f.shared, &*f.file) {
+        (true, File::Shm(shm)) => shm.paddr_of(vma.file_offset(vaddr)?),
+        _ => None
+    }
+}
+
+impl AddressSpace {
+    /// Unmap pages in `vma`. Dirty pages of shared file mapping are written back.
+    fn release_vma(&mut self, vma: &Vma) {
+        let mut vaddr = vma.start;
+        while vaddr < vma.end {
+            let dirty = match self.pgtable.entry_of(vaddr) {
+                Some(entry) => entry.is_d(),
+                None => false
+            };
+            if let Some(page) = self.pgtable.unmap(vaddr) {
+                if let (true, Some(f)) = (dirty, &vma.file) {
+                    if let (true, File::FsFile(file)) = (f.shared, &*f.file) {
+                        file.write_at(vma.file_offset(vaddr).unwrap(), &page.data);
+                    }
+                }
+            }
+            vaddr += PAGE_SIZE;
+        }
+    }
+
+    /// Remove all memory mappings in `[start, end)`
+    pub fn unmap_range(&mut self, start: usize, end: usize) {
+        for vma in self.vmas.remove_mmap(start, end) {
+            self.release_vma(&vma);
+        }
+    }
+}
+
+/// mmap syscall
+///
+/// Map `len` bytes at `addr` (a hint unless `MAP_FIXED` is set) from `file`
+/// at `offset`, or anonymous memory if `file` is `None`. Pages are allocated
+/// on first access. Shared anonymous memory is backed by a new shared memory
+/// object, so that it is shared with children after fork.
+/// Returns start address of mapping.
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize, file: Option<Arc<File>>, offset: usize) -> Result<usize, MmapError> {
+    if len == 0 || addr % PAGE_SIZE != 0 || offset % PAGE_SIZE != 0 {
+        return Err(MmapError::Invalid);
+    }
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(MmapError::Invalid)
+    };
+    // rounded up to pages, and no larger than user address space
+    let len = match len.checked_add(PAGE_SIZE - 1) {
+        Some(end) if end < USER_STACK_LIMIT => page_down(end),
+        _ => return Err(MmapError::NoMemory)
+    };
+    let file = match (flags & MAP_ANONYMOUS != 0, file) {
+        (true, _) => {
+            if shared {
+                let file = Arc::new(File::Shm(SharedMemory::new(len)));
+                Some(VmaFile { file, offset: 0, shared })
+            } else {
+                None
+            }
+        }
+        (false, Some(file)) => match &*file {
+            // changes would be silently dropped on write back
+            File::FsFile(f) if shared && prot & PROT_WRITE != 0 && !f.writable() => {
+                return Err(MmapError::Access);
+            }
+            File::FsFile(_) => Some(VmaFile { file, offset, shared }),
+            // mapping may not go beyond end of shared memory object
+            File::Shm(shm) if offset.checked_add(len).map_or(false, |end| end <= shm.size()) => {
+                Some(VmaFile { file, offset, shared })
+            }
+            _ => return Err(MmapError::Invalid)
+        }
+        (false, None) => return Err(MmapError::Invalid)
+    };
+    let mut mm = my_proc().mm.acquire();
+    let is_free = |start: usize| {
+        start.checked_add(len).map_or(false, |end| end <= USER_STACK_LIMIT - PAGE_SIZE)
+            && !mm.vmas.vmas.iter().any(|x| x.overlaps(start, start + len))
+    };
+    let start = if addr != 0 && is_free(addr) {
+        addr
+    } else if flags & MAP_FIXED != 0 {
+        return Err(MmapError::NoMemory);
+    } else {
+        mm.vmas.find_free(len).ok_or(MmapError::NoMemory)?
+    };
+    let mut vma = Vma::new(start, start + len, prot_flags(prot), VmaKind::Mmap);
+    vma.file = file;
+    mm.vmas.push(vma);
+    Ok(start)
+}
+
+/// munmap syscall
+///
+/// Remove mappings in `[addr, addr + len)`. Dirty pages of shared file
+/// mappings are written back to file.
+pub fn munmap(addr: usize, len: usize) -> Option<()> {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return None;
+    }
+    let len = page_down(len.checked_add(PAGE_SIZE - 1)?);
+    let end = addr.checked_add(len)?;
+    my_proc().mm.acquire().unmap_range(addr, end);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FsFile;
+    use crate::file::fsfile::{O_RDONLY, O_RDWR};
+    use crate::process::PageFault;
+
+    fn open(mode: usize) -> Arc<File> {
+        Arc::new(File::FsFile(FsFile::open("/test.txt", mode)))
+    }
+
+    /// Test writable shared mapping of read-only file is rejected
+    #[test_case]
+    fn test_mmap_readonly() {
+        assert_eq!(mmap(0, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED, Some(open(O_RDONLY)), 0), Err(MmapError::Access));
+        let addr = mmap(0, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE, Some(open(O_RDONLY)), 0).unwrap();
+        munmap(addr, PAGE_SIZE).unwrap();
+        let addr = mmap(0, PAGE_SIZE, PROT_READ, MAP_SHARED, Some(open(O_RDONLY)), 0).unwrap();
+        munmap(addr, PAGE_SIZE).unwrap();
+    }
+
+    /// Test lengths which overflow when rounded up to pages are rejected
+    #[test_case]
+    fn test_mmap_overflow() {
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+        assert_eq!(mmap(0, usize::MAX, PROT_READ, anon, None, 0), Err(MmapError::NoMemory));
+        assert_eq!(mmap(0, usize::MAX, PROT_READ, MAP_SHARED | MAP_ANONYMOUS, None, 0), Err(MmapError::NoMemory));
+        assert_eq!(munmap(PAGE_SIZE, usize::MAX), None);
+        assert_eq!(munmap(PAGE_SIZE, usize::MAX - PAGE_SIZE), None);
+    }
+
+    /// Test pages of shared file mapping are shared with forked address
+    /// space, while pages of private mapping are copied
+    #[test_case]
+    fn test_fork_shared_file() {
+        let mut mm = AddressSpace::new();
+        let (shared, private) = (0x1000_0000, 0x1000_0000 + PAGE_SIZE);
+        for &(start, is_shared) in &[(shared, true), (private, false)] {
+            let mut vma = Vma::new(start, start + PAGE_SIZE, prot_flags(PROT_READ | PROT_WRITE), VmaKind::Mmap);
+            vma.file = Some(VmaFile { file: open(O_RDWR), offset: 0, shared: is_shared });
+            mm.vmas.push(vma);
+        }
+        let shared_paddr = mm.user_paddr(shared, PageFault::Load).unwrap();
+        let private_paddr = mm.user_paddr(private, PageFault::Load).unwrap();
+        let forked = mm.fork();
+        assert_eq!(forked.pgtable.paddr_of(shared), Some(shared_paddr));
+        assert_ne!(forked.pgtable.paddr_of(private), Some(private_paddr));
+    }
+}
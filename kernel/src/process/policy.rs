@@ -0,0 +1,254 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Scheduling policies
+//!
+//! A policy is selected at build time with one of the cargo features
+//! `sched-rr`, `sched-mlfq` and `sched-cfs`. Round-robin is used if
+//! none of them is enabled.
+
+use core::time::Duration;
+use crate::symbols::*;
+use crate::arch;
+use super::{Process, ProcessState};
+
+/// Lowest nice value (highest priority)
+pub const NICE_MIN: i32 = -20;
+/// Highest nice value (lowest priority)
+pub const NICE_MAX: i32 = 19;
+
+/// Scheduling information of a process
+#[derive(Clone)]
+pub struct SchedInfo {
+    /// nice value in `NICE_MIN..=NICE_MAX`
+    pub nice: i32,
+    /// queue level in multilevel feedback queue
+    pub level: usize,
+    /// weighted running time in nanoseconds, used by CFS
+    pub vruntime: u64,
+    /// total time running on CPU
+    pub cpu_time: Duration,
+    /// when this process is last scheduled
+    pub last_run: Duration,
+    /// boost epoch in which `level` is set, used by MLFQ
+    pub epoch: u64,
+    /// bit mask of harts this process may run on
+    pub affinity: usize,
+}
+
+impl SchedInfo {
+    pub const fn new() -> Self {
+        Self {
+            nice: 0,
+            level: 0,
+            vruntime: 0,
+            cpu_time: Duration::from_secs(0),
+            last_run: Duration::from_secs(0),
+            epoch: 0,
+            affinity: !0,
+        }
+    }
+
+    /// Scheduling information of a forked process. CPU time is not inherited.
+    pub fn fork(&self) -> Self {
+        Self {
+            cpu_time: Duration::from_secs(0),
+            ..self.clone()
+        }
+    }
+}
+
+/// A scheduling policy.
+///
+/// Each hart has a run queue with its own policy object. A policy decides
+/// the order of processes in the queue by giving each of them a key when
+/// it is enqueued. Process with the least key runs first, and those with
+/// equal keys take turns to run.
+pub trait Policy: Send {
+    /// Key of runnable process `p` which is to be enqueued
+    fn key(&mut self, p: &mut Process) -> u64;
+
+    /// Adjust keys of queued `entries` before a process is picked
+    fn tick(&mut self, _entries: &mut [RunEntry]) {}
+
+    /// Notify that `entry` is picked to run
+    fn picked(&mut self, _entry: &RunEntry) {}
+
+    /// Account process `p` which has just run for `ran` before it is put back.
+    ///
+    /// If `p` is still runnable, it has used up its time slice.
+    fn put_back(&mut self, p: &mut Process, ran: Duration);
+}
+
+/// A runnable process in run queue
+#[derive(Clone)]
+pub struct RunEntry {
+    pub pid: usize,
+    /// ordering key given by policy
+    pub key: u64,
+    /// enqueue sequence number, to break ties in FIFO order
+    pub seq: u64,
+    pub nice: i32,
+    /// harts this process may run on
+    pub affinity: usize,
+}
+
+/// Round-robin among runnable processes of highest priority.
+///
+/// A queued process ages by one nice level each time another process is
+/// picked, so that a process of low priority waits at most
+/// `NICE_MAX - NICE_MIN` picks before it runs, and won't starve.
+pub struct RoundRobin;
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+impl Policy for RoundRobin {
+    fn key(&mut self, p: &mut Process) -> u64 {
+        (p.sched.nice - NICE_MIN) as u64
+    }
+
+    fn tick(&mut self, entries: &mut [RunEntry]) {
+        for entry in entries.iter_mut() {
+            entry.key = entry.key.saturating_sub(1);
+        }
+    }
+
+    fn put_back(&mut self, _p: &mut Process, _ran: Duration) {}
+}
+
+/// Number of queues in multilevel feedback queue
+pub const MLFQ_LEVELS: usize = 4;
+
+/// Interval of moving all processes back to their top queue
+pub const MLFQ_BOOST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Multilevel feedback queue.
+///
+/// A process starts at the queue given by its nice value, and is moved
+/// one queue down each time it uses up its time slice. All processes
+/// are periodically boosted back, so that they won't starve.
+///
+/// Boosts happen at the same time on all harts. Each boost period is
+/// an epoch, and a process is moved back to its top queue the first time
+/// it is enqueued in a new epoch.
+pub struct Mlfq {
+    epoch: u64,
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        Self { epoch: 0 }
+    }
+
+    /// Top queue of a process with `nice`
+    fn base_level(nice: i32) -> usize {
+        (nice - NICE_MIN) as usize * MLFQ_LEVELS / (NICE_MAX - NICE_MIN + 1) as usize
+    }
+
+    /// Current boost epoch
+    fn epoch() -> u64 {
+        (arch::time().as_nanos() / MLFQ_BOOST_INTERVAL.as_nanos()) as u64
+    }
+}
+
+impl Policy for Mlfq {
+    fn key(&mut self, p: &mut Process) -> u64 {
+        let epoch = Self::epoch();
+        if p.sched.epoch != epoch {
+            p.sched.epoch = epoch;
+            p.sched.level = Self::base_level(p.sched.nice);
+        }
+        // nice value may have been raised since last run
+        p.sched.level = p.sched.level.max(Self::base_level(p.sched.nice));
+        p.sched.level as u64
+    }
+
+    fn tick(&mut self, entries: &mut [RunEntry]) {
+        let epoch = Self::epoch();
+        if self.epoch != epoch {
+            self.epoch = epoch;
+            for entry in entries.iter_mut() {
+                entry.key = Self::base_level(entry.nice) as u64;
+            }
+        }
+    }
+
+    fn put_back(&mut self, p: &mut Process, _ran: Duration) {
+        if p.state == ProcessState::RUNNABLE && p.sched.level + 1 < MLFQ_LEVELS {
+            p.sched.level += 1;
+        }
+    }
+}
+
+/// Weight of each nice value, from Linux `sched_prio_to_weight`
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+/// Weight of nice value 0
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// A woken-up process may lag at most this much behind (in nanoseconds)
+pub const CFS_LATENCY: u64 = 20_000_000;
+
+/// Completely fair scheduler, which always runs the process with
+/// the least virtual runtime. Virtual runtime grows slower for
+/// processes of higher priority.
+pub struct Cfs {
+    min_vruntime: u64,
+}
+
+impl Cfs {
+    pub const fn new() -> Self {
+        Self { min_vruntime: 0 }
+    }
+}
+
+impl Policy for Cfs {
+    fn key(&mut self, p: &mut Process) -> u64 {
+        // don't let a long-sleeping or migrated process monopolize CPU
+        let floor = self.min_vruntime.saturating_sub(CFS_LATENCY);
+        if p.sched.vruntime < floor {
+            p.sched.vruntime = floor;
+        }
+        p.sched.vruntime
+    }
+
+    fn picked(&mut self, entry: &RunEntry) {
+        self.min_vruntime = self.min_vruntime.max(entry.key);
+    }
+
+    fn put_back(&mut self, p: &mut Process, ran: Duration) {
+        let weight = NICE_TO_WEIGHT[(p.sched.nice - NICE_MIN) as usize];
+        p.sched.vruntime += ran.as_nanos() as u64 * NICE_0_WEIGHT / weight;
+    }
+}
+
+#[cfg(any(
+    all(feature = "sched-rr", feature = "sched-mlfq"),
+    all(feature = "sched-rr", feature = "sched-cfs"),
+    all(feature = "sched-mlfq", feature = "sched-cfs"),
+))]
+compile_error!("only one scheduling policy may be selected");
+
+#[cfg(feature = "sched-mlfq")]
+pub type SchedPolicy = Mlfq;
+
+#[cfg(feature = "sched-cfs")]
+pub type SchedPolicy = Cfs;
+
+#[cfg(not(any(feature = "sched-mlfq", feature = "sched-cfs")))]
+pub type SchedPolicy = RoundRobin;
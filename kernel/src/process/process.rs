@@ -3,21 +3,27 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use super::{TrapFrame, Context, Register, ContextRegisters};
+use super::{TrapFrame, Context, Register, ContextRegisters, VmaList, Vma, VmaKind, fill_page, shared_paddr, is_shared};
+use super::{SchedInfo, NICE_MIN, NICE_MAX};
+use core::time::Duration;
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::{page, panic, info, warn};
 use crate::symbols::*;
-use crate::mem;
+use crate::mem::{self, page_down};
 use crate::arch;
 use crate::println;
 use crate::trap::usertrapret;
 use alloc::boxed::Box;
-use crate::process::{put_back_proc, my_proc, PROCS_POOL, my_cpu, sched, ProcInPool};
+use crate::process::{yield_cpu, put_back_proc, my_proc, PROCS_POOL, my_cpu, sched, ProcInPool, IntrLockGuard, ONLINE_HARTS};
+use crate::process::{kill_siblings, add_child, set_exit_status, renew_group};
 use crate::page::{Page, Table, EntryAttributes};
 use crate::process::Register::a0;
 use crate::jump::*;
 use crate::spinlock::{Mutex, MutexGuard};
+use crate::sleeplock::SleepLock;
 use alloc::sync::Arc;
 use crate::file::{File, FsFile};
+use crate::power::Action;
 
 #[derive(PartialEq)]
 #[derive(Debug)]
@@ -29,70 +35,221 @@ pub enum ProcessState {
     ZOMBIE,
 }
 
+/// Open files of a process, indexed by file descriptor
+pub type FileTable = [Option<Arc<File>>; 256];
+
+/// User address space, shared by all threads of a process
+pub struct AddressSpace {
+    pub pgtable: Box<page::Table>,
+    pub vmas: VmaList,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        Self::from_exist(box page::Table::new(), VmaList::new())
+    }
+
+    pub fn from_exist(pgtable: Box<Table>, vmas: VmaList) -> Self {
+        let mut mm = Self { pgtable, vmas };
+        // map trampoline
+        mm.pgtable.kernel_map(
+            TRAMPOLINE_START,
+            TRAMPOLINE_TEXT_START(),
+            page::EntryAttributes::RX as usize,
+        );
+        mm
+    }
+
+    /// Copy of this address space for a forked process. Trapframes are not copied.
+    pub fn fork(&self) -> Self {
+        Self::from_exist(self.pgtable.clone_with(&mut page::share), self.vmas.clone())
+    }
+
+    /// Map `trapframe` of process slot `pid`
+    pub fn map_trapframe(&mut self, pid: i32, trapframe: &TrapFrame) {
+        self.pgtable.kernel_map(
+            TRAPFRAME(pid as usize),
+            trapframe as *const _ as usize,
+            page::EntryAttributes::RW as usize,
+        );
+    }
+
+    /// Handle page fault at `vaddr`.
+    ///
+    /// If `vaddr` is in one of the VMAs (growing stack if necessary) and
+    /// not yet mapped, a zeroed page will be allocated and mapped. Pages of
+    /// shared memory mappings are mapped from the shared memory object, and
+    /// pages of shared file mappings are mapped as shared pages.
+    /// Returns `false` if this access is invalid and process should be killed.
+    pub fn handle_page_fault(&mut self, vaddr: usize, fault: PageFault) -> bool {
+        if vaddr >= MAXVA {
+            return false;
+        }
+        if self.vmas.find(vaddr).is_none() && !self.vmas.grow_stack(vaddr) {
+            return false;
+        }
+        let vma = self.vmas.find(vaddr).unwrap();
+        if vma.flags & fault.required_flag() == 0 {
+            return false;
+        }
+        let page = page_down(vaddr);
+        if self.pgtable.paddr_of(page).is_some() {
+            // page is mapped, but access is not permitted
+            return false;
+        }
+        if let Some(paddr) = shared_paddr(vma, page) {
+            let flags = vma.flags;
+            page::share(paddr);
+            self.pgtable.map_shared(page, paddr, flags);
+            return true;
+        }
+        let mut pg = Page::new();
+        fill_page(vma, page, &mut pg);
+        let flags = vma.flags;
+        if is_shared(vma) {
+            self.pgtable.map_shared(page, Box::into_raw(pg) as usize, flags);
+        } else {
+            self.pgtable.map(page, pg, flags);
+        }
+        true
+    }
+
+    /// Translate user `vaddr` to physical address, allocating the page
+    /// if it is not yet mapped. Returns `None` if user may not access it
+    /// by `fault`.
+    pub fn user_paddr(&mut self, vaddr: usize, fault: PageFault) -> Option<usize> {
+        if vaddr >= MAXVA {
+            return None;
+        }
+        let page = page_down(vaddr);
+        if self.pgtable.paddr_of(page).is_none() && !self.handle_page_fault(vaddr, fault) {
+            return None;
+        }
+        // trampoline and trapframe are mapped, but not for user
+        let entry = self.pgtable.entry_of(page)?;
+        if !entry.is_u() || entry.flags() & fault.required_flag() == 0 {
+            return None;
+        }
+        self.pgtable.paddr_of(page).map(|paddr| paddr + vaddr - page)
+    }
+}
+
+/// A process, or a thread sharing address space and file table with
+/// other threads in the same thread group
 #[repr(C)]
 #[repr(align(4096))]
 pub struct Process {
-    pub pgtable: Box<page::Table>,
+    pub mm: Arc<SleepLock<AddressSpace>>,
     pub trapframe: Box<TrapFrame>,
     pub context: Box<Context>,
     pub state: ProcessState,
     pub kstack: usize,
     pub kstack_sp: usize,
     pub pid: i32,
+    /// pid of the first thread in thread group
+    pub tgid: i32,
+    /// set when thread group exits, is killed or calls exec, shared by threads in the group
+    pub group_exit: Arc<AtomicBool>,
     pub channel: usize,
-    pub drop_on_put_back: Option<MutexGuard<'static, ()>>,
-    pub files: [Option<Arc<File>>; 256],
+    pub drop_on_put_back: Option<IntrLockGuard<'static>>,
+    pub files: Arc<Mutex<FileTable>>,
+    pub sched: SchedInfo,
+    /// exit status, valid when process is a zombie
+    pub exit_status: i32,
+    /// entry function and argument of a kernel thread
+    pub kthread: Option<(fn(usize), usize)>,
 }
 
 impl Process {
+    /// Create a process with an empty address space
     pub fn new(pid: i32) -> Self {
-        Self::from_exist(pid, box page::Table::new(), box TrapFrame::zero())
+        let trapframe = box TrapFrame::zero();
+        let mut mm = AddressSpace::new();
+        mm.map_trapframe(pid, &trapframe);
+        Self::from_exist(
+            pid,
+            Arc::new(SleepLock::new(mm, "address space")),
+            trapframe,
+            Arc::new(Mutex::new([None; 256], "files")),
+        )
     }
 
-    pub fn from_exist(pid: i32, pgtable: Box<Table>, trapframe: Box<TrapFrame>) -> Self {
+    /// Create a process in existing address space `mm`, in which `trapframe`
+    /// should have been mapped.
+    pub fn from_exist(pid: i32, mm: Arc<SleepLock<AddressSpace>>, trapframe: Box<TrapFrame>, files: Arc<Mutex<FileTable>>) -> Self {
         if pid < 0 {
             panic!("invalid pid");
         }
 
-        let kstack = mem::ALLOC().lock().allocate(PAGE_SIZE * 1024) as usize;
+        // kernel stack of each process slot is mapped in `process::init`
+        let kstack = KSTACK(pid as usize);
 
         let mut p = Self {
+            mm,
             trapframe,
-            pgtable,
             context: box Context::zero(),
             state: ProcessState::UNUSED,
             kstack: kstack,
-            kstack_sp: kstack + PAGE_SIZE * 1024,
+            kstack_sp: kstack + KSTACK_PAGE * PAGE_SIZE,
             pid,
+            tgid: pid,
+            group_exit: Arc::new(AtomicBool::new(false)),
             channel: 0,
             drop_on_put_back: None,
-            files: [None; 256],
+            files,
+            sched: SchedInfo::new(),
+            exit_status: 0,
+            kthread: None,
         };
 
-        // map trampoline
-        p.pgtable.kernel_map(
-            TRAMPOLINE_START,
-            TRAMPOLINE_TEXT_START(),
-            page::EntryAttributes::RX as usize,
-        );
-
-        let trapframe = &*p.trapframe as *const _ as usize;
-        // map trapframe
-        p.pgtable.kernel_map(
-            TRAPFRAME_START,
-            trapframe,
-            page::EntryAttributes::RW as usize,
-        );
         p.context.regs[ContextRegisters::ra as usize] = forkret as usize;
-        p.context.regs[ContextRegisters::sp as usize] = p.kstack + PAGE_SIZE;
+        p.context.regs[ContextRegisters::sp as usize] = p.kstack_sp;
 
         p
     }
+
+    /// Replace address space of this process with `mm`. If this process is
+    /// the last user of the old one, shared file mappings are written back.
+    pub fn replace_mm(&mut self, mm: AddressSpace) {
+        let old = core::mem::replace(&mut self.mm, Arc::new(SleepLock::new(mm, "address space")));
+        match Arc::try_unwrap(old) {
+            Ok(old) => old.into_inner().unmap_range(0, MAXVA),
+            Err(old) => old.acquire().pgtable.kernel_unmap(TRAPFRAME(self.pid as usize)),
+        }
+    }
+
+    /// Get file of descriptor `fd`
+    pub fn file(&self, fd: usize) -> Option<Arc<File>> {
+        self.files.lock().get(fd)?.clone()
+    }
+}
+
+/// Cause of a page fault
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PageFault {
+    Instruction,
+    Load,
+    Store,
 }
 
-impl Drop for Process {
-    fn drop(&mut self) {
-        let _kstack = unsafe { Box::from_raw(self.kstack as *mut Page) };
+impl PageFault {
+    /// Get page fault type from `scause`
+    pub fn from_scause(scause: usize) -> Option<Self> {
+        match scause {
+            12 => Some(PageFault::Instruction),
+            13 => Some(PageFault::Load),
+            15 => Some(PageFault::Store),
+            _ => None
+        }
+    }
+
+    /// Page table entry flag required for this access
+    fn required_flag(&self) -> usize {
+        match self {
+            PageFault::Instruction => EntryAttributes::X as usize,
+            PageFault::Load => EntryAttributes::R as usize,
+            PageFault::Store => EntryAttributes::W as usize,
+        }
     }
 }
 
@@ -115,25 +272,29 @@ fn init_code() -> &'static [u8] {
 /// Put init process into `PROCS_POOL`
 pub fn init_proc() {
     let mut p = Process::new(0);
+    // no process is running, so address space is accessed without lock
+    let mm = unsafe { p.mm.get() };
     // map init code
     let content = init_code();
     let mut page = Page::new();
     page.data[0..content.len()].copy_from_slice(content);
-    p.pgtable.map(0, page, EntryAttributes::URX as usize);
-    // map user stack
-    let sp = map_stack(&mut p.pgtable, 0x80001000);
+    mm.pgtable.map(0, page, EntryAttributes::URX as usize);
+    mm.vmas.push(Vma::new(0, PAGE_SIZE, EntryAttributes::URX as usize, VmaKind::Text));
+    // user stack will be allocated on first access
+    let sp = mm.vmas.init_heap_stack();
     p.trapframe.epc = 0;
     p.trapframe.regs[Register::sp as usize] = sp;
     p.state = ProcessState::RUNNABLE;
     put_back_proc(box p);
 }
 
+/// Find an unused pid and reserve it for a new process
 pub fn find_available_pid() -> Option<i32> {
-    let pool = PROCS_POOL.lock();
     for i in 0..NMAXPROCS {
-        match &pool[i] {
-            ProcInPool::NoProc => return Some(i as i32),
-            _ => {}
+        let mut slot = PROCS_POOL[i].lock();
+        if let ProcInPool::NoProc = &*slot {
+            *slot = ProcInPool::Scheduled;
+            return Some(i as i32);
         }
     }
     None
@@ -146,35 +307,128 @@ pub fn fork() -> i32 {
         panic!("pid unavailable");
     }
     let f_pid = f_pid.unwrap();
-    let pgtable = p.pgtable.clone();
     let trapframe = box *p.trapframe.clone();
-    let mut fork_p = Process::from_exist(f_pid, pgtable, trapframe);
-    for i in 0..fork_p.files.len() {
-        fork_p.files[i] = match &p.files[i] {
-            Some(x) => Some(x.clone()),
-            None => None
+    let mut mm = p.mm.acquire().fork();
+    mm.map_trapframe(f_pid, &trapframe);
+    let mut files: FileTable = [None; 256];
+    {
+        let p_files = p.files.lock();
+        for i in 0..files.len() {
+            files[i] = match &p_files[i] {
+                Some(x) => Some(x.clone()),
+                None => None
+            }
         }
     }
+    let mut fork_p = Process::from_exist(
+        f_pid,
+        Arc::new(SleepLock::new(mm, "address space")),
+        trapframe,
+        Arc::new(Mutex::new(files, "files")),
+    );
+    fork_p.sched = p.sched.fork();
     fork_p.trapframe.regs[a0 as usize] = 0;
     fork_p.state = ProcessState::RUNNABLE;
+    add_child(f_pid, &fork_p.group_exit);
     put_back_proc(box fork_p);
     f_pid
 }
 
-pub const USER_STACK_PAGE: usize = 4;
+/// setpriority syscall
+///
+/// Set nice value of process `pid`, or current process if `pid` is 0.
+/// Process running on other harts can't be changed.
+pub fn setpriority(pid: i32, nice: i32) -> Option<()> {
+    if nice < NICE_MIN || nice > NICE_MAX {
+        return None;
+    }
+    let p = my_proc();
+    if pid == 0 || pid == p.pid {
+        p.sched.nice = nice;
+        return Some(());
+    }
+    if pid < 0 || pid as usize >= NMAXPROCS {
+        return None;
+    }
+    match &mut *PROCS_POOL[pid as usize].lock() {
+        ProcInPool::Pooling(p) => {
+            p.sched.nice = nice;
+            Some(())
+        }
+        _ => None
+    }
+}
 
-/// map user stack in `pgtable` at `stack_begin` and returns `sp`
-pub fn map_stack(pgtable: &mut Table, stack_begin: usize) -> usize {
-    for i in 0..USER_STACK_PAGE {
-        let stack = page::Page::new();
-        pgtable.map(
-            stack_begin + i * PAGE_SIZE,
-            stack,
-            page::EntryAttributes::URW as usize,
-        );
+/// getpriority syscall
+///
+/// Get nice value of process `pid`, or current process if `pid` is 0.
+pub fn getpriority(pid: i32) -> Option<i32> {
+    let p = my_proc();
+    if pid == 0 || pid == p.pid {
+        return Some(p.sched.nice);
+    }
+    if pid < 0 || pid as usize >= NMAXPROCS {
+        return None;
     }
+    match &*PROCS_POOL[pid as usize].lock() {
+        ProcInPool::Pooling(p) => Some(p.sched.nice),
+        _ => None
+    }
+}
 
-    stack_begin + PAGE_SIZE * USER_STACK_PAGE
+/// setaffinity syscall
+///
+/// Restrict current process to run on harts in bit mask `mask`.
+/// Fails if none of these harts is online. If current hart is not in
+/// `mask`, current process is moved to another hart at once.
+pub fn setaffinity(mask: usize) -> Option<()> {
+    if mask & ONLINE_HARTS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let p = my_proc();
+    p.sched.affinity = mask;
+    if mask & (1 << arch::hart_id()) == 0 {
+        arch::intr_off();
+        yield_cpu();
+    }
+    Some(())
+}
+
+/// getaffinity syscall
+///
+/// Returns bit mask of harts current process may run on.
+pub fn getaffinity() -> usize {
+    my_proc().sched.affinity & ONLINE_HARTS.load(Ordering::Relaxed)
+}
+
+/// cputime syscall
+///
+/// Returns time current process has been running on CPU.
+pub fn cputime() -> Duration {
+    let p = my_proc();
+    p.sched.cpu_time + (arch::time() - p.sched.last_run)
+}
+
+/// sbrk syscall
+///
+/// Move end of heap by `increment` bytes and returns previous end of heap.
+/// Pages are allocated on first access, and freed when heap shrinks.
+pub fn sbrk(increment: isize) -> Option<usize> {
+    let p = my_proc();
+    let mut mm = p.mm.acquire();
+    let old_brk = mm.vmas.find_kind(VmaKind::Heap)?.end;
+    let brk = (old_brk as isize).checked_add(increment)?;
+    if brk < 0 {
+        return None;
+    }
+    let brk = brk as usize;
+    mm.vmas.set_brk(brk)?;
+    let mut page = mem::align_val(brk, PAGE_ORDER);
+    while page < old_brk {
+        mm.pgtable.unmap(page);
+        page += PAGE_SIZE;
+    }
+    Some(old_brk)
 }
 
 /// exec syscall
@@ -184,39 +438,58 @@ pub fn exec(path: &str) {
     let mut content: Box<[u8; 131072]> = box [0; 131072];
     {
         let f = FsFile::open(path, 0);
-        let mut blk = [0; 1024];
-        let mut i = 0;
-        while f.read(&mut blk) == 1024 {
-            // content[i..i + 1024].copy_from_slice(&blk);
-            for j in 0..1024 {
-                content[i + j] = blk[j];
-            }
-            i += 1024;
-            if i + 1024 >= content.len() {
-                panic!("elf file too large!");
-            }
+        if f.size() > content.len() {
+            panic!("elf file too large!");
         }
+        // whole file is read with batched requests
+        f.read_at(0, &mut content[..f.size()]);
     }
     info!("parsing...");
-    p.pgtable.unmap_user();
+    let mut mm = AddressSpace::new();
+    mm.map_trapframe(p.pid, &p.trapframe);
     let entry = crate::elf::parse_elf(
         &*content,
-        &mut p.pgtable,
+        &mut mm.pgtable,
+        &mut mm.vmas,
     );
     info!("done");
-    // map user stack
-    let sp = map_stack(&mut p.pgtable, 0x80001000);
+    kill_siblings();
+    renew_group();
+    // user heap and stack will be allocated on first access
+    let sp = mm.vmas.init_heap_stack();
+    // other threads keep running in the old address space
+    p.replace_mm(mm);
     p.trapframe.epc = entry as usize;
     p.trapframe.regs[Register::sp as usize] = sp;
 }
 
 /// exit syscall
+///
+/// Other threads in thread group of current process exit as well, and
+/// `status` is collected by parent with `wait`.
 pub fn exit(status: i32) -> ! {
+    set_exit_status(status);
+    kill_siblings();
+    thread_exit(status)
+}
+
+/// thread_exit syscall
+///
+/// Current thread becomes a zombie, until it is joined.
+pub fn thread_exit(status: i32) -> ! {
     {
         let p = my_proc();
         if p.pid == 0 {
-            panic!("init exiting");
+            // init exiting ends system, with its status as exit code of QEMU
+            info!("init exited with status {}", status);
+            crate::power::shutdown(Action::PowerOff(status as u32));
         }
+        // release address space, writing back shared file mappings
+        // if this is the last thread using it
+        p.replace_mm(AddressSpace::new());
+        // close files if this is the last thread using them
+        p.files = Arc::new(Mutex::new([None; 256], "files"));
+        p.exit_status = status;
         p.state = ProcessState::ZOMBIE;
     }
     arch::intr_off();
@@ -224,79 +497,30 @@ pub fn exit(status: i32) -> ! {
     unreachable!();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// A Mutex that will be locked if a process is being slept but not yet put back into `PROCS_POOL`.
-pub static PROCS_POOL_SLEEP: Mutex<()> = Mutex::new((), "proc pool sleep");
-
-/// put this process into sleep state
-///
-/// `channel` is an identifier of sleep lock channel. `wakeup` should be called with the same
-/// channel to properly wakeup previously slept process.
-///
-/// `lck` is the spinlock to be temporarily unlocked.
-///
-/// Returns the `lck` spinlock.
-///
-/// ## Technical Details
-///
-/// To avoid the lost wakeup issue, process must hold a global lock `PROCS_POOL_SLEEP`.
-/// This lock will be dropped after the process is put back into process pool.
-pub fn sleep<T, U>(channel: *const T, lck: MutexGuard<U>) -> MutexGuard<U> {
-    let p = my_proc();
-    p.channel = channel as *const _ as usize;
-    p.state = ProcessState::SLEEPING;
-
-    // set proc in proc pool as being slept, avoiding lost-wakeup issue
-    {
-        let mut pool = PROCS_POOL.lock();
-        let p_in_pool = &mut pool[p.pid as usize];
-        match p_in_pool {
-            ProcInPool::Scheduled => {}
-            _ => panic!("invalid proc pool state")
-        }
-        *p_in_pool = ProcInPool::BeingSlept;
+    /// Test changing nice value of current process
+    #[test_case]
+    fn test_priority() {
+        let nice = getpriority(0).unwrap();
+        assert_eq!(getpriority(my_proc().pid), Some(nice));
+        assert!(setpriority(0, NICE_MAX + 1).is_none());
+        assert!(setpriority(0, NICE_MIN - 1).is_none());
+        assert!(setpriority(0, NICE_MAX).is_some());
+        assert_eq!(getpriority(0), Some(NICE_MAX));
+        assert!(setpriority(0, nice).is_some());
+        assert!(getpriority(-1).is_none());
+        assert!(getpriority(NMAXPROCS as i32).is_none());
     }
-    p.drop_on_put_back = Some(PROCS_POOL_SLEEP.lock());
-
-    // temporarily unlock spinlock
-    let weak_lock = lck.into_weak();
-    // info!("sleep on {:x}", channel as usize);
-
-    sched();
-
-    p.channel = 0;
 
-    return weak_lock.into_guard();
-}
-
-/// wakeup process on channel
-///
-/// `channel` is an identifier of sleep lock channel. Should be the same as in `sleep`.
-///
-/// If `wakeup` finds a position in `PROCS_POOL` is `BeingSlept`, which means that a process
-/// is to be slept, but not yet being put back into the pool, `wakeup` will temporarily unlock
-/// `PROCS_POOL` lock and wait for `PROCS_POOL_SLEEP` to be unlocked, so that there won't be
-/// lost wakeup issues.
-pub fn wakeup<T>(channel: *const T) {
-    // info!("wakeup {:x}", channel as usize);
-    let channel = channel as *const _ as usize;
-    let mut pool = PROCS_POOL.lock();
-    let mut i = 0;
-    while i < NMAXPROCS {
-        match &mut pool[i] {
-            ProcInPool::Pooling(p) => {
-                // if p.state == ProcessState::SLEEPING { info!("channel of {} = {:x}", p.pid, p.channel); }
-                if p.state == ProcessState::SLEEPING && p.channel == channel {
-                    p.state = ProcessState::RUNNABLE;
-                }
-                i += 1;
-            }
-            ProcInPool::BeingSlept => {
-                let weak_lock = pool.into_weak();
-                PROCS_POOL_SLEEP.lock();
-                pool = weak_lock.into_guard();
-            }
-            _ => { i += 1; }
-        }
+    /// Test current process may run on some online hart
+    #[test_case]
+    fn test_affinity() {
+        let mask = getaffinity();
+        assert_ne!(mask, 0);
+        assert!(setaffinity(0).is_none());
+        assert_eq!(getaffinity(), mask);
     }
 }
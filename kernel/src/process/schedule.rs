@@ -3,71 +3,269 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
+//! Per-hart run queues and scheduler
+//!
+//! Each hart picks processes from its own run queue. A hart with an empty
+//! queue steals a process from the busiest hart it may run on.
+//!
+//! Locks should be obtained in the order of wait queue, process slot and
+//! run queue. At most one run queue is locked at a time.
+
 use crate::arch::wait_forever;
 use crate::arch;
 use crate::trap::usertrapret;
 use crate::symbols::*;
 use crate::process::{ProcInPool, PROCS_POOL, ProcessState, swtch, Register, Context, my_cpu, Process};
+use crate::process::{Policy, SchedPolicy, RunEntry, wakeup_joining, wakeup_parent, CHILDREN};
+use crate::spinlock::Mutex;
 use crate::{info, println};
 use crate::panic;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::borrow::BorrowMut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
 use crate::jump::*;
 
-/// Find a runnable process whose pid >= `from_pid`
-fn find_next_runnable_proc(from_pid: usize) -> Option<Box<Process>> {
-    let mut pool = PROCS_POOL.lock();
-    for pid in from_pid..NMAXPROCS {
-        let in_pool = &mut pool[pid];
-        let schedule_this = match in_pool {
-            ProcInPool::Pooling(p) => p.state == ProcessState::RUNNABLE,
-            _ => false
-        };
-        if schedule_this {
-            let p = core::mem::replace(in_pool, ProcInPool::Scheduled);
-            if let ProcInPool::Pooling(p) = p {
-                return Some(p);
-            }
+/// Runnable processes waiting to be scheduled on one hart
+pub struct RunQueue {
+    entries: Vec<RunEntry>,
+    seq: u64,
+    policy: SchedPolicy,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            seq: 0,
+            policy: SchedPolicy::new(),
         }
     }
-    None
+
+    /// Number of queued processes
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Add runnable process `p` to this queue
+    pub fn push(&mut self, p: &mut Process) {
+        let key = self.policy.key(p);
+        self.seq += 1;
+        self.entries.push(RunEntry {
+            pid: p.pid as usize,
+            key,
+            seq: self.seq,
+            nice: p.sched.nice,
+            affinity: p.sched.affinity,
+        });
+    }
+
+    /// Remove process with the least key
+    pub fn pop(&mut self) -> Option<RunEntry> {
+        self.policy.tick(&mut self.entries);
+        let (idx, _) = self.entries.iter().enumerate().min_by_key(|(_, x)| (x.key, x.seq))?;
+        let entry = self.entries.remove(idx);
+        self.policy.picked(&entry);
+        Some(entry)
+    }
+
+    /// Remove the last process that may run on `hart`
+    pub fn steal(&mut self, hart: usize) -> Option<RunEntry> {
+        let idx = self.entries.iter().rposition(|x| x.affinity & (1 << hart) != 0)?;
+        Some(self.entries.remove(idx))
+    }
+
+    /// Account process `p` which has just run for `ran` on this hart
+    pub fn put_back(&mut self, p: &mut Process, ran: Duration) {
+        self.policy.put_back(p, ran);
+    }
+}
+
+const EMPTY_RUN_QUEUE: Mutex<RunQueue> = Mutex::new(RunQueue::new(), "run queue");
+
+/// Run queue of each hart
+pub static RUN_QUEUES: [Mutex<RunQueue>; NCPUS] = [EMPTY_RUN_QUEUE; NCPUS];
+
+/// Bit mask of harts which have started scheduling
+pub static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Choose a hart in `affinity` to run a process, preferring `hint`
+fn target_hart(affinity: usize, hint: usize) -> usize {
+    let online = ONLINE_HARTS.load(Ordering::Relaxed);
+    let allowed = match affinity & online {
+        0 => affinity,
+        x => x
+    };
+    if allowed & (1 << hint) != 0 {
+        hint
+    } else {
+        allowed.trailing_zeros() as usize
+    }
+}
+
+/// Add runnable process `p` to a run queue, preferably the one of current hart.
+///
+/// Process slot of `p` should be locked by caller.
+pub fn enqueue(p: &mut Process) {
+    let hart = target_hart(p.sched.affinity, arch::hart_id());
+    RUN_QUEUES[hart].lock().push(p);
+    // kick other hart to reschedule
+    #[cfg(feature = "sbi")]
+    if hart != arch::hart_id() {
+        crate::sbi::send_ipi(1 << hart);
+    }
 }
 
-/// Put process back to `PROCS_POOL`
+/// Steal a process from the busiest other hart
+fn steal(hart: usize) -> Option<RunEntry> {
+    let mut busiest = None;
+    let mut max_len = 0;
+    for i in 0..NCPUS {
+        if i == hart {
+            continue;
+        }
+        let len = RUN_QUEUES[i].lock().len();
+        if len > max_len {
+            max_len = len;
+            busiest = Some(i);
+        }
+    }
+    RUN_QUEUES[busiest?].lock().steal(hart)
+}
+
+/// Find a runnable process in run queue of current hart, or steal one
+/// from other harts if there is none
+fn find_next_runnable_proc() -> Option<Box<Process>> {
+    let hart = arch::hart_id();
+    let entry = RUN_QUEUES[hart].lock().pop();
+    let entry = entry.or_else(|| steal(hart))?;
+    let mut slot = PROCS_POOL[entry.pid].lock();
+    match core::mem::replace(&mut *slot, ProcInPool::Scheduled) {
+        ProcInPool::Pooling(p) => Some(p),
+        _ => panic!("pid {} queued but not in pool", entry.pid)
+    }
+}
+
+/// Put process back to `PROCS_POOL`, and enqueue it if it is runnable.
+///
+/// If process is going to sleep but has been woken up before put back,
+/// it will be made runnable. If process has exited, threads joining it
+/// and its parent will be woken up, or it is released if its thread group
+/// has exited and no one may wait for it.
 pub fn put_back_proc(mut p: Box<Process>) {
-    let mut pool = PROCS_POOL.lock();
-    let p_in_pool = &mut pool[p.pid as usize];
-    p.drop_on_put_back = None;
-    match p_in_pool {
-        ProcInPool::Pooling(_) => { panic!("pid {} already occupied", p.pid); }
-        _ => { core::mem::replace(p_in_pool, ProcInPool::Pooling(p)); }
+    let pid = p.pid as usize;
+    let zombie = p.state == ProcessState::ZOMBIE;
+    {
+        // held until zombie is in pool, so that it is released if its
+        // parent is released meanwhile
+        let mut children = if zombie { Some(CHILDREN.lock()) } else { None };
+        if let Some(children) = &mut children {
+            if p.group_exit.load(Ordering::SeqCst) && children.release_exited(&p) {
+                *PROCS_POOL[pid].lock() = ProcInPool::NoProc;
+                return;
+            }
+        }
+        let mut slot = PROCS_POOL[pid].lock();
+        p.drop_on_put_back = None;
+        match &*slot {
+            ProcInPool::Pooling(_) => { panic!("pid {} already occupied", p.pid); }
+            ProcInPool::Scheduled if p.state == ProcessState::SLEEPING => {
+                p.state = ProcessState::RUNNABLE;
+            }
+            _ => {}
+        }
+        if p.state == ProcessState::RUNNABLE {
+            enqueue(&mut p);
+        }
+        *slot = ProcInPool::Pooling(p);
+    }
+    if zombie {
+        wakeup_joining(pid);
+        wakeup_parent(pid);
     }
 }
 
 /// Kernel scheduler
 pub fn scheduler() -> ! {
     let c = my_cpu();
-    let mut lst_pid = 0;
+    let hart = arch::hart_id();
+    ONLINE_HARTS.fetch_or(1 << hart, Ordering::Relaxed);
     // info!("scheduling on {}", arch::hart_id());
     loop {
+        if crate::power::halting() {
+            crate::power::halt();
+        }
         arch::intr_on();
-        if let Some(p) = find_next_runnable_proc(lst_pid) {
+        if let Some(p) = find_next_runnable_proc() {
             c.process = Some(p);
             let p = c.process.as_mut().unwrap();
             p.state = ProcessState::RUNNING;
+            p.sched.last_run = arch::time();
             let ctx = core::mem::replace(&mut p.context, box Context::zero());
             // info!("scheduler {}: switching to {}", arch::hart_id(), p.pid);
             swtch(&mut c.scheduler_context, *ctx);
             // info!("scheduler {}: come back", arch::hart_id());
-            let p = core::mem::replace(&mut c.process, None).unwrap();
-            lst_pid = p.pid as usize + 1;
-            if lst_pid >= NMAXPROCS {
-                lst_pid = 0;
-            }
+            let mut p = core::mem::replace(&mut c.process, None).unwrap();
+            let ran = arch::time() - p.sched.last_run;
+            p.sched.cpu_time += ran;
+            RUN_QUEUES[hart].lock().put_back(&mut p, ran);
             // info!("put back...");
             put_back_proc(p);
-        } else {
-            lst_pid = 0;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test processes of equal priority run in FIFO order
+    #[test_case]
+    fn test_fifo() {
+        let mut rq = RunQueue::new();
+        let mut a = Process::new(NMAXPROCS as i32 - 1);
+        let mut b = Process::new(NMAXPROCS as i32 - 2);
+        rq.push(&mut a);
+        rq.push(&mut b);
+        assert_eq!(rq.len(), 2);
+        assert_eq!(rq.pop().unwrap().pid, NMAXPROCS - 1);
+        assert_eq!(rq.pop().unwrap().pid, NMAXPROCS - 2);
+        assert!(rq.pop().is_none());
+    }
+
+    /// Test stealing respects CPU affinity
+    #[test_case]
+    fn test_steal() {
+        let mut rq = RunQueue::new();
+        let mut a = Process::new(NMAXPROCS as i32 - 1);
+        let mut b = Process::new(NMAXPROCS as i32 - 2);
+        b.sched.affinity = 1 << 1;
+        rq.push(&mut a);
+        rq.push(&mut b);
+        assert_eq!(rq.steal(2).unwrap().pid, NMAXPROCS - 1);
+        assert!(rq.steal(2).is_none());
+        assert_eq!(rq.steal(1).unwrap().pid, NMAXPROCS - 2);
+    }
+
+    /// Test a process of low priority runs while processes of higher
+    /// priority keep coming
+    #[test_case]
+    #[cfg(not(any(feature = "sched-mlfq", feature = "sched-cfs")))]
+    fn test_no_starvation() {
+        use crate::process::{NICE_MIN, NICE_MAX};
+        let mut rq = RunQueue::new();
+        let mut low = Process::new(NMAXPROCS as i32 - 1);
+        let mut high = Process::new(NMAXPROCS as i32 - 2);
+        low.sched.nice = NICE_MAX;
+        high.sched.nice = NICE_MIN;
+        rq.push(&mut low);
+        for _ in 0..=(NICE_MAX - NICE_MIN) {
+            rq.push(&mut high);
+            if rq.pop().unwrap().pid == NMAXPROCS - 1 {
+                return;
+            }
+        }
+        panic!("process of low priority starves");
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Threads
+//!
+//! A thread is a process sharing address space and file table with
+//! other threads in its thread group. It has its own pid (used as thread
+//! id), trapframe, kernel stack and user stack. Kernel threads run a
+//! kernel function and never return to user space.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::symbols::*;
+use crate::spinlock::Mutex;
+use super::{Process, ProcessState, ProcInPool, PROCS_POOL, Register, ContextRegisters};
+use super::{my_proc, find_available_pid, put_back_proc, exit, sleep, wakeup, enqueue};
+
+/// Lock to be held when waiting for a thread to exit, or waking up
+/// those waiting, so that wakeups won't be lost.
+pub static JOIN_LOCK: Mutex<()> = Mutex::new((), "join");
+
+/// Channel on which threads joining process slot `pid` sleep
+fn join_channel(pid: usize) -> *const Mutex<ProcInPool> {
+    &PROCS_POOL[pid] as *const _
+}
+
+/// Wake up threads joining process slot `pid`, which has just become a zombie
+pub fn wakeup_joining(pid: usize) {
+    let _join_lock = JOIN_LOCK.lock();
+    wakeup(join_channel(pid));
+}
+
+/// Make threads in thread group `group` exit, except the first thread
+/// and the current one, which is not in pool.
+///
+/// Zombie threads which are not yet joined are released, and sleeping
+/// threads are woken up, including those just going to sleep. Each of the other threads exits on its next
+/// return to user space, and is released at once as no one may join it.
+/// First thread of a thread group stays a zombie, as it holds pid of the
+/// process until parent waits for it.
+pub fn kill_group(group: &Arc<AtomicBool>) {
+    group.store(true, Ordering::SeqCst);
+    for pid in 0..NMAXPROCS {
+        let mut slot = PROCS_POOL[pid].lock();
+        match &mut *slot {
+            ProcInPool::Pooling(t) if Arc::ptr_eq(&t.group_exit, group) => match t.state {
+                ProcessState::ZOMBIE if t.pid != t.tgid => *slot = ProcInPool::NoProc,
+                ProcessState::SLEEPING => {
+                    t.state = ProcessState::RUNNABLE;
+                    enqueue(t);
+                }
+                _ => {}
+            }
+            // not yet put back, and will be made runnable on put back
+            ProcInPool::BeingSlept(g) if Arc::ptr_eq(g, group) => *slot = ProcInPool::Scheduled,
+            _ => {}
+        }
+    }
+}
+
+/// Make other threads in thread group of current process exit, when
+/// current thread exits or calls exec.
+pub fn kill_siblings() {
+    kill_group(&my_proc().group_exit);
+}
+
+/// Whether thread group of current process has exited, and current thread
+/// should exit instead of returning to user space
+pub fn group_exited() -> bool {
+    my_proc().group_exit.load(Ordering::SeqCst)
+}
+
+/// clone syscall
+///
+/// Create a thread sharing address space and file table with current process.
+/// The new thread starts at `entry` with stack pointer `stack` and `arg` in `a0`.
+/// Returns thread id of the new thread.
+pub fn clone(entry: usize, stack: usize, arg: usize) -> Option<i32> {
+    let p = my_proc();
+    let tid = find_available_pid()?;
+    let mut trapframe = box *p.trapframe.clone();
+    trapframe.epc = entry;
+    trapframe.regs[Register::sp as usize] = stack;
+    trapframe.regs[Register::a0 as usize] = arg;
+    p.mm.acquire().map_trapframe(tid, &trapframe);
+    let mut t = Process::from_exist(tid, p.mm.clone(), trapframe, p.files.clone());
+    t.tgid = p.tgid;
+    t.group_exit = p.group_exit.clone();
+    t.sched = p.sched.fork();
+    t.state = ProcessState::RUNNABLE;
+    put_back_proc(box t);
+    Some(tid)
+}
+
+/// join syscall
+///
+/// Wait for thread `tid` in the same thread group, other than the first
+/// one, or a kernel thread, to exit and release it. Returns its exit status.
+pub fn join(tid: i32) -> Option<i32> {
+    let p = my_proc();
+    if tid < 0 || tid as usize >= NMAXPROCS || tid == p.pid {
+        return None;
+    }
+    let tid = tid as usize;
+    let mut join_lock = JOIN_LOCK.lock();
+    loop {
+        if group_exited() {
+            return None;
+        }
+        {
+            let mut slot = PROCS_POOL[tid].lock();
+            match &*slot {
+                ProcInPool::NoProc => return None,
+                ProcInPool::Pooling(t) if t.tgid != p.tgid && t.kthread.is_none() => return None,
+                // first thread holds pid of process until parent waits for it
+                ProcInPool::Pooling(t) if t.pid == t.tgid && t.kthread.is_none() => return None,
+                ProcInPool::Pooling(t) if t.state == ProcessState::ZOMBIE => {
+                    let status = t.exit_status;
+                    *slot = ProcInPool::NoProc;
+                    return Some(status);
+                }
+                _ => {}
+            }
+        }
+        join_lock = sleep(join_channel(tid), join_lock);
+    }
+}
+
+/// Create a kernel thread running `func(arg)`. Returns its pid.
+pub fn kthread_create(func: fn(usize), arg: usize) -> Option<i32> {
+    let pid = find_available_pid()?;
+    let mut t = Process::new(pid);
+    t.kthread = Some((func, arg));
+    t.context.regs[ContextRegisters::ra as usize] = kthread_entry as usize;
+    t.state = ProcessState::RUNNABLE;
+    put_back_proc(box t);
+    Some(pid)
+}
+
+/// First function a kernel thread runs after scheduled
+extern "C" fn kthread_entry() -> ! {
+    let (func, arg) = my_proc().kthread.unwrap();
+    func(arg);
+    exit(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static KTHREAD_ARG: AtomicUsize = AtomicUsize::new(0);
+
+    fn kthread_func(arg: usize) {
+        KTHREAD_ARG.store(arg, Ordering::SeqCst);
+    }
+
+    /// Test running and joining a kernel thread
+    #[test_case]
+    fn test_kthread() {
+        let pid = kthread_create(kthread_func, 2333).unwrap();
+        assert_eq!(join(pid), Some(0));
+        assert_eq!(KTHREAD_ARG.load(Ordering::SeqCst), 2333);
+        assert_eq!(join(pid), None);
+    }
+}
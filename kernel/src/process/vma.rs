@@ -0,0 +1,291 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Virtual memory areas of user address space
+
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use crate::file::File;
+use crate::symbols::*;
+use crate::mem::{align_val, page_down};
+use crate::page::EntryAttributes;
+
+/// Kind of a virtual memory area
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum VmaKind {
+    Text,
+    Data,
+    Heap,
+    Stack,
+    Mmap,
+}
+
+/// File backing a memory mapping
+#[derive(Clone)]
+pub struct VmaFile {
+    pub file: Arc<File>,
+    /// offset in file corresponding to start of the area
+    pub offset: usize,
+    /// whether changes should be written back to file
+    pub shared: bool,
+}
+
+/// A virtual memory area `[start, end)` in user space.
+///
+/// Pages in a VMA are not necessarily mapped. Those not yet mapped
+/// will be allocated on first access in page fault handler.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// page table entry flags of pages in this area
+    pub flags: usize,
+    pub kind: VmaKind,
+    /// file to fill pages from, `None` for anonymous area
+    pub file: Option<VmaFile>,
+}
+
+impl Vma {
+    pub const fn new(start: usize, end: usize, flags: usize, kind: VmaKind) -> Self {
+        Self { start, end, flags, kind, file: None }
+    }
+
+    /// Get part `[start, end)` of this area, with file offset adjusted
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        let mut vma = self.clone();
+        vma.start = start;
+        vma.end = end;
+        if let Some(f) = &mut vma.file {
+            f.offset += start - self.start;
+        }
+        vma
+    }
+
+    /// Offset in backing file of `vaddr`
+    pub fn file_offset(&self, vaddr: usize) -> Option<usize> {
+        self.file.as_ref().map(|f| f.offset + vaddr - self.start)
+    }
+
+    /// Check if `vaddr` falls in this area
+    pub fn contains(&self, vaddr: usize) -> bool {
+        self.start <= vaddr && vaddr < self.end
+    }
+
+    /// Check if this area overlaps with `[start, end)`
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// All virtual memory areas of a process
+#[derive(Clone)]
+pub struct VmaList {
+    pub vmas: Vec<Vma>,
+}
+
+/// Lowest address user stack may grow to
+pub const USER_STACK_LIMIT: usize = USER_STACK_TOP - USER_STACK_MAX_PAGE * PAGE_SIZE;
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self { vmas: Vec::new() }
+    }
+
+    /// Remove all areas
+    pub fn clear(&mut self) {
+        self.vmas.clear();
+    }
+
+    /// Add an area
+    pub fn push(&mut self, vma: Vma) {
+        self.vmas.push(vma);
+    }
+
+    /// Find area containing `vaddr`
+    pub fn find(&self, vaddr: usize) -> Option<&Vma> {
+        self.vmas.iter().find(|x| x.contains(vaddr))
+    }
+
+    /// Find first area of `kind`
+    pub fn find_kind(&self, kind: VmaKind) -> Option<&Vma> {
+        self.vmas.iter().find(|x| x.kind == kind)
+    }
+
+    /// Find first area of `kind`, returns mutable reference
+    pub fn find_kind_mut(&mut self, kind: VmaKind) -> Option<&mut Vma> {
+        self.vmas.iter_mut().find(|x| x.kind == kind)
+    }
+
+    /// Check if `[start, end)` overlaps with any area other than `except`
+    pub fn overlaps(&self, start: usize, end: usize, except: VmaKind) -> bool {
+        self.vmas.iter().any(|x| x.kind != except && x.overlaps(start, end))
+    }
+
+    /// Find a free range of `len` bytes for memory mapping.
+    ///
+    /// Mappings are placed top-down from the guard page below the lowest
+    /// possible stack, so that they are far from heap.
+    pub fn find_free(&self, len: usize) -> Option<usize> {
+        let mut end = USER_STACK_LIMIT - PAGE_SIZE;
+        loop {
+            let start = end.checked_sub(len)?;
+            match self.vmas.iter().filter(|x| x.overlaps(start, end)).map(|x| x.start).min() {
+                Some(x) => end = page_down(x),
+                None => return Some(start)
+            }
+        }
+    }
+
+    /// Remove `[start, end)` from memory mappings, splitting areas if necessary.
+    /// Returns removed parts.
+    pub fn remove_mmap(&mut self, start: usize, end: usize) -> Vec<Vma> {
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for vma in self.vmas.drain(..) {
+            if vma.kind != VmaKind::Mmap || !vma.overlaps(start, end) {
+                kept.push(vma);
+                continue;
+            }
+            if vma.start < start {
+                kept.push(vma.slice(vma.start, start));
+            }
+            if end < vma.end {
+                kept.push(vma.slice(end, vma.end));
+            }
+            removed.push(vma.slice(vma.start.max(start), vma.end.min(end)));
+        }
+        self.vmas = kept;
+        removed
+    }
+
+    /// Add an empty heap right after the highest loaded segment, and a stack
+    /// of one page ending at `USER_STACK_TOP`. Returns initial `sp`.
+    pub fn init_heap_stack(&mut self) -> usize {
+        let heap_start = align_val(
+            self.vmas.iter().map(|x| x.end).max().unwrap_or(0),
+            PAGE_ORDER,
+        );
+        self.push(Vma::new(heap_start, heap_start, EntryAttributes::URW as usize, VmaKind::Heap));
+        self.push(Vma::new(
+            USER_STACK_TOP - PAGE_SIZE,
+            USER_STACK_TOP,
+            EntryAttributes::URW as usize,
+            VmaKind::Stack,
+        ));
+        USER_STACK_TOP
+    }
+
+    /// Grow stack down to the page containing `vaddr`.
+    ///
+    /// Stack may grow no lower than `USER_STACK_LIMIT`, and one guard page
+    /// is always kept between stack and other areas.
+    /// Returns `false` if `vaddr` can't be covered by stack.
+    pub fn grow_stack(&mut self, vaddr: usize) -> bool {
+        let page = page_down(vaddr);
+        if page < USER_STACK_LIMIT {
+            return false;
+        }
+        let stack_start = match self.find_kind(VmaKind::Stack) {
+            Some(stack) => stack.start,
+            None => return false
+        };
+        if page >= stack_start {
+            return false;
+        }
+        if self.overlaps(page - PAGE_SIZE, stack_start, VmaKind::Stack) {
+            return false;
+        }
+        self.find_kind_mut(VmaKind::Stack).unwrap().start = page;
+        true
+    }
+
+    /// Move end of heap to `brk`. Returns previous end of heap.
+    ///
+    /// Heap may not grow into the guard page below lowest possible stack.
+    pub fn set_brk(&mut self, brk: usize) -> Option<usize> {
+        let heap = self.find_kind(VmaKind::Heap)?;
+        let (start, old_brk) = (heap.start, heap.end);
+        if brk < start {
+            return None;
+        }
+        if brk > old_brk && self.overlaps(old_brk, align_val(brk, PAGE_ORDER), VmaKind::Heap) {
+            return None;
+        }
+        if brk > USER_STACK_LIMIT - PAGE_SIZE {
+            return None;
+        }
+        self.find_kind_mut(VmaKind::Heap).unwrap().end = brk;
+        Some(old_brk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_vmas() -> VmaList {
+        let mut vmas = VmaList::new();
+        vmas.push(Vma::new(0x10000, 0x12000, EntryAttributes::URX as usize, VmaKind::Text));
+        vmas.push(Vma::new(0x12000, 0x12800, EntryAttributes::URW as usize, VmaKind::Data));
+        vmas
+    }
+
+    /// Test finding areas
+    #[test_case]
+    fn test_find() {
+        let mut vmas = new_vmas();
+        assert_eq!(vmas.init_heap_stack(), USER_STACK_TOP);
+        assert_eq!(vmas.find(0x10000).unwrap().kind, VmaKind::Text);
+        assert_eq!(vmas.find(0x127ff).unwrap().kind, VmaKind::Data);
+        assert!(vmas.find(0x12800).is_none());
+        assert_eq!(vmas.find_kind(VmaKind::Heap).unwrap().start, 0x13000);
+        assert_eq!(vmas.find(USER_STACK_TOP - 8).unwrap().kind, VmaKind::Stack);
+        assert!(vmas.find(USER_STACK_TOP).is_none());
+    }
+
+    /// Test stack growth and guard page
+    #[test_case]
+    fn test_grow_stack() {
+        let mut vmas = new_vmas();
+        vmas.init_heap_stack();
+        assert!(vmas.grow_stack(USER_STACK_TOP - PAGE_SIZE * 3 + 8));
+        assert_eq!(vmas.find_kind(VmaKind::Stack).unwrap().start, USER_STACK_TOP - PAGE_SIZE * 3);
+        assert!(vmas.grow_stack(USER_STACK_LIMIT));
+        assert!(!vmas.grow_stack(USER_STACK_LIMIT - 8));
+        assert!(!vmas.grow_stack(USER_STACK_TOP));
+    }
+
+    /// Test moving end of heap
+    #[test_case]
+    fn test_brk() {
+        let mut vmas = new_vmas();
+        vmas.init_heap_stack();
+        assert_eq!(vmas.set_brk(0x14000), Some(0x13000));
+        assert_eq!(vmas.set_brk(0x13800), Some(0x14000));
+        assert_eq!(vmas.set_brk(0x12000), None);
+        assert_eq!(vmas.set_brk(USER_STACK_LIMIT), None);
+    }
+
+    /// Test finding free range and removing mappings
+    #[test_case]
+    fn test_mmap() {
+        let mut vmas = new_vmas();
+        vmas.init_heap_stack();
+        let top = USER_STACK_LIMIT - PAGE_SIZE;
+        assert_eq!(vmas.find_free(PAGE_SIZE * 4), Some(top - PAGE_SIZE * 4));
+        vmas.push(Vma::new(top - PAGE_SIZE * 4, top, EntryAttributes::URW as usize, VmaKind::Mmap));
+        assert_eq!(vmas.find_free(PAGE_SIZE), Some(top - PAGE_SIZE * 5));
+        let removed = vmas.remove_mmap(top - PAGE_SIZE * 3, top - PAGE_SIZE * 2);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].start, top - PAGE_SIZE * 3);
+        assert_eq!(removed[0].end, top - PAGE_SIZE * 2);
+        assert!(vmas.find(top - PAGE_SIZE * 3).is_none());
+        assert_eq!(vmas.find(top - PAGE_SIZE * 4).unwrap().end, top - PAGE_SIZE * 3);
+        assert_eq!(vmas.find(top - PAGE_SIZE).unwrap().start, top - PAGE_SIZE * 2);
+        assert_eq!(vmas.find_free(PAGE_SIZE), Some(top - PAGE_SIZE * 3));
+        assert!(vmas.remove_mmap(0, USER_STACK_TOP).len() == 2);
+        assert_eq!(vmas.find_kind(VmaKind::Text).unwrap().start, 0x10000);
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Parent and child processes
+//!
+//! Each process created by `fork` has an entry in `CHILDREN`, indexed by
+//! its pid, which is also the pid of its first thread. The first thread
+//! stays a zombie after process exits, so that its pid is not reused until
+//! parent collects exit status with `wait`. Children of a process are
+//! orphaned when it is released, and orphans are released once they exit.
+//!
+//! Locks should be obtained in the order of `JOIN_LOCK`, `CHILDREN` and
+//! process slot.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::symbols::*;
+use crate::spinlock::Mutex;
+use super::{Process, ProcessState, ProcInPool, PROCS_POOL, JOIN_LOCK};
+use super::{my_proc, sleep, wakeup, kill_group, group_exited};
+
+/// Parent of an orphan
+const NO_PARENT: i32 = -1;
+
+/// A process created by `fork`
+pub struct Child {
+    /// pid of parent process, or `NO_PARENT`
+    parent: i32,
+    /// exit status, set when process exits or is killed
+    status: Option<i32>,
+    /// exit flag of its thread group
+    group: Arc<AtomicBool>,
+}
+
+/// Processes created by `fork`, indexed by pid
+pub struct ChildTable([Option<Child>; NMAXPROCS]);
+
+const NO_CHILD: Option<Child> = None;
+
+pub static CHILDREN: Mutex<ChildTable> = Mutex::new(ChildTable([NO_CHILD; NMAXPROCS]), "children");
+
+impl ChildTable {
+    /// Whether zombie `p`, whose thread group has exited, should be
+    /// released at once instead of put back to pool.
+    ///
+    /// Threads other than the first one are released, and so is the first
+    /// thread of an orphan, whose children are orphaned in turn. First
+    /// threads of other processes and kernel threads are kept, to be waited
+    /// for or joined.
+    pub fn release_exited(&mut self, p: &Process) -> bool {
+        if p.pid != p.tgid {
+            return true;
+        }
+        match &self.0[p.pid as usize] {
+            Some(child) if child.parent == NO_PARENT => {
+                self.release(p.pid);
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Remove process `pid` and orphan its children. Orphans which have
+    /// exited are released, and so are their children.
+    fn release(&mut self, pid: i32) {
+        let mut released = vec![pid];
+        while let Some(pid) = released.pop() {
+            self.0[pid as usize] = None;
+            for i in 0..NMAXPROCS {
+                let child = match &mut self.0[i] {
+                    Some(child) if child.parent == pid => child,
+                    _ => continue
+                };
+                child.parent = NO_PARENT;
+                if child.status.is_some() && take_zombie(i) {
+                    released.push(i as i32);
+                }
+            }
+        }
+    }
+}
+
+/// Release process slot `pid` if it holds a zombie. Returns whether it did.
+fn take_zombie(pid: usize) -> bool {
+    let mut slot = PROCS_POOL[pid].lock();
+    match &*slot {
+        ProcInPool::Pooling(t) if t.state == ProcessState::ZOMBIE => {
+            *slot = ProcInPool::NoProc;
+            true
+        }
+        _ => false
+    }
+}
+
+/// Channel on which threads of process `pid` wait for its children
+fn wait_channel(pid: i32) -> *const u8 {
+    (&CHILDREN as *const _ as *const u8).wrapping_add(pid as usize)
+}
+
+/// Record process `pid` forked by current process, with exit flag `group`
+pub fn add_child(pid: i32, group: &Arc<AtomicBool>) {
+    CHILDREN.lock().0[pid as usize] = Some(Child {
+        parent: my_proc().tgid,
+        status: None,
+        group: group.clone(),
+    });
+}
+
+/// Set exit status of current process, unless it has been killed
+pub fn set_exit_status(status: i32) {
+    if let Some(child) = &mut CHILDREN.lock().0[my_proc().tgid as usize] {
+        child.status.get_or_insert(status);
+    }
+}
+
+/// Move current thread to a new thread group after it calls exec, as its
+/// siblings are exiting. A killed process stays killed.
+pub fn renew_group() {
+    let p = my_proc();
+    let group = Arc::new(AtomicBool::new(false));
+    let mut children = CHILDREN.lock();
+    if let Some(child) = &mut children.0[p.tgid as usize] {
+        group.store(child.status.is_some(), Ordering::SeqCst);
+        child.group = group.clone();
+    }
+    p.group_exit = group;
+}
+
+/// Wake up parent of process `pid`, whose first thread has just become a zombie
+pub fn wakeup_parent(pid: usize) {
+    let parent = match &CHILDREN.lock().0[pid] {
+        Some(child) => child.parent,
+        None => { return; }
+    };
+    let _join_lock = JOIN_LOCK.lock();
+    wakeup(wait_channel(parent));
+}
+
+/// wait syscall
+///
+/// Wait for child process `pid`, or any child if `pid` is -1, to exit and
+/// release it. Returns its exit status.
+pub fn wait(pid: i32) -> Option<i32> {
+    if pid < -1 || pid >= NMAXPROCS as i32 {
+        return None;
+    }
+    let tgid = my_proc().tgid;
+    let mut join_lock = JOIN_LOCK.lock();
+    loop {
+        if group_exited() {
+            return None;
+        }
+        {
+            let mut children = CHILDREN.lock();
+            let mut found = false;
+            for i in 0..NMAXPROCS {
+                let status = match &children.0[i] {
+                    Some(child) if child.parent == tgid && (pid == -1 || pid as usize == i) => child.status,
+                    _ => continue
+                };
+                found = true;
+                if let Some(status) = status {
+                    if take_zombie(i) {
+                        children.release(i as i32);
+                        return Some(status);
+                    }
+                }
+            }
+            if !found {
+                return None;
+            }
+        }
+        join_lock = sleep(wait_channel(tgid), join_lock);
+    }
+}
+
+/// kill syscall
+///
+/// Make process `pid` exit with status -1. Its threads exit on their next
+/// return to user space.
+pub fn kill(pid: i32) -> Option<()> {
+    if pid < 0 || pid >= NMAXPROCS as i32 {
+        return None;
+    }
+    let group = {
+        let mut children = CHILDREN.lock();
+        let child = children.0[pid as usize].as_mut()?;
+        child.status.get_or_insert(-1);
+        child.group.clone()
+    };
+    kill_group(&group);
+    // first thread may have become a zombie before
+    wakeup_parent(pid as usize);
+    Some(())
+}
+
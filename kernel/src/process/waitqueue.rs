@@ -0,0 +1,249 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Wait queues
+//!
+//! Processes sleeping on a channel are recorded in a wait queue, so that
+//! `wakeup` only touches processes sleeping on that queue. Channels passed
+//! to `sleep` and `wakeup` are hashed into `NWAITQUEUE` shared queues.
+
+use alloc::vec::Vec;
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::panic;
+use crate::arch;
+use core::time::Duration;
+use super::{my_proc, my_cpu, sched, enqueue, ProcessState, ProcInPool, PROCS_POOL};
+
+/// A queue of sleeping processes
+pub struct WaitQueue {
+    /// channel and pid of each sleeping process
+    sleepers: Mutex<Vec<(usize, usize)>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { sleepers: Mutex::new(Vec::new(), "wait queue") }
+    }
+
+    /// Put current process into sleep on this queue
+    ///
+    /// `channel` tells processes sleeping on the same queue apart.
+    /// `wakeup` should be called with the same channel to wake it up.
+    ///
+    /// `lck` is the spinlock to be temporarily unlocked.
+    ///
+    /// Returns the `lck` spinlock.
+    ///
+    /// ## Technical Details
+    ///
+    /// To avoid the lost wakeup issue, process is added to this queue and
+    /// its slot is marked `BeingSlept` before `lck` is unlocked. Interrupt
+    /// is kept disabled until the process is put back into process pool.
+    pub fn sleep<'a, U>(&self, channel: usize, lck: MutexGuard<'a, U>) -> MutexGuard<'a, U> {
+        let p = my_proc();
+        {
+            let mut sleepers = self.sleepers.lock();
+            let mut slot = PROCS_POOL[p.pid as usize].lock();
+            match &*slot {
+                ProcInPool::Scheduled => {}
+                _ => panic!("invalid proc pool state")
+            }
+            *slot = ProcInPool::BeingSlept(p.group_exit.clone());
+            sleepers.push((channel, p.pid as usize));
+            p.channel = channel;
+            p.state = ProcessState::SLEEPING;
+        }
+        p.drop_on_put_back = Some(my_cpu().intr_lock.lock());
+
+        // temporarily unlock spinlock
+        let weak_lock = lck.into_weak();
+
+        sched();
+
+        p.channel = 0;
+        // still in queue if woken up by timer or by thread group exit
+        self.remove_waiter(channel);
+
+        weak_lock.into_guard()
+    }
+
+    /// Like `sleep`, but wake up at `deadline` if no one wakes it up before.
+    ///
+    /// Returns the `lck` spinlock, and whether deadline has passed.
+    pub fn sleep_timeout<'a, U>(&self, channel: usize, lck: MutexGuard<'a, U>, deadline: Duration) -> (MutexGuard<'a, U>, bool) {
+        if arch::time() >= deadline {
+            return (lck, true);
+        }
+        add_timeout(deadline);
+        let lck = self.sleep(channel, lck);
+        remove_timeout();
+        (lck, arch::time() >= deadline)
+    }
+
+    /// Add current process to this queue without sleeping, so that it can
+    /// wait on several queues at once. See `prepare_sleep`.
+    pub fn add_waiter(&self, channel: usize) {
+        let pid = my_proc().pid as usize;
+        self.sleepers.lock().push((channel, pid));
+    }
+
+    /// Remove current process from this queue, if it is not yet woken up
+    pub fn remove_waiter(&self, channel: usize) {
+        let pid = my_proc().pid as usize;
+        self.sleepers.lock().retain(|&x| x != (channel, pid));
+    }
+
+    /// Wake up all processes sleeping on this queue with `channel`
+    pub fn wakeup(&self, channel: usize) {
+        self.wakeup_n(channel, usize::MAX);
+    }
+
+    /// Wake up at most `n` processes sleeping on this queue with `channel`,
+    /// in the order they go to sleep. Returns number of processes woken up.
+    ///
+    /// Processes already woken up by timeout or by another queue are still
+    /// here until they remove themselves. They are skipped and not counted,
+    /// so that the wakeup goes to a process that is really sleeping.
+    pub fn wakeup_n(&self, channel: usize, n: usize) -> usize {
+        let mut sleepers = self.sleepers.lock();
+        let mut woken = 0;
+        sleepers.retain(|&(c, pid)| {
+            if c != channel || woken >= n {
+                return true;
+            }
+            if wake(pid) {
+                woken += 1;
+            }
+            false
+        });
+        woken
+    }
+}
+
+/// Make sleeping process `pid` runnable. Returns false if it is not sleeping.
+fn wake(pid: usize) -> bool {
+    let mut slot = PROCS_POOL[pid].lock();
+    match &mut *slot {
+        ProcInPool::Pooling(p) => {
+            if p.state == ProcessState::SLEEPING {
+                p.state = ProcessState::RUNNABLE;
+                enqueue(p);
+                true
+            } else {
+                false
+            }
+        }
+        // not yet put back, `put_back_proc` will make it runnable
+        ProcInPool::BeingSlept(_) => {
+            *slot = ProcInPool::Scheduled;
+            true
+        }
+        _ => false
+    }
+}
+
+/// Mark current process as sleeping, before checking the condition it
+/// waits for. Used to sleep on several wait queues at once:
+///
+/// 1. `add_waiter` on each queue
+/// 2. `prepare_sleep`
+/// 3. check condition, then `cancel_sleep` if it holds, or `sleep_prepared`
+/// 4. `remove_waiter` on each queue
+///
+/// Wakeups after `prepare_sleep` are not lost, as the slot is marked
+/// `BeingSlept`, and `sleep_prepared` will return immediately.
+pub fn prepare_sleep() {
+    let p = my_proc();
+    // interrupt is kept disabled until the process is put back
+    p.drop_on_put_back = Some(my_cpu().intr_lock.lock());
+    let mut slot = PROCS_POOL[p.pid as usize].lock();
+    match &*slot {
+        ProcInPool::Scheduled => {}
+        _ => panic!("invalid proc pool state")
+    }
+    *slot = ProcInPool::BeingSlept(p.group_exit.clone());
+    p.state = ProcessState::SLEEPING;
+}
+
+/// Sleep until woken up after `prepare_sleep`
+pub fn sleep_prepared() {
+    sched();
+}
+
+/// Stay running after `prepare_sleep`
+pub fn cancel_sleep() {
+    let p = my_proc();
+    {
+        let mut slot = PROCS_POOL[p.pid as usize].lock();
+        *slot = ProcInPool::Scheduled;
+        p.state = ProcessState::RUNNING;
+    }
+    p.drop_on_put_back = None;
+}
+
+/// Wake up current process at `deadline`
+pub fn add_timeout(deadline: Duration) {
+    let pid = my_proc().pid as usize;
+    TIMEOUTS.lock().push((deadline, pid));
+}
+
+/// Remove deadline of current process
+pub fn remove_timeout() {
+    let pid = my_proc().pid as usize;
+    TIMEOUTS.lock().retain(|&(_, x)| x != pid);
+}
+
+/// Deadline and pid of processes sleeping with timeout
+static TIMEOUTS: Mutex<Vec<(Duration, usize)>> = Mutex::new(Vec::new(), "timeouts");
+
+/// Wake up processes whose sleep deadline has passed. Called on timer interrupt.
+///
+/// `TIMEOUTS` is held while waking up, so that a process which has been
+/// woken up by others and has removed its deadline won't be woken up again.
+pub fn wakeup_timeouts() {
+    let now = arch::time();
+    let mut timeouts = TIMEOUTS.lock();
+    timeouts.retain(|&(deadline, pid)| {
+        if deadline > now {
+            return true;
+        }
+        wake(pid);
+        false
+    });
+}
+
+/// Number of wait queues for `sleep` and `wakeup`
+pub const NWAITQUEUE: usize = 64;
+
+const EMPTY_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+static WAIT_QUEUES: [WaitQueue; NWAITQUEUE] = [EMPTY_WAIT_QUEUE; NWAITQUEUE];
+
+/// Wait queue of `channel`
+fn wait_queue_of(channel: usize) -> &'static WaitQueue {
+    // channels are usually addresses of aligned objects
+    &WAIT_QUEUES[(channel >> 3) % NWAITQUEUE]
+}
+
+/// put this process into sleep state
+///
+/// `channel` is an identifier of sleep lock channel. `wakeup` should be called with the same
+/// channel to properly wakeup previously slept process.
+///
+/// `lck` is the spinlock to be temporarily unlocked.
+///
+/// Returns the `lck` spinlock.
+pub fn sleep<T, U>(channel: *const T, lck: MutexGuard<U>) -> MutexGuard<U> {
+    let channel = channel as *const _ as usize;
+    wait_queue_of(channel).sleep(channel, lck)
+}
+
+/// wakeup process on channel
+///
+/// `channel` is an identifier of sleep lock channel. Should be the same as in `sleep`.
+pub fn wakeup<T>(channel: *const T) {
+    let channel = channel as *const _ as usize;
+    wait_queue_of(channel).wakeup(channel);
+}
@@ -0,0 +1,237 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Kernel entropy pool
+//!
+//! Random bytes are ChaCha20 keystream under a key held by pool, into which
+//! entropy from hardware sources and writes to `/dev/random` are mixed.
+//! Key is replaced after each read with a block which is never output, so
+//! that bytes read earlier can't be recovered from pool state.
+//! If a hardware source is attached, reads wait until pool has been seeded
+//! with `SEED_BITS` of entropy, and never block afterwards.
+
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+use crate::process::WaitQueue;
+use crate::arch;
+
+/// Entropy needed before pool is considered seeded
+pub const SEED_BITS: usize = 256;
+/// Entropy estimate below which hardware sources are asked for more
+const REFILL_BITS: usize = 1024;
+/// Maximum entropy estimate
+const POOL_BITS: usize = 4096;
+
+/// A hardware source of entropy
+pub trait EntropySource: Sync {
+    /// Ask source for more entropy, which is later passed to `add_entropy`.
+    /// Must not block.
+    fn request(&self);
+}
+
+/// ChaCha20 quarter round
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// ChaCha20 block function of RFC 8439
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: [u32; 3]) -> [u32; 16] {
+    let mut init = [0; 16];
+    init[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    init[4..12].copy_from_slice(key);
+    init[12] = counter;
+    init[13..].copy_from_slice(&nonce);
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    for (x, y) in s.iter_mut().zip(init.iter()) {
+        *x = x.wrapping_add(*y);
+    }
+    s
+}
+
+/// Nonce of blocks output, and of block replacing key after output
+const OUTPUT_NONCE: [u32; 3] = [0, 0, 0];
+/// Nonce of block replacing key after mixing
+const MIX_NONCE: [u32; 3] = [1, 0, 0];
+
+struct Pool {
+    /// ChaCha20 key
+    key: [u32; 8],
+    /// estimated bits of entropy in pool
+    entropy: usize,
+    seeded: bool,
+}
+
+impl Pool {
+    /// Replace key with first half of block 0 under `nonce`
+    fn rekey(&mut self, nonce: [u32; 3]) {
+        let block = chacha20_block(&self.key, 0, nonce);
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    /// Mix `data` into key. Each 32 bytes of it are XORed into key, which is
+    /// then replaced, so that earlier keys can't be recovered.
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (word, bytes) in self.key.iter_mut().zip(chunk.chunks(4)) {
+                let mut x = [0; 4];
+                x[..bytes.len()].copy_from_slice(bytes);
+                *word ^= u32::from_le_bytes(x);
+            }
+            self.rekey(MIX_NONCE);
+        }
+    }
+
+    /// Fill `content` with keystream from block 1, and replace key with
+    /// block 0, which is not output
+    fn generate(&mut self, content: &mut [u8]) {
+        if self.key.iter().all(|&x| x == 0) {
+            self.mix(&(arch::time().as_nanos() as u64).to_le_bytes());
+        }
+        for (i, chunk) in content.chunks_mut(64).enumerate() {
+            let block = chacha20_block(&self.key, i as u32 + 1, OUTPUT_NONCE);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        self.rekey(OUTPUT_NONCE);
+    }
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool { key: [0; 8], entropy: 0, seeded: false }, "entropy pool");
+static SOURCES: Mutex<Vec<&'static dyn EntropySource>> = Mutex::new(Vec::new(), "entropy sources");
+/// Readers waiting for pool to be seeded
+static SEED_WAIT: WaitQueue = WaitQueue::new();
+
+fn seed_channel() -> usize {
+    &POOL as *const _ as usize
+}
+
+/// Attach a hardware entropy source, and ask it for entropy to seed pool
+pub fn attach(source: &'static dyn EntropySource) {
+    SOURCES.lock().push(source);
+    source.request();
+}
+
+/// Ask all sources for more entropy
+fn request_all() {
+    let sources = SOURCES.lock().clone();
+    for source in sources {
+        source.request();
+    }
+}
+
+/// Mix `data` carrying `bits` of entropy into pool. May be called in
+/// interrupt context.
+pub fn add_entropy(data: &[u8], bits: usize) {
+    let mut pool = POOL.lock();
+    pool.mix(data);
+    pool.entropy = (pool.entropy + bits).min(POOL_BITS);
+    if !pool.seeded && pool.entropy >= SEED_BITS {
+        pool.seeded = true;
+        SEED_WAIT.wakeup(seed_channel());
+    }
+}
+
+/// Estimated bits of entropy in pool
+pub fn entropy() -> usize {
+    POOL.lock().entropy
+}
+
+/// Fill `content` with random bytes, waiting for pool to be seeded if
+/// there's a hardware source
+pub fn fill(content: &mut [u8]) {
+    let has_source = !SOURCES.lock().is_empty();
+    let mut pool = POOL.lock();
+    while has_source && !pool.seeded {
+        pool = SEED_WAIT.sleep(seed_channel(), pool);
+    }
+    pool.generate(content);
+    pool.entropy = pool.entropy.saturating_sub(content.len() * 8);
+    let refill = pool.entropy < REFILL_BITS;
+    drop(pool);
+    if refill {
+        request_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test random bytes are not all the same
+    #[test_case]
+    fn test_fill() {
+        let mut a = [0; 32];
+        let mut b = [0; 32];
+        fill(&mut a);
+        fill(&mut b);
+        assert_ne!(a, [0; 32]);
+        assert_ne!(a, b);
+    }
+
+    /// Test ChaCha20 block function with test vector of RFC 8439
+    #[test_case]
+    fn test_chacha20() {
+        let mut key = [0; 8];
+        for (i, word) in key.iter_mut().enumerate() {
+            let x = (i * 4) as u32;
+            *word = u32::from_le_bytes([x as u8, x as u8 + 1, x as u8 + 2, x as u8 + 3]);
+        }
+        let block = chacha20_block(&key, 1, [0x09000000, 0x4a000000, 0]);
+        assert_eq!(block, [
+            0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3,
+            0xc7f4d1c7, 0x0368c033, 0x9aaa2204, 0x4e6cd4c3,
+            0x466482d2, 0x09aa9f07, 0x05d7c214, 0xa2028bd9,
+            0xd19c12b5, 0xb94e16de, 0xe883d0cb, 0x4e3c50a2,
+        ]);
+    }
+
+    /// Test key is replaced after each read
+    #[test_case]
+    fn test_rekey() {
+        let mut pool = Pool { key: [1, 2, 3, 4, 5, 6, 7, 8], entropy: 0, seeded: false };
+        let mut a = [0; 100];
+        let mut b = [0; 100];
+        pool.generate(&mut a);
+        let key = pool.key;
+        assert_ne!(key, [1, 2, 3, 4, 5, 6, 7, 8]);
+        pool.generate(&mut b);
+        assert_ne!(pool.key, key);
+        assert_ne!(a[..], b[..]);
+    }
+
+    /// Test mixing changes key and raises entropy estimate
+    #[test_case]
+    fn test_mix() {
+        let mut pool = Pool { key: [1, 2, 3, 4, 5, 6, 7, 8], entropy: 0, seeded: false };
+        let mut other = Pool { key: [1, 2, 3, 4, 5, 6, 7, 8], entropy: 0, seeded: false };
+        pool.mix(b"entropy");
+        assert_ne!(pool.key, other.key);
+        let (mut a, mut b) = ([0; 8], [0; 8]);
+        pool.generate(&mut a);
+        other.generate(&mut b);
+        assert_ne!(a, b);
+        let before = entropy();
+        add_entropy(&[0x5a; 8], 8);
+        assert!(entropy() >= before.min(POOL_BITS - 8));
+    }
+}
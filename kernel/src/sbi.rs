@@ -0,0 +1,151 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! RISC-V Supervisor Binary Interface
+//!
+//! With feature `sbi`, kernel is started in supervisor mode by firmware
+//! such as OpenSBI, which owns machine mode. Timer, inter-processor
+//! interrupts and starting harts then go through SBI calls.
+
+use crate::symbols::SCHEDULER_INTERVAL;
+use crate::arch::hart_id;
+use crate::{panic, info};
+
+/// Legacy console extension, which has no function ID
+const EID_CONSOLE_PUTCHAR: usize = 0x01;
+const EID_BASE: usize = 0x10;
+const EID_TIME: usize = 0x54494D45;
+const EID_IPI: usize = 0x735049;
+const EID_HSM: usize = 0x48534D;
+const EID_SRST: usize = 0x53525354;
+
+const BASE_PROBE_EXTENSION: usize = 3;
+const TIME_SET_TIMER: usize = 0;
+const IPI_SEND_IPI: usize = 0;
+const HSM_HART_START: usize = 0;
+const HSM_HART_STATUS: usize = 2;
+const SRST_SYSTEM_RESET: usize = 0;
+
+/// Reset types and reasons of SRST extension
+pub const RESET_TYPE_SHUTDOWN: usize = 0;
+pub const RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const RESET_REASON_NONE: usize = 0;
+pub const RESET_REASON_FAILURE: usize = 1;
+
+/// Error codes, SBI spec 3
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+/// State of a running hart in HSM extension
+pub const HART_STARTED: usize = 0;
+
+/// Call SBI function `fid` of extension `eid`. Returns error and value.
+#[inline(always)]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}"(error), "={x11}"(value)
+            : "{x10}"(arg0), "{x11}"(arg1), "{x12}"(arg2), "{x16}"(fid), "{x17}"(eid)
+            : "memory"
+            : "volatile");
+    }
+    (error, value)
+}
+
+fn sbi_result(ret: (isize, usize)) -> Result<usize, isize> {
+    match ret {
+        (0, value) => Ok(value),
+        (error, _) => Err(error)
+    }
+}
+
+/// Check if firmware implements extension `eid`
+pub fn probe_extension(eid: usize) -> bool {
+    sbi_call(EID_BASE, BASE_PROBE_EXTENSION, eid, 0, 0).1 != 0
+}
+
+/// Program timer interrupt of current hart at `stime`
+pub fn set_timer(stime: u64) {
+    sbi_call(EID_TIME, TIME_SET_TIMER, stime as usize, 0, 0);
+}
+
+/// Send software interrupt to harts in `hart_mask`
+pub fn send_ipi(hart_mask: usize) {
+    sbi_call(EID_IPI, IPI_SEND_IPI, hart_mask, 0, 0);
+}
+
+/// Start stopped hart `hartid` in supervisor mode at `start_addr`, with
+/// hart ID in a0 and `opaque` in a1
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), isize> {
+    sbi_result(sbi_call(EID_HSM, HSM_HART_START, hartid, start_addr, opaque)).map(|_| ())
+}
+
+/// State of hart `hartid`, such as `HART_STARTED`
+pub fn hart_status(hartid: usize) -> Result<usize, isize> {
+    sbi_result(sbi_call(EID_HSM, HSM_HART_STATUS, hartid, 0, 0))
+}
+
+/// Shut down or reboot system by `reset_type`. Returns only if firmware
+/// can't do that.
+pub fn system_reset(reset_type: usize, reason: usize) {
+    if probe_extension(EID_SRST) {
+        sbi_call(EID_SRST, SRST_SYSTEM_RESET, reset_type, reason, 0);
+    }
+}
+
+/// Write a character to firmware console
+pub fn console_putchar(c: u8) {
+    sbi_call(EID_CONSOLE_PUTCHAR, 0, c as usize, 0, 0);
+}
+
+/// Schedule next timer interrupt of current hart
+pub fn next_timer() {
+    let now = riscv::register::time::read() as u64;
+    set_timer(now + SCHEDULER_INTERVAL as u64);
+}
+
+/// Start timer interrupt of current hart
+pub fn timer_init() {
+    if !probe_extension(EID_TIME) {
+        panic!("SBI timer extension not supported");
+    }
+    next_timer();
+}
+
+/// Start all other harts at `entry`
+pub fn start_harts(harts: usize, entry: usize) {
+    for hart in 0..harts {
+        if hart == hart_id() {
+            continue;
+        }
+        match hart_start(hart, entry, 0) {
+            Ok(()) | Err(SBI_ERR_ALREADY_AVAILABLE) => {}
+            Err(err) => info!("failed to start hart {}: SBI error {}", hart, err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test extensions used by kernel are implemented
+    #[test_case]
+    fn test_probe() {
+        assert!(probe_extension(EID_TIME));
+        assert!(probe_extension(EID_IPI));
+        assert!(probe_extension(EID_HSM));
+        assert!(!probe_extension(0x0abcdef));
+    }
+
+    /// Test current hart is reported as started
+    #[test_case]
+    fn test_hsm() {
+        assert_eq!(hart_status(hart_id()), Ok(HART_STARTED));
+        assert_eq!(hart_status(usize::max_value()), Err(SBI_ERR_INVALID_PARAM));
+    }
+}
@@ -8,6 +8,8 @@
 use crate::spinlock::{Mutex, MutexGuard};
 use crate::process::{sleep, my_proc, wakeup};
 use crate::info;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
 
 /// locked, pid
 struct SleepLockInfo {
@@ -21,27 +23,41 @@ impl SleepLockInfo {
     }
 }
 
-pub struct SleepLock {
+/// A lock protecting `data`, which puts current process into sleep while
+/// waiting. Interrupt is not disabled while it is held, so its holder may
+/// sleep, e.g. waiting for disk.
+pub struct SleepLock<T> {
     spin: Mutex<SleepLockInfo>,
     name: &'static str,
+    data: UnsafeCell<T>,
 }
 
-pub struct SleepLockGuard<'a> {
-    lock: &'a SleepLock,
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
 }
 
-impl SleepLock {
-    pub const fn new(name: &'static str) -> Self {
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+
+unsafe impl<T: Send> Send for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
         Self {
             spin: Mutex::new(SleepLockInfo::new(false, 0), "sleep lock"),
             name,
+            data: UnsafeCell::new(data),
         }
     }
 
-    pub fn acquire(&mut self) -> SleepLockGuard {
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    pub fn acquire(&self) -> SleepLockGuard<T> {
         let mut lk = self.spin.lock();
         while lk.locked {
-            lk = sleep(&self, lk);
+            lk = sleep(self as *const Self, lk);
         }
         lk.locked = true;
         lk.pid = my_proc().pid;
@@ -54,13 +70,27 @@ impl SleepLock {
         let lk = self.spin.lock();
         lk.locked && lk.pid == my_proc().pid
     }
+
+    /// Directly get data regardless whether it is locked or not
+    pub unsafe fn get(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &*self.lock.data.get() } }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.lock.data.get() } }
 }
 
-impl Drop for SleepLockGuard<'_> {
+impl<T> Drop for SleepLockGuard<'_, T> {
     fn drop(&mut self) {
         let mut lk = self.lock.spin.lock();
         lk.locked = false;
         lk.pid = 0;
-        wakeup(&self.lock);
+        wakeup(self.lock as *const SleepLock<T>);
     }
 }
@@ -168,3 +168,37 @@ impl<'a, T: ?Sized> WeakMutexGuard<'a, T> {
         self.mutex.lock()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test locking and unlocking
+    #[test_case]
+    fn test_lock() {
+        let mutex = Mutex::new(0, "test");
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(unsafe { mutex.holding() });
+        }
+        assert!(!unsafe { mutex.holding() });
+        *mutex.lock() += 1;
+        assert_eq!(mutex.into_inner(), 2);
+    }
+
+    /// Test interrupt is off while any lock is held
+    #[test_case]
+    fn test_intr() {
+        let enabled = arch::intr_get();
+        let (a, b) = (Mutex::new((), "a"), Mutex::new((), "b"));
+        let guard_a = a.lock();
+        {
+            let _guard_b = b.lock();
+            assert!(!arch::intr_get());
+        }
+        assert!(!arch::intr_get());
+        drop(guard_a);
+        assert_eq!(arch::intr_get(), enabled);
+    }
+}
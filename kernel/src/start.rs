@@ -7,14 +7,33 @@
 
 use riscv::{asm, register::*};
 use crate::arch::{hart_id, wait_forever};
-use crate::{clint, plic, mem, uart, process, spinlock, trap, virtio};
+use crate::{plic, mem, uart, process, spinlock, trap, virtio, file, fdt};
+#[cfg(not(feature = "sbi"))]
+use crate::clint;
+#[cfg(feature = "sbi")]
+use crate::sbi;
+use crate::symbols::TEXT_START;
 use crate::info;
 use crate::jump::*;
 
-/// Initialize kernel page table and drivers in machine mode,
-/// and prepare to switch to supervisor mode
+/// Set when boot hart has parsed device tree. It is in data section, as
+/// other harts check it while boot hart clears bss.
+#[cfg(not(feature = "sbi"))]
+#[link_section = ".data"]
+static mut MACHINE_READY: bool = false;
+
+/// Parse device tree at `dtb` passed by firmware, initialize timer in
+/// machine mode, and prepare to switch to supervisor mode
+#[cfg(not(feature = "sbi"))]
 #[no_mangle]
-unsafe extern "C" fn kinit() {
+unsafe extern "C" fn kinit(dtb: usize) {
+    if mhartid::read() == 0 {
+        fdt::init(dtb, TEXT_START());
+        asm!("fence");
+        core::ptr::write_volatile(&mut MACHINE_READY, true);
+    } else {
+        while !core::ptr::read_volatile(&MACHINE_READY) {}
+    }
     // next mode is supervisor mode
     mstatus::set_mpp(mstatus::MPP::Supervisor);
     // mret jump to kmain
@@ -35,6 +54,15 @@ unsafe extern "C" fn kinit() {
     asm!("mret");
 }
 
+/// Parse device tree at `dtb` passed by firmware. Under SBI, kernel is
+/// already in supervisor mode, and only boot hart gets here.
+#[cfg(feature = "sbi")]
+#[no_mangle]
+unsafe extern "C" fn kinit(dtb: usize) -> ! {
+    fdt::init(dtb, TEXT_START());
+    kmain()
+}
+
 /// Controls whether other harts may start boot procedure
 static mut MAY_BOOT: bool = false;
 
@@ -45,10 +73,18 @@ extern "C" fn kmain() -> ! {
         unsafe { uart::init(); }
         info!("booting core-os on hart {}...", hart_id());
         info!("  UART... \x1b[0;32minitialized\x1b[0m");
+        let machine = fdt::machine();
+        if machine.dtb == 0 {
+            info!("  no device tree, assuming QEMU virt machine");
+        }
+        info!("  {} harts, RAM 0x{:x} -> 0x{:x}, {} virt-io slots",
+            machine.harts, machine.memory.0, machine.memory.1, machine.virtio_count);
         unsafe { mem::init(); }
         info!("  kernel page table... \x1b[0;32minitialized\x1b[0m");
         unsafe { virtio::init(); }
         info!("  virt-io... \x1b[0;32minitialized\x1b[0m");
+        file::device::init();
+        info!("  device drivers... \x1b[0;32minitialized\x1b[0m");
         unsafe { plic::init(); }
         info!("  PLIC... \x1b[0;32minitialized\x1b[0m");
         mem::hartinit();
@@ -65,6 +101,8 @@ extern "C" fn kmain() -> ! {
             asm!("fence");
             MAY_BOOT = true
         }
+        #[cfg(feature = "sbi")]
+        sbi::start_harts(fdt::machine().harts, crate::symbols::_start_hart as usize);
     } else {
         loop {
             if unsafe { MAY_BOOT } == true {
@@ -76,5 +114,7 @@ extern "C" fn kmain() -> ! {
         unsafe { trap::hartinit(); }
         plic::hartinit();
     }
+    #[cfg(feature = "sbi")]
+    sbi::timer_init();
     process::scheduler()
 }
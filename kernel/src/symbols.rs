@@ -20,13 +20,12 @@ extern "C" {
 	pub fn kernelvec();
 	/// `m_trap_vector` function in `trap.S`
 	pub fn timervec();
+	/// entry of harts started by SBI in `boot.S`
+	#[cfg(feature = "sbi")]
+	pub fn _start_hart();
 }
 
-/// Page order
-pub const PAGE_ORDER: usize = 12;
-
-/// Page size
-pub const PAGE_SIZE: usize = 1 << PAGE_ORDER;
+pub use kernel_core::{PAGE_ORDER, PAGE_SIZE};
 
 /// Maximum virtual address supported on Sv39
 pub const MAXVA: usize = 1 << (9 + 9 + 9 + 12 - 1);
@@ -34,9 +33,51 @@ pub const MAXVA: usize = 1 << (9 + 9 + 9 + 12 - 1);
 /// Address to map kernel and user trampoline
 pub const TRAMPOLINE_START: usize = MAXVA - PAGE_SIZE;
 
-/// Address to map trapframe
+/// Address to map trapframe of process slot 0
 pub const TRAPFRAME_START: usize = TRAMPOLINE_START - PAGE_SIZE;
 
+/// Address to map trapframe of process slot `id` in user page table.
+///
+/// Threads share one page table, so each of them maps its own
+/// trapframe at a different address.
+#[allow(non_snake_case)]
+pub const fn TRAPFRAME(id: usize) -> usize {
+    TRAPFRAME_START - id * PAGE_SIZE
+}
+
+/// Number of pages of each kernel stack
+pub const KSTACK_PAGE: usize = 4;
+
+/// Bottom of kernel stack of process slot `id`.
+///
+/// Kernel stacks are mapped below trampoline in kernel page table.
+/// Each of them has an unmapped guard page below, so that a kernel
+/// stack overflow will cause page fault instead of corrupting memory.
+#[allow(non_snake_case)]
+pub const fn KSTACK(id: usize) -> usize {
+    TRAMPOLINE_START - (id + 1) * (KSTACK_PAGE + 1) * PAGE_SIZE + PAGE_SIZE
+}
+
+/// Get process slot whose kernel stack guard page contains `vaddr`
+pub fn kstack_guard_of(vaddr: usize) -> Option<usize> {
+    if vaddr < KSTACK(NMAXPROCS - 1) - PAGE_SIZE || vaddr >= TRAMPOLINE_START {
+        return None;
+    }
+    let id = (TRAMPOLINE_START - 1 - vaddr) / ((KSTACK_PAGE + 1) * PAGE_SIZE);
+    if vaddr < KSTACK(id) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// Top of user stack. One unmapped page is left between
+/// user stack and the lowest trapframe.
+pub const USER_STACK_TOP: usize = TRAPFRAME(NMAXPROCS - 1) - PAGE_SIZE;
+
+/// Maximum number of pages user stack may grow to
+pub const USER_STACK_MAX_PAGE: usize = 256;
+
 /// Maximum supported CPU on machine
 pub const NCPUS : usize = 8;
 
@@ -65,6 +106,6 @@ pub fn print_map_symbols() {
 	println!(
 		"HEAP:   0x{:x} -> 0x{:x}",
 		HEAP_START(),
-		HEAP_START() + HEAP_SIZE()
+		crate::mem::ALLOC().lock().end()
 	);
 }
@@ -7,8 +7,6 @@
 
 extern "C" { static __heap_start: usize; }
 #[inline] pub fn HEAP_START() -> usize { unsafe { &__heap_start as *const _ as _ } }
-extern "C" { static __heap_size: usize; }
-#[inline] pub fn HEAP_SIZE() -> usize { unsafe { &__heap_size as *const _ as _ } }
 extern "C" { static __text_start: usize; }
 #[inline] pub fn TEXT_START() -> usize { unsafe { &__text_start as *const _ as _ } }
 extern "C" { static __text_end: usize; }
@@ -31,3 +29,7 @@ extern "C" { static __kernel_stack_end: usize; }
 #[inline] pub fn KERNEL_STACK_END() -> usize { unsafe { &__kernel_stack_end as *const _ as _ } }
 extern "C" { static __trampoline_text_start: usize; }
 #[inline] pub fn TRAMPOLINE_TEXT_START() -> usize { unsafe { &__trampoline_text_start as *const _ as _ } }
+extern "C" { static __ksyms_start: usize; }
+#[inline] pub fn KSYMS_START() -> usize { unsafe { &__ksyms_start as *const _ as _ } }
+extern "C" { static __ksyms_end: usize; }
+#[inline] pub fn KSYMS_END() -> usize { unsafe { &__ksyms_end as *const _ as _ } }
@@ -15,18 +15,58 @@
 
 mod gen;
 mod file;
+mod mm;
+mod net;
 
 pub use gen::*;
-use crate::process::{TrapFrame, Register, my_proc, fork, exec, exit, Process};
+use crate::process::{TrapFrame, Register, my_proc, fork, exec, exit, Process, PageFault};
+use crate::process::{setpriority, getpriority, cputime, setaffinity, getaffinity, clone, join, thread_exit};
+use crate::process::{futex_wait, futex_wake, FutexError, wait, kill};
+use core::time::Duration;
 use crate::{info, panic, print, println};
 use crate::page;
+use crate::power;
 use crate::mem::{align_val, page_down};
 use crate::symbols::{PAGE_ORDER, PAGE_SIZE};
 use file::*;
+use mm::*;
+use net::*;
 use alloc::sync::Arc;
 use crate::file::File;
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
 use crate::spinlock::Mutex;
+use crate::file::{FsFile, DirEntry};
+
+/// No such file or directory
+pub const ENOENT: i32 = 2;
+/// No such process
+pub const ESRCH: i32 = 3;
+/// Interrupted system call
+pub const EINTR: i32 = 4;
+/// Bad file descriptor
+pub const EBADF: i32 = 9;
+/// No child processes
+pub const ECHILD: i32 = 10;
+/// Out of memory
+pub const ENOMEM: i32 = 12;
+/// Permission denied
+pub const EACCES: i32 = 13;
+/// Bad address
+pub const EFAULT: i32 = 14;
+/// File exists
+pub const EEXIST: i32 = 17;
+/// Invalid argument
+pub const EINVAL: i32 = 22;
+/// No space left on device
+pub const ENOSPC: i32 = 28;
+/// Broken pipe
+pub const EPIPE: i32 = 32;
+/// Function not implemented
+pub const ENOSYS: i32 = 38;
 
 /// Get the `pos`th argument from syscall
 pub fn argraw(tf: &TrapFrame, pos: usize) -> usize {
@@ -46,39 +86,107 @@ pub fn arg_int(tf: &TrapFrame, pos: usize) -> i32 {
     argraw(tf, pos) as i32
 }
 
-/// Get the `pos`th argument as usize from syscall
-pub fn arg_uint(tf: &TrapFrame, pos: usize) -> usize {
+/// Get the `pos`th argument as usize from syscall. Returns `-EINVAL` if it
+/// is negative as i32.
+pub fn arg_uint(tf: &TrapFrame, pos: usize) -> Result<usize, i32> {
     let sz = argraw(tf, pos) as i32;
     if sz < 0 {
-        panic!("invalid unsigned int");
+        return Err(-EINVAL);
+    }
+    Ok(sz as usize)
+}
+
+/// Get the `pos`th argument as timeout in microseconds. Negative value means no timeout.
+pub fn arg_timeout(tf: &TrapFrame, pos: usize) -> Option<Duration> {
+    let timeout = argraw(tf, pos) as i64;
+    if timeout < 0 {
+        None
+    } else {
+        Some(Duration::from_micros(timeout as u64))
     }
-    sz as usize
 }
 
-/// Get the `pos`th argument as a pointer from syscall, return kernel-space pointer (involve security issues!)
-pub fn arg_ptr(pgtable: &page::Table, tf: &TrapFrame, pos: usize, sz: usize) -> *const u8 {
-    let ptr = argraw(tf, pos);
-    let pg_begin = page_down(ptr);
-    if ptr + sz >= pg_begin + PAGE_SIZE {
-        panic!("out of bound!");
+/// Largest user buffer copied into kernel by one syscall. Syscalls which
+/// may transfer less than asked, like `sendto`, shorten larger buffers.
+pub const MAX_USER_BUF: usize = 64 * 1024;
+
+/// Call `f` with physical address, offset and length of each part of user
+/// buffer at `vaddr` of `sz` bytes that lies in one page, allocating pages
+/// not yet mapped. Returns `-EFAULT` if any page can't be accessed by `fault`.
+fn for_each_user_page<F>(p: &Process, vaddr: usize, sz: usize, fault: PageFault, mut f: F) -> Result<(), i32>
+    where F: FnMut(usize, usize, usize) {
+    let end = match vaddr.checked_add(sz) {
+        Some(end) => end,
+        None => { return Err(-EFAULT); }
+    };
+    let mut mm = p.mm.acquire();
+    let mut addr = vaddr;
+    while addr < end {
+        let len = core::cmp::min(page_down(addr) + PAGE_SIZE, end) - addr;
+        match mm.user_paddr(addr, fault) {
+            Some(paddr) => f(paddr, addr - vaddr, len),
+            None => { return Err(-EFAULT); }
+        }
+        addr += len;
     }
-    let paddr = pgtable.paddr_of(pg_begin).unwrap();
-    unsafe { (paddr as *const u8).add(ptr - pg_begin) }
+    Ok(())
+}
+
+/// Check that user buffer at `vaddr` of `sz` bytes can be written, so that
+/// a syscall may fail before it has any effect
+pub fn check_user_mut(p: &Process, vaddr: usize, sz: usize) -> Result<(), i32> {
+    for_each_user_page(p, vaddr, sz, PageFault::Store, |_, _, _| {})
+}
+
+/// Copy user buffer at `vaddr` into `buf`
+pub fn copy_in(p: &Process, vaddr: usize, buf: &mut [u8]) -> Result<(), i32> {
+    for_each_user_page(p, vaddr, buf.len(), PageFault::Load, |paddr, offset, len| {
+        let src = unsafe { core::slice::from_raw_parts(paddr as *const u8, len) };
+        buf[offset..offset + len].copy_from_slice(src);
+    })
 }
 
-/// Get the `pos`th argument as a pointer from syscall, return kernel-space mutable pointer (involve security issues!)
-pub fn arg_ptr_mut(pgtable: &page::Table, tf: &TrapFrame, pos: usize, sz: usize) -> *mut u8 {
-    arg_ptr(pgtable, tf, pos, sz) as *mut u8
+/// Copy `buf` to user buffer at `vaddr`
+pub fn copy_out(p: &Process, vaddr: usize, buf: &[u8]) -> Result<(), i32> {
+    for_each_user_page(p, vaddr, buf.len(), PageFault::Store, |paddr, offset, len| {
+        let dst = unsafe { core::slice::from_raw_parts_mut(paddr as *mut u8, len) };
+        dst.copy_from_slice(&buf[offset..offset + len]);
+    })
 }
 
+/// Read a `T` from user address `vaddr`
+pub fn read_user<T: Copy>(p: &Process, vaddr: usize) -> Result<T, i32> {
+    let mut val = core::mem::MaybeUninit::<T>::uninit();
+    let buf = unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_in(p, vaddr, buf)?;
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Write `val` to user address `vaddr`
+pub fn write_user<T: Copy>(p: &Process, vaddr: usize, val: &T) -> Result<(), i32> {
+    let buf = unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+    copy_out(p, vaddr, buf)
+}
 
-/// Get file corresponding to a file descriptor
-pub fn arg_fd(p: &Process, pos: usize) -> &Arc<File> {
-    let fd = argraw(&p.trapframe, pos);
-    match &p.files[fd] {
-        Some(x) => return x,
-        None => panic!("invalid file handler {}", fd)
+/// Get the `pos`th argument as a user buffer of `sz` bytes, copied into
+/// kernel. Buffers larger than `MAX_USER_BUF` are rejected with `-EINVAL`.
+pub fn arg_buf(p: &Process, pos: usize, sz: usize) -> Result<Vec<u8>, i32> {
+    if sz > MAX_USER_BUF {
+        return Err(-EINVAL);
     }
+    let mut buf = vec![0; sz];
+    copy_in(p, argraw(&p.trapframe, pos), &mut buf)?;
+    Ok(buf)
+}
+
+/// Get the `pos`th argument as a string of `sz` bytes, such as a path
+pub fn arg_str(p: &Process, pos: usize, sz: usize) -> Result<String, i32> {
+    String::from_utf8(arg_buf(p, pos, sz)?).map_err(|_| -EINVAL)
+}
+
+/// Get file corresponding to a file descriptor, or `None` if it is not open
+pub fn arg_fd(p: &Process, pos: usize) -> Option<Arc<File>> {
+    p.file(argraw(&p.trapframe, pos))
 }
 
 /// fork syscall entry
@@ -86,25 +194,32 @@ fn sys_fork() -> i32 {
     fork()
 }
 
-/// exec syscall entry
+/// exec syscall entry, which returns only if `path` is not a file
 fn sys_exec() -> i32 {
     let path;
     {
         let p = my_proc();
-        let sz = arg_uint(&p.trapframe, 1);
-        let ptr = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
-        path = unsafe {
-            // First, we build a &[u8]...
-            let slice = core::slice::from_raw_parts(ptr, sz);
-            // ... and then convert that slice into a string slice
-            core::str::from_utf8(slice).unwrap()
+        let sz = match arg_uint(&p.trapframe, 1) {
+            Ok(sz) => sz,
+            Err(err) => { return err; }
+        };
+        path = match arg_str(p, 0, sz) {
+            Ok(path) => path,
+            Err(err) => { return err; }
         };
     }
-    if path == "/init" {
-        info!("running tests before init...");
-        crate::test::run_tests();
+    #[cfg(test)]
+    {
+        if path == "/init" {
+            info!("running tests before init...");
+            crate::test_main();
+        }
+    }
+    match FsFile::stat(&path) {
+        Some(DirEntry::File { .. }) => {}
+        _ => { return -ENOENT; }
     }
-    exec(path);
+    exec(&path);
     0
 }
 
@@ -118,8 +233,187 @@ fn sys_exit() -> i32 {
     exit(code);
 }
 
+/// thread_exit syscall entry
+fn sys_thread_exit() -> i32 {
+    let code;
+    {
+        let p = my_proc();
+        code = arg_int(&p.trapframe, 0);
+    }
+    thread_exit(code);
+}
+
+/// wait syscall entry
+fn sys_wait() -> i32 {
+    let pid;
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+    }
+    match wait(pid) {
+        Some(status) => status,
+        None => -ECHILD
+    }
+}
+
+/// kill syscall entry
+fn sys_kill() -> i32 {
+    let pid;
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+    }
+    match kill(pid) {
+        Some(()) => 0,
+        None => -ESRCH
+    }
+}
+
+/// reboot syscall entry, which halts, powers off or restarts system by
+/// command, and returns only if command is invalid
+fn sys_reboot() -> i32 {
+    let cmd;
+    {
+        let p = my_proc();
+        cmd = argraw(&p.trapframe, 0) as u32;
+    }
+    match power::action_of(cmd) {
+        Some(action) => power::shutdown(action),
+        None => -EINVAL
+    }
+}
+
+/// setpriority syscall entry
+fn sys_setpriority() -> i32 {
+    let (pid, nice);
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+        nice = arg_int(&p.trapframe, 1);
+    }
+    match setpriority(pid, nice) {
+        Some(()) => 0,
+        None => -1
+    }
+}
+
+/// getpriority syscall entry
+///
+/// As nice value may be negative, `20 - nice` is returned on success.
+fn sys_getpriority() -> i32 {
+    let pid;
+    {
+        let p = my_proc();
+        pid = arg_int(&p.trapframe, 0);
+    }
+    match getpriority(pid) {
+        Some(nice) => 20 - nice,
+        None => -1
+    }
+}
+
+/// cputime syscall entry, returns CPU time in microseconds
+fn sys_cputime() -> i64 {
+    cputime().as_micros() as i64
+}
+
+/// setaffinity syscall entry
+fn sys_setaffinity() -> i32 {
+    let mask;
+    {
+        let p = my_proc();
+        mask = argraw(&p.trapframe, 0);
+    }
+    match setaffinity(mask) {
+        Some(()) => 0,
+        None => -1
+    }
+}
+
+/// getaffinity syscall entry
+fn sys_getaffinity() -> i64 {
+    getaffinity() as i64
+}
+
+/// clone syscall entry
+fn sys_clone() -> i32 {
+    let (entry, stack, arg);
+    {
+        let p = my_proc();
+        entry = argraw(&p.trapframe, 0);
+        stack = argraw(&p.trapframe, 1);
+        arg = argraw(&p.trapframe, 2);
+    }
+    match clone(entry, stack, arg) {
+        Some(tid) => tid,
+        None => -1
+    }
+}
+
+/// join syscall entry, exit status is written to the second argument
+fn sys_join() -> i32 {
+    let tid;
+    {
+        let p = my_proc();
+        tid = arg_int(&p.trapframe, 0);
+    }
+    match join(tid) {
+        Some(status) => {
+            let p = my_proc();
+            match write_user(p, argraw(&p.trapframe, 1), &status) {
+                Ok(()) => 0,
+                Err(err) => err
+            }
+        }
+        None => -1
+    }
+}
+
+/// Syscall return value of futex error, same as Linux errno
+fn futex_errno(err: FutexError) -> i32 {
+    match err {
+        FutexError::Invalid => -EINVAL,
+        FutexError::Again => -11,
+        FutexError::TimedOut => -110,
+    }
+}
+
+/// futex_wait syscall entry
+///
+/// Timeout is given in microseconds, and negative value means no timeout.
+fn sys_futex_wait() -> i32 {
+    let (addr, val, timeout);
+    {
+        let p = my_proc();
+        addr = argraw(&p.trapframe, 0);
+        val = argraw(&p.trapframe, 1) as u32;
+        timeout = arg_timeout(&p.trapframe, 2);
+    }
+    match futex_wait(addr, val, timeout) {
+        Ok(()) => 0,
+        Err(err) => futex_errno(err)
+    }
+}
+
+/// futex_wake syscall entry
+fn sys_futex_wake() -> i32 {
+    let (addr, n);
+    {
+        let p = my_proc();
+        addr = argraw(&p.trapframe, 0);
+        n = argraw(&p.trapframe, 1);
+    }
+    match futex_wake(addr, n) {
+        Ok(woken) => woken as i32,
+        Err(err) => futex_errno(err)
+    }
+}
+
 /// Process all syscall
-pub fn syscall() -> i32 {
+///
+/// Return value is extended to `i64` so that syscalls like `mmap`
+/// may return a full user address. Unknown syscalls return `-ENOSYS`.
+pub fn syscall() -> i64 {
     let syscall_id;
     {
         let p = my_proc();
@@ -127,14 +421,46 @@ pub fn syscall() -> i32 {
         syscall_id = tf.regs[Register::a7 as usize] as i64;
     }
     match syscall_id {
-        SYS_WRITE => sys_write(),
-        SYS_READ => sys_read(),
-        SYS_FORK => sys_fork(),
-        SYS_EXEC => sys_exec(),
-        SYS_EXIT => sys_exit(),
-        SYS_DUP => sys_dup(),
-        SYS_OPEN => sys_open(),
-        SYS_CLOSE => sys_close(),
-        _ => unreachable!()
+        SYS_WRITE => sys_write() as i64,
+        SYS_READ => sys_read() as i64,
+        SYS_FORK => sys_fork() as i64,
+        SYS_EXEC => sys_exec() as i64,
+        SYS_EXIT => sys_exit() as i64,
+        SYS_WAIT => sys_wait() as i64,
+        SYS_PIPE => sys_pipe() as i64,
+        SYS_KILL => sys_kill() as i64,
+        SYS_DUP => sys_dup() as i64,
+        SYS_OPEN => sys_open() as i64,
+        SYS_MKNOD => sys_mknod() as i64,
+        SYS_CLOSE => sys_close() as i64,
+        SYS_SBRK => sys_sbrk() as i64,
+        SYS_MMAP => sys_mmap(),
+        SYS_MUNMAP => sys_munmap() as i64,
+        SYS_SETPRIORITY => sys_setpriority() as i64,
+        SYS_GETPRIORITY => sys_getpriority() as i64,
+        SYS_CPUTIME => sys_cputime(),
+        SYS_SETAFFINITY => sys_setaffinity() as i64,
+        SYS_GETAFFINITY => sys_getaffinity(),
+        SYS_CLONE => sys_clone() as i64,
+        SYS_JOIN => sys_join() as i64,
+        SYS_THREAD_EXIT => sys_thread_exit() as i64,
+        SYS_FUTEX_WAIT => sys_futex_wait() as i64,
+        SYS_FUTEX_WAKE => sys_futex_wake() as i64,
+        SYS_SHM_CREATE => sys_shm_create() as i64,
+        SYS_CHAN_CREATE => sys_chan_create() as i64,
+        SYS_CHAN_SEND => sys_chan_send() as i64,
+        SYS_CHAN_RECV => sys_chan_recv() as i64,
+        SYS_POLL => sys_poll() as i64,
+        SYS_IOCTL => sys_ioctl() as i64,
+        SYS_LSEEK => sys_lseek(),
+        SYS_SOCKET => sys_socket() as i64,
+        SYS_BIND => sys_bind() as i64,
+        SYS_CONNECT => sys_connect() as i64,
+        SYS_SENDTO => sys_sendto() as i64,
+        SYS_RECVFROM => sys_recvfrom() as i64,
+        SYS_LISTEN => sys_listen() as i64,
+        SYS_ACCEPT => sys_accept() as i64,
+        SYS_REBOOT => sys_reboot() as i64,
+        _ => -ENOSYS as i64
     }
 }
@@ -6,49 +6,97 @@
 //! File-related syscalls
 
 use crate::process::my_proc;
-use crate::syscall::{arg_int, arg_uint, arg_ptr, arg_fd, arg_ptr_mut};
-use crate::file::{File, Console, FsFile};
+use crate::syscall::{argraw, arg_int, arg_uint, arg_fd, arg_timeout, EBADF, EEXIST, EINVAL, ENOSPC, EPIPE, EINTR};
+use crate::syscall::{arg_buf, arg_str, check_user_mut, copy_out, read_user, write_user};
+use crate::file::{File, FsFile, DirEntry, MknodError, SeekFrom, open_device, SharedMemory, Channel, ChannelError, PollEntry, poll};
+use crate::file::{PipeEnd, PipeError};
+use crate::file::channel::MAX_MESSAGE;
+use crate::file::device::ENOTTY;
+use alloc::vec;
+use alloc::vec::Vec;
 use alloc::sync::Arc;
 use crate::spinlock::Mutex;
 use crate::symbols::PAGE_SIZE;
 use crate::virtio::BSIZE;
+use core::mem::size_of;
+use super::net::net_errno;
 
 /// write syscall
 pub fn sys_write() -> i32 {
     let p = my_proc();
-    let sz = arg_uint(&p.trapframe, 2);
-    if sz > BSIZE {
-        panic!("size > BSIZE not supported");
-    }
-    let content = arg_ptr(&p.pgtable, &p.trapframe, 1, sz);
-    let u8_slice = unsafe { core::slice::from_raw_parts(content, sz) };
-    let file = arg_fd(&p, 0);
-    match (*file).as_ref() {
+    // larger buffers are transferred in part, and a short count returned
+    let sz = core::cmp::min(argraw(&p.trapframe, 2), BSIZE);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => { return -EBADF; }
+    };
+    let content = match arg_buf(p, 1, sz) {
+        Ok(content) => content,
+        Err(err) => { return err; }
+    };
+    let u8_slice = &content[..];
+    match &*file {
         File::Device(dev) => dev.write(u8_slice),
         File::FsFile(file) => file.write(u8_slice),
-        _ => { unimplemented!(); }
+        File::Channel(chan) => match chan.send(u8_slice, None, None) {
+            Ok(()) => sz as i32,
+            Err(err) => channel_errno(err)
+        }
+        File::Socket(sock) => match sock.send_to(u8_slice, None) {
+            Ok(sz) => sz as i32,
+            Err(err) => net_errno(err)
+        }
+        File::Pipe(pipe) => match pipe.write(u8_slice) {
+            Ok(sz) => sz as i32,
+            Err(err) => pipe_errno(err)
+        }
+        File::Shm(_) => -EINVAL
     }
 }
 
 /// read syscall
 pub fn sys_read() -> i32 {
     let p = my_proc();
-    let sz = arg_uint(&p.trapframe, 2);
-    if sz > BSIZE {
-        panic!("size > BSIZE not supported");
-    }
-    let content = arg_ptr_mut(&p.pgtable, &p.trapframe, 1, sz);
-    let u8_slice = unsafe { core::slice::from_raw_parts_mut(content, sz) };
-    let file = arg_fd(&p, 0);
-    match (*file).as_ref() {
+    // larger buffers are transferred in part, and a short count returned
+    let sz = core::cmp::min(argraw(&p.trapframe, 2), BSIZE);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => { return -EBADF; }
+    };
+    let addr = argraw(&p.trapframe, 1);
+    // fail before anything is consumed from file
+    if let Err(err) = check_user_mut(p, addr, sz) {
+        return err;
+    }
+    let mut content = vec![0; sz];
+    let u8_slice = &mut content[..];
+    let ret = match &*file {
         File::Device(dev) => dev.read(u8_slice),
         File::FsFile(file) => file.read(u8_slice),
-        _ => { unimplemented!(); }
+        File::Channel(chan) => match chan.recv(u8_slice, None) {
+            Ok((sz, _)) => sz as i32,
+            Err(err) => channel_errno(err)
+        }
+        File::Socket(sock) => match sock.recv_from(u8_slice, false) {
+            Ok((sz, _)) => sz as i32,
+            Err(err) => net_errno(err)
+        }
+        File::Pipe(pipe) => match pipe.read(u8_slice) {
+            Ok(sz) => sz as i32,
+            Err(err) => pipe_errno(err)
+        }
+        File::Shm(_) => -EINVAL
+    };
+    if ret > 0 {
+        if let Err(err) = copy_out(p, addr, &content[..ret as usize]) {
+            return err;
+        }
     }
+    ret
 }
 
 /// find a available file descriptor from files array in process
-fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
+pub(super) fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
     for i in 0..files.len() {
         match files[i] {
             None => { return Some(i); }
@@ -58,22 +106,37 @@ fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
     return None;
 }
 
-/// open syscall, currently only support `/console` file.
+/// open syscall
+///
+/// Device nodes are opened through device driver registry.
 pub fn sys_open() -> i32 {
     let p = my_proc();
-    let sz = arg_uint(&p.trapframe, 1);
-    let mode = arg_uint(&p.trapframe, 2);
-    let content = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
-    let path = core::str::from_utf8(unsafe { core::slice::from_raw_parts(content, sz) }).unwrap();
-    let fd = match next_available_fd(&p.files) {
+    let sz = match arg_uint(&p.trapframe, 1) {
+        Ok(sz) => sz,
+        Err(err) => { return err; }
+    };
+    let mode = match arg_uint(&p.trapframe, 2) {
+        Ok(mode) => mode,
+        Err(err) => { return err; }
+    };
+    let path = match arg_str(p, 0, sz) {
+        Ok(path) => path,
+        Err(err) => { return err; }
+    };
+    let file = match FsFile::stat(&path) {
+        Some(DirEntry::Device { major, minor }) => match open_device(major, minor) {
+            Some(dev) => Arc::new(File::Device(dev)),
+            None => { return -1; }
+        }
+        Some(DirEntry::File { .. }) => Arc::new(File::FsFile(FsFile::open(&path, mode))),
+        None => { return -1; }
+    };
+    let mut files = p.files.lock();
+    let fd = match next_available_fd(&*files) {
         Some(fd) => fd,
         None => { return -1; }
     };
-    if path == "/console" {
-        p.files[fd] = Some(Arc::new(File::Device(box Console {})));
-    } else {
-        p.files[fd] = Some(Arc::new(File::FsFile(FsFile::open(path, mode))));
-    }
+    files[fd] = Some(file);
     return fd as i32;
 }
 
@@ -81,19 +144,357 @@ pub fn sys_open() -> i32 {
 pub fn sys_close() -> i32 {
     let p = my_proc();
     let fd = arg_int(&p.trapframe, 0) as usize;
-    p.files[fd] = None;
+    let file = match p.files.lock().get_mut(fd) {
+        Some(file) => file.take(),
+        None => None
+    };
+    if file.is_none() {
+        return -EBADF;
+    }
+    // file may be released here, outside of files lock
+    drop(file);
     0
 }
 
 /// dup syscall
 pub fn sys_dup() -> i32 {
     let p = my_proc();
-    let old_fd = arg_uint(&p.trapframe, 0);
-    let fd = match next_available_fd(&p.files) {
+    let old_fd = arg_int(&p.trapframe, 0) as usize;
+    let mut files = p.files.lock();
+    let file = match files.get(old_fd) {
+        Some(Some(file)) => file.clone(),
+        _ => { return -EBADF; }
+    };
+    let fd = match next_available_fd(&*files) {
+        Some(fd) => fd,
+        None => { return -1; }
+    };
+    files[fd] = Some(file);
+    fd as i32
+}
+
+/// shm_create syscall
+///
+/// Create a shared memory object of `size` bytes, and returns its file
+/// descriptor. The object can be mapped with `mmap` and `MAP_SHARED`.
+pub fn sys_shm_create() -> i32 {
+    let p = my_proc();
+    let size = match arg_uint(&p.trapframe, 0) {
+        Ok(size) => size,
+        Err(err) => { return err; }
+    };
+    if size == 0 {
+        return -1;
+    }
+    let file = Arc::new(File::Shm(SharedMemory::new(size)));
+    let mut files = p.files.lock();
+    let fd = match next_available_fd(&*files) {
         Some(fd) => fd,
         None => { return -1; }
     };
-    p.files[fd] = Some(p.files[old_fd].as_ref().unwrap().clone());
-    use crate::info;
+    files[fd] = Some(file);
     fd as i32
 }
+
+/// Syscall return value of pipe error, same as Linux errno
+fn pipe_errno(err: PipeError) -> i32 {
+    match err {
+        PipeError::WrongEnd => -EBADF,
+        PipeError::Broken => -EPIPE,
+        PipeError::Interrupted => -EINTR,
+    }
+}
+
+/// pipe syscall
+///
+/// Create a pipe, and write file descriptors of its read end and write
+/// end to the array at first argument.
+pub fn sys_pipe() -> i32 {
+    let p = my_proc();
+    let addr = argraw(&p.trapframe, 0);
+    if let Err(err) = check_user_mut(p, addr, size_of::<[i32; 2]>()) {
+        return err;
+    }
+    let (r, w) = PipeEnd::new();
+    let fds = {
+        let mut files = p.files.lock();
+        let r_fd = match next_available_fd(&*files) {
+            Some(fd) => fd,
+            None => { return -1; }
+        };
+        files[r_fd] = Some(Arc::new(File::Pipe(r)));
+        let w_fd = match next_available_fd(&*files) {
+            Some(fd) => fd,
+            None => {
+                files[r_fd] = None;
+                return -1;
+            }
+        };
+        files[w_fd] = Some(Arc::new(File::Pipe(w)));
+        [r_fd as i32, w_fd as i32]
+    };
+    match write_user(p, addr, &fds) {
+        Ok(()) => 0,
+        Err(err) => err
+    }
+}
+
+/// Syscall return value of channel error, same as Linux errno
+fn channel_errno(err: ChannelError) -> i32 {
+    match err {
+        ChannelError::Invalid => -1,
+        ChannelError::TooLarge => -90,
+        ChannelError::TimedOut => -110,
+    }
+}
+
+/// chan_create syscall
+///
+/// Create a channel holding at most `capacity` messages, and returns its
+/// file descriptor.
+pub fn sys_chan_create() -> i32 {
+    let p = my_proc();
+    let capacity = match arg_uint(&p.trapframe, 0) {
+        Ok(capacity) => capacity,
+        Err(err) => { return err; }
+    };
+    let chan = match Channel::new(capacity) {
+        Ok(chan) => chan,
+        Err(err) => { return channel_errno(err); }
+    };
+    let mut files = p.files.lock();
+    let fd = match next_available_fd(&*files) {
+        Some(fd) => fd,
+        None => { return -1; }
+    };
+    files[fd] = Some(Arc::new(File::Channel(chan)));
+    fd as i32
+}
+
+/// chan_send syscall
+///
+/// Send a message of `sz` bytes to channel `fd`, with file `pass_fd`
+/// (none if negative) passed along. Timeout is given in microseconds,
+/// and negative value means no timeout.
+pub fn sys_chan_send() -> i32 {
+    let p = my_proc();
+    let sz = match arg_uint(&p.trapframe, 2) {
+        Ok(sz) => sz,
+        Err(err) => { return err; }
+    };
+    let pass_fd = arg_int(&p.trapframe, 3);
+    let timeout = arg_timeout(&p.trapframe, 4);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => { return -EBADF; }
+    };
+    let chan = match &*file {
+        File::Channel(chan) => chan,
+        _ => { return -1; }
+    };
+    if sz > MAX_MESSAGE {
+        return channel_errno(ChannelError::TooLarge);
+    }
+    let content = match arg_buf(p, 1, sz) {
+        Ok(content) => content,
+        Err(err) => { return err; }
+    };
+    let u8_slice = &content[..];
+    let pass_file = if pass_fd >= 0 {
+        match p.file(pass_fd as usize) {
+            Some(f) => Some(f),
+            None => { return -1; }
+        }
+    } else {
+        None
+    };
+    match chan.send(u8_slice, pass_file, timeout) {
+        Ok(()) => sz as i32,
+        Err(err) => channel_errno(err)
+    }
+}
+
+/// chan_recv syscall
+///
+/// Receive a message of at most `sz` bytes from channel `fd`. File passed
+/// with message is installed into file table, and its file descriptor is
+/// written to `fd_out` (-1 if there is none). Timeout is the same as in
+/// `chan_send`.
+pub fn sys_chan_recv() -> i32 {
+    let p = my_proc();
+    let sz = match arg_uint(&p.trapframe, 2) {
+        Ok(sz) => sz,
+        Err(err) => { return err; }
+    };
+    let timeout = arg_timeout(&p.trapframe, 4);
+    let addr = argraw(&p.trapframe, 1);
+    let fd_out = argraw(&p.trapframe, 3);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => { return -EBADF; }
+    };
+    let chan = match &*file {
+        File::Channel(chan) => chan,
+        _ => { return -1; }
+    };
+    // messages are never larger than this
+    let sz = core::cmp::min(sz, MAX_MESSAGE);
+    // fail before message is taken from channel
+    if let Err(err) = check_user_mut(p, addr, sz).and(check_user_mut(p, fd_out, size_of::<i32>())) {
+        return err;
+    }
+    let mut content = vec![0; sz];
+    let (sz, pass_file) = match chan.recv(&mut content, timeout) {
+        Ok(x) => x,
+        Err(err) => { return channel_errno(err); }
+    };
+    let pass_fd = match pass_file {
+        Some(f) => {
+            let mut files = p.files.lock();
+            match next_available_fd(&*files) {
+                Some(fd) => {
+                    files[fd] = Some(f);
+                    fd as i32
+                }
+                // file is dropped if there's no room for it
+                None => -1
+            }
+        }
+        None => -1
+    };
+    match copy_out(p, addr, &content[..sz]).and(write_user(p, fd_out, &pass_fd)) {
+        Ok(()) => sz as i32,
+        Err(err) => err
+    }
+}
+
+/// File descriptor to be polled, same as `struct pollfd` in Linux
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+/// poll syscall
+///
+/// Wait until any of `n` file descriptors is ready, or timeout (in
+/// microseconds, no timeout if negative) has passed. Returns number of
+/// file descriptors ready.
+pub fn sys_poll() -> i32 {
+    let p = my_proc();
+    let n = match arg_uint(&p.trapframe, 1) {
+        Ok(n) => n,
+        Err(err) => { return err; }
+    };
+    let timeout = arg_timeout(&p.trapframe, 2);
+    let addr = argraw(&p.trapframe, 0);
+    if n > p.files.lock().len() {
+        return -EINVAL;
+    }
+    if let Err(err) = check_user_mut(p, addr, n * size_of::<PollFd>()) {
+        return err;
+    }
+    let mut fds = Vec::new();
+    for i in 0..n {
+        match read_user::<PollFd>(p, addr + i * size_of::<PollFd>()) {
+            Ok(fd) => fds.push(fd),
+            Err(err) => { return err; }
+        }
+    }
+    // negative descriptors are ignored, with `revents` of 0
+    let mut entries: Vec<PollEntry> = fds.iter().filter(|x| x.fd >= 0).map(|x| PollEntry {
+        file: p.file(x.fd as usize),
+        events: x.events as u16 as usize,
+        revents: 0,
+    }).collect();
+    let ready = poll(&mut entries, timeout);
+    let mut entries = entries.iter();
+    for fd in fds.iter_mut() {
+        fd.revents = match fd.fd {
+            x if x >= 0 => entries.next().unwrap().revents as i16,
+            _ => 0
+        };
+    }
+    for (i, fd) in fds.iter().enumerate() {
+        if let Err(err) = write_user(p, addr + i * size_of::<PollFd>(), fd) {
+            return err;
+        }
+    }
+    ready as i32
+}
+
+/// mknod syscall
+///
+/// Create a device node of `major` and `minor` number at `path`.
+pub fn sys_mknod() -> i32 {
+    let p = my_proc();
+    let sz = match arg_uint(&p.trapframe, 1) {
+        Ok(sz) => sz,
+        Err(err) => { return err; }
+    };
+    // full 64-bit values, so that out-of-range ones are rejected by `mknod`
+    let major = argraw(&p.trapframe, 2);
+    let minor = argraw(&p.trapframe, 3);
+    let path = match arg_str(p, 0, sz) {
+        Ok(path) => path,
+        Err(err) => { return err; }
+    };
+    match FsFile::mknod(&path, major, minor) {
+        Ok(()) => 0,
+        Err(MknodError::Invalid) => -EINVAL,
+        Err(MknodError::Exists) => -EEXIST,
+        Err(MknodError::NoSpace) => -ENOSPC,
+    }
+}
+
+/// ioctl syscall
+///
+/// Device-specific control operation `cmd` with argument `arg` on device `fd`.
+pub fn sys_ioctl() -> i32 {
+    let p = my_proc();
+    let cmd = argraw(&p.trapframe, 1);
+    let arg = argraw(&p.trapframe, 2);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => { return -EBADF; }
+    };
+    match &*file {
+        File::Device(dev) => dev.ioctl(cmd, arg),
+        _ => -ENOTTY
+    }
+}
+
+/// Seek from start of file
+const SEEK_SET: usize = 0;
+/// Seek from current offset
+const SEEK_CUR: usize = 1;
+/// Seek from end of file
+const SEEK_END: usize = 2;
+
+/// lseek syscall
+///
+/// Move offset of seekable device `fd`. Returns new offset.
+pub fn sys_lseek() -> i64 {
+    let p = my_proc();
+    let offset = argraw(&p.trapframe, 1) as isize;
+    let whence = argraw(&p.trapframe, 2);
+    let file = match arg_fd(&p, 0) {
+        Some(file) => file,
+        None => { return -EBADF as i64; }
+    };
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as usize),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => { return -1; }
+    };
+    match &*file {
+        File::Device(dev) => match dev.seek(pos) {
+            Some(offset) => offset as i64,
+            None => -1
+        }
+        _ => -1
+    }
+}
@@ -43,3 +43,57 @@ pub const SYS_SBRK : i64 = 18;
 pub const SYS_SLEEP : i64 = 19;
 /// `20`: uptime
 pub const SYS_UPTIME : i64 = 20;
+/// `21`: mmap
+pub const SYS_MMAP : i64 = 21;
+/// `22`: munmap
+pub const SYS_MUNMAP : i64 = 22;
+/// `23`: setpriority
+pub const SYS_SETPRIORITY : i64 = 23;
+/// `24`: getpriority
+pub const SYS_GETPRIORITY : i64 = 24;
+/// `25`: cputime
+pub const SYS_CPUTIME : i64 = 25;
+/// `26`: setaffinity
+pub const SYS_SETAFFINITY : i64 = 26;
+/// `27`: getaffinity
+pub const SYS_GETAFFINITY : i64 = 27;
+/// `28`: clone
+pub const SYS_CLONE : i64 = 28;
+/// `29`: join
+pub const SYS_JOIN : i64 = 29;
+/// `30`: futex_wait
+pub const SYS_FUTEX_WAIT : i64 = 30;
+/// `31`: futex_wake
+pub const SYS_FUTEX_WAKE : i64 = 31;
+/// `32`: shm_create
+pub const SYS_SHM_CREATE : i64 = 32;
+/// `33`: chan_create
+pub const SYS_CHAN_CREATE : i64 = 33;
+/// `34`: chan_send
+pub const SYS_CHAN_SEND : i64 = 34;
+/// `35`: chan_recv
+pub const SYS_CHAN_RECV : i64 = 35;
+/// `36`: poll
+pub const SYS_POLL : i64 = 36;
+/// `37`: ioctl
+pub const SYS_IOCTL : i64 = 37;
+/// `38`: lseek
+pub const SYS_LSEEK : i64 = 38;
+/// `39`: socket
+pub const SYS_SOCKET : i64 = 39;
+/// `40`: bind
+pub const SYS_BIND : i64 = 40;
+/// `41`: connect
+pub const SYS_CONNECT : i64 = 41;
+/// `42`: sendto
+pub const SYS_SENDTO : i64 = 42;
+/// `43`: recvfrom
+pub const SYS_RECVFROM : i64 = 43;
+/// `44`: listen
+pub const SYS_LISTEN : i64 = 44;
+/// `45`: accept
+pub const SYS_ACCEPT : i64 = 45;
+/// `46`: reboot
+pub const SYS_REBOOT : i64 = 46;
+/// `47`: thread_exit
+pub const SYS_THREAD_EXIT : i64 = 47;
@@ -0,0 +1,56 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Memory-related syscalls
+
+use crate::process::{my_proc, sbrk, mmap, munmap, MmapError};
+use crate::syscall::{argraw, arg_int, EACCES, EINVAL, ENOMEM};
+use alloc::sync::Arc;
+
+/// sbrk syscall
+pub fn sys_sbrk() -> i32 {
+    let increment;
+    {
+        let p = my_proc();
+        increment = arg_int(&p.trapframe, 0);
+    }
+    match sbrk(increment as isize) {
+        Some(brk) => brk as i32,
+        None => -1
+    }
+}
+
+/// mmap syscall
+pub fn sys_mmap() -> i64 {
+    let p = my_proc();
+    let addr = argraw(&p.trapframe, 0);
+    let len = argraw(&p.trapframe, 1);
+    let prot = argraw(&p.trapframe, 2);
+    let flags = argraw(&p.trapframe, 3);
+    let fd = arg_int(&p.trapframe, 4);
+    let offset = argraw(&p.trapframe, 5);
+    let file = if fd >= 0 {
+        p.file(fd as usize)
+    } else {
+        None
+    };
+    match mmap(addr, len, prot, flags, file, offset) {
+        Ok(addr) => addr as i64,
+        Err(MmapError::Invalid) => -EINVAL as i64,
+        Err(MmapError::Access) => -EACCES as i64,
+        Err(MmapError::NoMemory) => -ENOMEM as i64,
+    }
+}
+
+/// munmap syscall
+pub fn sys_munmap() -> i32 {
+    let p = my_proc();
+    let addr = argraw(&p.trapframe, 0);
+    let len = argraw(&p.trapframe, 1);
+    match munmap(addr, len) {
+        Some(()) => 0,
+        None => -EINVAL
+    }
+}
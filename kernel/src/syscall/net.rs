@@ -0,0 +1,281 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Socket-related syscalls
+
+use crate::process::my_proc;
+use crate::syscall::{argraw, arg_uint, arg_fd, arg_buf, check_user_mut, copy_out, read_user, write_user, MAX_USER_BUF};
+use crate::file::{File, Socket};
+use crate::file::socket::{AF_INET, MSG_DONTWAIT};
+use crate::net::{Ipv4Addr, SocketAddr, NetError};
+use super::file::next_available_fd;
+use alloc::sync::Arc;
+use alloc::vec;
+
+/// Socket address, same as `struct sockaddr_in` in Linux.
+/// Port and address are in network byte order.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SockAddrIn {
+    family: u16,
+    port: u16,
+    addr: [u8; 4],
+    zero: [u8; 8],
+}
+
+/// Errno of network error
+pub(super) fn net_errno(err: NetError) -> i32 {
+    match err {
+        NetError::Invalid => -22,
+        NetError::AddrInUse => -98,
+        NetError::NotConnected => -107,
+        NetError::Unreachable => -101,
+        NetError::TooLarge => -90,
+        NetError::WouldBlock => -11,
+        NetError::Refused => -111,
+        NetError::Reset => -104,
+        NetError::TimedOut => -110,
+    }
+}
+
+/// Get the `pos`th argument as socket address, `None` if it is null.
+/// Returns errno if it can't be read or is not an Internet address.
+fn arg_sockaddr(pos: usize) -> Result<Option<SocketAddr>, i32> {
+    let p = my_proc();
+    let ptr = argraw(&p.trapframe, pos);
+    if ptr == 0 {
+        return Ok(None);
+    }
+    let sa: SockAddrIn = read_user(p, ptr)?;
+    if sa.family as usize != AF_INET {
+        return Err(net_errno(NetError::Invalid));
+    }
+    Ok(Some(SocketAddr::new(Ipv4Addr(sa.addr), u16::from_be(sa.port))))
+}
+
+/// Check socket address can be written to the `pos`th argument
+fn check_sockaddr(pos: usize) -> Result<(), i32> {
+    let p = my_proc();
+    let ptr = argraw(&p.trapframe, pos);
+    if ptr == 0 {
+        return Ok(());
+    }
+    check_user_mut(p, ptr, core::mem::size_of::<SockAddrIn>())
+}
+
+/// Write socket address to the `pos`th argument, unless it is null
+fn write_sockaddr(pos: usize, addr: SocketAddr) -> Result<(), i32> {
+    let p = my_proc();
+    let ptr = argraw(&p.trapframe, pos);
+    if ptr == 0 {
+        return Ok(());
+    }
+    write_user(p, ptr, &SockAddrIn {
+        family: AF_INET as u16,
+        port: addr.port.to_be(),
+        addr: addr.addr.0,
+        zero: [0; 8],
+    })
+}
+
+/// Get socket of file descriptor at `pos`th argument
+fn arg_socket(pos: usize) -> Option<Arc<File>> {
+    let p = my_proc();
+    let file = arg_fd(&p, pos)?;
+    match &*file {
+        File::Socket(_) => Some(file),
+        _ => None
+    }
+}
+
+/// socket syscall
+///
+/// Create a socket of `domain`, `type` and `protocol`, and returns its
+/// file descriptor.
+pub fn sys_socket() -> i32 {
+    let p = my_proc();
+    let domain = argraw(&p.trapframe, 0);
+    let ty = argraw(&p.trapframe, 1);
+    let protocol = argraw(&p.trapframe, 2);
+    let sock = match Socket::new(domain, ty, protocol) {
+        Some(sock) => sock,
+        None => { return -22; }
+    };
+    let mut files = p.files.lock();
+    let fd = match next_available_fd(&*files) {
+        Some(fd) => fd,
+        None => { return -1; }
+    };
+    files[fd] = Some(Arc::new(File::Socket(sock)));
+    fd as i32
+}
+
+/// bind syscall
+///
+/// Bind socket to local address. Port 0 means an unused port.
+pub fn sys_bind() -> i32 {
+    let file = match arg_socket(0) {
+        Some(file) => file,
+        None => { return -1; }
+    };
+    let addr = match arg_sockaddr(1) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => { return -22; }
+        Err(err) => { return err; }
+    };
+    match &*file {
+        File::Socket(sock) => match sock.bind(addr) {
+            Ok(()) => 0,
+            Err(err) => net_errno(err)
+        }
+        _ => unreachable!()
+    }
+}
+
+/// connect syscall
+///
+/// Set default destination of datagram socket, or connect stream socket.
+pub fn sys_connect() -> i32 {
+    let file = match arg_socket(0) {
+        Some(file) => file,
+        None => { return -1; }
+    };
+    let addr = match arg_sockaddr(1) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => { return -22; }
+        Err(err) => { return err; }
+    };
+    match &*file {
+        File::Socket(sock) => match sock.connect(addr) {
+            Ok(()) => 0,
+            Err(err) => net_errno(err)
+        }
+        _ => unreachable!()
+    }
+}
+
+/// sendto syscall
+///
+/// Send `len` bytes to address, or to connected peer if address is null.
+/// Returns number of bytes sent.
+pub fn sys_sendto() -> i32 {
+    let file = match arg_socket(0) {
+        Some(file) => file,
+        None => { return -1; }
+    };
+    let dst = match arg_sockaddr(4) {
+        Ok(dst) => dst,
+        Err(err) => { return err; }
+    };
+    let p = my_proc();
+    // no more can be sent at once, and datagrams larger are rejected anyway
+    let sz = match arg_uint(&p.trapframe, 2) {
+        Ok(sz) => core::cmp::min(sz, MAX_USER_BUF),
+        Err(err) => { return err; }
+    };
+    let content = match arg_buf(p, 1, sz) {
+        Ok(content) => content,
+        Err(err) => { return err; }
+    };
+    match &*file {
+        File::Socket(sock) => match sock.send_to(&content, dst) {
+            Ok(sz) => sz as i32,
+            Err(err) => net_errno(err)
+        }
+        _ => unreachable!()
+    }
+}
+
+/// recvfrom syscall
+///
+/// Receive at most `len` bytes, and write source address if address is not
+/// null. Returns `-EAGAIN` instead of waiting if `flags` has `MSG_DONTWAIT`.
+pub fn sys_recvfrom() -> i32 {
+    let file = match arg_socket(0) {
+        Some(file) => file,
+        None => { return -1; }
+    };
+    let p = my_proc();
+    let sz = match arg_uint(&p.trapframe, 2) {
+        Ok(sz) => core::cmp::min(sz, MAX_USER_BUF),
+        Err(err) => { return err; }
+    };
+    let flags = argraw(&p.trapframe, 3);
+    let addr = argraw(&p.trapframe, 1);
+    // fail before anything is received
+    if let Err(err) = check_user_mut(p, addr, sz).and(check_sockaddr(4)) {
+        return err;
+    }
+    let mut content = vec![0; sz];
+    let (sz, src) = match &*file {
+        File::Socket(sock) => match sock.recv_from(&mut content, flags & MSG_DONTWAIT != 0) {
+            Ok(x) => x,
+            Err(err) => { return net_errno(err); }
+        }
+        _ => unreachable!()
+    };
+    let p = my_proc();
+    match copy_out(p, addr, &content[..sz]).and(write_sockaddr(4, src)) {
+        Ok(()) => sz as i32,
+        Err(err) => err
+    }
+}
+
+/// listen syscall
+///
+/// Listen for connections on stream socket, keeping at most `backlog` of
+/// them until accepted.
+pub fn sys_listen() -> i32 {
+    let file = match arg_socket(0) {
+        Some(file) => file,
+        None => { return -1; }
+    };
+    let p = my_proc();
+    let backlog = match arg_uint(&p.trapframe, 1) {
+        Ok(backlog) => backlog,
+        Err(err) => { return err; }
+    };
+    match &*file {
+        File::Socket(sock) => match sock.listen(backlog) {
+            Ok(()) => 0,
+            Err(err) => net_errno(err)
+        }
+        _ => unreachable!()
+    }
+}
+
+/// accept syscall
+///
+/// Wait for a connection on listening socket, and write address of peer if
+/// address is not null. Returns file descriptor of connected socket.
+pub fn sys_accept() -> i32 {
+    let file = match arg_socket(0) {
+        Some(file) => file,
+        None => { return -1; }
+    };
+    if let Err(err) = check_sockaddr(1) {
+        return err;
+    }
+    let (sock, addr) = match &*file {
+        File::Socket(sock) => match sock.accept(false) {
+            Ok(x) => x,
+            Err(err) => { return net_errno(err); }
+        }
+        _ => unreachable!()
+    };
+    let p = my_proc();
+    let mut files = p.files.lock();
+    let fd = match next_available_fd(&*files) {
+        Some(fd) => fd,
+        None => { return -1; }
+    };
+    files[fd] = Some(Arc::new(File::Socket(sock)));
+    drop(files);
+    // address is checked before, so this fails only if it is unmapped meanwhile
+    match write_sockaddr(1, addr) {
+        Ok(()) => fd as i32,
+        Err(err) => err
+    }
+}
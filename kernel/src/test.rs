@@ -4,24 +4,140 @@
 // https://opensource.org/licenses/MIT
 
 //! Test runner
+//!
+//! Tests are functions marked with `#[test_case]` in any module, and are
+//! collected by `custom_test_frameworks` when kernel is built with
+//! `make test`. They run in context of first process, just before `/init`
+//! is executed. Each test runs on a stack of its own, so that a failed
+//! test returns to runner from panic handler and remaining tests still
+//! run. QEMU then exits with number of failed tests, through `shutdown`
+//! as `reboot` syscall does if all tests pass.
 
-use crate::virtio;
-use crate::info;
-
-type TestSuite = fn() -> &'static [(&'static str, fn())];
-
-/// Run all tests in core os
-pub fn run_tests() {
-    let suites = [
-        ("virtio", crate::virtio::tests::tests as TestSuite),
-        ("fsfile", crate::file::fsfile::tests::tests as TestSuite)];
-    for (name, suite) in &suites {
-        let tests = suite();
-        info!("  {}", name);
-        for (name, func) in tests {
-            info!("    {}", name);
-            func();
+use crate::process::{Context, ContextRegisters, swtch, my_cpu};
+use crate::{arch, info, power};
+use alloc::boxed::Box;
+
+/// Size of stack each test runs on
+const TEST_STACK_SIZE: usize = 64 * 1024;
+
+/// A test collected by `#[test_case]`
+pub trait Testable {
+    fn run(&self);
+    fn name(&self) -> &'static str;
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        self()
+    }
+
+    /// path of test function, without crate name
+    fn name(&self) -> &'static str {
+        let name = core::any::type_name::<T>();
+        name.find("::").map_or(name, |pos| &name[pos + 2..])
+    }
+}
+
+/// State of test being run
+struct Harness {
+    /// context of runner, restored when test returns or panics
+    runner: Context,
+    /// test being run
+    test: Option<*const dyn Testable>,
+    /// stack of test being run
+    stack: core::ops::Range<usize>,
+    /// whether interrupt was enabled when test started
+    intr_enabled: bool,
+    passed: bool,
+}
+
+static mut HARNESS: Harness = Harness {
+    runner: Context::zero(),
+    test: None,
+    stack: 0..0,
+    intr_enabled: false,
+    passed: false,
+};
+
+/// Entry of test stack
+extern "C" fn test_entry() -> ! {
+    unsafe {
+        (*HARNESS.test.unwrap()).run();
+        HARNESS.passed = true;
+    }
+    return_to_runner()
+}
+
+/// Switch back to runner, dropping test stack
+fn return_to_runner() -> ! {
+    let mut current = Context::zero();
+    let runner = unsafe { Context { regs: HARNESS.runner.regs } };
+    swtch(&mut current, runner);
+    unreachable!()
+}
+
+/// Check if a test is running on current stack
+pub fn in_test() -> bool {
+    unsafe { HARNESS.test.is_some() && HARNESS.stack.contains(&arch::sp()) }
+}
+
+/// Called by panic handler when test on current stack panics. Marks it
+/// failed and goes on with next test.
+///
+/// Locks held by test when it panics are never released, so later tests
+/// using them may hang.
+pub fn fail() -> ! {
+    unsafe {
+        HARNESS.passed = false;
+        *my_cpu().intr_lock.cnt.get() = 0;
+        if HARNESS.intr_enabled {
+            arch::intr_on();
+        }
+    }
+    return_to_runner()
+}
+
+/// Run `test` on `stack`. Returns if it passed.
+fn run_test(test: &dyn Testable, stack: &mut [u8]) -> bool {
+    let range = stack.as_ptr() as usize..stack.as_ptr() as usize + stack.len();
+    let mut ctx = Context::zero();
+    ctx.regs[ContextRegisters::ra as usize] = test_entry as usize;
+    ctx.regs[ContextRegisters::sp as usize] = range.end & !0xf;
+    unsafe {
+        // lifetime is only extended while test runs
+        HARNESS.test = Some(core::mem::transmute::<&dyn Testable, &'static dyn Testable>(test));
+        HARNESS.stack = range;
+        HARNESS.intr_enabled = arch::intr_get();
+        HARNESS.passed = false;
+        swtch(&mut HARNESS.runner, ctx);
+        HARNESS.test = None;
+        HARNESS.passed
+    }
+}
+
+/// Run all tests, and exit QEMU with number of failed tests
+pub fn runner(tests: &[&dyn Testable]) {
+    info!("running {} tests", tests.len());
+    let mut stack: Box<[u8; TEST_STACK_SIZE]> = box [0; TEST_STACK_SIZE];
+    let mut failed = 0;
+    for test in tests {
+        info!("test {} ...", test.name());
+        if run_test(*test, &mut stack[..]) {
+            info!("test {} \x1b[0;32mok\x1b[0m", test.name());
+        } else {
+            info!("test {} \x1b[0;31mFAILED\x1b[0m", test.name());
+            failed += 1;
         }
     }
-    info!("\x1b[0;32mall tests passed!\x1b[0m");
+    if failed == 0 {
+        info!("\x1b[0;32mtest result: ok. {} passed; 0 failed\x1b[0m", tests.len());
+    } else {
+        info!("\x1b[0;31mtest result: FAILED. {} passed; {} failed\x1b[0m", tests.len() - failed, failed);
+    }
+    if failed == 0 {
+        power::shutdown(power::Action::PowerOff(0));
+    } else {
+        // a failed test may have left locks needed by `shutdown` held
+        power::exit(failed as u32);
+    }
 }
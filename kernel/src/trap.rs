@@ -5,8 +5,9 @@
 
 //! Machine mode and supervisor mode traps
 
-use crate::{println, print, info, panic};
-use crate::process::{TrapFrame, self, Process, CPU, my_proc, my_cpu, yield_cpu};
+use crate::{println, print, info, warn, panic};
+use crate::process::{TrapFrame, self, Process, CPU, my_proc, my_cpu, yield_cpu, PageFault, exit};
+use crate::process::{group_exited, thread_exit};
 use crate::arch;
 use crate::symbols::*;
 use crate::page;
@@ -81,6 +82,14 @@ extern "C" fn kerneltrap() {
                     hart, epc, tval
                 );
             }
+            13 | 15 if kstack_guard_of(tval).is_some() => {
+                // Load or store page fault on kernel stack guard page.
+                // `kernelvec` has switched to overflow stack of this hart.
+                panic!(
+                    "Kernel stack overflow of process slot {} CPU#{} -> 0x{:08x}: 0x{:08x}",
+                    kstack_guard_of(tval).unwrap(), hart, epc, tval
+                );
+            }
             13 => {
                 // Load page fault
                 panic!(
@@ -116,6 +125,11 @@ extern "C" fn kerneltrap() {
     arch::w_sstatus(sstatus_bits);
 }
 
+/// Per-hart stack used by `kernelvec` when kernel stack overflows,
+/// so that `kerneltrap` can report it.
+#[no_mangle]
+static mut KSTACK_OVERFLOW: [[u8; PAGE_SIZE]; NCPUS] = [[0; PAGE_SIZE]; NCPUS];
+
 /// Called by `uservec` in `trampoline.S`, return from user space.
 #[no_mangle]
 pub extern "C" fn usertrap() -> ! {
@@ -135,6 +149,15 @@ pub extern "C" fn usertrap() -> ! {
         p.trapframe.epc += 4;
         arch::intr_on();
         p.trapframe.regs[a0 as usize] = syscall::syscall() as usize;
+    } else if let Some(fault) = PageFault::from_scause(scause) {
+        let vaddr = stval::read();
+        if !p.mm.acquire().handle_page_fault(vaddr, fault) {
+            warn!(
+                "pid {}: {:?} page fault at 0x{:x}, epc 0x{:x}, killed",
+                p.pid, fault, vaddr, p.trapframe.epc
+            );
+            exit(-1);
+        }
     } else {
         intr = devintr();
         match intr {
@@ -166,7 +189,11 @@ fn trampoline_userret(tf: usize, satp_val: usize) -> ! {
 /// should be wrapped in brackets so that all objects are
 /// dropped before jumping to trampoline.
 pub fn usertrapret() -> ! {
+    if group_exited() {
+        thread_exit(-1);
+    }
     let satp_val: usize;
+    let trapframe: usize;
     {
         use riscv::register::*;
         arch::intr_off();
@@ -199,13 +226,15 @@ pub fn usertrapret() -> ! {
         sepc::write(p.trapframe.epc);
 
         // tell trampoline.S the user page table to switch to.
-        let root_ppn = &mut *p.pgtable as *mut page::Table as usize;
+        // page table root won't move while address space is alive
+        let root_ppn = unsafe { &mut *p.mm.get().pgtable as *mut page::Table as usize };
         satp_val = crate::arch::build_satp(8, 0, root_ppn);
+        trapframe = TRAPFRAME(p.pid as usize);
     }
     // jump to trampoline.S at the top of memory, which
     // switches to the user page table, restores user registers,
     // and switches to user mode with sret.
-    trampoline_userret(TRAPFRAME_START, satp_val)
+    trampoline_userret(trapframe, satp_val)
 }
 
 /// Initialize supervisor-mode trap
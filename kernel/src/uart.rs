@@ -10,14 +10,24 @@ use core::fmt::Write;
 use core::fmt::Error;
 use crate::spinlock::Mutex;
 use crate::{println, print};
+use crate::process::WaitQueue;
 
-/// UART base address on QEMU RISC-V
+/// UART base address on QEMU RISC-V, used without device tree
 pub const UART_BASE_ADDR: usize = 0x1000_0000;
 
+/// Size of input buffer
+pub const UART_RX_SIZE: usize = 128;
+
 /// UART driver
 pub struct Uart {
     /// UART MMIO base address
     base_address: usize,
+    /// characters received in interrupt and not yet read
+    rx_buf: [u8; UART_RX_SIZE],
+    /// read index of `rx_buf`
+    rx_r: usize,
+    /// write index of `rx_buf`
+    rx_w: usize,
 }
 
 impl Write for Uart {
@@ -32,7 +42,10 @@ impl Write for Uart {
 impl Uart {
     pub const fn new(base_address: usize) -> Self {
         Uart {
-            base_address
+            base_address,
+            rx_buf: [0; UART_RX_SIZE],
+            rx_r: 0,
+            rx_w: 0,
         }
     }
 
@@ -129,13 +142,53 @@ impl Uart {
             }
         }
     }
+
+    /// Put a received character into input buffer. It is discarded if buffer is full.
+    pub fn push_rx(&mut self, c: u8) {
+        if self.rx_w - self.rx_r < UART_RX_SIZE {
+            self.rx_buf[self.rx_w % UART_RX_SIZE] = c;
+            self.rx_w += 1;
+        }
+    }
+
+    /// Take a character from input buffer
+    pub fn pop_rx(&mut self) -> Option<u8> {
+        if self.rx_r == self.rx_w {
+            None
+        } else {
+            let c = self.rx_buf[self.rx_r % UART_RX_SIZE];
+            self.rx_r += 1;
+            Some(c)
+        }
+    }
+
+    /// Number of characters in input buffer
+    pub fn rx_len(&self) -> usize {
+        self.rx_w - self.rx_r
+    }
+}
+
+/// Processes waiting for UART input
+pub static UART_RX_QUEUE: WaitQueue = WaitQueue::new();
+
+/// Channel on which processes wait for UART input
+pub fn uart_rx_channel() -> usize {
+    UART() as *const _ as usize
 }
 
 /// Process UART interrupt. Should only be called when interrupt.
+///
+/// Received characters are echoed and buffered for console readers.
 pub fn uartintr() {
-    let mut uart = UART().lock();
-    if let Some(c) = uart.get() {
+    loop {
+        let mut uart = UART().lock();
+        let c = match uart.get() {
+            Some(c) => c,
+            None => break
+        };
+        uart.push_rx(c);
         drop(uart);
+        UART_RX_QUEUE.wakeup(uart_rx_channel());
         match c {
             8 => {
                 // This is a backspace, so we
@@ -165,5 +218,10 @@ static __UART: Mutex<Uart> = Mutex::new(Uart::new(UART_BASE_ADDR), "uart driver"
 pub fn UART() -> &'static Mutex<Uart> { &__UART }
 
 pub unsafe fn init() {
-    UART().get().init();
+    let uart = UART().get();
+    uart.base_address = crate::fdt::machine().uart.base;
+    // there may be no UART under SBI, and firmware console is used instead
+    if uart.base_address != 0 {
+        uart.init();
+    }
 }
\ No newline at end of file
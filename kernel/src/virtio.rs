@@ -3,20 +3,44 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! virt-io driver
-
-use crate::spinlock::{Mutex, MutexGuard};
-use crate::{panic, info};
+//! virt-io drivers
+//!
+//! All virtio-mmio slots are probed at boot. Each device found is
+//! identified by its device ID and bound to the driver registered for
+//! that ID, which then receives interrupts of its slot.
+
+use crate::spinlock::Mutex;
+use crate::{panic, info, println};
 use crate::symbols::{PAGE_SIZE, PAGE_ORDER};
-use crate::process::{wakeup, sleep};
-use alloc::boxed::Box;
+use crate::process::wakeup;
 use crate::arch::__sync_synchronize;
-use crate::uart::UART;
-use core::sync::atomic::Ordering;
-
-/// VIRTIO base address on QEMU RISC-V
+use crate::fdt::machine;
+use alloc::vec::Vec;
+
+pub mod blk;
+pub mod net;
+pub mod rng;
+pub mod console;
+pub mod input;
+pub use blk::{VirtIO, Buf, BSIZE, VIRTIO};
+
+/// Address of first virtio-mmio slot on QEMU RISC-V, used without
+/// device tree
 pub const VIRTIO_MMIO_BASE: usize = 0x10001000;
 
+/// Size of MMIO region of each slot
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+
+/// Maximum number of virtio-mmio slots. QEMU virt machine has 8.
+pub const VIRTIO_SLOTS: usize = 8;
+
+/// Device IDs defined by VIRTIO spec 5
+pub const VIRTIO_ID_NET: u32 = 1;
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_ID_RNG: u32 = 4;
+pub const VIRTIO_ID_INPUT: u32 = 18;
+
 /// VIRTIO MMIO address offset
 #[allow(non_camel_case_types)]
 pub enum VIRTIO_MMIO {
@@ -37,16 +61,18 @@ pub enum VIRTIO_MMIO {
     INTERRUPT_STATUS = 0x60,
     INTERRUPT_ACK = 0x64,
     STATUS = 0x70,
+    /// device-specific configuration space
+    CONFIG = 0x100,
 }
 
 impl VIRTIO_MMIO {
-    /// Get address of MMIO from enum
-    pub const fn val(self) -> usize {
-        self as usize + VIRTIO_MMIO_BASE
+    /// Get address of MMIO register from enum in slot starting at `base`
+    pub const fn val(self, base: usize) -> usize {
+        self as usize + base
     }
-    /// Get pointer to MMIO register from enum
-    pub const fn ptr(self) -> *mut u32 {
-        self.val() as _
+    /// Get pointer to MMIO register from enum in slot starting at `base`
+    pub const fn ptr(self, base: usize) -> *mut u32 {
+        self.val(base) as _
     }
 }
 
@@ -66,6 +92,7 @@ impl VIRTIO_CONFIG_S {
 pub enum VIRTIO_FEATURE {
     BLK_F_RO = 5,
     BLK_F_SCSI = 7,
+    BLK_F_FLUSH = 9,
     BLK_F_CONFIG_WCE = 11,
     BLK_F_MQ = 12,
     F_ANY_LAYOUT = 27,
@@ -79,7 +106,8 @@ impl VIRTIO_FEATURE {
     }
 }
 
-pub const DESC_NUM: usize = 8;
+/// Number of descriptors in each virtqueue
+pub const DESC_NUM: usize = 32;
 
 #[repr(C)]
 pub struct VRingDesc {
@@ -102,6 +130,7 @@ impl VRingDesc {
 
 pub const VRING_DESC_F_NEXT: u16 = 1;
 pub const VRING_DESC_F_WRITE: u16 = 2;
+pub const VRING_DESC_F_INDIRECT: u16 = 4;
 
 #[repr(C)]
 pub struct VRingUsedElem {
@@ -118,9 +147,6 @@ impl VRingUsedElem {
     }
 }
 
-pub const VIRTIO_BLK_T_IN: u32 = 0;
-pub const VIRTIO_BLK_T_OUT: u32 = 1;
-
 #[repr(C)]
 pub struct UsedArea {
     pub flags: u16,
@@ -138,73 +164,39 @@ impl UsedArea {
     }
 }
 
-pub struct InflightOp {
-    pub buf: Box<Buf>,
-    pub status: u8,
-}
-
 /// Size of avail array
 const AVAIL_SZ: usize = (PAGE_SIZE - DESC_NUM * core::mem::size_of::<VRingDesc>()) / core::mem::size_of::<u16>();
 
+/// A virtqueue in legacy layout, with used ring starting on the next page
 #[repr(C)]
 #[repr(align(4096))]
-pub struct VirtIOData {
-    /// VIRTIO MMIO descriptor register
+pub struct VirtQueue {
+    /// descriptor table
     pub desc: [VRingDesc; DESC_NUM],
-    /// VIRTIO MMIO descriptor avail register (padding to page size)
+    /// available ring (padding to page size)
     pub avail: [u16; AVAIL_SZ],
-    /// VIRTIO MMIO descriptor used register
-    pub used: [UsedArea; DESC_NUM],
+    /// used ring
+    pub used: UsedArea,
 
     /// is descriptor free
     pub free: [bool; DESC_NUM],
     /// used index of used array
     pub used_idx: u16,
-    /// in-flight operations
-    pub info: [Option<InflightOp>; DESC_NUM],
-}
-
-pub struct VirtIO(Mutex<VirtIOData>);
-
-/// VIRTIO buffer size
-pub const BSIZE: usize = 1024;
-
-/// VIRTIO Buffer
-#[repr(C)]
-pub struct Buf {
-    pub valid: bool,
-    /// TODO: this can be removed
-    pub disk: i32,
-    /// device ID
-    pub dev: u32,
-    /// block number
-    pub blockno: u32,
-    /// buffer data
-    pub data: [u8; BSIZE],
 }
 
-impl Buf {
+impl VirtQueue {
     pub const fn new() -> Self {
         Self {
-            valid: false,
-            disk: 0,
-            dev: 0,
-            blockno: 0,
-            data: [0; BSIZE],
+            desc: [VRingDesc::new(); DESC_NUM],
+            avail: [0; AVAIL_SZ],
+            used: UsedArea::new(),
+            free: [true; DESC_NUM],
+            used_idx: 0,
         }
     }
-}
-
-#[repr(C)]
-pub struct BlkOutHdr {
-    pub blk_type: u32,
-    reserved: u32,
-    sector: usize,
-}
 
-impl VirtIOData {
     /// Free one descriptor
-    fn free_desc(&mut self, i: usize) {
+    pub fn free_desc(&mut self, i: usize) {
         if i >= DESC_NUM {
             panic!("invalid desc");
         }
@@ -217,7 +209,7 @@ impl VirtIOData {
     }
 
     /// Allocate one descriptor
-    fn alloc_desc(&mut self) -> Option<usize> {
+    pub fn alloc_desc(&mut self) -> Option<usize> {
         for i in 0..DESC_NUM {
             if self.free[i] {
                 self.free[i] = false;
@@ -227,285 +219,221 @@ impl VirtIOData {
         None
     }
 
-    /// Allocate three descriptors, return array of indices
-    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
-        let mut idx = [0; 3];
-        for i in 0..3 {
-            match self.alloc_desc() {
-                Some(x) => idx[i] = x,
-                None => {
-                    for j in 0..i {
-                        self.free_desc(idx[j]);
-                    }
-                    return None;
-                }
-            }
-        }
-        Some(idx)
-    }
+    /// Make descriptor chain starting from `head` available to device,
+    /// and notify queue `queue` of `slot`.
+    pub fn push(&mut self, slot: Slot, queue: u32, head: usize) {
+        let idx_id = 2 + self.avail[1] as usize % DESC_NUM;
+        self.avail[idx_id] = head as u16;
 
-    /// Free descriptor chain
-    fn free_chain(&mut self, mut i: usize) {
-        loop {
-            self.free_desc(i);
-            if self.desc[i].flags & VRING_DESC_F_NEXT != 0 {
-                i = self.desc[i].next as usize;
-            } else {
-                break;
-            }
-        }
-    }
-}
+        __sync_synchronize();
 
-impl VirtIO {
-    pub const fn new() -> Self {
-        Self(
-            Mutex::new(VirtIOData {
-                desc: [VRingDesc::new(); DESC_NUM],
-                avail: [0; AVAIL_SZ],
-                used: [UsedArea::new(); DESC_NUM],
-                free: [false; DESC_NUM],
-                used_idx: 0,
-                info: [None; DESC_NUM],
-            }, "vdisk"))
-    }
-
-    /// Initialize VIRTIO driver.
-    ///
-    /// Should be called in booting hart.
-    pub unsafe fn init(&mut self) {
-        use VIRTIO_MMIO::*;
-        use VIRTIO_CONFIG_S::*;
-        use VIRTIO_FEATURE::*;
+        self.avail[1] = self.avail[1].wrapping_add(1);
 
-        let mut vio = self.0.get();
+        unsafe { slot.write(VIRTIO_MMIO::QUEUE_NOTIFY, queue); }
+    }
 
-        if MAGIC_VALUE.ptr().read_volatile() != 0x74726976 {
-            panic!("cannot find virtio disk: magic value");
-        }
-        if VERSION.ptr().read_volatile() != 1 {
-            panic!("cannot find virtio disk: version");
-        }
-        if DEVICE_ID.ptr().read_volatile() != 2 {
-            panic!("cannot find virtio disk: device id");
-        }
-        if VENDOR_ID.ptr().read_volatile() != 0x554d4551 {
-            panic!("cannot find virtio disk: vendor id");
+    /// Take next descriptor chain used by device, returns its head and
+    /// number of bytes written by device.
+    pub fn pop_used(&mut self) -> Option<(usize, u32)> {
+        if self.used_idx == self.used.id {
+            return None;
         }
+        __sync_synchronize();
+        let elem = &self.used.elems[self.used_idx as usize % DESC_NUM];
+        let result = (elem.id as usize, elem.len);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Some(result)
+    }
+}
 
-        let mut status: u32 = 0;
-        status |= ACKNOWLDGE.val();
-        STATUS.ptr().write_volatile(status);
+/// A virtio-mmio slot
+#[derive(Clone, Copy)]
+pub struct Slot {
+    pub index: usize,
+}
 
-        status |= DRIVER.val();
-        STATUS.ptr().write_volatile(status);
-
-        let mut features: u32 = DEVICE_FEATURES.ptr().read_volatile();
-        features &= !BLK_F_RO.bit();
-        features &= !BLK_F_SCSI.bit();
-        features &= !BLK_F_CONFIG_WCE.bit();
-        features &= !BLK_F_MQ.bit();
-        features &= !F_ANY_LAYOUT.bit();
-        features &= !RING_F_EVENT_IDX.bit();
-        features &= !RING_F_INDIRECT_DESC.bit();
-        DEVICE_FEATURES.ptr().write_volatile(features);
+impl Slot {
+    pub const fn new(index: usize) -> Self {
+        Self { index }
+    }
 
-        status |= FEATURES_OK.val();
-        STATUS.ptr().write_volatile(status);
+    /// Base address of MMIO registers
+    pub fn base(&self) -> usize {
+        machine().virtio[self.index].base
+    }
 
-        status |= DRIVER_OK.val();
-        STATUS.ptr().write_volatile(status);
+    /// PLIC interrupt of this slot
+    pub fn irq(&self) -> u32 {
+        machine().virtio[self.index].irq
+    }
 
-        GUEST_PAGE_SIZE.ptr().write_volatile(PAGE_SIZE as u32);
+    pub unsafe fn read(&self, reg: VIRTIO_MMIO) -> u32 {
+        reg.ptr(self.base()).read_volatile()
+    }
 
-        QUEUE_SEL.ptr().write_volatile(0);
-        let max = QUEUE_NUM_MAX.ptr().read_volatile();
-        if max == 0 {
-            panic!("virtio disk has no queue");
-        }
-        if max < DESC_NUM as u32 {
-            panic!("virtio disk max queue too short {} < {}", max, DESC_NUM);
-        }
-        QUEUE_NUM.ptr().write_volatile(DESC_NUM as u32);
+    pub unsafe fn write(&self, reg: VIRTIO_MMIO, val: u32) {
+        reg.ptr(self.base()).write_volatile(val)
+    }
 
-        QUEUE_PFN.ptr().write_volatile(((vio as *mut _ as usize) >> PAGE_ORDER) as u32);
+    /// Read 32-bit word at `offset` of device-specific configuration space
+    pub unsafe fn config(&self, offset: usize) -> u32 {
+        (VIRTIO_MMIO::CONFIG.val(self.base()) as *const u32).add(offset / 4).read_volatile()
+    }
 
-        for i in 0..DESC_NUM {
-            vio.free[i] = true;
+    /// Device ID of this slot, 0 if there is no device
+    pub fn device_id(&self) -> u32 {
+        use VIRTIO_MMIO::*;
+        unsafe {
+            if self.read(MAGIC_VALUE) != 0x74726976
+                || self.read(VERSION) != 1
+                || self.read(VENDOR_ID) != 0x554d4551 {
+                return 0;
+            }
+            self.read(DEVICE_ID)
         }
     }
 
-    /// Read-write operation
-    fn rw(&mut self, mut b: Box<Buf>, write: bool) -> Box<Buf> {
+    /// Reset device and negotiate features. Device features are passed to
+    /// `accept`, which returns features used by driver.
+    pub unsafe fn negotiate(&self, accept: impl FnOnce(u32) -> u32) {
         use VIRTIO_MMIO::*;
+        use VIRTIO_CONFIG_S::*;
 
-        let sector = b.blockno as usize * (BSIZE / 512);
-
-        let mut vio = self.0.lock();
+        self.write(STATUS, 0);
 
-        let idx: [usize; 3] = loop {
-            if let Some(idx) = vio.alloc3_desc() {
-                break idx;
-            }
-            vio = sleep(&vio.free[0] as *const _, vio);
-        };
+        let mut status: u32 = 0;
+        status |= ACKNOWLDGE.val();
+        self.write(STATUS, status);
 
-        // info!("3 desc: {:?}", idx);
+        status |= DRIVER.val();
+        self.write(STATUS, status);
 
-        let buf0 = BlkOutHdr {
-            reserved: 0,
-            sector,
-            blk_type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
-        };
+        let features = accept(self.read(DEVICE_FEATURES));
+        self.write(DRIVER_FEATURES, features);
 
+        status |= FEATURES_OK.val();
+        self.write(STATUS, status);
 
-        // VIRTIO 5.2.6.4
-        // MUST use a single 8-byte descriptor containing type, reserved and sector,
-        // followed by descriptors for data, then finally a separate 1-byte descriptor for status.
+        self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+    }
 
-        {
-            let desc0 = &mut vio.desc[idx[0]];
-            desc0.addr = &buf0 as *const _ as usize;
-            desc0.len = core::mem::size_of::<BlkOutHdr>() as u32;
-            desc0.flags = VRING_DESC_F_NEXT;
-            desc0.next = idx[1] as u16;
-        }
+    /// Set up virtqueue `queue` with ring memory of `vq`
+    pub unsafe fn setup_queue(&self, queue: u32, vq: &mut VirtQueue) {
+        use VIRTIO_MMIO::*;
 
-        {
-            let desc1 = &mut vio.desc[idx[1]];
-            desc1.addr = b.data.as_mut_ptr() as usize;
-            desc1.len = BSIZE as u32;
-            desc1.flags = if write { 0 } else { VRING_DESC_F_WRITE };
-            desc1.flags |= VRING_DESC_F_NEXT;
-            desc1.next = idx[2] as u16;
+        self.write(QUEUE_SEL, queue);
+        let max = self.read(QUEUE_NUM_MAX);
+        if max == 0 {
+            panic!("virtio slot {} has no queue {}", self.index, queue);
         }
-
-        b.disk = 1;
-        vio.info[idx[0]] = Some(InflightOp {
-            buf: b,
-            status: 0,
-        });
-
-        {
-            let addr = &vio.info[idx[0]].as_ref().unwrap().status as *const _ as usize;
-            {
-                let desc2 = &mut vio.desc[idx[2]];
-
-                desc2.addr = addr;
-                desc2.len = 1;
-                desc2.flags = VRING_DESC_F_WRITE;
-                desc2.next = 0;
-            }
-
-            let idx_id = 2 + vio.avail[1] as usize % DESC_NUM;
-            vio.avail[idx_id] = idx[0] as u16;
-
-            __sync_synchronize();
-
-            vio.avail[1] = vio.avail[1] + 1;
-
-            unsafe { QUEUE_NOTIFY.ptr().write_volatile(0); }
-            // info!("{:x}", &vio.info[idx[0]].as_ref().unwrap().status as *const _ as usize);
-            let buf_addr = &*vio.info[idx[0]].as_ref().unwrap().buf as *const _;
-            // info!("sleep {:x} id={}", buf_addr as usize, vio.info[idx[0]].as_ref().unwrap().status);
-            while vio.info[idx[0]].as_ref().unwrap().buf.disk == 1 {
-                vio = sleep(buf_addr, vio);
-            }
+        if max < DESC_NUM as u32 {
+            panic!("virtio slot {} max queue too short {} < {}", self.index, max, DESC_NUM);
         }
-        let result = core::mem::replace(&mut vio.info[idx[0]], None);
-        unsafe { vio.info[idx[0]] = core::mem::zeroed(); }
-        vio.info[idx[0]] = None;
-        vio.free_chain(idx[0]);
-        result.unwrap().buf
+        self.write(QUEUE_NUM, DESC_NUM as u32);
+        self.write(QUEUE_PFN, ((vq as *mut _ as usize) >> PAGE_ORDER) as u32);
     }
 
-    /// Read from device and block number
-    pub fn read(&mut self, dev: u32, blockno: u32) -> Box<Buf> {
-        let mut buf = box Buf::new();
-        buf.dev = dev;
-        buf.blockno = blockno;
-        self.rw(buf, false)
+    /// Tell device that driver is ready, after all queues are set up
+    pub unsafe fn driver_ok(&self) {
+        use VIRTIO_MMIO::*;
+        use VIRTIO_CONFIG_S::*;
+        let status = self.read(STATUS);
+        self.write(STATUS, status | DRIVER_OK.val());
     }
 
-    /// Write buffer to disk
-    pub fn write(&mut self, buf: Box<Buf>) {
-        self.rw(buf, true);
+    /// Acknowledge pending interrupts
+    pub unsafe fn ack_interrupt(&self) {
+        use VIRTIO_MMIO::*;
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status & 0x3);
     }
 }
 
-/// VirtIO driver object
-static mut __VIRTIO: VirtIO = VirtIO::new();
-
-/// Global function to get an instance of VirtIO driver
-#[allow(non_snake_case)]
-pub fn VIRTIO() -> &'static mut VirtIO { unsafe { &mut __VIRTIO } }
-
-pub unsafe fn init() {
-    VIRTIO().init();
+/// Driver bound to a virtio-mmio slot
+pub trait VirtIODriver: Sync {
+    /// Handle interrupt of the slot
+    fn interrupt(&self);
 }
 
+/// Initialize device in slot, returning driver bound to it
+pub type VirtIOProbe = fn(Slot) -> Option<&'static dyn VirtIODriver>;
 
-/// VIRTIO interrupt
-pub fn virtiointr() {
-    use crate::info;
-    let mut disk = VIRTIO().0.lock();
-    while disk.used_idx as usize % DESC_NUM != disk.used[0].id as usize % DESC_NUM {
-
-        let id = disk.used[0].elems[disk.used_idx as usize].id as usize;
+/// Device IDs and their drivers
+static DRIVERS: Mutex<Vec<(u32, VirtIOProbe)>> = Mutex::new(Vec::new(), "virtio drivers");
 
-        if disk.info[id].is_none() {
-            panic!("invalid id");
-        }
+/// Drivers bound to each slot
+static BOUND: Mutex<[Option<&'static dyn VirtIODriver>; VIRTIO_SLOTS]> = Mutex::new([None; VIRTIO_SLOTS], "virtio slots");
 
-        let info = disk.info[id].as_mut().unwrap();
+/// Register driver for devices of `device_id`
+pub fn register_driver(device_id: u32, probe: VirtIOProbe) {
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().any(|&(x, _)| x == device_id) {
+        panic!("virtio device {} already registered", device_id);
+    }
+    drivers.push((device_id, probe));
+}
 
-        // info!("processing 0x{:x}", &*info.buf as *const _ as usize);
+/// Driver bound to slot
+pub fn driver_of(index: usize) -> Option<&'static dyn VirtIODriver> {
+    BOUND.lock()[index]
+}
 
-        if info.status != 0 {
-            panic!("virtio_disk_intr status status={} id={}", info.status, id);
+/// Probe all slots, and bind each device found to its driver
+///
+/// Should be called in booting hart.
+pub unsafe fn init() {
+    register_driver(VIRTIO_ID_BLOCK, blk::probe);
+    register_driver(VIRTIO_ID_NET, net::probe);
+    register_driver(VIRTIO_ID_RNG, rng::probe);
+    register_driver(VIRTIO_ID_CONSOLE, console::probe);
+    register_driver(VIRTIO_ID_INPUT, input::probe);
+
+    for index in 0..machine().virtio_count {
+        let slot = Slot::new(index);
+        let device_id = slot.device_id();
+        if device_id == 0 {
+            continue;
+        }
+        let probe = DRIVERS.lock().iter().find(|&&(x, _)| x == device_id).map(|&(_, p)| p);
+        match probe.and_then(|probe| probe(slot)) {
+            Some(driver) => {
+                info!("  virt-io slot {}: device {} bound", index, device_id);
+                BOUND.lock()[index] = Some(driver);
+            },
+            None => info!("  virt-io slot {}: no driver for device {}", index, device_id)
         }
-
-        info.buf.disk = 0;
-
-        wakeup(&*info.buf);
-
-        disk.used_idx = ((disk.used_idx + 1) as usize % DESC_NUM) as u16;
     }
 }
 
-pub mod tests {
-    use super::*;
-
-    pub fn tests() -> &'static [(&'static str, fn())] {
-        &[
-            ("memory layout", test_memory_layout),
-            ("read and write", test_rw)
-        ]
+/// VIRTIO interrupt of `irq`
+pub fn virtiointr(irq: u32) {
+    let slot = match machine().virtio_slot(irq) {
+        Some(index) => Slot::new(index),
+        None => {
+            println!("virtio interrupt {} of no slot", irq);
+            return;
+        }
+    };
+    unsafe { slot.ack_interrupt(); }
+    match driver_of(slot.index) {
+        Some(driver) => driver.interrupt(),
+        None => println!("virtio interrupt of unbound slot {}", slot.index)
     }
+}
 
-    /// Test virtio memory layout
-    pub fn test_memory_layout() {
-        let virtio = VIRTIO().0.lock();
-        assert_eq!(&virtio.desc as *const _ as usize % PAGE_SIZE, 0);
-        assert_eq!(&virtio.used as *const _ as usize % PAGE_SIZE, 0);
-        assert_eq!(&virtio.used as *const _ as usize - &virtio.desc as *const _ as usize, PAGE_SIZE);
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    use crate::{print, println};
-
-    /// Test read and write
-    pub fn test_rw() {
-        let virtio = VIRTIO();
-        let b = virtio.read(1, 0);
-        unsafe { println!("size: {}", core::ptr::read(b.data.as_ptr() as *const usize)); }
-        unsafe { println!("offset: {}", core::ptr::read(b.data.as_ptr().add(8) as *const usize)); }
-        for i in 16..b.data.len() {
-            let d = b.data[i];
-            if d == 0 {
-                break;
+    /// Test devices found are bound to drivers
+    #[test_case]
+    fn test_probe() {
+        assert_eq!(Slot::new(0).device_id(), VIRTIO_ID_BLOCK);
+        assert!(driver_of(0).is_some());
+        for index in 0..machine().virtio_count {
+            if Slot::new(index).device_id() == 0 {
+                assert!(driver_of(index).is_none());
             }
-            print!("{}", b.data[i] as char);
         }
-        println!();
     }
 }
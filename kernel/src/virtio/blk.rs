@@ -0,0 +1,475 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virt-io block device driver
+//!
+//! Each request takes one descriptor in ring, pointing to an indirect
+//! descriptor table of header, data blocks and status. Requests are
+//! submitted without waiting, so that many of them can be in flight,
+//! and completed requests are collected with `wait`.
+//!
+//! A caller submitting many requests collects its own ones when ring is
+//! full, instead of sleeping while holding their descriptors, so that
+//! callers won't wait for each other forever.
+
+use crate::spinlock::Mutex;
+use crate::panic;
+use crate::process::{wakeup, sleep};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::*;
+
+/// Maximum number of blocks in one request
+pub const MAX_SEGMENTS: usize = 32;
+
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+/// An in-flight request. Descriptors in indirect table point into this
+/// structure, so it is boxed and never moved until completed.
+pub struct InflightOp {
+    pub hdr: BlkOutHdr,
+    /// contiguous blocks to be read or written
+    pub bufs: Vec<Box<Buf>>,
+    pub status: u8,
+    /// indirect descriptor table
+    pub table: Vec<VRingDesc>,
+    /// set in interrupt when device has completed this request
+    pub done: bool,
+}
+
+pub struct VirtIOData {
+    /// request queue
+    pub queue: VirtQueue,
+    /// in-flight requests
+    pub info: [Option<Box<InflightOp>>; DESC_NUM],
+}
+
+/// A virtio block device
+pub struct VirtIO {
+    slot: Slot,
+    data: Mutex<VirtIOData>,
+    /// device has a write cache, which is written to disk on flush
+    flush: bool,
+}
+
+/// VIRTIO buffer size
+pub const BSIZE: usize = 1024;
+
+/// VIRTIO Buffer
+#[repr(C)]
+pub struct Buf {
+    pub valid: bool,
+    /// TODO: this can be removed
+    pub disk: i32,
+    /// device ID
+    pub dev: u32,
+    /// block number
+    pub blockno: u32,
+    /// buffer data
+    pub data: [u8; BSIZE],
+}
+
+impl Buf {
+    pub const fn new() -> Self {
+        Self {
+            valid: false,
+            disk: 0,
+            dev: 0,
+            blockno: 0,
+            data: [0; BSIZE],
+        }
+    }
+}
+
+#[repr(C)]
+pub struct BlkOutHdr {
+    pub blk_type: u32,
+    reserved: u32,
+    sector: usize,
+}
+
+/// A request submitted and not yet collected
+#[must_use = "request should be collected with `wait`"]
+pub struct BlkRequest {
+    /// index of descriptor in ring
+    id: usize,
+}
+
+impl VirtIO {
+    pub const fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            data: Mutex::new(VirtIOData {
+                queue: VirtQueue::new(),
+                info: [None; DESC_NUM],
+            }, "vdisk"),
+            flush: false,
+        }
+    }
+
+    /// Initialize device in slot
+    pub unsafe fn init(&mut self) {
+        use VIRTIO_FEATURE::*;
+
+        let vio = self.data.get();
+        let mut flush = false;
+
+        self.slot.negotiate(|mut features| {
+            features &= !BLK_F_RO.bit();
+            features &= !BLK_F_SCSI.bit();
+            features &= !BLK_F_CONFIG_WCE.bit();
+            features &= !BLK_F_MQ.bit();
+            features &= !F_ANY_LAYOUT.bit();
+            features &= !RING_F_EVENT_IDX.bit();
+            if features & RING_F_INDIRECT_DESC.bit() == 0 {
+                panic!("virtio disk doesn't support indirect descriptors");
+            }
+            flush = features & BLK_F_FLUSH.bit() != 0;
+            features
+        });
+        self.flush = flush;
+
+        self.slot.setup_queue(0, &mut vio.queue);
+
+        self.slot.driver_ok();
+    }
+
+    /// Submit a request to read or write `bufs`, which are contiguous blocks
+    /// starting from `blockno`. Returns without waiting for completion,
+    /// but sleeps until a descriptor is free if ring is full.
+    ///
+    /// The request must be collected with `wait`. Caller holding other
+    /// requests should use `read_blocks` or `write_blocks` instead.
+    pub fn submit(&self, blockno: u32, bufs: Vec<Box<Buf>>, write: bool) -> BlkRequest {
+        if bufs.is_empty() || bufs.len() > MAX_SEGMENTS {
+            panic!("invalid number of blocks {}", bufs.len());
+        }
+        let blk_type = if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN };
+        self.submit_op(blk_type, blockno, bufs, &mut VecDeque::new(), &mut Vec::new())
+    }
+
+    /// Submit a request of `blk_type` on `bufs`, which may be empty.
+    ///
+    /// `pending` holds requests submitted earlier by caller. If ring is
+    /// full, they are collected in order, with their buffers appended to
+    /// `done`, until a descriptor is free. Caller only sleeps for a free
+    /// descriptor when it holds none.
+    fn submit_op(&self, blk_type: u32, blockno: u32, bufs: Vec<Box<Buf>>,
+                 pending: &mut VecDeque<BlkRequest>, done: &mut Vec<Box<Buf>>) -> BlkRequest {
+        let write = blk_type != VIRTIO_BLK_T_IN;
+        let mut op = box InflightOp {
+            hdr: BlkOutHdr {
+                reserved: 0,
+                sector: blockno as usize * (BSIZE / 512),
+                blk_type,
+            },
+            bufs,
+            status: 0xff,
+            table: Vec::new(),
+            done: false,
+        };
+
+        // VIRTIO 5.2.6.4
+        // MUST use a single 8-byte descriptor containing type, reserved and sector,
+        // followed by descriptors for data, then finally a separate 1-byte descriptor for status.
+
+        let op_ref = &mut *op;
+        let n = op_ref.bufs.len();
+        op_ref.table.push(VRingDesc {
+            addr: &op_ref.hdr as *const _ as usize,
+            len: core::mem::size_of::<BlkOutHdr>() as u32,
+            flags: VRING_DESC_F_NEXT,
+            next: 1,
+        });
+        for (i, b) in op_ref.bufs.iter_mut().enumerate() {
+            b.disk = 1;
+            op_ref.table.push(VRingDesc {
+                addr: b.data.as_mut_ptr() as usize,
+                len: BSIZE as u32,
+                flags: VRING_DESC_F_NEXT | if write { 0 } else { VRING_DESC_F_WRITE },
+                next: (i + 2) as u16,
+            });
+        }
+        op_ref.table.push(VRingDesc {
+            addr: &op_ref.status as *const _ as usize,
+            len: 1,
+            flags: VRING_DESC_F_WRITE,
+            next: 0,
+        });
+        let table_addr = op_ref.table.as_ptr() as usize;
+        let table_len = ((n + 2) * core::mem::size_of::<VRingDesc>()) as u32;
+
+        let mut vio = self.data.lock();
+
+        let id = loop {
+            if let Some(id) = vio.queue.alloc_desc() {
+                break id;
+            }
+            match pending.pop_front() {
+                Some(req) => {
+                    drop(vio);
+                    done.extend(self.wait(req));
+                    vio = self.data.lock();
+                }
+                None => vio = sleep(&vio.queue.free[0] as *const _, vio)
+            }
+        };
+
+        {
+            let desc = &mut vio.queue.desc[id];
+            desc.addr = table_addr;
+            desc.len = table_len;
+            desc.flags = VRING_DESC_F_INDIRECT;
+            desc.next = 0;
+        }
+        vio.info[id] = Some(op);
+
+        vio.queue.push(self.slot, 0, id);
+
+        BlkRequest { id }
+    }
+
+    /// Check if request has completed
+    pub fn is_done(&self, req: &BlkRequest) -> bool {
+        self.data.lock().info[req.id].as_ref().unwrap().done
+    }
+
+    /// Sleep until request has completed, and returns its buffers
+    pub fn wait(&self, req: BlkRequest) -> Vec<Box<Buf>> {
+        let mut vio = self.data.lock();
+        loop {
+            let op = vio.info[req.id].as_ref().unwrap();
+            if op.done {
+                break;
+            }
+            let channel = &**op as *const InflightOp;
+            vio = sleep(channel, vio);
+        }
+        let op = vio.info[req.id].take().unwrap();
+        vio.queue.free_desc(req.id);
+        if op.status != 0 {
+            panic!("virtio disk request failed status={} id={}", op.status, req.id);
+        }
+        op.bufs
+    }
+
+    /// Read `n` contiguous blocks from `blockno`. Requests of at most
+    /// `MAX_SEGMENTS` blocks are submitted before waiting for any of them,
+    /// as long as there are free descriptors.
+    pub fn read_blocks(&self, dev: u32, blockno: u32, n: usize) -> Vec<Box<Buf>> {
+        let mut pending = VecDeque::new();
+        let mut done = Vec::new();
+        let mut i = 0;
+        while i < n {
+            let cnt = MAX_SEGMENTS.min(n - i);
+            let bufs = (0..cnt).map(|j| {
+                let mut buf = box Buf::new();
+                buf.dev = dev;
+                buf.blockno = blockno + (i + j) as u32;
+                buf
+            }).collect();
+            let req = self.submit_op(VIRTIO_BLK_T_IN, blockno + i as u32, bufs, &mut pending, &mut done);
+            pending.push_back(req);
+            i += cnt;
+        }
+        for req in pending {
+            done.extend(self.wait(req));
+        }
+        done
+    }
+
+    /// Write contiguous blocks, starting from block number of the first one
+    pub fn write_blocks(&self, bufs: Vec<Box<Buf>>) {
+        let mut pending = VecDeque::new();
+        let mut done = Vec::new();
+        let mut bufs = bufs.into_iter();
+        while let Some(first) = bufs.next() {
+            let blockno = first.blockno;
+            let mut chunk = vec![first];
+            while chunk.len() < MAX_SEGMENTS {
+                match bufs.next() {
+                    Some(b) => chunk.push(b),
+                    None => break
+                }
+            }
+            let req = self.submit_op(VIRTIO_BLK_T_OUT, blockno, chunk, &mut pending, &mut done);
+            pending.push_back(req);
+        }
+        for req in pending {
+            self.wait(req);
+        }
+    }
+
+    /// Capacity of disk in bytes, read from configuration space
+    pub fn capacity(&self) -> usize {
+        // capacity is a 64-bit number of 512-byte sectors, read in two
+        // 32-bit accesses in legacy interface
+        let (lo, hi) = unsafe { (self.slot.config(0), self.slot.config(4)) };
+        ((hi as usize) << 32 | lo as usize) * 512
+    }
+
+    /// Read from device and block number
+    pub fn read(&self, dev: u32, blockno: u32) -> Box<Buf> {
+        self.read_blocks(dev, blockno, 1).pop().unwrap()
+    }
+
+    /// Write buffer to disk
+    pub fn write(&self, buf: Box<Buf>) {
+        let req = self.submit(buf.blockno, vec![buf], true);
+        self.wait(req);
+    }
+
+    /// Write cache of device to disk, waiting until it's done. Does
+    /// nothing if device has no write cache.
+    pub fn flush(&self) {
+        if self.flush {
+            let req = self.submit_op(VIRTIO_BLK_T_FLUSH, 0, Vec::new(), &mut VecDeque::new(), &mut Vec::new());
+            self.wait(req);
+        }
+    }
+}
+
+impl VirtIODriver for VirtIO {
+    /// Mark completed requests as done and wake up those waiting for them.
+    fn interrupt(&self) {
+        let mut disk = self.data.lock();
+        while let Some((id, _)) = disk.queue.pop_used() {
+            let info = match disk.info[id].as_mut() {
+                Some(info) => info,
+                None => panic!("invalid id")
+            };
+
+            info.done = true;
+            for b in info.bufs.iter_mut() {
+                b.disk = 0;
+            }
+
+            wakeup(&**info as *const InflightOp);
+        }
+    }
+}
+
+/// Block devices, in order of slots
+static DISKS: Mutex<Vec<&'static VirtIO>> = Mutex::new(Vec::new(), "disks");
+
+/// Initialize block device in `slot`
+pub fn probe(slot: Slot) -> Option<&'static dyn VirtIODriver> {
+    let disk = Box::leak(box VirtIO::new(slot));
+    unsafe { disk.init(); }
+    let disk: &'static VirtIO = disk;
+    DISKS.lock().push(disk);
+    Some(disk)
+}
+
+/// Get `n`th block device
+pub fn disk(n: usize) -> Option<&'static VirtIO> {
+    DISKS.lock().get(n).copied()
+}
+
+/// Write caches of all disks to disk
+pub fn flush_all() {
+    let disks = DISKS.lock().clone();
+    for disk in disks {
+        disk.flush();
+    }
+}
+
+/// Global function to get the disk holding file system
+#[allow(non_snake_case)]
+pub fn VIRTIO() -> &'static VirtIO {
+    disk(0).expect("no virtio disk")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test virtio memory layout
+    #[test_case]
+    fn test_memory_layout() {
+        let virtio = VIRTIO().data.lock();
+        assert_eq!(&virtio.queue.desc as *const _ as usize % PAGE_SIZE, 0);
+        assert_eq!(&virtio.queue.used as *const _ as usize % PAGE_SIZE, 0);
+        assert_eq!(&virtio.queue.used as *const _ as usize - &virtio.queue.desc as *const _ as usize, PAGE_SIZE);
+    }
+
+    use crate::{print, println};
+
+    /// Test read and write
+    #[test_case]
+    fn test_rw() {
+        let virtio = VIRTIO();
+        let b = virtio.read(1, 0);
+        unsafe { println!("size: {}", core::ptr::read(b.data.as_ptr() as *const usize)); }
+        unsafe { println!("offset: {}", core::ptr::read(b.data.as_ptr().add(8) as *const usize)); }
+        for i in 16..b.data.len() {
+            let d = b.data[i];
+            if d == 0 {
+                break;
+            }
+            print!("{}", b.data[i] as char);
+        }
+        println!();
+    }
+
+    /// Test requests of multiple blocks in flight at once
+    #[test_case]
+    fn test_batch() {
+        let virtio = VIRTIO();
+        let n = MAX_SEGMENTS + 3;
+        let bufs = virtio.read_blocks(1, 0, n);
+        assert_eq!(bufs.len(), n);
+        for (i, b) in bufs.iter().enumerate() {
+            assert_eq!(b.blockno, i as u32);
+            assert_eq!(&b.data[..], &virtio.read(1, i as u32).data[..]);
+        }
+        let r1 = virtio.submit(1, bufs.into_iter().skip(1).take(2).collect(), false);
+        let r2 = virtio.submit(0, vec![box Buf::new()], false);
+        assert_eq!(virtio.wait(r2).len(), 1);
+        assert_eq!(virtio.wait(r1).len(), 2);
+    }
+
+    /// Read blocks from 0 with `DESC_NUM * 2` requests of one block each,
+    /// which are more than ring holds
+    fn read_many(_arg: usize) {
+        let virtio = VIRTIO();
+        let mut pending = VecDeque::new();
+        let mut done = Vec::new();
+        for i in 0..DESC_NUM * 2 {
+            let mut buf = box Buf::new();
+            buf.blockno = i as u32;
+            let req = virtio.submit_op(VIRTIO_BLK_T_IN, i as u32, vec![buf], &mut pending, &mut done);
+            pending.push_back(req);
+        }
+        for req in pending {
+            done.extend(virtio.wait(req));
+        }
+        assert_eq!(done.len(), DESC_NUM * 2);
+        assert!(done.iter().enumerate().all(|(i, b)| b.blockno == i as u32));
+    }
+
+    /// Test callers submitting more requests than ring holds at the same time
+    #[test_case]
+    fn test_ring_full() {
+        use crate::process::{kthread_create, join};
+        let tid = kthread_create(read_many, 0).unwrap();
+        read_many(0);
+        assert_eq!(join(tid), Some(0));
+        assert!(VIRTIO().data.lock().info.iter().all(|op| op.is_none()));
+    }
+
+    /// Test flushing write cache of disks
+    #[test_case]
+    fn test_flush() {
+        let virtio = VIRTIO();
+        virtio.write(virtio.read(1, 0));
+        flush_all();
+        assert!(virtio.data.lock().info.iter().all(|op| op.is_none()));
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virt-io console device driver
+//!
+//! With multiport feature, ports are added by device through control
+//! messages after driver is ready, and each port has its own pair of
+//! queues. Otherwise there is only port 0. Port `n` is `/dev/hvc<n>`.
+//!
+//! Receive queues are kept filled with buffers, and characters received
+//! are queued for readers. Written data is copied to a buffer owned by
+//! device until it is sent.
+
+use crate::spinlock::Mutex;
+use crate::process::{WaitQueue, sleep};
+use crate::warn;
+use crate::file::{Device, DeviceHandle, POLLIN, POLLOUT};
+use crate::file::device::{FIONREAD, ENOTTY};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::*;
+
+/// Device supports multiple ports and control queues
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+
+/// Maximum number of ports supported by driver
+pub const MAX_PORTS: usize = 4;
+
+/// Major number of virtio consoles. Minor number is port.
+pub const MAJOR_HVC: usize = 229;
+
+const CONTROL_RX: u32 = 2;
+const CONTROL_TX: u32 = 3;
+
+/// Size of each receive buffer
+const RX_BUF_SIZE: usize = 128;
+/// Number of receive buffers of each port
+const RX_BUFS: usize = 8;
+/// Size of each control receive buffer, large enough for port names
+const CONTROL_BUF_SIZE: usize = 64;
+/// Maximum size of data sent in one buffer
+const TX_BUF_SIZE: usize = 4096;
+/// Maximum number of characters kept for readers of each port
+const INPUT_MAX: usize = 4096;
+
+/// Control events, VIRTIO spec 5.3.6.2
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const PORT_OPEN: u16 = 6;
+
+/// Receive and transmit queue of `port`
+fn port_queues(port: usize) -> (u32, u32) {
+    if port == 0 {
+        (0, 1)
+    } else {
+        (2 + 2 * port as u32, 3 + 2 * port as u32)
+    }
+}
+
+/// A virtqueue in which each descriptor points to one buffer
+pub struct BufQueue {
+    pub queue: VirtQueue,
+    /// buffers owned by device, indexed by descriptor
+    pub bufs: [Option<Vec<u8>>; DESC_NUM],
+}
+
+impl BufQueue {
+    pub const fn new() -> Self {
+        Self {
+            queue: VirtQueue::new(),
+            bufs: [None; DESC_NUM],
+        }
+    }
+
+    fn has_free(&self) -> bool {
+        self.queue.free.iter().any(|&x| x)
+    }
+
+    /// Give `buf` to device, in which device may write if `write`.
+    /// Returns false if there are no free descriptors.
+    fn post(&mut self, slot: Slot, queue: u32, buf: Vec<u8>, write: bool) -> bool {
+        let id = match self.queue.alloc_desc() {
+            Some(id) => id,
+            None => return false
+        };
+        {
+            let desc = &mut self.queue.desc[id];
+            desc.addr = buf.as_ptr() as usize;
+            desc.len = buf.len() as u32;
+            desc.flags = if write { VRING_DESC_F_WRITE } else { 0 };
+            desc.next = 0;
+        }
+        self.bufs[id] = Some(buf);
+        self.queue.push(slot, queue, id);
+        true
+    }
+
+    /// Take back next buffer used by device, with number of bytes written
+    fn take(&mut self) -> Option<(Vec<u8>, usize)> {
+        let (id, len) = self.queue.pop_used()?;
+        self.queue.free_desc(id);
+        let buf = self.bufs[id].take().expect("virtio console: invalid id");
+        Some((buf, len as usize))
+    }
+}
+
+pub struct Port {
+    pub rx: BufQueue,
+    pub tx: BufQueue,
+    /// port is added by device
+    pub present: bool,
+    /// host side of port is connected
+    pub host_open: bool,
+    /// characters received and not yet read
+    pub input: VecDeque<u8>,
+}
+
+impl Port {
+    fn new() -> Self {
+        Self {
+            rx: BufQueue::new(),
+            tx: BufQueue::new(),
+            present: false,
+            host_open: false,
+            input: VecDeque::new(),
+        }
+    }
+}
+
+pub struct ConsoleData {
+    pub control_rx: BufQueue,
+    pub control_tx: BufQueue,
+    /// ports, whose queues are set up at initialization and never move
+    pub ports: Vec<Port>,
+    pub multiport: bool,
+}
+
+impl ConsoleData {
+    /// Send control message. Message is dropped if control queue is full.
+    fn send_control(&mut self, slot: Slot, id: u32, event: u16, value: u16) {
+        let mut msg = Vec::with_capacity(8);
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        if !self.control_tx.post(slot, CONTROL_TX, msg, false) {
+            warn!("virtio console: control message {} dropped", event);
+        }
+    }
+
+    /// Fill receive queue of `port` and mark it present
+    fn add_port(&mut self, slot: Slot, port: usize) {
+        let p = &mut self.ports[port];
+        p.present = true;
+        for _ in 0..RX_BUFS {
+            p.rx.post(slot, port_queues(port).0, vec![0; RX_BUF_SIZE], true);
+        }
+    }
+
+    /// Handle a control message from device
+    fn control(&mut self, slot: Slot, msg: &[u8]) {
+        if msg.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes([msg[0], msg[1], msg[2], msg[3]]);
+        let event = u16::from_le_bytes([msg[4], msg[5]]);
+        let value = u16::from_le_bytes([msg[6], msg[7]]);
+        let port = id as usize;
+        match event {
+            DEVICE_ADD => {
+                if port < self.ports.len() && !self.ports[port].present {
+                    self.add_port(slot, port);
+                    self.send_control(slot, id, PORT_READY, 1);
+                    // ports are always open on our side
+                    self.send_control(slot, id, PORT_OPEN, 1);
+                } else {
+                    self.send_control(slot, id, PORT_READY, 0);
+                }
+            }
+            DEVICE_REMOVE => if port < self.ports.len() {
+                self.ports[port].present = false;
+            },
+            PORT_OPEN => if port < self.ports.len() {
+                self.ports[port].host_open = value != 0;
+            },
+            _ => {}
+        }
+    }
+}
+
+/// A virtio console device
+pub struct VirtIOConsole {
+    slot: Slot,
+    data: Mutex<ConsoleData>,
+    /// readers of all ports waiting for characters
+    readers: WaitQueue,
+}
+
+impl VirtIOConsole {
+    pub fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            data: Mutex::new(ConsoleData {
+                control_rx: BufQueue::new(),
+                control_tx: BufQueue::new(),
+                ports: Vec::new(),
+                multiport: false,
+            }, "vconsole"),
+            readers: WaitQueue::new(),
+        }
+    }
+
+    /// Initialize device in slot. With multiport, tell device that driver
+    /// is ready, so that it begins to add ports.
+    pub unsafe fn init(&mut self) {
+        let mut accepted = 0;
+        self.slot.negotiate(|features| {
+            accepted = features & VIRTIO_CONSOLE_F_MULTIPORT;
+            accepted
+        });
+        let data = self.data.get();
+        data.multiport = accepted != 0;
+        let nr_ports = if data.multiport {
+            (self.slot.config(4) as usize).min(MAX_PORTS).max(1)
+        } else {
+            1
+        };
+        data.ports = (0..nr_ports).map(|_| Port::new()).collect();
+
+        for (i, port) in data.ports.iter_mut().enumerate() {
+            let (rx, tx) = port_queues(i);
+            self.slot.setup_queue(rx, &mut port.rx.queue);
+            self.slot.setup_queue(tx, &mut port.tx.queue);
+        }
+        if data.multiport {
+            self.slot.setup_queue(CONTROL_RX, &mut data.control_rx.queue);
+            self.slot.setup_queue(CONTROL_TX, &mut data.control_tx.queue);
+        }
+
+        self.slot.driver_ok();
+
+        if data.multiport {
+            while data.control_rx.post(self.slot, CONTROL_RX, vec![0; CONTROL_BUF_SIZE], true) {}
+            data.send_control(self.slot, 0, DEVICE_READY, 1);
+        } else {
+            data.add_port(self.slot, 0);
+        }
+    }
+
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Number of ports supported
+    pub fn ports(&self) -> usize {
+        self.data.lock().ports.len()
+    }
+
+    /// Check if `port` has been added by device
+    pub fn is_present(&self, port: usize) -> bool {
+        self.data.lock().ports.get(port).map_or(false, |p| p.present)
+    }
+
+    /// Read characters received on `port` into `content` without waiting.
+    /// Returns number of characters read.
+    pub fn read(&self, port: usize, content: &mut [u8]) -> usize {
+        let mut data = self.data.lock();
+        let input = &mut data.ports[port].input;
+        let n = content.len().min(input.len());
+        for (dst, src) in content.iter_mut().zip(input.drain(..n)) {
+            *dst = src;
+        }
+        n
+    }
+
+    /// Write `content` to `port`, waiting for free descriptors. Returns
+    /// number of bytes written, or `None` if port has been removed.
+    pub fn write(&self, port: usize, content: &[u8]) -> Option<usize> {
+        let (_, queue) = port_queues(port);
+        let mut data = self.data.lock();
+        for chunk in content.chunks(TX_BUF_SIZE) {
+            loop {
+                if !data.ports[port].present {
+                    return None;
+                }
+                if data.ports[port].tx.has_free() {
+                    break;
+                }
+                let channel = &data.ports[port].tx.queue.free[0] as *const bool;
+                data = sleep(channel, data);
+            }
+            data.ports[port].tx.post(self.slot, queue, chunk.to_vec(), false);
+        }
+        Some(content.len())
+    }
+
+    /// Number of characters received on `port` and not yet read
+    pub fn input_len(&self, port: usize) -> usize {
+        self.data.lock().ports[port].input.len()
+    }
+}
+
+impl VirtIODriver for VirtIOConsole {
+    /// Release sent buffers, handle control messages, and queue received
+    /// characters for readers
+    fn interrupt(&self) {
+        let mut received = false;
+        {
+            let mut data = self.data.lock();
+            let data = &mut *data;
+            while data.control_tx.take().is_some() {}
+            while let Some((mut buf, len)) = data.control_rx.take() {
+                data.control(self.slot, &buf[..len.min(buf.len())]);
+                buf.resize(CONTROL_BUF_SIZE, 0);
+                data.control_rx.post(self.slot, CONTROL_RX, buf, true);
+            }
+            for (i, port) in data.ports.iter_mut().enumerate() {
+                while port.tx.take().is_some() {}
+                while let Some((buf, len)) = port.rx.take() {
+                    for &x in &buf[..len.min(buf.len())] {
+                        if port.input.len() < INPUT_MAX {
+                            port.input.push_back(x);
+                        }
+                    }
+                    received = true;
+                    if port.present {
+                        port.rx.post(self.slot, port_queues(i).0, buf, true);
+                    }
+                }
+            }
+        }
+        if received {
+            self.readers.wakeup(self.channel());
+        }
+    }
+}
+
+/// Console device found, if any
+static CONSOLE: Mutex<Option<&'static VirtIOConsole>> = Mutex::new(None, "vconsole");
+
+/// Initialize console device in `slot`
+pub fn probe(slot: Slot) -> Option<&'static dyn VirtIODriver> {
+    if CONSOLE.lock().is_some() {
+        return None;
+    }
+    let console = Box::leak(box VirtIOConsole::new(slot));
+    unsafe { console.init(); }
+    let console: &'static VirtIOConsole = console;
+    *CONSOLE.lock() = Some(console);
+    Some(console)
+}
+
+/// A port of virtio console
+pub struct Hvc {
+    console: &'static VirtIOConsole,
+    port: usize,
+}
+
+impl Device for Hvc {
+    /// read characters received without waiting
+    fn read(&self, _handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
+        self.console.read(self.port, content) as i32
+    }
+
+    fn write(&self, _handle: &mut DeviceHandle, content: &[u8]) -> i32 {
+        match self.console.write(self.port, content) {
+            Some(n) => n as i32,
+            None => -1
+        }
+    }
+
+    /// `FIONREAD` returns number of characters received and not yet read
+    fn ioctl(&self, _handle: &mut DeviceHandle, cmd: usize, _arg: usize) -> i32 {
+        match cmd {
+            FIONREAD => self.console.input_len(self.port) as i32,
+            _ => -ENOTTY
+        }
+    }
+
+    fn poll(&self) -> usize {
+        if self.console.input_len(self.port) > 0 {
+            POLLIN | POLLOUT
+        } else {
+            POLLOUT
+        }
+    }
+
+    fn wait_queue(&self) -> Option<(&WaitQueue, usize)> {
+        Some((&self.console.readers, self.console.channel()))
+    }
+}
+
+/// Create console device of port `minor`, or `None` if port is not present
+pub fn open_hvc(minor: usize) -> Option<Arc<dyn Device>> {
+    let console = (*CONSOLE.lock())?;
+    if !console.is_present(minor) {
+        return None;
+    }
+    Some(Arc::new(Hvc { console, port: minor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::open_device;
+
+    /// Test ports added by device
+    #[test_case]
+    fn test_ports() {
+        let console = (*CONSOLE.lock()).expect("no virtio console");
+        assert!(console.ports() >= 1);
+        assert!(console.is_present(0));
+        assert!(!console.is_present(MAX_PORTS));
+        assert!(open_device(MAJOR_HVC, MAX_PORTS).is_none());
+    }
+
+    /// Test writing to each port present
+    #[test_case]
+    fn test_write() {
+        let console = (*CONSOLE.lock()).unwrap();
+        for port in 0..console.ports() {
+            if let Some(hvc) = open_device(MAJOR_HVC, port) {
+                assert_eq!(hvc.write(b"hello hvc\n"), 10);
+                assert_eq!(hvc.poll() & POLLOUT, POLLOUT);
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virt-io input device driver
+//!
+//! Event queue is kept filled with one buffer per descriptor, each holding
+//! one event. Events are queued for readers of `/dev/input`, and oldest
+//! ones are dropped if readers fall behind.
+
+use crate::spinlock::Mutex;
+use crate::process::WaitQueue;
+use crate::info;
+use crate::file::{Device, DeviceHandle, POLLIN};
+use crate::file::device::{FIONREAD, ENOTTY};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::*;
+
+/// Major number of input devices
+pub const MAJOR_INPUT: usize = 13;
+/// Minor number of `/dev/input`. Minor number `MINOR_EVENT0 + n` is the
+/// `n`th input device found.
+pub const MINOR_EVENT0: usize = 64;
+
+const EVENT_QUEUE: u32 = 0;
+
+/// Maximum number of events kept for readers
+const EVENTS_MAX: usize = 256;
+
+/// Configuration select of device name, VIRTIO spec 5.8.5
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+
+/// Event types, same as Linux evdev
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+/// An input event, as written by device and read from `/dev/input`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    pub ty: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    pub const fn new() -> Self {
+        Self { ty: 0, code: 0, value: 0 }
+    }
+
+    /// Size of event read from `/dev/input`
+    pub const SIZE: usize = core::mem::size_of::<InputEvent>();
+
+    fn to_bytes(&self) -> [u8; 8] {
+        let mut buf = [0; 8];
+        buf[0..2].copy_from_slice(&self.ty.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.code.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.value.to_le_bytes());
+        buf
+    }
+}
+
+pub struct InputData {
+    pub queue: VirtQueue,
+    /// buffer of each descriptor
+    pub bufs: Box<[InputEvent; DESC_NUM]>,
+    /// events not yet read
+    pub events: VecDeque<InputEvent>,
+}
+
+impl InputData {
+    /// Give buffer of descriptor `id` to device
+    fn post(&mut self, slot: Slot, id: usize) {
+        {
+            let desc = &mut self.queue.desc[id];
+            desc.addr = &self.bufs[id] as *const _ as usize;
+            desc.len = InputEvent::SIZE as u32;
+            desc.flags = VRING_DESC_F_WRITE;
+            desc.next = 0;
+        }
+        self.queue.push(slot, EVENT_QUEUE, id);
+    }
+}
+
+/// A virtio input device
+pub struct VirtIOInput {
+    slot: Slot,
+    name: String,
+    data: Mutex<InputData>,
+    /// readers waiting for events
+    readers: WaitQueue,
+}
+
+impl VirtIOInput {
+    pub fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            name: String::new(),
+            data: Mutex::new(InputData {
+                queue: VirtQueue::new(),
+                bufs: box [InputEvent::new(); DESC_NUM],
+                events: VecDeque::new(),
+            }, "vinput"),
+            readers: WaitQueue::new(),
+        }
+    }
+
+    /// Read device name from configuration space
+    unsafe fn read_name(&self) -> String {
+        let config = VIRTIO_MMIO::CONFIG.val(self.slot.base()) as *mut u8;
+        config.write_volatile(VIRTIO_INPUT_CFG_ID_NAME);
+        config.add(1).write_volatile(0);
+        let size = config.add(2).read_volatile() as usize;
+        (0..size.min(128)).map(|i| config.add(8 + i).read_volatile() as char).collect()
+    }
+
+    /// Initialize device in slot, and fill event queue
+    pub unsafe fn init(&mut self) {
+        self.slot.negotiate(|_| 0);
+        self.name = self.read_name();
+
+        let data = self.data.get();
+        self.slot.setup_queue(EVENT_QUEUE, &mut data.queue);
+
+        self.slot.driver_ok();
+
+        while let Some(id) = data.queue.alloc_desc() {
+            data.post(self.slot, id);
+        }
+    }
+
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Name of device, such as "QEMU Virtio Keyboard"
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Take events that fit in `content` without waiting. Returns number
+    /// of bytes read, which is a multiple of `InputEvent::SIZE`.
+    pub fn read(&self, content: &mut [u8]) -> usize {
+        let mut data = self.data.lock();
+        let n = (content.len() / InputEvent::SIZE).min(data.events.len());
+        for (chunk, event) in content.chunks_mut(InputEvent::SIZE).zip(data.events.drain(..n)) {
+            chunk.copy_from_slice(&event.to_bytes());
+        }
+        n * InputEvent::SIZE
+    }
+
+    /// Number of events not yet read
+    pub fn pending(&self) -> usize {
+        self.data.lock().events.len()
+    }
+}
+
+impl VirtIODriver for VirtIOInput {
+    /// Queue events for readers and give buffers back to device
+    fn interrupt(&self) {
+        let mut received = false;
+        {
+            let mut data = self.data.lock();
+            while let Some((id, _)) = data.queue.pop_used() {
+                let event = data.bufs[id];
+                if data.events.len() == EVENTS_MAX {
+                    data.events.pop_front();
+                }
+                data.events.push_back(event);
+                data.post(self.slot, id);
+                received = true;
+            }
+        }
+        if received {
+            self.readers.wakeup(self.channel());
+        }
+    }
+}
+
+/// Input devices, in order of slots
+static INPUTS: Mutex<Vec<&'static VirtIOInput>> = Mutex::new(Vec::new(), "inputs");
+
+/// Initialize input device in `slot`
+pub fn probe(slot: Slot) -> Option<&'static dyn VirtIODriver> {
+    let input = Box::leak(box VirtIOInput::new(slot));
+    unsafe { input.init(); }
+    let input: &'static VirtIOInput = input;
+    info!("  virt-io input: {}", input.name());
+    INPUTS.lock().push(input);
+    Some(input)
+}
+
+/// Event device of an input device
+pub struct EventDevice {
+    input: &'static VirtIOInput,
+}
+
+impl Device for EventDevice {
+    /// read whole events received without waiting
+    fn read(&self, _handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
+        self.input.read(content) as i32
+    }
+
+    fn write(&self, _handle: &mut DeviceHandle, _content: &[u8]) -> i32 {
+        -1
+    }
+
+    /// `FIONREAD` returns number of bytes of events not yet read
+    fn ioctl(&self, _handle: &mut DeviceHandle, cmd: usize, _arg: usize) -> i32 {
+        match cmd {
+            FIONREAD => (self.input.pending() * InputEvent::SIZE) as i32,
+            _ => -ENOTTY
+        }
+    }
+
+    fn poll(&self) -> usize {
+        if self.input.pending() > 0 { POLLIN } else { 0 }
+    }
+
+    fn wait_queue(&self) -> Option<(&WaitQueue, usize)> {
+        Some((&self.input.readers, self.input.channel()))
+    }
+}
+
+/// Create event device of `minor` number
+pub fn open_input(minor: usize) -> Option<Arc<dyn Device>> {
+    let index = minor.checked_sub(MINOR_EVENT0)?;
+    let input = INPUTS.lock().get(index).copied()?;
+    Some(Arc::new(EventDevice { input }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::open_device;
+
+    /// Test layout of events
+    #[test_case]
+    fn test_event() {
+        assert_eq!(InputEvent::SIZE, 8);
+        let event = InputEvent { ty: EV_KEY, code: 30, value: 1 };
+        assert_eq!(event.to_bytes(), [1, 0, 30, 0, 1, 0, 0, 0]);
+    }
+
+    /// Test reading events without waiting
+    #[test_case]
+    fn test_read() {
+        let dev = open_device(MAJOR_INPUT, MINOR_EVENT0).expect("no virtio input");
+        assert!(!INPUTS.lock()[0].name().is_empty());
+        assert!(open_device(MAJOR_INPUT, 0).is_none());
+        let mut buf = [0; InputEvent::SIZE * 4 + 3];
+        let n = dev.read(&mut buf);
+        assert!(n >= 0 && n as usize % InputEvent::SIZE == 0);
+        assert_eq!(dev.read(&mut buf[..InputEvent::SIZE - 1]), 0);
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virt-io network device driver
+//!
+//! Receive queue is kept filled with buffers, each made of two
+//! descriptors for header and frame. Frames are copied out in interrupt
+//! and passed to network stack after locks of driver are released, so
+//! that stack may send replies right away. Frames sent when transmit
+//! queue is full are dropped.
+
+use crate::spinlock::Mutex;
+use crate::net::{self, MacAddr, NetInterface};
+use crate::net::ether::{ETH_HLEN, ETH_MTU};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use super::*;
+
+/// Device has given MAC address in configuration space
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// Maximum size of frame without checksum
+pub const FRAME_SIZE: usize = ETH_HLEN + ETH_MTU;
+
+/// Header preceding each frame, in legacy layout without mergeable buffers
+#[repr(C)]
+pub struct NetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl NetHdr {
+    pub const fn new() -> Self {
+        Self { flags: 0, gso_type: 0, hdr_len: 0, gso_size: 0, csum_start: 0, csum_offset: 0 }
+    }
+}
+
+/// Buffer of one frame, in which device writes or from which device reads
+pub struct Packet {
+    pub hdr: NetHdr,
+    pub data: [u8; FRAME_SIZE],
+}
+
+impl Packet {
+    pub const fn new() -> Self {
+        Self { hdr: NetHdr::new(), data: [0; FRAME_SIZE] }
+    }
+}
+
+pub struct NetQueue {
+    pub queue: VirtQueue,
+    /// buffers owned by device, indexed by head descriptor
+    pub packets: [Option<Box<Packet>>; DESC_NUM],
+}
+
+impl NetQueue {
+    pub const fn new() -> Self {
+        Self {
+            queue: VirtQueue::new(),
+            packets: [None; DESC_NUM],
+        }
+    }
+
+    /// Give `packet` to device, in which device may write if `write`.
+    /// Returns false if there are no free descriptors.
+    fn post(&mut self, slot: Slot, queue: u32, packet: Box<Packet>, len: usize, write: bool) -> bool {
+        let head = match self.queue.alloc_desc() {
+            Some(head) => head,
+            None => return false
+        };
+        let next = match self.queue.alloc_desc() {
+            Some(next) => next,
+            None => {
+                self.queue.free_desc(head);
+                return false;
+            }
+        };
+        let flags = if write { VRING_DESC_F_WRITE } else { 0 };
+        {
+            let desc = &mut self.queue.desc[head];
+            desc.addr = &packet.hdr as *const _ as usize;
+            desc.len = core::mem::size_of::<NetHdr>() as u32;
+            desc.flags = flags | VRING_DESC_F_NEXT;
+            desc.next = next as u16;
+        }
+        {
+            let desc = &mut self.queue.desc[next];
+            desc.addr = packet.data.as_ptr() as usize;
+            desc.len = len as u32;
+            desc.flags = flags;
+            desc.next = 0;
+        }
+        self.packets[head] = Some(packet);
+        self.queue.push(slot, queue, head);
+        true
+    }
+
+    /// Take back next buffer used by device, with number of bytes written
+    fn take(&mut self) -> Option<(Box<Packet>, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let next = self.queue.desc[head].next as usize;
+        self.queue.free_desc(next);
+        self.queue.free_desc(head);
+        let packet = self.packets[head].take().expect("virtio net: invalid id");
+        Some((packet, len as usize))
+    }
+}
+
+/// A virtio network device
+pub struct VirtIONet {
+    slot: Slot,
+    mac: MacAddr,
+    rx: Mutex<NetQueue>,
+    tx: Mutex<NetQueue>,
+}
+
+impl VirtIONet {
+    pub const fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            mac: MacAddr([0; 6]),
+            rx: Mutex::new(NetQueue::new(), "vnet rx"),
+            tx: Mutex::new(NetQueue::new(), "vnet tx"),
+        }
+    }
+
+    /// Initialize device in slot, and fill receive queue
+    pub unsafe fn init(&mut self) {
+        let mut accepted = 0;
+        self.slot.negotiate(|features| {
+            accepted = features & VIRTIO_NET_F_MAC;
+            accepted
+        });
+        if accepted == 0 {
+            panic!("virtio net doesn't provide MAC address");
+        }
+
+        let (lo, hi) = (self.slot.config(0).to_le_bytes(), self.slot.config(4).to_le_bytes());
+        self.mac = MacAddr([lo[0], lo[1], lo[2], lo[3], hi[0], hi[1]]);
+
+        let rx = self.rx.get();
+        let tx = self.tx.get();
+        self.slot.setup_queue(RECEIVE_QUEUE, &mut rx.queue);
+        self.slot.setup_queue(TRANSMIT_QUEUE, &mut tx.queue);
+
+        self.slot.driver_ok();
+
+        while rx.post(self.slot, RECEIVE_QUEUE, box Packet::new(), FRAME_SIZE, true) {}
+    }
+}
+
+impl NetInterface for VirtIONet {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> bool {
+        if frame.len() > FRAME_SIZE {
+            return false;
+        }
+        let mut packet = box Packet::new();
+        packet.data[..frame.len()].copy_from_slice(frame);
+        self.tx.lock().post(self.slot, TRANSMIT_QUEUE, packet, frame.len(), false)
+    }
+}
+
+impl VirtIODriver for VirtIONet {
+    /// Release sent buffers, refill receive queue and pass received
+    /// frames to network stack.
+    fn interrupt(&self) {
+        {
+            let mut tx = self.tx.lock();
+            while tx.take().is_some() {}
+        }
+        let mut frames = Vec::new();
+        {
+            let mut rx = self.rx.lock();
+            while let Some((packet, len)) = rx.take() {
+                let len = len.saturating_sub(core::mem::size_of::<NetHdr>()).min(FRAME_SIZE);
+                frames.push(packet.data[..len].to_vec());
+                rx.post(self.slot, RECEIVE_QUEUE, packet, FRAME_SIZE, true);
+            }
+        }
+        for frame in frames {
+            net::receive(&frame);
+        }
+    }
+}
+
+/// Initialize network device in `slot`, and attach it to network stack
+pub fn probe(slot: Slot) -> Option<&'static dyn VirtIODriver> {
+    if net::interface().is_some() {
+        return None;
+    }
+    let dev = Box::leak(box VirtIONet::new(slot));
+    unsafe { dev.init(); }
+    let dev: &'static VirtIONet = dev;
+    net::attach(dev);
+    Some(dev)
+}
@@ -0,0 +1,203 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virt-io entropy device driver
+//!
+//! One buffer is given to device at a time, whenever entropy pool or
+//! readers of `/dev/hwrng` ask for more. Bytes filled in by device are
+//! mixed into kernel entropy pool, and also kept for `/dev/hwrng`.
+
+use crate::spinlock::Mutex;
+use crate::process::WaitQueue;
+use crate::random::{self, EntropySource};
+use crate::file::{Device, DeviceHandle, POLLIN};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::*;
+
+/// Size of buffer given to device
+pub const RNG_BUF_SIZE: usize = 64;
+/// Maximum number of bytes kept for `/dev/hwrng`
+const AVAIL_MAX: usize = 4 * RNG_BUF_SIZE;
+
+pub struct RngData {
+    pub queue: VirtQueue,
+    /// buffer filled by device
+    pub buf: Box<[u8; RNG_BUF_SIZE]>,
+    /// buffer is owned by device
+    pub pending: bool,
+    /// bytes not yet read from `/dev/hwrng`
+    pub avail: VecDeque<u8>,
+}
+
+impl RngData {
+    /// Give buffer to device, unless it already has it
+    fn post(&mut self, slot: Slot) {
+        if self.pending {
+            return;
+        }
+        let id = self.queue.alloc_desc().expect("virtio rng: no free descriptor");
+        {
+            let desc = &mut self.queue.desc[id];
+            desc.addr = self.buf.as_ptr() as usize;
+            desc.len = RNG_BUF_SIZE as u32;
+            desc.flags = VRING_DESC_F_WRITE;
+            desc.next = 0;
+        }
+        self.pending = true;
+        self.queue.push(slot, 0, id);
+    }
+}
+
+/// A virtio entropy device
+pub struct VirtIORng {
+    slot: Slot,
+    data: Mutex<RngData>,
+    /// readers of `/dev/hwrng` waiting for bytes
+    readers: WaitQueue,
+}
+
+impl VirtIORng {
+    pub fn new(slot: Slot) -> Self {
+        Self {
+            slot,
+            data: Mutex::new(RngData {
+                queue: VirtQueue::new(),
+                buf: box [0; RNG_BUF_SIZE],
+                pending: false,
+                avail: VecDeque::new(),
+            }, "vrng"),
+            readers: WaitQueue::new(),
+        }
+    }
+
+    /// Initialize device in slot
+    pub unsafe fn init(&mut self) {
+        self.slot.negotiate(|_| 0);
+        self.slot.setup_queue(0, &mut self.data.get().queue);
+        self.slot.driver_ok();
+    }
+
+    fn channel(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    /// Read at least one byte from device into `content`, waiting for it.
+    /// Returns number of bytes read.
+    pub fn read(&self, content: &mut [u8]) -> usize {
+        if content.is_empty() {
+            return 0;
+        }
+        let mut data = self.data.lock();
+        while data.avail.is_empty() {
+            data.post(self.slot);
+            data = self.readers.sleep(self.channel(), data);
+        }
+        let n = content.len().min(data.avail.len());
+        for (dst, src) in content.iter_mut().zip(data.avail.drain(..n)) {
+            *dst = src;
+        }
+        n
+    }
+}
+
+impl EntropySource for VirtIORng {
+    fn request(&self) {
+        self.data.lock().post(self.slot);
+    }
+}
+
+impl VirtIODriver for VirtIORng {
+    /// Keep bytes filled in for readers, and mix them into entropy pool
+    fn interrupt(&self) {
+        let mut bytes = Vec::new();
+        {
+            let mut data = self.data.lock();
+            while let Some((id, len)) = data.queue.pop_used() {
+                data.queue.free_desc(id);
+                data.pending = false;
+                let len = (len as usize).min(RNG_BUF_SIZE);
+                bytes.extend_from_slice(&data.buf[..len]);
+            }
+            for &x in bytes.iter() {
+                if data.avail.len() == AVAIL_MAX {
+                    data.avail.pop_front();
+                }
+                data.avail.push_back(x);
+            }
+        }
+        if !bytes.is_empty() {
+            self.readers.wakeup(self.channel());
+            random::add_entropy(&bytes, bytes.len() * 8);
+        }
+    }
+}
+
+/// Entropy device found, if any
+static RNG: Mutex<Option<&'static VirtIORng>> = Mutex::new(None, "rng");
+
+/// Initialize entropy device in `slot`, and attach it to entropy pool
+pub fn probe(slot: Slot) -> Option<&'static dyn VirtIODriver> {
+    if RNG.lock().is_some() {
+        return None;
+    }
+    let rng = Box::leak(box VirtIORng::new(slot));
+    unsafe { rng.init(); }
+    let rng: &'static VirtIORng = rng;
+    *RNG.lock() = Some(rng);
+    random::attach(rng);
+    Some(rng)
+}
+
+/// `/dev/hwrng`, reading bytes from entropy device directly
+pub struct HwRng {
+    rng: &'static VirtIORng,
+}
+
+impl Device for HwRng {
+    /// read at least one byte, waiting for device
+    fn read(&self, _handle: &mut DeviceHandle, content: &mut [u8]) -> i32 {
+        self.rng.read(content) as i32
+    }
+
+    fn write(&self, _handle: &mut DeviceHandle, _content: &[u8]) -> i32 {
+        -1
+    }
+
+    fn poll(&self) -> usize {
+        if self.rng.data.lock().avail.is_empty() { 0 } else { POLLIN }
+    }
+
+    fn wait_queue(&self) -> Option<(&WaitQueue, usize)> {
+        Some((&self.rng.readers, self.rng.channel()))
+    }
+}
+
+/// Create `/dev/hwrng`, or `None` if there's no entropy device
+pub fn open_hwrng() -> Option<Arc<dyn Device>> {
+    let rng = (*RNG.lock())?;
+    Some(Arc::new(HwRng { rng }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::open_device;
+    use crate::file::device::{MAJOR_MISC, MINOR_HWRNG};
+
+    /// Test reading from device, which also feeds entropy pool
+    #[test_case]
+    fn test_hwrng() {
+        let hwrng = open_device(MAJOR_MISC, MINOR_HWRNG).expect("no virtio rng");
+        let mut buf = [0; 16];
+        let n = hwrng.read(&mut buf);
+        assert!(n > 0 && n <= 16);
+        let mut seeded = [0; 16];
+        random::fill(&mut seeded);
+        assert_ne!(seeded, [0; 16]);
+    }
+}
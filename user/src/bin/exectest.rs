@@ -0,0 +1,22 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Executed by `usertests`, which reads what is written to stdout
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use user::syscall::{exit, write};
+use user::constant::STDOUT;
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    write(STDOUT, b"exec ok");
+    exit(0);
+}
@@ -11,18 +11,30 @@
 #![feature(const_generics)]
 
 use user::println;
-use user::syscall::{fork, exec, open, dup};
+use user::syscall::{exit, fork, exec, open, dup};
 
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
-    open("/console", 0);
+    open("/dev/console", 0);
     dup(0);
     dup(0);
     println!("ready to fork!");
+    #[cfg(feature = "echo-servers")]
+    {
+        if fork() == 0 {
+            exec("/udpecho", &["udpecho"]);
+            exit(1);
+        }
+        if fork() == 0 {
+            exec("/tcpecho", &["tcpecho"]);
+            exit(1);
+        }
+    }
     let p = fork();
     if p == 0 {
         println!("calling test1...");
         exec("/test1", &["test1", "test2"]);
+        exit(1);
     } else {
         loop {}
     }
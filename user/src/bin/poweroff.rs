@@ -0,0 +1,23 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use user::println;
+use user::syscall::{exit, reboot};
+use user::constant::REBOOT_CMD_POWER_OFF;
+
+/// Write back disks and power off
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    reboot(REBOOT_CMD_POWER_OFF);
+    println!("poweroff: failed");
+    exit(1);
+}
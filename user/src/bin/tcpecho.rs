@@ -0,0 +1,54 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use user::println;
+use user::syscall::{exit, socket, bind, listen, accept, read, write, close, SockAddrIn};
+use user::constant::{AF_INET, SOCK_STREAM};
+
+/// Port forwarded from host by QEMU
+const PORT: u16 = 5555;
+
+/// Echo everything received on each TCP connection to `PORT`, serving one
+/// connection at a time
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    let sock = socket(AF_INET, SOCK_STREAM, 0);
+    if sock < 0 || bind(sock, &SockAddrIn::new([0, 0, 0, 0], PORT)) < 0 || listen(sock, 4) < 0 {
+        println!("tcpecho: cannot listen on port {}", PORT);
+        exit(1);
+    }
+    println!("tcpecho: listening on port {}", PORT);
+    let mut buf = [0; 512];
+    loop {
+        let mut peer = SockAddrIn::new([0; 4], 0);
+        let conn = accept(sock, Some(&mut peer));
+        if conn < 0 {
+            println!("tcpecho: accept failed {}", conn);
+            exit(1);
+        }
+        loop {
+            let sz = read(conn, &mut buf);
+            if sz <= 0 {
+                break;
+            }
+            let mut sent = 0;
+            while sent < sz {
+                let n = write(conn, &buf[sent as usize..sz as usize]);
+                if n < 0 {
+                    break;
+                }
+                sent += n;
+            }
+        }
+        close(conn);
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use user::println;
+use user::syscall::{exit, socket, bind, sendto, recvfrom, SockAddrIn};
+use user::constant::{AF_INET, SOCK_DGRAM};
+
+/// Port forwarded from host by QEMU
+const PORT: u16 = 5555;
+
+/// Echo every UDP datagram received on `PORT` back to its sender
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    let sock = socket(AF_INET, SOCK_DGRAM, 0);
+    if sock < 0 || bind(sock, &SockAddrIn::new([0, 0, 0, 0], PORT)) < 0 {
+        println!("udpecho: cannot bind port {}", PORT);
+        exit(1);
+    }
+    println!("udpecho: listening on port {}", PORT);
+    let mut buf = [0; 512];
+    loop {
+        let mut src = SockAddrIn::new([0; 4], 0);
+        let sz = recvfrom(sock, &mut buf, 0, Some(&mut src));
+        if sz < 0 {
+            println!("udpecho: recvfrom failed {}", sz);
+            exit(1);
+        }
+        sendto(sock, &buf[..sz as usize], 0, Some(&src));
+    }
+}
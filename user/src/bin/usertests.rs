@@ -0,0 +1,500 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Syscall conformance tests
+//!
+//! `usertests` runs as `/init` on its own disk image, prints one line for
+//! each test and a summary, and exits with number of failed tests, which
+//! becomes exit code of QEMU. Output is checked by `utils/usertests.py`.
+//!
+//! ```text
+//! usertests: running <total> tests
+//! test <name>: ok
+//! test <name>: FAIL line <line>: <condition>
+//! usertests: <passed> passed; <failed> failed
+//! ```
+//!
+//! Tests reap the children they fork, so that `wait` sees no others.
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(global_asm)]
+#![feature(format_args_nl)]
+#![feature(const_generics)]
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+use user::syscall::{exit, fork, exec, wait, pipe, kill, open, close, dup, read, write, sbrk, mknod};
+use user::syscall::{chan_create, chan_recv, futex_wait, futex_wake, shm_create, poll, PollFd, reboot};
+use user::constant::{STDOUT, ENOENT, EBADF, EAGAIN, EFAULT, EINVAL, EEXIST, ETIMEDOUT};
+use user::constant::{ECHILD, ESRCH, EPIPE};
+use user::constant::{POLLIN, POLLNVAL};
+use user::sync::{Mutex, Condvar};
+use user::thread;
+
+/// How long to wait for a child to report back
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const PAGE_SIZE: i32 = 4096;
+
+/// Condition failed at line
+struct Failure(&'static str, u32);
+
+type TestResult = Result<(), Failure>;
+
+/// Fail test if `cond` doesn't hold
+macro_rules! check {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(Failure(stringify!($cond), line!()));
+        }
+    };
+}
+
+/// Buffer passed to syscalls
+#[repr(C, align(64))]
+struct Buf([u8; 64]);
+
+impl Buf {
+    fn new() -> Self {
+        Buf([0; 64])
+    }
+}
+
+/// A line of output, written with one syscall so that it is not mixed
+/// with kernel messages
+struct Line {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let sz = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + sz].copy_from_slice(&s.as_bytes()[..sz]);
+        self.len += sz;
+        Ok(())
+    }
+}
+
+/// Print a line with `write` syscall
+fn report(args: fmt::Arguments) {
+    let mut line = Line { buf: [0; 128], len: 0 };
+    line.write_fmt(args).ok();
+    line.write_str("\n").ok();
+    write(STDOUT, &line.buf[..line.len]);
+}
+
+/// Receive a message from child on channel `chan`, into `buf`
+fn recv_from_child(chan: i32, buf: &mut Buf) -> i32 {
+    chan_recv(chan, &mut buf.0, Some(TIMEOUT)).0
+}
+
+/// Sleep for `d`, by waiting on a futex no one wakes up
+fn sleep(d: Duration) {
+    let futex = AtomicU32::new(0);
+    futex_wait(&futex, 0, Some(d));
+}
+
+/// Wait until `cond` holds, for at most `TIMEOUT`
+fn wait_until(cond: impl Fn() -> bool) -> bool {
+    for _ in 0..TIMEOUT.as_millis() {
+        if cond() {
+            return true;
+        }
+        sleep(Duration::from_millis(1));
+    }
+    cond()
+}
+
+/// Child gets 0 from fork, and has its own copy of memory
+fn test_fork() -> TestResult {
+    let chan = chan_create(1);
+    check!(chan >= 0);
+    let mut value = 1u8;
+    let pid = fork();
+    check!(pid >= 0);
+    if pid == 0 {
+        value += 1;
+        write(chan, &[value]);
+        exit(0);
+    }
+    check!(pid > 0);
+    let mut buf = Buf::new();
+    let sz = recv_from_child(chan, &mut buf);
+    check!(sz == 1);
+    check!(buf.0[0] == 2);
+    check!(value == 1);
+    check!(close(chan) == 0);
+    check!(wait(pid) == 0);
+    Ok(())
+}
+
+/// exec replaces process image and keeps file table, and returns on
+/// missing file
+fn test_exec() -> TestResult {
+    check!(exec("/nonexistent", &[]) == -ENOENT);
+    let chan = chan_create(1);
+    check!(chan >= 0);
+    let pid = fork();
+    check!(pid >= 0);
+    if pid == 0 {
+        // `exectest` reports on stdout, which is now the channel
+        close(STDOUT);
+        if dup(chan) != STDOUT {
+            exit(1);
+        }
+        exec("/exectest", &["exectest"]);
+        exit(1);
+    }
+    let mut buf = Buf::new();
+    let sz = recv_from_child(chan, &mut buf);
+    check!(sz == 7);
+    check!(&buf.0[..7] == b"exec ok");
+    check!(close(chan) == 0);
+    check!(wait(pid) == 0);
+    Ok(())
+}
+
+/// wait returns exit status of child, and fails on other processes
+fn test_wait() -> TestResult {
+    check!(wait(-1) == -ECHILD);
+    let pid = fork();
+    check!(pid >= 0);
+    if pid == 0 {
+        exit(7);
+    }
+    check!(wait(pid) == 7);
+    check!(wait(pid) == -ECHILD);
+    check!(wait(-1) == -ECHILD);
+    // a child which exits before being waited for
+    let pid = fork();
+    check!(pid >= 0);
+    if pid == 0 {
+        exit(8);
+    }
+    sleep(Duration::from_millis(10));
+    check!(wait(-1) == 8);
+    check!(wait(-2) == -ECHILD);
+    Ok(())
+}
+
+/// Data written to pipe is read from the other end, and end of file is
+/// seen after write end is closed
+fn test_pipe() -> TestResult {
+    let mut fds = [-1; 2];
+    check!(pipe(&mut fds) == 0);
+    check!(fds[0] >= 0 && fds[1] >= 0 && fds[0] != fds[1]);
+    check!(write(fds[1], b"hello") == 5);
+    let mut buf = Buf::new();
+    check!(read(fds[0], &mut buf.0) == 5);
+    check!(&buf.0[..5] == b"hello");
+    check!(write(fds[0], b"wrong end") == -EBADF);
+    check!(close(fds[1]) == 0);
+    check!(read(fds[0], &mut buf.0) == 0);
+    check!(close(fds[0]) == 0);
+    // reader blocks until child writes, and sees end of file after child exits
+    check!(pipe(&mut fds) == 0);
+    let pid = fork();
+    check!(pid >= 0);
+    if pid == 0 {
+        close(fds[0]);
+        sleep(Duration::from_millis(10));
+        write(fds[1], b"child");
+        exit(0);
+    }
+    check!(close(fds[1]) == 0);
+    check!(read(fds[0], &mut buf.0) == 5);
+    check!(&buf.0[..5] == b"child");
+    check!(read(fds[0], &mut buf.0) == 0);
+    check!(wait(pid) == 0);
+    check!(close(fds[0]) == 0);
+    // writing fails once read end is closed
+    check!(pipe(&mut fds) == 0);
+    check!(close(fds[0]) == 0);
+    check!(write(fds[1], b"broken") == -EPIPE);
+    check!(close(fds[1]) == 0);
+    Ok(())
+}
+
+/// Reads continue from file offset, and return 0 at end of file
+fn test_read() -> TestResult {
+    let fd = open("/test.txt", 0);
+    check!(fd >= 0);
+    let mut buf = Buf::new();
+    check!(read(fd, &mut buf.0[..4]) == 4);
+    check!(&buf.0[..4] == b"0123");
+    check!(read(fd, &mut buf.0[..4]) == 4);
+    check!(&buf.0[..4] == b"4567");
+    let mut total = 8;
+    loop {
+        let sz = read(fd, &mut buf.0);
+        check!(sz >= 0);
+        if sz == 0 {
+            break;
+        }
+        total += sz;
+    }
+    check!(total == 51);
+    check!(close(fd) == 0);
+    check!(read(fd, &mut buf.0) == -EBADF);
+    check!(read(-1, &mut buf.0) == -EBADF);
+    Ok(())
+}
+
+/// Writes to devices, and to descriptors which can't be written
+fn test_write() -> TestResult {
+    let null = open("/dev/null", 0);
+    check!(null >= 0);
+    check!(write(null, b"discarded") == 9);
+    check!(close(null) == 0);
+    check!(write(null, b"closed") == -EBADF);
+    let zero = open("/dev/zero", 0);
+    check!(zero >= 0);
+    let mut buf = Buf([0xff; 64]);
+    check!(read(zero, &mut buf.0) == 64);
+    check!(buf.0.iter().all(|&b| b == 0));
+    // large buffers are not rejected, but may be transferred in part
+    let mut big = [0xffu8; 4096];
+    let sz = read(zero, &mut big);
+    check!(sz > 0 && sz as usize <= big.len());
+    check!(big[..sz as usize].iter().all(|&b| b == 0));
+    check!(close(zero) == 0);
+    let fd = open("/test.txt", 0);
+    check!(fd >= 0);
+    check!(write(fd, b"read only") < 0);
+    check!(close(fd) == 0);
+    let shm = shm_create(PAGE_SIZE as usize);
+    check!(shm >= 0);
+    check!(write(shm, b"shared") == -EINVAL);
+    check!(read(shm, &mut buf.0) == -EINVAL);
+    check!(close(shm) == 0);
+    Ok(())
+}
+
+/// open fails on missing file, and close only once on open descriptors
+fn test_open_close() -> TestResult {
+    check!(open("/nonexistent", 0) < 0);
+    check!(open("/test.txt", -1) == -EINVAL);
+    let fd = open("/test.txt", 0);
+    check!(fd > STDOUT);
+    check!(close(fd) == 0);
+    check!(close(fd) == -EBADF);
+    check!(close(-1) == -EBADF);
+    check!(close(1000) == -EBADF);
+    // lowest free descriptor is reused
+    let again = open("/test.txt", 0);
+    check!(again == fd);
+    check!(close(again) == 0);
+    Ok(())
+}
+
+/// mknod fails on existing paths, and on device numbers out of range
+fn test_mknod() -> TestResult {
+    check!(mknod("/test.txt", 1, 3) == -EEXIST);
+    check!(mknod("/dev/big", 1 << 32, 0) == -EINVAL);
+    check!(mknod("/dev/big", 1, 1 << 32) == -EINVAL);
+    check!(open("/dev/big", 0) < 0);
+    Ok(())
+}
+
+/// Duplicated descriptor shares file offset, and outlives the original
+fn test_dup() -> TestResult {
+    let fd = open("/test.txt", 0);
+    check!(fd >= 0);
+    let fd2 = dup(fd);
+    check!(fd2 >= 0 && fd2 != fd);
+    let mut buf = Buf::new();
+    check!(read(fd, &mut buf.0[..2]) == 2);
+    check!(read(fd2, &mut buf.0[2..4]) == 2);
+    check!(&buf.0[..4] == b"0123");
+    check!(close(fd) == 0);
+    check!(read(fd2, &mut buf.0[..2]) == 2);
+    check!(&buf.0[..2] == b"45");
+    check!(close(fd2) == 0);
+    check!(dup(fd) == -EBADF);
+    check!(dup(-1) == -EBADF);
+    Ok(())
+}
+
+/// Heap grows and shrinks, and can't go below zero
+fn test_sbrk() -> TestResult {
+    let start = sbrk(0);
+    check!(start > 0);
+    check!(sbrk(PAGE_SIZE * 2) == start);
+    check!(sbrk(0) == start + PAGE_SIZE * 2);
+    let heap = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, (PAGE_SIZE * 2) as usize) };
+    for (i, b) in heap.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    check!(heap.iter().enumerate().all(|(i, &b)| b == i as u8));
+    check!(sbrk(-PAGE_SIZE * 2) == start + PAGE_SIZE * 2);
+    check!(sbrk(0) == start);
+    check!(sbrk(i32::min_value()) < 0);
+    check!(sbrk(0) == start);
+    Ok(())
+}
+
+/// Buffers crossing pages are copied in whole, and bad addresses are
+/// rejected with `-EFAULT` instead of crashing kernel
+fn test_user_buffer() -> TestResult {
+    let start = sbrk(PAGE_SIZE * 2);
+    check!(start > 0);
+    // buffer of 8 bytes, 4 of them in each page
+    let buf = unsafe { core::slice::from_raw_parts_mut((start + PAGE_SIZE - 4) as *mut u8, 8) };
+    let fd = open("/test.txt", 0);
+    check!(fd >= 0);
+    check!(read(fd, buf) == 8);
+    check!(buf == b"01234567");
+    check!(close(fd) == 0);
+    let null = open("/dev/null", 0);
+    check!(null >= 0);
+    check!(write(null, buf) == 8);
+    // beyond user address space, and trampoline which user can't access
+    let maxva = 1usize << 38;
+    for &addr in &[maxva, maxva - PAGE_SIZE as usize] {
+        let bad = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 8) };
+        check!(write(null, bad) == -EFAULT);
+        check!(read(null, bad) == -EFAULT);
+    }
+    check!(close(null) == 0);
+    check!(sbrk(-PAGE_SIZE * 2) == start + PAGE_SIZE * 2);
+    Ok(())
+}
+
+/// kill terminates another process, and fails on missing process
+fn test_kill() -> TestResult {
+    check!(kill(-1) == -ESRCH);
+    let pid = fork();
+    check!(pid >= 0);
+    if pid == 0 {
+        loop {}
+    }
+    check!(kill(pid) == 0);
+    check!(wait(pid) == -1);
+    check!(kill(pid) == -ESRCH);
+    Ok(())
+}
+
+/// futex_wait checks value and times out, and futex_wake wakes up only
+/// threads still sleeping
+fn test_futex() -> TestResult {
+    static FUTEX: AtomicU32 = AtomicU32::new(0);
+    static WOKEN: AtomicBool = AtomicBool::new(false);
+    check!(futex_wait(&FUTEX, 1, None) == -EAGAIN);
+    check!(futex_wait(&FUTEX, 0, Some(Duration::from_millis(10))) == -ETIMEDOUT);
+    check!(futex_wake(&FUTEX, 1) == 0);
+    // the first waiter times out, and the wakeup should go to the second one
+    let timed = thread::spawn(|| futex_wait(&FUTEX, 0, Some(Duration::from_millis(5))));
+    let waiter = thread::spawn(|| {
+        let ret = futex_wait(&FUTEX, 0, None);
+        WOKEN.store(true, Ordering::Release);
+        ret
+    });
+    sleep(Duration::from_millis(20));
+    check!(wait_until(|| futex_wake(&FUTEX, 1) == 1));
+    check!(wait_until(|| WOKEN.load(Ordering::Acquire)));
+    check!(timed.join() == -ETIMEDOUT);
+    check!(waiter.join() == 0);
+    Ok(())
+}
+
+/// Threads contending on a mutex don't lose updates, and a condition
+/// variable wakes up its waiter
+fn test_mutex() -> TestResult {
+    const ROUNDS: usize = 50;
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+    static READY: Mutex<bool> = Mutex::new(false);
+    static COND: Condvar = Condvar::new();
+    let add = || {
+        for _ in 0..ROUNDS {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            // the other thread has to sleep on the lock meanwhile
+            sleep(Duration::from_millis(1));
+            *counter = value + 1;
+        }
+    };
+    let a = thread::spawn(add);
+    let b = thread::spawn(add);
+    a.join();
+    b.join();
+    check!(*COUNTER.lock() == ROUNDS * 2);
+    let waiter = thread::spawn(|| {
+        let mut ready = READY.lock();
+        while !*ready {
+            ready = COND.wait(ready);
+        }
+    });
+    sleep(Duration::from_millis(10));
+    *READY.lock() = true;
+    COND.notify_one();
+    waiter.join();
+    Ok(())
+}
+
+/// reboot fails on invalid commands. Valid ones are tested by exit of
+/// `usertests`, which powers off in the same way.
+fn test_reboot() -> TestResult {
+    check!(reboot(0) == -EINVAL);
+    check!(reboot(!0) == -EINVAL);
+    Ok(())
+}
+
+/// poll reports invalid descriptors with `POLLNVAL`, and ignores negative ones
+fn test_poll() -> TestResult {
+    let mut fds = [PollFd::new(-1, POLLIN), PollFd::new(1000, POLLIN)];
+    check!(poll(&mut fds, Some(Duration::from_millis(0))) == 1);
+    check!(fds[0].revents == 0);
+    check!(fds[1].revents == POLLNVAL);
+    let mut fds = [PollFd::new(-1, POLLIN)];
+    check!(poll(&mut fds, Some(Duration::from_millis(1))) == 0);
+    check!(fds[0].revents == 0);
+    Ok(())
+}
+
+const TESTS: &[(&str, fn() -> TestResult)] = &[
+    ("fork", test_fork),
+    ("exec", test_exec),
+    ("wait", test_wait),
+    ("pipe", test_pipe),
+    ("read", test_read),
+    ("write", test_write),
+    ("open_close", test_open_close),
+    ("mknod", test_mknod),
+    ("dup", test_dup),
+    ("sbrk", test_sbrk),
+    ("user_buffer", test_user_buffer),
+    ("kill", test_kill),
+    ("futex", test_futex),
+    ("mutex", test_mutex),
+    ("poll", test_poll),
+    ("reboot", test_reboot),
+];
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    open("/dev/console", 0);
+    dup(0);
+    dup(0);
+    report(format_args!("usertests: running {} tests", TESTS.len()));
+    let (mut passed, mut failed) = (0, 0);
+    for (name, test) in TESTS {
+        match test() {
+            Ok(()) => {
+                passed += 1;
+                report(format_args!("test {}: ok", name));
+            }
+            Err(Failure(cond, line)) => {
+                failed += 1;
+                report(format_args!("test {}: FAIL line {}: {}", name, line, cond));
+            }
+        }
+    }
+    report(format_args!("usertests: {} passed; {} failed", passed, failed));
+    exit(failed);
+}
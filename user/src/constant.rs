@@ -8,3 +8,103 @@
 pub const STDIN: i32 = 0;
 pub const STDOUT: i32 = 1;
 pub const STDERR: i32 = 2;
+
+/// Open for reading only
+pub const O_RDONLY: i32 = 0;
+/// Open for writing only
+pub const O_WRONLY: i32 = 1;
+/// Open for reading and writing
+pub const O_RDWR: i32 = 2;
+
+/// Pages may be read
+pub const PROT_READ: i32 = 0x1;
+/// Pages may be written
+pub const PROT_WRITE: i32 = 0x2;
+/// Pages may be executed
+pub const PROT_EXEC: i32 = 0x4;
+
+/// Changes are written back to file
+pub const MAP_SHARED: i32 = 0x01;
+/// Changes are private to this process
+pub const MAP_PRIVATE: i32 = 0x02;
+/// Place mapping exactly at `addr`
+pub const MAP_FIXED: i32 = 0x10;
+/// Mapping is not backed by any file
+pub const MAP_ANONYMOUS: i32 = 0x20;
+
+/// File is readable
+pub const POLLIN: i16 = 0x1;
+/// File is writable
+pub const POLLOUT: i16 = 0x4;
+/// File descriptor is invalid
+pub const POLLNVAL: i16 = 0x20;
+
+/// Seek from start of file
+pub const SEEK_SET: i32 = 0;
+/// Seek from current offset
+pub const SEEK_CUR: i32 = 1;
+/// Seek from end of file
+pub const SEEK_END: i32 = 2;
+
+/// ioctl command to get number of bytes available for reading, which is returned
+pub const FIONREAD: usize = 0x541b;
+
+/// ioctl command to get number of 512-byte sectors of block device, which is returned
+pub const BLKGETSIZE: usize = 0x1260;
+/// ioctl command to get block size of block device, which is returned
+pub const BLKSSZGET: usize = 0x1268;
+
+/// Internet address family
+pub const AF_INET: i32 = 2;
+/// Reliable byte stream
+pub const SOCK_STREAM: i32 = 1;
+/// Unreliable datagrams
+pub const SOCK_DGRAM: i32 = 2;
+/// Return instead of waiting
+pub const MSG_DONTWAIT: i32 = 0x40;
+
+/// `reboot` commands, same as Linux
+pub const REBOOT_CMD_HALT: u32 = 0xCDEF0123;
+pub const REBOOT_CMD_POWER_OFF: u32 = 0x4321FEDC;
+pub const REBOOT_CMD_RESTART: u32 = 0x01234567;
+
+/// No such file or directory
+pub const ENOENT: i32 = 2;
+/// No such process
+pub const ESRCH: i32 = 3;
+/// Interrupted system call
+pub const EINTR: i32 = 4;
+/// Bad file descriptor
+pub const EBADF: i32 = 9;
+/// No child processes
+pub const ECHILD: i32 = 10;
+/// Try again
+pub const EAGAIN: i32 = 11;
+/// Bad address
+pub const EFAULT: i32 = 14;
+/// File exists
+pub const EEXIST: i32 = 17;
+/// Invalid argument
+pub const EINVAL: i32 = 22;
+/// Inappropriate ioctl for device
+pub const ENOTTY: i32 = 25;
+/// No space left on device
+pub const ENOSPC: i32 = 28;
+/// Broken pipe
+pub const EPIPE: i32 = 32;
+/// Function not implemented
+pub const ENOSYS: i32 = 38;
+/// Message too long
+pub const EMSGSIZE: i32 = 90;
+/// Address already in use
+pub const EADDRINUSE: i32 = 98;
+/// Network is unreachable
+pub const ENETUNREACH: i32 = 101;
+/// Connection reset by peer
+pub const ECONNRESET: i32 = 104;
+/// Socket is not connected
+pub const ENOTCONN: i32 = 107;
+/// Operation timed out
+pub const ETIMEDOUT: i32 = 110;
+/// Connection refused
+pub const ECONNREFUSED: i32 = 111;
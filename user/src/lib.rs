@@ -11,6 +11,9 @@
 pub mod print;
 pub mod syscall;
 pub mod constant;
+pub mod thread;
+pub mod sync;
+pub mod shm;
 mod syscall_internal;
 
 use core::panic::PanicInfo;
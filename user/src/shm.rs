@@ -0,0 +1,91 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Memory shared between processes
+//!
+//! # Examples
+//! ```
+//! use user::shm::SharedRegion;
+//! use user::syscall::fork;
+//! use core::sync::atomic::{AtomicUsize, Ordering};
+//! let counter = SharedRegion::new(AtomicUsize::new(0)).unwrap();
+//! if fork() == 0 {
+//!     counter.fetch_add(1, Ordering::SeqCst);
+//! }
+//! ```
+
+use crate::syscall::{shm_create, mmap, munmap, close};
+use crate::constant::*;
+use core::ops::Deref;
+use core::ptr::null_mut;
+use core::mem::size_of;
+
+/// A value of type `T` in a shared memory object
+///
+/// The region stays shared with children after `fork`, and can be mapped
+/// by other processes through its file descriptor. As it may be accessed
+/// by several processes at the same time, only shared references are
+/// given out, and `T` should synchronize itself (e.g. atomics or
+/// `sync::Mutex`). `T` should not contain pointers to private memory.
+pub struct SharedRegion<T> {
+    fd: i32,
+    ptr: *mut T,
+}
+
+impl<T: Sync> SharedRegion<T> {
+    /// Create a shared memory object holding `value`
+    pub fn new(value: T) -> Option<Self> {
+        let fd = shm_create(Self::size());
+        if fd < 0 {
+            return None;
+        }
+        let region = match unsafe { Self::from_fd(fd) } {
+            Some(region) => region,
+            None => {
+                close(fd);
+                return None;
+            }
+        };
+        unsafe { region.ptr.write(value); }
+        Some(region)
+    }
+
+    /// Map shared memory object `fd`. The region owns `fd` from now on.
+    ///
+    /// # Safety
+    ///
+    /// The object should be at least as large as `T`, and already hold
+    /// a valid value of `T`.
+    pub unsafe fn from_fd(fd: i32) -> Option<Self> {
+        let ptr = mmap(null_mut(), Self::size(), PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+        if ptr.is_null() {
+            return None;
+        }
+        Some(Self { fd, ptr: ptr as *mut T })
+    }
+
+    /// File descriptor of the shared memory object
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    fn size() -> usize {
+        size_of::<T>().max(1)
+    }
+}
+
+impl<T> Deref for SharedRegion<T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &*self.ptr } }
+}
+
+/// Unmap the region. The value is not dropped, as other processes may
+/// still be using it.
+impl<T> Drop for SharedRegion<T> {
+    fn drop(&mut self) {
+        munmap(self.ptr as *mut u8, size_of::<T>().max(1));
+        close(self.fd);
+    }
+}
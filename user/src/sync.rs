@@ -0,0 +1,202 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Synchronization primitives based on futex
+//!
+//! # Examples
+//! ```
+//! use user::sync::Mutex;
+//! static COUNTER: Mutex<usize> = Mutex::new(0);
+//! *COUNTER.lock() += 1;
+//! ```
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use crate::syscall::{futex_wait, futex_wake};
+
+/// Mutex is not locked
+const UNLOCKED: u32 = 0;
+/// Mutex is locked, and no one is waiting
+const LOCKED: u32 = 1;
+/// Mutex is locked, and there may be threads waiting
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock. Threads waiting for the lock sleep in kernel.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+/// A guard to which the protected data can be accessed
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Lock mutex and return a guard
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) != UNLOCKED {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Try to lock mutex without sleeping
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) == UNLOCKED {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Sleep until lock is obtained. The lock is marked contended,
+    /// so that its holder will wake up others on unlock.
+    fn lock_contended(&self) {
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            futex_wait(&self.state, CONTENDED, None);
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &*self.mutex.data.get() } }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.mutex.data.get() } }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable
+///
+/// # Examples
+/// ```
+/// use user::sync::{Mutex, Condvar};
+/// let ready = Mutex::new(false);
+/// let cond = Condvar::new();
+/// let mut guard = ready.lock();
+/// while !*guard {
+///     guard = cond.wait(guard);
+/// }
+/// ```
+pub struct Condvar {
+    /// incremented on each notification
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { seq: AtomicU32::new(0) }
+    }
+
+    /// Unlock `guard` and sleep until notified, then lock it again.
+    /// Spurious wakeups are possible.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_timeout(guard, None).0
+    }
+
+    /// Like `wait`, but wakes up after `timeout`. Returns whether timeout has passed.
+    pub fn wait_timeout<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, timeout: Option<Duration>) -> (MutexGuard<'a, T>, bool) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        // if notified after unlock, `seq` will have changed and we won't sleep
+        let ret = futex_wait(&self.seq, seq, timeout);
+        // there may be other waiters, so lock as contended
+        mutex.lock_contended();
+        (MutexGuard { mutex }, ret == -crate::constant::ETIMEDOUT)
+    }
+
+    /// Wake up one thread waiting on this condition variable
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex_wake(&self.seq, 1);
+    }
+
+    /// Wake up all threads waiting on this condition variable
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex_wake(&self.seq, usize::MAX);
+    }
+}
+
+/// Once is not started
+const INCOMPLETE: u32 = 0;
+/// Once is running by some thread
+const RUNNING: u32 = 1;
+/// Once has completed
+const COMPLETE: u32 = 2;
+
+/// Run an initialization routine only once
+///
+/// # Examples
+/// ```
+/// use user::sync::Once;
+/// static INIT: Once = Once::new();
+/// INIT.call_once(|| println!("initialized"));
+/// ```
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self { state: AtomicU32::new(INCOMPLETE) }
+    }
+
+    /// Run `f` if no one has called `call_once` on this object. Otherwise,
+    /// wait until the first call completes.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.state.compare_and_swap(INCOMPLETE, RUNNING, Ordering::Acquire) == INCOMPLETE {
+            f();
+            self.state.store(COMPLETE, Ordering::Release);
+            futex_wake(&self.state, usize::MAX);
+            return;
+        }
+        while self.state.load(Ordering::Acquire) == RUNNING {
+            futex_wait(&self.state, RUNNING, None);
+        }
+    }
+
+    /// Check if `call_once` has completed
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
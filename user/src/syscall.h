@@ -22,3 +22,30 @@
 #define SYS_sbrk 18
 #define SYS_sleep 19
 #define SYS_uptime 20
+#define SYS_mmap 21
+#define SYS_munmap 22
+#define SYS_setpriority 23
+#define SYS_getpriority 24
+#define SYS_cputime 25
+#define SYS_setaffinity 26
+#define SYS_getaffinity 27
+#define SYS_clone 28
+#define SYS_join 29
+#define SYS_futex_wait 30
+#define SYS_futex_wake 31
+#define SYS_shm_create 32
+#define SYS_chan_create 33
+#define SYS_chan_send 34
+#define SYS_chan_recv 35
+#define SYS_poll 36
+#define SYS_ioctl 37
+#define SYS_lseek 38
+#define SYS_socket 39
+#define SYS_bind 40
+#define SYS_connect 41
+#define SYS_sendto 42
+#define SYS_recvfrom 43
+#define SYS_listen 44
+#define SYS_accept 45
+#define SYS_reboot 46
+#define SYS_thread_exit 47
@@ -14,9 +14,12 @@
 //! Usage of syscalls is listed in their corresponding sub-page.
 
 use crate::syscall_internal::*;
-use core::ptr::null;
+use core::ptr::{null, null_mut};
+use core::time::Duration;
+use core::sync::atomic::AtomicU32;
 
-/// Exit current process with exit code `code`.
+/// Exit current process with exit code `code`. Other threads of current
+/// process exit as well.
 /// 
 /// # Examples
 ///
@@ -53,14 +56,15 @@ pub const EXEC_MAX_ARGS: usize = 10;
 /// Replace current process image with the new one
 /// in the filesystem.
 ///
-/// This function will not return.
+/// This function returns only on error, such as `-ENOENT` if `path` is
+/// not a file.
 ///
 /// # Examples
 /// ```
 /// use user::syscall::exec;
 /// exec("/init", &[]);
 /// ```
-pub fn exec(path: &str, args: &[&str]) -> ! {
+pub fn exec(path: &str, args: &[&str]) -> i32 {
     let arg_cnt = args.len();
     let mut args_sz = [0; EXEC_MAX_ARGS];
     let mut args_ptr = [null(); EXEC_MAX_ARGS];
@@ -82,6 +86,7 @@ pub fn exec(path: &str, args: &[&str]) -> ! {
 /// Write `content` to file descriptor `fd`.
 ///
 /// Returns number of characters written. A negative return value means error while writing.
+/// At most 1024 bytes are written at once, and fewer may be written than `content.len()`.
 ///
 /// # Examples
 /// ```
@@ -99,8 +104,8 @@ pub fn write(fd: i32, content: &[u8]) -> i32 {
 
 /// Read `content` from file descriptor `fd`.
 ///
-/// You may read a maximum of `content.len()` characters from `fd`.
-/// Returns number of characters read.
+/// You may read a maximum of `content.len()` characters from `fd`, and
+/// at most 1024 at once. Returns number of characters read.
 pub fn read(fd: i32, content: &mut [u8]) -> i32 {
     unsafe {
         __read(fd,
@@ -116,7 +121,7 @@ pub fn read(fd: i32, content: &mut [u8]) -> i32 {
 /// # Examples
 /// ```
 /// use user::syscall::open;
-/// let fd = open("/console", 0);
+/// let fd = open("/dev/console", 0);
 /// ```
 pub fn open(path: &str, mode: i32) -> i32 {
     unsafe {
@@ -124,6 +129,21 @@ pub fn open(path: &str, mode: i32) -> i32 {
     }
 }
 
+/// Create a device node of `major` and `minor` number at `path`.
+///
+/// Returns `-EEXIST` if `path` exists, `-EINVAL` if `path` is too long or
+/// `major` or `minor` is larger than 32 bits, and `-ENOSPC` if there's no
+/// room in file system.
+///
+/// # Examples
+/// ```
+/// use user::syscall::mknod;
+/// mknod("/dev/null", 1, 3);
+/// ```
+pub fn mknod(path: &str, major: usize, minor: usize) -> i32 {
+    unsafe { __mknod(path.as_ptr(), path.len() as i32, major, minor) }
+}
+
 /// Close a file with file descriptor `fd`.
 ///
 /// # Examples
@@ -149,6 +169,465 @@ pub fn dup(fd: i32) -> i32 {
     unsafe { __dup(fd) }
 }
 
+/// Wait for child process `pid`, or any child if `pid` is -1, to exit,
+/// and release it.
+///
+/// Returns exit status of child, which is -1 if it is killed. Returns
+/// `-ECHILD` if there is no such child.
 pub fn wait(pid: i32) -> i32 {
     unsafe { __wait(pid) }
 }
+
+/// Create a pipe, with file descriptor of read end in `fds[0]` and
+/// write end in `fds[1]`.
+///
+/// Reading from an empty pipe blocks until there is data, and returns 0
+/// once all write ends are closed. Writing to a full pipe blocks, and
+/// returns `-EPIPE` once all read ends are closed.
+pub fn pipe(fds: &mut [i32; 2]) -> i32 {
+    unsafe { __pipe(fds.as_mut_ptr()) }
+}
+
+/// Terminate process `pid`, which exits with status -1.
+///
+/// Its threads exit on their next return to user space. Returns `-ESRCH`
+/// if there is no such process.
+pub fn kill(pid: i32) -> i32 {
+    unsafe { __kill(pid) }
+}
+
+/// Grow (or shrink) heap by `increment` bytes.
+///
+/// Returns previous end of heap, which is the start of newly allocated
+/// memory. Negative value means error. Pages are allocated on first access.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sbrk;
+/// let ptr = sbrk(4096) as *mut u8;
+/// ```
+pub fn sbrk(increment: i32) -> i32 {
+    unsafe { __sbrk(increment) }
+}
+
+/// Map `len` bytes of file `fd` from `offset` into memory, or anonymous
+/// memory if `MAP_ANONYMOUS` is set in `flags`.
+///
+/// `addr` is a hint of where to place the mapping, unless `MAP_FIXED` is set.
+/// `prot` is a combination of `PROT_READ`, `PROT_WRITE` and `PROT_EXEC`.
+/// Exactly one of `MAP_SHARED` and `MAP_PRIVATE` should be set in `flags`.
+/// Changes to a `MAP_SHARED` file mapping are written back on `munmap` and exit.
+///
+/// Returns start address of mapping. Null pointer means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::mmap;
+/// use user::constant::*;
+/// let ptr = mmap(null_mut(), 4096, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+/// ```
+pub fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: usize) -> *mut u8 {
+    let ret = unsafe { __mmap(addr, len, prot, flags, fd, offset) };
+    if ret < 0 {
+        null_mut()
+    } else {
+        ret as *mut u8
+    }
+}
+
+/// Remove memory mappings in `[addr, addr + len)`.
+///
+/// Returns `-EINVAL` if `addr` is not page aligned, or the range is empty
+/// or overflows.
+///
+/// # Examples
+/// ```
+/// use user::syscall::munmap;
+/// munmap(ptr, 4096);
+/// ```
+pub fn munmap(addr: *mut u8, len: usize) -> i32 {
+    unsafe { __munmap(addr, len) }
+}
+
+/// Set nice value of process `pid` (or current process if `pid` is 0) to `nice`.
+///
+/// `nice` ranges from -20 (highest priority) to 19 (lowest priority).
+/// Negative return value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::setpriority;
+/// setpriority(0, 10);
+/// ```
+pub fn setpriority(pid: i32, nice: i32) -> i32 {
+    unsafe { __setpriority(pid, nice) }
+}
+
+/// Get nice value of process `pid`, or current process if `pid` is 0.
+///
+/// Returns `None` if there's no such process.
+pub fn getpriority(pid: i32) -> Option<i32> {
+    let ret = unsafe { __getpriority(pid) };
+    if ret < 0 {
+        None
+    } else {
+        Some(20 - ret)
+    }
+}
+
+/// Add `inc` to nice value of current process, and returns new nice value.
+///
+/// # Examples
+/// ```
+/// use user::syscall::nice;
+/// nice(5);
+/// ```
+pub fn nice(inc: i32) -> i32 {
+    let nice = (getpriority(0).unwrap() + inc).max(-20).min(19);
+    setpriority(0, nice);
+    nice
+}
+
+/// Get time current process has been running on CPU.
+pub fn cputime() -> Duration {
+    Duration::from_micros(unsafe { __cputime() } as u64)
+}
+
+/// Restrict current process to run on harts in bit mask `mask`.
+///
+/// Negative return value means none of these harts is online.
+///
+/// # Examples
+/// ```
+/// use user::syscall::setaffinity;
+/// // run only on hart 1
+/// setaffinity(1 << 1);
+/// ```
+pub fn setaffinity(mask: usize) -> i32 {
+    unsafe { __setaffinity(mask) }
+}
+
+/// Get bit mask of harts current process may run on.
+pub fn getaffinity() -> usize {
+    unsafe { __getaffinity() as usize }
+}
+
+/// Create a thread sharing memory and files with current process.
+///
+/// The thread runs `entry(arg)` on `stack`, which is the top of its stack.
+/// `entry` should never return, and should call `thread_exit` instead.
+/// Returns thread id. Negative value means error.
+///
+/// See `thread::spawn` for a safe interface.
+pub fn clone(entry: extern "C" fn(usize) -> !, stack: *mut u8, arg: usize) -> i32 {
+    unsafe { __clone(entry, stack, arg) }
+}
+
+/// Exit current thread with exit code `code`, which may be got by `join`.
+/// Other threads of current process keep running.
+pub fn thread_exit(code: i32) -> ! {
+    unsafe { __thread_exit(code) }
+}
+
+/// Wait for thread `tid` to exit, and returns its exit status.
+///
+/// Returns `None` if `tid` is not a thread of current process.
+pub fn join(tid: i32) -> Option<i32> {
+    let mut status = 0;
+    if unsafe { __join(tid, &mut status) } < 0 {
+        None
+    } else {
+        Some(status)
+    }
+}
+
+/// Timeout in microseconds passed to kernel. Negative value means no timeout.
+fn timeout_micros(timeout: Option<Duration>) -> i64 {
+    match timeout {
+        Some(t) => t.as_micros() as i64,
+        None => -1
+    }
+}
+
+/// Sleep if `futex` contains `val`, until woken up by `futex_wake` or
+/// `timeout` has passed.
+///
+/// Returns 0 if woken up, `-EAGAIN` if `futex` doesn't contain `val`,
+/// `-ETIMEDOUT` on timeout, and `-EINVAL` if `futex` can't be accessed.
+/// Spurious wakeups are possible.
+///
+/// # Examples
+/// ```
+/// use user::syscall::futex_wait;
+/// let futex = AtomicU32::new(0);
+/// futex_wait(&futex, 0, None);
+/// ```
+pub fn futex_wait(futex: &AtomicU32, val: u32, timeout: Option<Duration>) -> i32 {
+    unsafe { __futex_wait(futex as *const _ as *const u32, val, timeout_micros(timeout)) }
+}
+
+/// Wake up at most `n` threads waiting on `futex`.
+///
+/// Returns number of threads woken up.
+pub fn futex_wake(futex: &AtomicU32, n: usize) -> i32 {
+    unsafe { __futex_wake(futex as *const _ as *const u32, n) }
+}
+
+/// Create a shared memory object of `size` bytes, filled with zero.
+///
+/// Returns file descriptor of the object, which can be mapped with `mmap`
+/// and `MAP_SHARED`. Negative value means error.
+///
+/// See `shm::SharedRegion` for a typed interface.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{shm_create, mmap};
+/// use user::constant::*;
+/// let fd = shm_create(4096);
+/// let ptr = mmap(null_mut(), 4096, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+/// ```
+pub fn shm_create(size: usize) -> i32 {
+    unsafe { __shm_create(size) }
+}
+
+/// Create a channel holding at most `capacity` messages.
+///
+/// Returns file descriptor of the channel. `read` and `write` on it receive
+/// and send one message without timeout. Negative value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{chan_create, write};
+/// let fd = chan_create(16);
+/// write(fd, b"ping");
+/// ```
+pub fn chan_create(capacity: usize) -> i32 {
+    unsafe { __chan_create(capacity) }
+}
+
+/// Send `content` as one message to channel `fd`, waiting at most `timeout`
+/// if channel is full. File `pass_fd` is passed along with the message,
+/// and stays open in current process.
+///
+/// Returns number of bytes sent, `-EMSGSIZE` if message is too large and
+/// `-ETIMEDOUT` on timeout.
+pub fn chan_send(fd: i32, content: &[u8], pass_fd: Option<i32>, timeout: Option<Duration>) -> i32 {
+    unsafe {
+        __chan_send(fd, content.as_ptr(), content.len(), pass_fd.unwrap_or(-1), timeout_micros(timeout))
+    }
+}
+
+/// Receive a message from channel `fd` into `content`, waiting at most
+/// `timeout` if channel is empty. Message longer than `content` is truncated.
+///
+/// Returns number of bytes received (`-ETIMEDOUT` on timeout), and new file
+/// descriptor of the file passed with message.
+///
+/// # Examples
+/// ```
+/// use user::syscall::chan_recv;
+/// let mut buf = [0; 64];
+/// let (sz, fd) = chan_recv(chan, &mut buf, None);
+/// ```
+pub fn chan_recv(fd: i32, content: &mut [u8], timeout: Option<Duration>) -> (i32, Option<i32>) {
+    let mut pass_fd = -1;
+    let ret = unsafe {
+        __chan_recv(fd, content.as_mut_ptr(), content.len(), &mut pass_fd, timeout_micros(timeout))
+    };
+    (ret, if pass_fd < 0 { None } else { Some(pass_fd) })
+}
+
+/// File descriptor to be polled
+#[repr(C)]
+pub struct PollFd {
+    pub fd: i32,
+    /// events to wait for, e.g. `POLLIN`
+    pub events: i16,
+    /// events ready, set by `poll`
+    pub revents: i16,
+}
+
+impl PollFd {
+    pub const fn new(fd: i32, events: i16) -> Self {
+        Self { fd, events, revents: 0 }
+    }
+}
+
+/// Wait until any file descriptor in `fds` is ready for its events, or
+/// `timeout` has passed.
+///
+/// Returns number of file descriptors ready, which is 0 on timeout.
+/// Invalid file descriptors are reported with `POLLNVAL`, and negative ones
+/// are ignored, with `revents` of 0.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{poll, PollFd};
+/// use user::constant::*;
+/// let mut fds = [PollFd::new(STDIN, POLLIN), PollFd::new(chan, POLLIN)];
+/// poll(&mut fds, None);
+/// ```
+pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>) -> i32 {
+    unsafe { __poll(fds.as_mut_ptr(), fds.len(), timeout_micros(timeout)) }
+}
+
+/// Device-specific control operation `cmd` with argument `arg` on device `fd`.
+///
+/// Returns `-ENOTTY` if device doesn't support `cmd`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::ioctl;
+/// use user::constant::*;
+/// let available = ioctl(STDIN, FIONREAD, 0);
+/// ```
+pub fn ioctl(fd: i32, cmd: usize, arg: usize) -> i32 {
+    unsafe { __ioctl(fd, cmd, arg) }
+}
+
+/// Move offset of seekable device `fd` to `offset` relative to `whence`
+/// (`SEEK_SET`, `SEEK_CUR` or `SEEK_END`).
+///
+/// Returns new offset. Negative value means device is not seekable or
+/// offset is invalid.
+///
+/// # Examples
+/// ```
+/// use user::syscall::lseek;
+/// use user::constant::*;
+/// let size = lseek(fd, 0, SEEK_END);
+/// ```
+pub fn lseek(fd: i32, offset: isize, whence: i32) -> i64 {
+    unsafe { __lseek(fd, offset, whence) }
+}
+
+/// Socket address, same as `struct sockaddr_in` in Linux
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SockAddrIn {
+    pub family: u16,
+    /// port in network byte order
+    pub port: u16,
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub const fn new(addr: [u8; 4], port: u16) -> Self {
+        Self { family: crate::constant::AF_INET as u16, port: port.to_be(), addr, zero: [0; 8] }
+    }
+
+    /// Port in host byte order
+    pub fn port(&self) -> u16 {
+        u16::from_be(self.port)
+    }
+}
+
+/// Create a socket of `domain` (`AF_INET`), `ty` (`SOCK_DGRAM`) and
+/// `protocol`, where 0 means default protocol of `ty`.
+///
+/// Returns file descriptor of socket.
+///
+/// # Examples
+/// ```
+/// use user::syscall::socket;
+/// use user::constant::*;
+/// let sock = socket(AF_INET, SOCK_DGRAM, 0);
+/// ```
+pub fn socket(domain: i32, ty: i32, protocol: i32) -> i32 {
+    unsafe { __socket(domain, ty, protocol) }
+}
+
+/// Bind socket `fd` to local address `addr`. Port 0 means an unused port.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{bind, SockAddrIn};
+/// bind(sock, &SockAddrIn::new([0, 0, 0, 0], 5555));
+/// ```
+pub fn bind(fd: i32, addr: &SockAddrIn) -> i32 {
+    unsafe { __bind(fd, addr) }
+}
+
+/// Set default destination of datagram socket `fd`. Only datagrams from
+/// `addr` will be received afterwards.
+///
+/// Stream socket is connected to `addr`, waiting until connection is
+/// established. Returns `-ECONNREFUSED` if nobody listens on `addr`.
+pub fn connect(fd: i32, addr: &SockAddrIn) -> i32 {
+    unsafe { __connect(fd, addr) }
+}
+
+/// Send `content` through socket `fd` to `addr`, or to connected peer if
+/// `addr` is `None`.
+///
+/// Returns number of bytes sent.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{sendto, SockAddrIn};
+/// sendto(sock, b"hello", 0, Some(&SockAddrIn::new([10, 0, 2, 2], 5555)));
+/// ```
+pub fn sendto(fd: i32, content: &[u8], flags: i32, addr: Option<&SockAddrIn>) -> i32 {
+    let addr = addr.map_or(null(), |addr| addr as *const _);
+    unsafe { __sendto(fd, content.as_ptr(), content.len(), flags, addr) }
+}
+
+/// Receive a datagram from socket `fd` into `content`, and write its
+/// source to `addr` if given. With `MSG_DONTWAIT` in `flags`, returns
+/// `-EAGAIN` instead of waiting.
+///
+/// Returns number of bytes received. Longer datagram is truncated. For
+/// stream socket, 0 means peer has closed connection.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{recvfrom, SockAddrIn};
+/// let mut buf = [0; 64];
+/// let mut src = SockAddrIn::new([0; 4], 0);
+/// let sz = recvfrom(sock, &mut buf, 0, Some(&mut src));
+/// ```
+pub fn recvfrom(fd: i32, content: &mut [u8], flags: i32, addr: Option<&mut SockAddrIn>) -> i32 {
+    let addr = addr.map_or(null_mut(), |addr| addr as *mut _);
+    unsafe { __recvfrom(fd, content.as_mut_ptr(), content.len(), flags, addr) }
+}
+
+/// Listen for connections on stream socket `fd`, keeping at most
+/// `backlog` of them until accepted.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{socket, bind, listen, SockAddrIn};
+/// use user::constant::{AF_INET, SOCK_STREAM};
+/// let sock = socket(AF_INET, SOCK_STREAM, 0);
+/// bind(sock, &SockAddrIn::new([0, 0, 0, 0], 8080));
+/// listen(sock, 4);
+/// ```
+pub fn listen(fd: i32, backlog: i32) -> i32 {
+    unsafe { __listen(fd, backlog) }
+}
+
+/// Accept a connection on listening socket `fd`, and write address of peer
+/// to `addr` if given. Waits until a connection is established.
+///
+/// Returns file descriptor of connected socket.
+pub fn accept(fd: i32, addr: Option<&mut SockAddrIn>) -> i32 {
+    let addr = addr.map_or(null_mut(), |addr| addr as *mut _);
+    unsafe { __accept(fd, addr) }
+}
+
+/// Halt, power off or restart system by `cmd`, such as
+/// `REBOOT_CMD_POWER_OFF`. Disks are written back first.
+///
+/// Returns `-EINVAL` only if `cmd` is invalid.
+///
+/// # Examples
+/// ```
+/// use user::syscall::reboot;
+/// use user::constant::REBOOT_CMD_POWER_OFF;
+/// reboot(REBOOT_CMD_POWER_OFF);
+/// ```
+pub fn reboot(cmd: u32) -> i32 {
+    unsafe { __reboot(cmd) }
+}
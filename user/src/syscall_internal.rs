@@ -10,6 +10,8 @@
 //! transmuted into pointers in `syscall` module, and then
 //! this module will finally trap into kernel.
 
+use crate::syscall::{PollFd, SockAddrIn};
+
 global_asm!(include_str!("usys.S"));
 
 extern "C" {
@@ -17,9 +19,40 @@ extern "C" {
     pub fn __read(fd: i32, content: *mut u8, sz: i32) -> i32;
     pub fn __exit(code: i32) -> !;
     pub fn __fork() -> i32;
-    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> !;
+    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> i32;
     pub fn __open(path: *const u8, sz: i32, mode: i32) -> i32;
     pub fn __close(fd: i32) -> i32;
+    pub fn __mknod(path: *const u8, sz: i32, major: usize, minor: usize) -> i32;
     pub fn __dup(fd: i32) -> i32;
     pub fn __wait(pid: i32) -> i32;
+    pub fn __pipe(fds: *mut i32) -> i32;
+    pub fn __kill(pid: i32) -> i32;
+    pub fn __sbrk(increment: i32) -> i32;
+    pub fn __mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: usize) -> isize;
+    pub fn __munmap(addr: *mut u8, len: usize) -> i32;
+    pub fn __setpriority(pid: i32, nice: i32) -> i32;
+    pub fn __getpriority(pid: i32) -> i32;
+    pub fn __cputime() -> i64;
+    pub fn __setaffinity(mask: usize) -> i32;
+    pub fn __getaffinity() -> i64;
+    pub fn __clone(entry: extern "C" fn(usize) -> !, stack: *mut u8, arg: usize) -> i32;
+    pub fn __join(tid: i32, status: *mut i32) -> i32;
+    pub fn __futex_wait(addr: *const u32, val: u32, timeout: i64) -> i32;
+    pub fn __futex_wake(addr: *const u32, n: usize) -> i32;
+    pub fn __shm_create(size: usize) -> i32;
+    pub fn __chan_create(capacity: usize) -> i32;
+    pub fn __chan_send(fd: i32, content: *const u8, sz: usize, pass_fd: i32, timeout: i64) -> i32;
+    pub fn __chan_recv(fd: i32, content: *mut u8, sz: usize, fd_out: *mut i32, timeout: i64) -> i32;
+    pub fn __poll(fds: *mut PollFd, n: usize, timeout: i64) -> i32;
+    pub fn __ioctl(fd: i32, cmd: usize, arg: usize) -> i32;
+    pub fn __lseek(fd: i32, offset: isize, whence: i32) -> i64;
+    pub fn __socket(domain: i32, ty: i32, protocol: i32) -> i32;
+    pub fn __bind(fd: i32, addr: *const SockAddrIn) -> i32;
+    pub fn __connect(fd: i32, addr: *const SockAddrIn) -> i32;
+    pub fn __sendto(fd: i32, content: *const u8, sz: usize, flags: i32, addr: *const SockAddrIn) -> i32;
+    pub fn __recvfrom(fd: i32, content: *mut u8, sz: usize, flags: i32, addr: *mut SockAddrIn) -> i32;
+    pub fn __listen(fd: i32, backlog: i32) -> i32;
+    pub fn __accept(fd: i32, addr: *mut SockAddrIn) -> i32;
+    pub fn __reboot(cmd: u32) -> i32;
+    pub fn __thread_exit(code: i32) -> !;
 }
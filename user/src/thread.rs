@@ -0,0 +1,87 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Threads sharing memory with current process
+//!
+//! # Examples
+//! ```
+//! use user::thread;
+//! let handle = thread::spawn(|| 1 + 1);
+//! assert_eq!(handle.join(), 2);
+//! ```
+
+use crate::syscall::{clone, join, thread_exit, mmap, munmap};
+use crate::constant::*;
+use core::ptr::null_mut;
+use core::mem::{size_of, align_of};
+
+/// Size of user stack of each thread
+pub const STACK_SIZE: usize = 16 * 4096;
+
+/// Closure to run and its result, placed at the top of thread stack
+struct Packet<F, T> {
+    f: Option<F>,
+    result: Option<T>,
+}
+
+/// Handle to wait for a thread and get its result
+pub struct JoinHandle<T> {
+    tid: i32,
+    stack: *mut u8,
+    result: *mut Option<T>,
+}
+
+extern "C" fn thread_start<F: FnOnce() -> T, T>(packet: usize) -> ! {
+    let packet = unsafe { &mut *(packet as *mut Packet<F, T>) };
+    let f = packet.f.take().unwrap();
+    packet.result = Some(f());
+    thread_exit(0)
+}
+
+/// Spawn a thread running `f`.
+///
+/// # Panics
+///
+/// Panics if stack of the thread can't be allocated or thread can't be created.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let stack = mmap(null_mut(), STACK_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if stack.is_null() {
+        panic!("failed to allocate thread stack");
+    }
+    let size = size_of::<Packet<F, T>>();
+    if size >= STACK_SIZE / 2 {
+        panic!("closure too large");
+    }
+    let packet_addr = (stack as usize + STACK_SIZE - size) & !(align_of::<Packet<F, T>>() - 1);
+    let packet = packet_addr as *mut Packet<F, T>;
+    unsafe { packet.write(Packet { f: Some(f), result: None }); }
+    // stack pointer should be aligned to 16 bytes
+    let sp = packet_addr & !15;
+    let tid = clone(thread_start::<F, T>, sp as *mut u8, packet_addr);
+    if tid < 0 {
+        panic!("failed to create thread");
+    }
+    JoinHandle {
+        tid,
+        stack,
+        result: unsafe { &mut (*packet).result },
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Thread id
+    pub fn tid(&self) -> i32 {
+        self.tid
+    }
+
+    /// Wait for the thread to finish, and returns result of its closure
+    pub fn join(self) -> T {
+        join(self.tid).unwrap();
+        let result = unsafe { (*self.result).take().unwrap() };
+        munmap(self.stack, STACK_SIZE);
+        result
+    }
+}
@@ -0,0 +1,57 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+###
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+"""Generate symbol table embedded in kernel for backtraces on panic.
+
+Reads output of `nm -n -C --defined-only kernel.elf` on stdin, and prints
+assembly of section `.ksyms`, which holds one entry of address, name and
+name length for each function sorted by address, and section `.ksyms.str`
+holding names. See `kernel/src/backtrace.rs`.
+"""
+
+import re
+import sys
+
+# text symbols, global, local and weak
+TEXT_TYPES = "tTW"
+# hash appended to Rust symbols, which is left by some demanglers
+RUST_HASH = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def parse(lines):
+    """Returns list of (address, name) of functions, sorted by address.
+    Only the first name is kept for each address."""
+    symbols = {}
+    for line in lines:
+        parts = line.rstrip("\n").split(" ", 2)
+        if len(parts) != 3:
+            continue
+        addr, kind, name = parts
+        if kind not in TEXT_TYPES or name.startswith((".L", "$")):
+            continue
+        symbols.setdefault(int(addr, 16), RUST_HASH.sub("", name))
+    return sorted(symbols.items())
+
+
+def escape(name):
+    return name.replace("\\", "\\\\").replace("\"", "\\\"")
+
+
+def main():
+    symbols = parse(sys.stdin)
+    print("# generated by utils/ksyms.py")
+    print(".section .ksyms, \"a\"")
+    print(".balign 8")
+    for i, (addr, name) in enumerate(symbols):
+        print(".dword 0x{:x}, .Lksym{}, {}".format(addr, i, len(name.encode())))
+    print(".section .ksyms.str, \"a\"")
+    for i, (_, name) in enumerate(symbols):
+        print(".Lksym{}: .ascii \"{}\"".format(i, escape(name)))
+
+
+if __name__ == "__main__":
+    main()
@@ -1,6 +1,5 @@
 symbols = [
     "HEAP_START",
-    "HEAP_SIZE",
     "TEXT_START",
     "TEXT_END",
     "RODATA_START",
@@ -11,5 +10,7 @@ symbols = [
     "BSS_END",
     "KERNEL_STACK_START",
     "KERNEL_STACK_END",
-    "TRAMPOLINE_TEXT_START"
+    "TRAMPOLINE_TEXT_START",
+    "KSYMS_START",
+    "KSYMS_END"
 ]
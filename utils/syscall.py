@@ -19,5 +19,32 @@ syscalls = [
     "getpid",
     "sbrk",
     "sleep",
-    "uptime"
+    "uptime",
+    "mmap",
+    "munmap",
+    "setpriority",
+    "getpriority",
+    "cputime",
+    "setaffinity",
+    "getaffinity",
+    "clone",
+    "join",
+    "futex_wait",
+    "futex_wake",
+    "shm_create",
+    "chan_create",
+    "chan_send",
+    "chan_recv",
+    "poll",
+    "ioctl",
+    "lseek",
+    "socket",
+    "bind",
+    "connect",
+    "sendto",
+    "recvfrom",
+    "listen",
+    "accept",
+    "reboot",
+    "thread_exit"
 ]
@@ -0,0 +1,43 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+### 
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+"""Exchange data over a TCP connection with tcpecho running in QEMU.
+
+Port 5555 on host is forwarded to the guest by `make qemu ECHO=1`.
+"""
+
+import socket
+import sys
+
+PORT = 5555
+
+
+def main():
+    sock = socket.create_connection(("127.0.0.1", PORT), timeout=5)
+    # larger than one segment and one receive buffer
+    msg = bytes(i % 251 for i in range(20000))
+    sock.sendall(msg)
+    data = b""
+    try:
+        while len(data) < len(msg):
+            chunk = sock.recv(4096)
+            if not chunk:
+                break
+            data += chunk
+    except socket.timeout:
+        print("timeout waiting for echo, received {} bytes".format(len(data)))
+        return 1
+    sock.close()
+    if data != msg:
+        print("mismatch: sent {} bytes, received {} bytes".format(len(msg), len(data)))
+        return 1
+    print("tcp echo: ok")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
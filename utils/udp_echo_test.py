@@ -0,0 +1,39 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+### 
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+"""Exchange UDP datagrams with udpecho running in QEMU.
+
+Port 5555 on host is forwarded to the guest by `make qemu ECHO=1`.
+"""
+
+import socket
+import sys
+
+PORT = 5555
+
+
+def main():
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    sock.settimeout(2)
+    for i in range(5):
+        msg = "hello core-os {}".format(i).encode()
+        sock.sendto(msg, ("127.0.0.1", PORT))
+        try:
+            data, _ = sock.recvfrom(1024)
+        except socket.timeout:
+            print("timeout waiting for echo")
+            return 1
+        if data != msg:
+            print("mismatch: sent {!r}, received {!r}".format(msg, data))
+            return 1
+        print("received {!r}".format(data))
+    print("udp echo: ok")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
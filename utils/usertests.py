@@ -0,0 +1,101 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+###
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+"""Boot QEMU with usertests as init, and check its serial output.
+
+Usage: usertests.py <qemu> <qemu options...>, as run by `make usertests`.
+Exits with 0 only if every test passed, summary matches the per-test
+results, and QEMU exited with number of failed tests. A test that reports
+anything other than `ok`, or doesn't report at all, fails the run.
+"""
+
+import re
+import subprocess
+import sys
+import threading
+
+TIMEOUT = 300
+
+# kernel messages may come before results on the same line
+RUNNING = re.compile(r"usertests: running (\d+) tests$")
+RESULT = re.compile(r"test (\w+): (\w+)(?: (.*))?$")
+SUMMARY = re.compile(r"usertests: (\d+) passed; (\d+) failed$")
+
+
+def run(cmd):
+    """Run QEMU, echoing and returning its output and exit code. Exit code
+    is None if QEMU is killed on timeout."""
+    proc = subprocess.Popen(cmd, stdin=subprocess.DEVNULL, stdout=subprocess.PIPE,
+                            stderr=subprocess.STDOUT)
+    timer = threading.Timer(TIMEOUT, proc.kill)
+    timer.start()
+    lines = []
+    for raw in proc.stdout:
+        line = raw.decode("utf-8", "replace").rstrip("\r\n")
+        print(line, flush=True)
+        lines.append(line)
+    code = proc.wait()
+    timed_out = not timer.is_alive()
+    timer.cancel()
+    return lines, None if timed_out else code
+
+
+def check(lines, code):
+    """Returns list of problems found in output"""
+    problems = []
+    results = {}
+    total = None
+    summary = None
+    for line in lines:
+        m = RUNNING.search(line)
+        if m:
+            total = int(m.group(1))
+        m = RESULT.search(line)
+        if m:
+            name, status, detail = m.groups()
+            if name in results:
+                problems.append("test {} reported twice".format(name))
+            results[name] = (status, detail)
+        m = SUMMARY.search(line)
+        if m:
+            summary = tuple(int(n) for n in m.groups())
+    if summary is None:
+        problems.append("no summary, usertests didn't finish")
+        return problems
+    counts = tuple(sum(1 for s, _ in results.values() if s == status)
+                   for status in ("ok", "FAIL"))
+    if counts != summary:
+        problems.append("summary {} doesn't match results {}".format(summary, counts))
+    for name, (status, detail) in sorted(results.items()):
+        if status != "ok":
+            problems.append("test {} {}: {}".format(name, status, detail))
+    if total is None or summary[0] != total:
+        problems.append("{} of {} tests passed".format(summary[0], total))
+    if code != summary[1]:
+        problems.append("QEMU exited with {}, expected {}".format(code, summary[1]))
+    return problems
+
+
+def main():
+    if len(sys.argv) < 2:
+        print(__doc__)
+        return 2
+    lines, code = run(sys.argv[1:])
+    if code is None:
+        print("usertests: timeout after {} seconds".format(TIMEOUT))
+        return 1
+    problems = check(lines, code)
+    for problem in problems:
+        print("usertests: " + problem)
+    if problems:
+        return 1
+    print("usertests: ok")
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())